@@ -0,0 +1,203 @@
+//! Asynchronous translation with the Textsynth server (feature `async`).
+//!
+//! The chunks are sent concurrently to the server. The other steps
+//! (preamble, subfiles...) use the blocking client and must be called
+//! outside of the async runtime.
+
+use std::sync::Mutex;
+
+use futures::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::backend::is_transient_status;
+use crate::{
+    restore_commands, ts_answer, ts_request, ChunkMeta, ChunkRequest, CompletionParams,
+    ProgressEvent, RateLimitState, RetryPolicy, TextsynthBackend, Trsltx, TrsltxError,
+};
+
+/// Same as `backend::send_with_backoff`, with the async client of reqwest
+async fn send_with_backoff_async(
+    request: reqwest::RequestBuilder,
+    retry: &RetryPolicy,
+) -> Result<reqwest::Response, String> {
+    let mut attempt = 0;
+    loop {
+        let req = request
+            .try_clone()
+            .ok_or("The request cannot be sent again")?;
+        let error = match req.send().await {
+            Ok(res) if is_transient_status(res.status()) => format!("HTTP status {}", res.status()),
+            Ok(res) if !res.status().is_success() => {
+                let status = res.status();
+                return Err(format!(
+                    "Request error: HTTP status {}: {}",
+                    status,
+                    res.text().await.unwrap_or_default()
+                ));
+            }
+            Ok(res) => return Ok(res),
+            Err(e) if e.is_timeout() || e.is_connect() => format!("{:?}", e),
+            Err(e) => return Err(format!("Failed to send request: {:?}", e)),
+        };
+        if attempt >= retry.max_http_retries {
+            return Err(format!(
+                "Failed to send request after {} retries: {}",
+                attempt, error
+            ));
+        }
+        let wait = retry.backoff(attempt);
+        warn!(
+            "Transient error ({}), retry {}/{} in {} s",
+            error,
+            attempt + 1,
+            retry.max_http_retries,
+            wait.as_secs_f32()
+        );
+        tokio::time::sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+/// Same as `TextsynthBackend::complete`, with the async client of reqwest.
+/// The rate limit is shared with the blocking requests of the backend.
+async fn complete_with_ts_async(
+    backend: &TextsynthBackend,
+    prompt: &str,
+    grammar: &Option<String>,
+    params: &CompletionParams,
+) -> Result<String, String> {
+    if let Some(wait) = backend.rate_limit_wait() {
+        tokio::time::sleep(wait).await;
+    }
+    let params = backend.params(params);
+    let (url, api_key, req) = ts_request(prompt, grammar, &params)?;
+    info!("Translate with {}", params.model);
+    let client = reqwest::Client::new();
+    let request = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", api_key))
+        .json(&req);
+    let res = send_with_backoff_async(request, &params.retry).await?;
+    backend.set_rate_limit(RateLimitState::from_headers(res.headers()));
+    ts_answer(res.json::<serde_json::Value>().await)
+}
+
+/// Same as `translate_one_chunk`, with the async client of the Textsynth server
+async fn translate_one_chunk_async(
+    backend: &TextsynthBackend,
+    chunk: &str,
+    trsltx: &Trsltx,
+    meta: &mut ChunkMeta,
+    max_tokens: usize,
+) -> Result<String, String> {
+    debug!("Translating chunk: {:?}", chunk);
+    let Some(request) = ChunkRequest::new(chunk, trsltx, max_tokens, None)? else {
+        debug!("Empty chunk");
+        return Ok("\\commandevide\n".to_string());
+    };
+    let mut distmin = usize::MAX;
+    let mut iter = 0;
+    let mut trs_chunk = "".to_string();
+    let itermax = trsltx.retry_policy.max_attempts;
+    meta.used_grammar = request.grammar.is_some() && itermax > 1;
+    while distmin > 1 && iter < itermax {
+        meta.attempts += 1;
+        if iter > 0 {
+            trsltx.report(ProgressEvent::ChunkRetried {
+                index: meta.index,
+                attempt: meta.attempts as u32,
+            });
+        }
+        let answer = match complete_with_ts_async(
+            backend,
+            request.question.as_str(),
+            &request.grammar(iter, itermax).map(|gr| gr.to_string()),
+            &request.params,
+        )
+        .await
+        {
+            Ok(answer) => answer,
+            // keep the translation of the previous attempts
+            Err(e) if iter > 0 => {
+                warn!(
+                    "attempt {} failed, keep the previous translation: {}",
+                    iter + 1,
+                    e
+                );
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let (trs_try, dist) = request.evaluate(&answer);
+        if dist < distmin {
+            distmin = dist;
+            trs_chunk = trs_try;
+        }
+        iter += 1;
+    }
+    if iter > 0 {
+        meta.distmin = Some(distmin);
+    }
+    let trs_chunk = trsltx.correct_glossary(chunk, trs_chunk, meta);
+    Ok(restore_commands(&trs_chunk, &request.originals))
+}
+
+impl Trsltx {
+    /// Same as `translate_chunks`, but the chunks are translated concurrently
+    /// with the async client of the Textsynth server.
+    /// At most `jobs` chunks (see `TrsltxBuilder::jobs`) are translated at the same time.
+    /// `prepare_translation` should be called before.
+    /// Returns `TrsltxError::Config` if the backend is not Textsynth: then use `translate_chunks`
+    /// outside of the async runtime.
+    pub async fn translate_chunks_async(&mut self) -> Result<(), TrsltxError> {
+        let backend = self.backend.clone();
+        let Some(backend) = backend.as_textsynth() else {
+            return Err(TrsltxError::Config(
+                "The async translation needs the Textsynth backend".to_string(),
+            ));
+        };
+        self.load_cache();
+        let this = &*self;
+        let checkpoint = &Mutex::new(this.load_checkpoint());
+        let mut results: Vec<_> =
+            futures::stream::iter(this.translation_order().into_iter().map(|i| async move {
+                let (mut meta, copy) = this.start_chunk(i, checkpoint);
+                let (trs_chunk, failed) = match copy {
+                    Some(chunk) => (chunk, meta.failed),
+                    None => {
+                        let chunk = this.chunks[i].0.as_str();
+                        let start = std::time::Instant::now();
+                        let trs_try = translate_one_chunk_async(
+                            backend,
+                            chunk,
+                            this,
+                            &mut meta,
+                            this.max_tokens,
+                        )
+                        .await;
+                        meta.elapsed_ms = start.elapsed().as_millis() as u64;
+                        meta.failed = trs_try.is_err();
+                        let failed = meta.failed;
+                        (this.end_chunk(i, &meta, trs_try, checkpoint), failed)
+                    }
+                };
+                meta.finished_at = crate::meta::unix_millis();
+                (i, trs_chunk, meta, failed)
+            }))
+            .buffer_unordered(this.jobs.max(1))
+            .collect()
+            .await;
+        self.end_checkpoint(results.iter().filter(|(_, _, _, failed)| *failed).count());
+        self.save_cache();
+        // back to the order of the document
+        results.sort_by_key(|(i, _, _, _)| *i);
+        self.assemble(
+            results
+                .into_iter()
+                .map(|(_, trs_chunk, meta, _)| (trs_chunk, meta))
+                .collect(),
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,639 @@
+//! Backends for sending the completion requests to a LLM server.
+//!
+//! The Textsynth backend is the default one. It is the only one that supports
+//! the formal grammar constraining the generated output.
+//! The OpenAI backend works with any server providing the OpenAI completion API
+//! (OpenAI, Ollama, vLLM, llama.cpp...).
+//! The DeepL backend is a translator: it receives the chunks without prompt.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::json;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{RetryPolicy, KEY_ARG_COMMANDS};
+
+/// List of the engines of the Textsynth server
+pub const TEXTSYNTH_ENGINES_URL: &str = "https://api.textsynth.com/v1/engines";
+/// Default base URL of the OpenAI API
+pub const OPENAI_URL: &str = "https://api.openai.com/v1";
+/// Default base URL of the OpenAI-compatible API of a local Ollama server
+pub const OLLAMA_URL: &str = "http://localhost:11434/v1";
+
+/// Parameters of a completion request
+#[derive(Debug, Clone)]
+pub struct CompletionParams {
+    pub model: String,
+    /// api key given by the library user, it has priority over the key of the backend
+    pub api_key: Option<String>,
+    pub temperature: f32,
+    /// nucleus sampling, not sent if None
+    pub top_p: Option<f32>,
+    pub max_tokens: usize,
+    /// retries of the requests after a transient HTTP error
+    pub retry: RetryPolicy,
+}
+
+/// True for the HTTP statuses worth sending the request again:
+/// too many requests and server errors
+pub fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Send a request, and send it again after a transient error (see `is_transient_status`,
+/// timeouts and connection errors), waiting `retry.backoff(n)` before the retry `n`.
+/// The other HTTP errors are returned without retry.
+pub(crate) fn send_with_backoff(
+    request: reqwest::blocking::RequestBuilder,
+    retry: &RetryPolicy,
+) -> Result<reqwest::blocking::Response, String> {
+    let mut attempt = 0;
+    loop {
+        let req = request
+            .try_clone()
+            .ok_or("The request cannot be sent again")?;
+        let error = match req.send() {
+            Ok(res) if is_transient_status(res.status()) => format!("HTTP status {}", res.status()),
+            Ok(res) if !res.status().is_success() => {
+                let status = res.status();
+                return Err(format!(
+                    "Request error: HTTP status {}: {}",
+                    status,
+                    res.text().unwrap_or_default()
+                ));
+            }
+            Ok(res) => return Ok(res),
+            Err(e) if e.is_timeout() || e.is_connect() => format!("{:?}", e),
+            Err(e) => return Err(format!("Failed to send request: {:?}", e)),
+        };
+        if attempt >= retry.max_http_retries {
+            return Err(format!(
+                "Failed to send request after {} retries: {}",
+                attempt, error
+            ));
+        }
+        let wait = retry.backoff(attempt);
+        warn!(
+            "Transient error ({}), retry {}/{} in {} s",
+            error,
+            attempt + 1,
+            retry.max_http_retries,
+            wait.as_secs_f32()
+        );
+        std::thread::sleep(wait);
+        attempt += 1;
+    }
+}
+
+/// A LLM server able to complete a prompt
+pub trait LlmBackend: std::fmt::Debug + Send + Sync {
+    /// send the prompt and a formal grammar (ignored if not supported)
+    /// and returns the completion
+    fn complete(
+        &self,
+        prompt: &str,
+        grammar: Option<&str>,
+        params: &CompletionParams,
+    ) -> Result<String, String>;
+
+    /// true if the backend can constrain the output with a BNF grammar
+    fn supports_grammar(&self) -> bool {
+        false
+    }
+
+    /// true if the backend is a translator and not a LLM:
+    /// the chunks are sent to `translate` instead of `complete`
+    fn is_translator(&self) -> bool {
+        false
+    }
+
+    /// translate a LaTeX chunk without prompt, for the translators
+    fn translate(
+        &self,
+        _latex: &str,
+        _input_lang: &str,
+        _output_lang: &str,
+        _params: &CompletionParams,
+    ) -> Result<String, String> {
+        Err("This backend needs a prompt".to_string())
+    }
+
+    /// identifiers of the models available on the server
+    fn list_models(&self, _params: &CompletionParams) -> Result<Vec<String>, String> {
+        Err("This backend cannot list its models".to_string())
+    }
+
+    /// the Textsynth backend, used by the async translation
+    fn as_textsynth(&self) -> Option<&TextsynthBackend> {
+        None
+    }
+}
+
+/// Identifiers of the models in the answer of a models endpoint: the list `data`
+/// (OpenAI) or `engines` (Textsynth) of objects with an `id`, or of strings
+pub(crate) fn model_ids(resp: &Value) -> Result<Vec<String>, String> {
+    let list = [&resp["data"], &resp["engines"], resp]
+        .into_iter()
+        .find_map(|v| v.as_array())
+        .ok_or(format!(
+            "The server did not return a list of models: {}",
+            resp
+        ))?;
+    Ok(list
+        .iter()
+        .filter_map(|m| m.as_str().or(m["id"].as_str()))
+        .map(|id| id.to_string())
+        .collect())
+}
+
+/// GET request to a models endpoint
+fn fetch_models(
+    url: &str,
+    api_key: Option<String>,
+    retry: &RetryPolicy,
+) -> Result<Vec<String>, String> {
+    let client = reqwest::blocking::Client::new();
+    let mut request = client.get(url);
+    if let Some(api_key) = api_key {
+        request = request.header("Authorization", format!("Bearer {}", api_key));
+    }
+    let resp = send_with_backoff(request, retry)?
+        .json::<Value>()
+        .map_err(|e| format!("Request error: {:?}", e))?;
+    model_ids(&resp)
+}
+
+/// Rate limit of a server, given by the headers of its last answer
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimitState {
+    /// number of requests remaining in the current period (`X-RateLimit-Remaining`)
+    pub remaining: Option<u64>,
+    /// end of the current period, in seconds since the Unix epoch (`X-RateLimit-Reset`)
+    pub reset: Option<u64>,
+}
+
+impl RateLimitState {
+    /// Read the rate limit headers of an answer, missing or invalid headers are ignored
+    pub fn from_headers(headers: &reqwest::header::HeaderMap) -> RateLimitState {
+        let read = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+        };
+        RateLimitState {
+            remaining: read("X-RateLimit-Remaining"),
+            reset: read("X-RateLimit-Reset"),
+        }
+    }
+
+    /// Time to wait before the next request, at the Unix time `now`:
+    /// until the reset if no request remains
+    pub fn wait_time(&self, now: u64) -> Option<Duration> {
+        match (self.remaining, self.reset) {
+            (Some(0), Some(reset)) if reset > now => Some(Duration::from_secs(reset - now)),
+            _ => None,
+        }
+    }
+}
+
+/// The Textsynth server <https://textsynth.com>
+#[derive(Debug, Clone, Default)]
+pub struct TextsynthBackend {
+    api_key: Option<String>,
+    /// shared by the clones of the backend, e.g. for the subfiles
+    rate_limit: Arc<Mutex<RateLimitState>>,
+}
+
+impl TextsynthBackend {
+    /// If the api key is not given, it is read from the file "api_key.txt"
+    /// or from the environment variable "TEXTSYNTH_API_KEY"
+    pub fn new(api_key: Option<String>) -> TextsynthBackend {
+        TextsynthBackend {
+            api_key,
+            rate_limit: Arc::default(),
+        }
+    }
+
+    /// Rate limit given by the last answer of the server
+    pub fn rate_limit(&self) -> RateLimitState {
+        *self.rate_limit.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Keep the rate limit given by an answer of the server
+    pub(crate) fn set_rate_limit(&self, state: RateLimitState) {
+        *self.rate_limit.lock().unwrap_or_else(|e| e.into_inner()) = state;
+    }
+
+    /// Time to wait until the reset of the rate limit instead of receiving 429 errors
+    pub(crate) fn rate_limit_wait(&self) -> Option<Duration> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let state = self.rate_limit();
+        let wait = state.wait_time(now)?;
+        warn!(
+            "Rate limit hit, sleeping until {} (Unix time, {} s)",
+            state.reset.unwrap_or(now),
+            wait.as_secs()
+        );
+        Some(wait)
+    }
+
+    /// The parameters of a request, with the api key of the backend if none is given
+    pub(crate) fn params(&self, params: &CompletionParams) -> CompletionParams {
+        CompletionParams {
+            api_key: params.api_key.clone().or(self.api_key.clone()),
+            ..params.clone()
+        }
+    }
+}
+
+impl LlmBackend for TextsynthBackend {
+    fn complete(
+        &self,
+        prompt: &str,
+        grammar: Option<&str>,
+        params: &CompletionParams,
+    ) -> Result<String, String> {
+        if let Some(wait) = self.rate_limit_wait() {
+            std::thread::sleep(wait);
+        }
+        let (text, rate_limit) = crate::complete_with_ts(
+            prompt,
+            &grammar.map(|gr| gr.to_string()),
+            &self.params(params),
+        )?;
+        self.set_rate_limit(rate_limit);
+        Ok(text)
+    }
+
+    fn supports_grammar(&self) -> bool {
+        true
+    }
+
+    fn list_models(&self, params: &CompletionParams) -> Result<Vec<String>, String> {
+        let api_key = crate::ts_api_key(&params.api_key.clone().or(self.api_key.clone()))?;
+        fetch_models(TEXTSYNTH_ENGINES_URL, Some(api_key), &params.retry)
+    }
+
+    fn as_textsynth(&self) -> Option<&TextsynthBackend> {
+        Some(self)
+    }
+}
+
+/// A server with the OpenAI completion API, or its chat completion API
+#[derive(Debug, Clone)]
+pub struct OpenAiBackend {
+    base_url: String,
+    api_key: Option<String>,
+    /// environment variable of the api key
+    api_key_env: String,
+    /// use the endpoint `chat/completions` instead of `completions`
+    chat: bool,
+}
+
+impl OpenAiBackend {
+    /// `base_url` is for instance `OPENAI_URL` or `OLLAMA_URL`.
+    /// If the api key is not given, it is read from the environment variable
+    /// "OPENAI_API_KEY". Local servers generally do not need a key.
+    pub fn new(base_url: &str, api_key: Option<String>) -> OpenAiBackend {
+        OpenAiBackend {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            api_key_env: "OPENAI_API_KEY".to_string(),
+            chat: false,
+        }
+    }
+
+    /// Backend of a local Ollama server, `OLLAMA_URL` by default. The root URL
+    /// of the server (`http://localhost:11434`) is completed with its OpenAI API path `/v1`.
+    pub fn ollama(base_url: Option<&str>) -> OpenAiBackend {
+        let base_url = base_url.unwrap_or(OLLAMA_URL).trim_end_matches('/');
+        if base_url.ends_with("/v1") {
+            OpenAiBackend::new(base_url, None)
+        } else {
+            OpenAiBackend::new(format!("{}/v1", base_url).as_str(), None)
+        }
+    }
+
+    /// Read the api key from another environment variable than "OPENAI_API_KEY"
+    pub fn api_key_env(mut self, var: &str) -> OpenAiBackend {
+        self.api_key_env = var.to_string();
+        self
+    }
+
+    /// Send the prompt as a user message to the chat completion API
+    /// (`chat/completions`), needed by the recent OpenAI models
+    pub fn chat(mut self, chat: bool) -> OpenAiBackend {
+        self.chat = chat;
+        self
+    }
+
+    /// URL and JSON body of a request
+    pub(crate) fn request(&self, prompt: &str, params: &CompletionParams) -> (String, Value) {
+        let (url, mut req) = if self.chat {
+            let req = json!({
+                "model": params.model,
+                "messages": [{"role": "user", "content": prompt}],
+                "temperature": params.temperature,
+                "max_tokens": params.max_tokens
+            });
+            (format!("{}/chat/completions", self.base_url), req)
+        } else {
+            let req = json!({
+                "model": params.model,
+                "prompt": prompt,
+                "temperature": params.temperature,
+                "max_tokens": params.max_tokens
+            });
+            (format!("{}/completions", self.base_url), req)
+        };
+        if let Some(top_p) = params.top_p {
+            req["top_p"] = json!(top_p);
+        }
+        (url, req)
+    }
+
+    /// Text of the answer of the server
+    pub(crate) fn answer(&self, resp: &Value) -> Result<String, String> {
+        let text = if self.chat {
+            resp["choices"][0]["message"]["content"].as_str()
+        } else {
+            resp["choices"][0]["text"].as_str()
+        };
+        text.map(|t| t.to_string()).ok_or(format!(
+            "The result of the server does not contain text: {}",
+            resp
+        ))
+    }
+}
+
+impl LlmBackend for OpenAiBackend {
+    fn complete(
+        &self,
+        prompt: &str,
+        _grammar: Option<&str>,
+        params: &CompletionParams,
+    ) -> Result<String, String> {
+        let api_key = params
+            .api_key
+            .clone()
+            .or(self.api_key.clone())
+            .or_else(|| std::env::var(&self.api_key_env).ok());
+        let (url, req) = self.request(prompt, params);
+        info!("Translate with {} on {}", params.model, self.base_url);
+        let client = reqwest::blocking::Client::new();
+        let mut request = client.post(url).header("Content-Type", "application/json");
+        if let Some(api_key) = api_key {
+            request = request.header("Authorization", format!("Bearer {}", api_key));
+        }
+        let resp = send_with_backoff(request.json(&req), &params.retry)?
+            .json::<Value>()
+            .map_err(|e| format!("Request error: {:?}", e))?;
+        self.answer(&resp)
+    }
+
+    fn list_models(&self, params: &CompletionParams) -> Result<Vec<String>, String> {
+        let api_key = params
+            .api_key
+            .clone()
+            .or(self.api_key.clone())
+            .or_else(|| std::env::var(&self.api_key_env).ok());
+        fetch_models(&format!("{}/models", self.base_url), api_key, &params.retry)
+    }
+}
+
+/// Default base URL of the DeepL API, for the free keys ending with ":fx"
+pub const DEEPL_FREE_URL: &str = "https://api-free.deepl.com/v2";
+/// Default base URL of the DeepL API, for the other keys
+pub const DEEPL_URL: &str = "https://api.deepl.com/v2";
+
+/// The DeepL translator <https://www.deepl.com>, which is not a LLM:
+/// the chunk is sent without prompt, see `LlmBackend::translate`.
+/// The LaTeX markup is replaced by XML tags which DeepL keeps unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct DeeplBackend {
+    base_url: Option<String>,
+    api_key: Option<String>,
+}
+
+impl DeeplBackend {
+    /// If the base URL is not given, it is chosen from the api key.
+    /// If the api key is not given, it is read from the environment variable
+    /// "DEEPL_API_KEY".
+    pub fn new(base_url: Option<&str>, api_key: Option<String>) -> DeeplBackend {
+        DeeplBackend {
+            base_url: base_url.map(|url| url.trim_end_matches('/').to_string()),
+            api_key,
+        }
+    }
+}
+
+/// Language code of DeepL: the English and Portuguese targets need a variant
+fn deepl_lang(lang: &str, target: bool) -> String {
+    match (lang, target) {
+        ("en", true) => "EN-US".to_string(),
+        ("pt", true) => "PT-PT".to_string(),
+        // Norwegian Bokmål
+        ("no", _) => "NB".to_string(),
+        _ => lang.to_uppercase(),
+    }
+}
+
+impl LlmBackend for DeeplBackend {
+    fn complete(
+        &self,
+        _prompt: &str,
+        _grammar: Option<&str>,
+        _params: &CompletionParams,
+    ) -> Result<String, String> {
+        Err("DeepL translates the chunks without prompt".to_string())
+    }
+
+    fn is_translator(&self) -> bool {
+        true
+    }
+
+    fn translate(
+        &self,
+        latex: &str,
+        input_lang: &str,
+        output_lang: &str,
+        params: &CompletionParams,
+    ) -> Result<String, String> {
+        let api_key = params
+            .api_key
+            .clone()
+            .or(self.api_key.clone())
+            .or_else(|| std::env::var("DEEPL_API_KEY").ok())
+            .ok_or("You have to provide a DeepL api key by export DEEPL_API_KEY=api_key")?;
+        let base_url = self.base_url.clone().unwrap_or_else(|| {
+            if api_key.ends_with(":fx") {
+                DEEPL_FREE_URL.to_string()
+            } else {
+                DEEPL_URL.to_string()
+            }
+        });
+        let (xml, originals) = latex_to_xml(latex);
+        let req = json!({
+            "text": [xml],
+            "source_lang": deepl_lang(input_lang, false),
+            "target_lang": deepl_lang(output_lang, true),
+            "tag_handling": "xml",
+            "ignore_tags": ["x"]
+        });
+        info!("Translate with DeepL on {}", base_url);
+        let client = reqwest::blocking::Client::new();
+        let request = client
+            .post(format!("{}/translate", base_url))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("DeepL-Auth-Key {}", api_key))
+            .json(&req);
+        let resp = send_with_backoff(request, &params.retry)?
+            .json::<Value>()
+            .map_err(|e| format!("Request error: {:?}", e))?;
+        let text = resp["translations"][0]["text"].as_str().ok_or(format!(
+            "The result of DeepL does not contain text: {}",
+            resp
+        ))?;
+        Ok(xml_to_latex(text, &originals))
+    }
+}
+
+/// Length of the group `{...}` or `[...]` at the beginning of `latex`, 0 if there is none
+fn group_len(latex: &str, open: char, close: char) -> usize {
+    if !latex.starts_with(open) {
+        return 0;
+    }
+    let mut depth = 0;
+    let mut prev = ' ';
+    for (i, c) in latex.char_indices() {
+        if c == open && prev != '\\' {
+            depth += 1;
+        } else if c == close && prev != '\\' {
+            depth -= 1;
+            if depth == 0 {
+                return i + c.len_utf8();
+            }
+        }
+        prev = c;
+    }
+    latex.len()
+}
+
+/// Length of the LaTeX markup at the beginning of `latex`, 0 if it begins with text.
+/// The markup is a comment, a formula, a command name with its options
+/// (and its argument for the commands with a key), a brace or a special character.
+fn markup_len(rest: &str) -> usize {
+    let find_end = |start: usize, end: &str| {
+        rest[start..]
+            .find(end)
+            .map_or(rest.len(), |i| start + i + end.len())
+    };
+    if rest.starts_with('%') {
+        return rest.find('\n').unwrap_or(rest.len());
+    }
+    if rest.starts_with("$$") {
+        return find_end(2, "$$");
+    }
+    if rest.starts_with('$') {
+        let mut prev = ' ';
+        for (i, c) in rest.char_indices().skip(1) {
+            if c == '$' && prev != '\\' {
+                return i + 1;
+            }
+            prev = c;
+        }
+        return rest.len();
+    }
+    if rest.starts_with("\\[") {
+        return find_end(2, "\\]");
+    }
+    if rest.starts_with("\\(") {
+        return find_end(2, "\\)");
+    }
+    if let Some(name) = rest
+        .strip_prefix("\\begin{")
+        .and_then(|r| r.split_once('}'))
+    {
+        let env = name.0.trim_end_matches('*');
+        if crate::MATH_ENVS.contains(&env) || crate::VERBATIM_ENVS.contains(&env) {
+            return find_end(0, format!("\\end{{{}}}", name.0).as_str());
+        }
+    }
+    if let Some(after) = rest.strip_prefix('\\') {
+        let name_len: usize = after
+            .chars()
+            .take_while(|c| c.is_ascii_alphabetic())
+            .map(|c| c.len_utf8())
+            .sum();
+        if name_len == 0 {
+            // escaped character or \\
+            return 1 + after.chars().next().map_or(0, |c| c.len_utf8());
+        }
+        let name = &after[..name_len];
+        let mut len = 1 + name_len;
+        if rest[len..].starts_with('*') {
+            len += 1;
+        }
+        len += group_len(&rest[len..], '[', ']');
+        if KEY_ARG_COMMANDS.contains(&name) {
+            len += group_len(&rest[len..], '{', '}');
+        }
+        return len;
+    }
+    match rest.chars().next() {
+        Some(c @ ('{' | '}' | '&' | '~' | '^' | '_' | '#')) => c.len_utf8(),
+        _ => 0,
+    }
+}
+
+/// Replace the LaTeX markup by the tags `<x i="n"/>`, which DeepL keeps unchanged
+/// with the XML tag handling. Returns the XML text and the replaced markup.
+pub fn latex_to_xml(latex: &str) -> (String, Vec<String>) {
+    let mut xml = String::new();
+    let mut originals: Vec<String> = vec![];
+    let mut markup = String::new();
+    let mut i = 0;
+    while i < latex.len() {
+        let len = markup_len(&latex[i..]);
+        if len > 0 {
+            markup.push_str(&latex[i..i + len]);
+            i += len;
+            continue;
+        }
+        if !markup.is_empty() {
+            xml.push_str(&format!("<x i=\"{}\"/>", originals.len()));
+            originals.push(std::mem::take(&mut markup));
+        }
+        let c = latex[i..].chars().next().unwrap_or(' ');
+        match c {
+            '<' => xml.push_str("&lt;"),
+            '>' => xml.push_str("&gt;"),
+            _ => xml.push(c),
+        }
+        i += c.len_utf8();
+    }
+    if !markup.is_empty() {
+        xml.push_str(&format!("<x i=\"{}\"/>", originals.len()));
+        originals.push(markup);
+    }
+    (xml, originals)
+}
+
+/// Restore the LaTeX markup replaced by `latex_to_xml`
+pub fn xml_to_latex(xml: &str, originals: &[String]) -> String {
+    // the text is unescaped before the markup is restored, which is kept as it is
+    let mut latex = xml
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&");
+    for (i, original) in originals.iter().enumerate() {
+        latex = latex.replace(format!("<x i=\"{}\"/>", i).as_str(), original);
+    }
+    latex
+}
@@ -0,0 +1,91 @@
+//! Translation of several files with the same settings (batch mode),
+//! e.g. `trsltx -f 'chapters/*.tex'` or `trsltx -f intro.tex -f conclusion.tex`.
+//!
+//! The files are translated one after the other. In batch mode, the translation
+//! cache is shared by the files (`trsltx_cache.json` by default), and the report
+//! of `--report` sums up the runs, with the report of each file:
+//!
+//! ```json
+//! {
+//!   "total": { "chunks": 40, "translated_chunks": 35, ... },
+//!   "files": [
+//!     { "file": "chapters/ch1.tex", "chunks": 12, "translated_chunks": 10, ... }
+//!   ]
+//! }
+//! ```
+
+use serde::Serialize;
+
+use crate::{find_language, split_lang_file_name, RunReport, TrsltxError};
+
+/// Cache file shared by the files of a batch, if none is given
+pub const BATCH_CACHE_FILE: &str = "trsltx_cache.json";
+
+/// Expand the glob patterns (`*`, `?`, `[...]`) of a list of files.
+/// The matches whose name ends with a language (`ch1_fr.tex`, `ch1_en.tex`) are skipped:
+/// they are the split files and the translations written by `trsltx`.
+/// The other names are kept as they are, even if the file does not exist.
+pub fn expand_file_patterns(patterns: &[String]) -> Result<Vec<String>, TrsltxError> {
+    let mut files = vec![];
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            files.push(pattern.clone());
+            continue;
+        }
+        let paths = glob::glob(pattern)
+            .map_err(|e| TrsltxError::Config(format!("Invalid file pattern {}: {}", pattern, e)))?;
+        let matches: Vec<String> = paths
+            .filter_map(|path| path.ok())
+            .map(|path| path.to_string_lossy().to_string())
+            .filter(|file| {
+                !split_lang_file_name(file).is_ok_and(|(_, lang)| find_language(&lang).is_some())
+            })
+            .collect();
+        if matches.is_empty() {
+            return Err(TrsltxError::Config(format!(
+                "No file to translate matches {}",
+                pattern
+            )));
+        }
+        files.extend(matches);
+    }
+    let mut seen = vec![];
+    files.retain(|file| {
+        let new = !seen.contains(file);
+        seen.push(file.clone());
+        new
+    });
+    Ok(files)
+}
+
+/// Report of one file of a batch
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileReport {
+    pub file: String,
+    #[serde(flatten)]
+    pub report: RunReport,
+}
+
+/// Report of a batch: the sum of the reports of the files
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BatchReport {
+    pub total: RunReport,
+    pub files: Vec<FileReport>,
+}
+
+impl BatchReport {
+    pub fn add(&mut self, file: &str, report: RunReport) {
+        self.total.merge(&report);
+        self.files.push(FileReport {
+            file: file.to_string(),
+            report,
+        });
+    }
+
+    /// Write the report as JSON
+    pub fn write(&self, path: &str) -> Result<(), TrsltxError> {
+        let json =
+            serde_json::to_string_pretty(self).map_err(|e| TrsltxError::io(path, e.into()))?;
+        std::fs::write(path, json).map_err(|e| TrsltxError::io(path, e))
+    }
+}
@@ -0,0 +1,311 @@
+//! Translation of the fields of a bibliography file (`trsltx -f refs.bib`).
+//!
+//! The entries of the .bib file are parsed and the selected fields (title, abstract
+//! and note by default) are translated. The translation is added in a new field after
+//! the source field, `titleaddon` for the title and `abstract_en`, `note_en`... for the
+//! other fields, so that the style of the bibliography chooses what is printed:
+//!
+//! ```bibtex
+//! @article{dupont2020,
+//!   title = {Sur l'équation de {Fourier}},
+//!   titleaddon = {On the {Fourier} equation},
+//! ```
+//!
+//! The keys and the other fields are left unchanged. The braces protecting the
+//! capitalization of whole words (`{Fourier}`, `{DNA}`) must be found in the
+//! translation, and its braces must be balanced: otherwise the field is not translated.
+//! The fields whose translation field already exists are skipped.
+
+use tracing::{info, warn};
+
+use crate::{translate_one_chunk, ChunkMeta, Trsltx};
+
+/// Fields translated by default
+pub const BIB_FIELDS: [&str; 3] = ["title", "abstract", "note"];
+
+/// A field of a bibliography entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct BibField {
+    /// name in lower case
+    pub name: String,
+    /// position of the name in the file
+    pub start: usize,
+    /// text between the braces or the quotes, None for the numbers,
+    /// the macros (`month = jan`) and the concatenations
+    pub value: Option<String>,
+    /// position after the value in the file
+    pub end: usize,
+}
+
+/// An entry of a bibliography file, without the `@string`, `@preamble` and `@comment`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BibEntry {
+    /// type in lower case (`article`, `book`...)
+    pub kind: String,
+    pub key: String,
+    pub fields: Vec<BibField>,
+}
+
+/// Result of the translation of a bibliography file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BibTranslation {
+    /// the bibliography with the translated fields
+    pub content: String,
+    /// number of fields to translate
+    pub fields: usize,
+    pub translated: usize,
+    /// the fields left untranslated, as `key: field (reason)`
+    pub failed: Vec<String>,
+}
+
+/// Name of the field receiving the translation of `field` in the language `lang`
+pub fn translated_field_name(field: &str, lang: &str) -> String {
+    if field == "title" {
+        "titleaddon".to_string()
+    } else {
+        format!("{}_{}", field, lang)
+    }
+}
+
+/// Name of the translated bibliography: `refs_en.bib` for `refs.bib`
+pub fn bib_lang_file_name(bib_file: &str, lang: &str) -> String {
+    let stem = bib_file.strip_suffix(".bib").unwrap_or(bib_file);
+    format!("{}_{}.bib", stem, lang)
+}
+
+// position after the text delimited by {...}, (...) or "..." starting at `start`,
+// with balanced braces inside
+fn group_end(bib: &str, start: usize) -> Option<usize> {
+    let bytes = bib.as_bytes();
+    let open = bytes[start];
+    let mut depth = 0;
+    for (i, &c) in bytes.iter().enumerate().skip(start + 1) {
+        match c {
+            b'{' => depth += 1,
+            b'}' if depth > 0 => depth -= 1,
+            b'}' if open == b'{' => return Some(i + 1),
+            b')' if open == b'(' && depth == 0 => return Some(i + 1),
+            b'"' if open == b'"' && depth == 0 => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+// the fields of an entry, from `pos` to the position `end` of the closing delimiter
+fn parse_fields(bib: &str, mut pos: usize, end: usize) -> Vec<BibField> {
+    let bytes = bib.as_bytes();
+    let mut fields = vec![];
+    loop {
+        while pos < end && (bytes[pos].is_ascii_whitespace() || bytes[pos] == b',') {
+            pos += 1;
+        }
+        let Some(eq) = bib[pos..end].find('=').map(|i| pos + i) else {
+            break;
+        };
+        let start = pos;
+        let name = bib[pos..eq].trim().to_lowercase();
+        pos = eq + 1;
+        // the value: {...}, "..." or a bare word, possibly concatenated with #
+        let mut parts = vec![];
+        loop {
+            while pos < end && bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            let part_end = match bytes.get(pos) {
+                Some(&c) if pos < end && (c == b'{' || c == b'"') => {
+                    group_end(bib, pos).unwrap_or(end).min(end)
+                }
+                _ => bib[pos..end]
+                    .find(|c: char| c == ',' || c == '#' || c.is_whitespace())
+                    .map_or(end, |i| pos + i),
+            };
+            parts.push(&bib[pos..part_end]);
+            pos = part_end;
+            while pos < end && bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if pos < end && bytes[pos] == b'#' {
+                pos += 1;
+            } else {
+                break;
+            }
+        }
+        let value = match parts[..] {
+            [part] if part.starts_with(['{', '"']) && part.len() >= 2 => {
+                Some(part[1..part.len() - 1].to_string())
+            }
+            _ => None,
+        };
+        let value_end = bib[..pos].trim_end().len();
+        if !name.is_empty() {
+            fields.push(BibField {
+                name,
+                start,
+                value,
+                end: value_end,
+            });
+        }
+        if pos >= end {
+            break;
+        }
+    }
+    fields
+}
+
+/// Parse the entries of a bibliography file
+pub fn parse_bib_entries(bib: &str) -> Vec<BibEntry> {
+    let mut entries = vec![];
+    let mut pos = 0;
+    while let Some(at) = bib[pos..].find('@').map(|i| pos + i) {
+        pos = at + 1;
+        let Some(open) = bib[pos..].find(['{', '(']).map(|i| pos + i) else {
+            break;
+        };
+        let kind = bib[pos..open].trim().to_lowercase();
+        if kind.is_empty() || !kind.chars().all(|c| c.is_ascii_alphabetic()) {
+            continue;
+        }
+        let Some(end) = group_end(bib, open) else {
+            warn!("unbalanced braces in the bibliography entry at {}", at);
+            break;
+        };
+        pos = end;
+        if ["string", "preamble", "comment"].contains(&kind.as_str()) {
+            continue;
+        }
+        // the position of the closing delimiter
+        let end = end - 1;
+        let Some(comma) = bib[open + 1..end].find(',').map(|i| open + 1 + i) else {
+            continue;
+        };
+        entries.push(BibEntry {
+            kind,
+            key: bib[open + 1..comma].trim().to_string(),
+            fields: parse_fields(bib, comma + 1, end),
+        });
+    }
+    entries
+}
+
+/// The brace groups protecting whole words (`{Fourier}` but not `{T}he`),
+/// which are kept by the translation
+pub fn protected_groups(value: &str) -> Vec<String> {
+    let bytes = value.as_bytes();
+    let mut groups = vec![];
+    let mut start = None;
+    let mut depth = 0;
+    for (i, &c) in bytes.iter().enumerate() {
+        match c {
+            b'{' => {
+                if depth == 0 && !(i > 0 && bytes[i - 1] == b'\\') {
+                    start = Some(i);
+                }
+                depth += 1;
+            }
+            b'}' if depth > 0 => {
+                depth -= 1;
+                // the groups after a letter are the arguments of commands or parts of words
+                let word = |b: Option<&u8>| b.is_some_and(|b| b.is_ascii_alphanumeric());
+                match start.take() {
+                    Some(s) if depth == 0 => {
+                        if !word(s.checked_sub(1).and_then(|j| bytes.get(j)))
+                            && !word(bytes.get(i + 1))
+                        {
+                            groups.push(value[s..=i].to_string());
+                        }
+                    }
+                    s => start = s,
+                }
+            }
+            _ => {}
+        }
+    }
+    groups
+}
+
+// reason why a translated field cannot be kept
+fn check_translated_field(source: &str, translation: &str) -> Result<(), String> {
+    let mut depth = 0i32;
+    for c in translation.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        if depth < 0 {
+            return Err("unbalanced braces".to_string());
+        }
+    }
+    if depth != 0 {
+        return Err("unbalanced braces".to_string());
+    }
+    let translated_groups = protected_groups(translation);
+    match protected_groups(source)
+        .into_iter()
+        .find(|group| !translated_groups.contains(group))
+    {
+        Some(group) => Err(format!("{} lost", group)),
+        None => Ok(()),
+    }
+}
+
+impl Trsltx {
+    /// Translate the `fields` of the entries of the bibliography `bib`,
+    /// see the module `bib`
+    pub fn translate_bib(&self, bib: &str, fields: &[&str]) -> BibTranslation {
+        let mut result = BibTranslation::default();
+        // the new fields, inserted from the end so that the positions remain valid
+        let mut insertions = vec![];
+        for entry in parse_bib_entries(bib) {
+            for field in entry.fields.iter() {
+                let Some(value) = &field.value else {
+                    continue;
+                };
+                let target = translated_field_name(&field.name, &self.output_lang);
+                if !fields.contains(&field.name.as_str())
+                    || value.trim().is_empty()
+                    || entry.fields.iter().any(|f| f.name == target)
+                {
+                    continue;
+                }
+                result.fields += 1;
+                info!("Translating the {} of {}", field.name, entry.key);
+                let mut meta = ChunkMeta::default();
+                let translation =
+                    translate_one_chunk(value.trim(), self, &mut meta, self.max_tokens, None)
+                        .and_then(|t| {
+                            let t = t.trim().to_string();
+                            check_translated_field(value, &t).map(|_| t)
+                        });
+                match translation {
+                    Ok(translation) => {
+                        let line_start = bib[..field.start].rfind('\n').map_or(0, |i| i + 1);
+                        let indent: String = bib[line_start..]
+                            .chars()
+                            .take_while(|c| *c == ' ' || *c == '\t')
+                            .collect();
+                        insertions.push((
+                            field.end,
+                            format!(",\n{}{} = {{{}}}", indent, target, translation),
+                        ));
+                        result.translated += 1;
+                    }
+                    Err(e) => {
+                        warn!("{}: {} left untranslated: {}", entry.key, field.name, e);
+                        result
+                            .failed
+                            .push(format!("{}: {} ({})", entry.key, field.name, e));
+                    }
+                }
+            }
+        }
+        let mut content = bib.to_string();
+        insertions.sort_by_key(|(pos, _)| *pos);
+        for (pos, text) in insertions.into_iter().rev() {
+            content.insert_str(pos, &text);
+        }
+        result.content = content;
+        result
+    }
+}
@@ -0,0 +1,133 @@
+//! Translation memory (`--cache-file` option).
+//!
+//! The cache is a JSON file mapping the translation settings and the hash of a
+//! source chunk to its translation. When a document is translated again after
+//! minor edits, only the modified chunks are sent to the server. The same cache
+//! file can be shared by several documents.
+//!
+//! ```json
+//! {
+//!   "fr:en:mistral47b:8f2e5c0a9b1d3e47": "Hello world."
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{Trsltx, TrsltxError};
+
+/// Translations indexed by `cache_key`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TranslationCache {
+    entries: BTreeMap<String, String>,
+}
+
+/// 64-bit FNV-1a hash of a chunk, stable across platforms and Rust versions
+pub fn chunk_hash(source: &str) -> u64 {
+    source.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Key of a chunk in the cache: languages, model and hash of the source
+pub fn cache_key(source: &str, input_lang: &str, output_lang: &str, model: &str) -> String {
+    format!(
+        "{}:{}:{}:{:016x}",
+        input_lang,
+        output_lang,
+        model,
+        chunk_hash(source)
+    )
+}
+
+impl TranslationCache {
+    /// Read a cache file, or return an empty cache if the file does not exist
+    pub fn read(path: &str) -> Result<TranslationCache, TrsltxError> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| {
+                TrsltxError::Parse(format!("Cannot read the cache file {}: {}", path, e))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TranslationCache::default()),
+            Err(e) => Err(TrsltxError::io(path, e)),
+        }
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), TrsltxError> {
+        let json =
+            serde_json::to_string_pretty(self).map_err(|e| TrsltxError::io(path, e.into()))?;
+        std::fs::write(path, json).map_err(|e| TrsltxError::io(path, e))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|t| t.as_str())
+    }
+
+    pub fn insert(&mut self, key: String, translation: &str) {
+        self.entries.insert(key, translation.to_string());
+    }
+}
+
+impl Trsltx {
+    /// Key of a source chunk in the cache, with the settings of the translation
+    fn cache_key(&self, source: &str) -> String {
+        cache_key(
+            source,
+            &self.input_lang,
+            &self.output_lang,
+            &self.model_name,
+        )
+    }
+
+    /// Read the cache file before the translation, if any
+    pub(crate) fn load_cache(&mut self) {
+        let Some(path) = &self.cache_file else {
+            return;
+        };
+        let cache = TranslationCache::read(path).unwrap_or_else(|e| {
+            warn!("{}, start with an empty cache", e);
+            TranslationCache::default()
+        });
+        info!("{} translations found in the cache {}", cache.len(), path);
+        *self.cache.get_mut().unwrap_or_else(|e| e.into_inner()) = cache;
+    }
+
+    /// Write the cache file after the translation, if any.
+    /// A failure is only reported: the translation itself is not lost.
+    pub(crate) fn save_cache(&self) {
+        let Some(path) = &self.cache_file else {
+            return;
+        };
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = cache.write(path) {
+            warn!("{}", e);
+        }
+    }
+
+    /// Translation of a source chunk found in the cache
+    pub(crate) fn cached_translation(&self, source: &str) -> Option<String> {
+        self.cache_file.as_ref()?;
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.get(&self.cache_key(source)).map(|t| t.to_string())
+    }
+
+    /// Add the translation of a source chunk to the cache
+    pub(crate) fn cache_translation(&self, source: &str, translation: &str) {
+        if self.cache_file.is_none() {
+            return;
+        }
+        let key = self.cache_key(source);
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.insert(key, translation);
+    }
+}
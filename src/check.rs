@@ -0,0 +1,238 @@
+//! Offline check of a LaTeX file before its translation (`--check` option).
+//!
+//! The check does not contact the translation server: it verifies the split
+//! markers, the size of the chunks, the labels and references and the language
+//! declaration of the preamble.
+//!
+//! The cross-reference check is also run before each translation: a `\ref`
+//! whose `\label` is in another chunk, or missing, may lead the LLM to invent a label.
+
+use serde::Serialize;
+
+use crate::{command_args, extracts_references, ChunkType, Trsltx};
+
+/// Severity of a problem found by the check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// the translation is possible but may be improved
+    Warning,
+    /// the translation will fail or give a wrong document
+    Error,
+}
+
+/// A problem found by the check
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CheckIssue {
+    pub severity: Severity,
+    /// chunk number, starting from 1, if the problem concerns a chunk
+    pub chunk: Option<usize>,
+    pub message: String,
+}
+
+/// Kind of a cross-reference problem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrossRefKind {
+    /// `\ref{key}` without `\label{key}` in the body
+    UndefinedLabel,
+    /// `\label{key}` without reference in the body
+    UnusedLabel,
+}
+
+/// A reference without label, or a label without reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrossRefWarning {
+    pub ref_key: String,
+    /// chunk of the reference or of the label, starting from 0
+    pub chunk_index: usize,
+    pub kind: CrossRefKind,
+}
+
+impl std::fmt::Display for CrossRefWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.kind {
+            CrossRefKind::UndefinedLabel => write!(
+                f,
+                "chunk {}: reference to the undefined label {}",
+                self.chunk_index + 1,
+                self.ref_key
+            ),
+            CrossRefKind::UnusedLabel => write!(
+                f,
+                "chunk {}: \\label{{{}}} is never referenced",
+                self.chunk_index + 1,
+                self.ref_key
+            ),
+        }
+    }
+}
+
+/// Result of the check of a file
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CheckReport {
+    pub issues: Vec<CheckIssue>,
+}
+
+impl CheckReport {
+    fn push(&mut self, severity: Severity, chunk: Option<usize>, message: String) {
+        self.issues.push(CheckIssue {
+            severity,
+            chunk,
+            message,
+        });
+    }
+
+    /// Exit code of the `--check` option: 0 without problem,
+    /// 1 if there are only warnings, 2 if there are errors
+    pub fn exit_code(&self) -> i32 {
+        match self.issues.iter().map(|i| i.severity).max() {
+            None => 0,
+            Some(Severity::Warning) => 1,
+            Some(Severity::Error) => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.issues.is_empty() {
+            return writeln!(f, "No problem found");
+        }
+        for issue in self.issues.iter() {
+            let severity = match issue.severity {
+                Severity::Warning => "Warning",
+                Severity::Error => "Error",
+            };
+            match issue.chunk {
+                Some(chunk) => writeln!(f, "{} in chunk {}: {}", severity, chunk, issue.message)?,
+                None => writeln!(f, "{}: {}", severity, issue.message)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Trsltx {
+    /// Check the file read by `read_file`, without translating it.
+    /// The chunks are extracted if it has not been done.
+    pub fn check(&mut self) -> CheckReport {
+        let mut report = CheckReport::default();
+
+        // language declaration
+        if !self.preamble.contains("{babel}") && !self.preamble.contains("{polyglossia}") {
+            report.push(
+                Severity::Warning,
+                None,
+                "no babel or polyglossia package in the preamble".to_string(),
+            );
+        }
+
+        // labels and references
+        let labels: Vec<String> = command_args(&self.body, "label")
+            .into_iter()
+            .map(|(_, key)| key)
+            .collect();
+        let references = extracts_references(&self.body);
+        for label in labels.iter() {
+            if !references.contains(label) {
+                report.push(
+                    Severity::Warning,
+                    None,
+                    format!("\\label{{{}}} is never referenced", label),
+                );
+            }
+        }
+        let mut missing: Vec<&String> = vec![];
+        for reference in references.iter() {
+            if !labels.contains(reference) && !missing.contains(&reference) {
+                missing.push(reference);
+            }
+        }
+        for reference in missing {
+            report.push(
+                Severity::Error,
+                None,
+                format!("reference to the undefined label {}", reference),
+            );
+        }
+
+        // split markers
+        if self.chunks.is_empty() {
+            if let Err(e) = self.extract_chunks() {
+                report.push(Severity::Error, None, e.to_string());
+                return report;
+            }
+        }
+
+        // size of the chunks
+        let max_chars = self.chunk_length_limit();
+        let context = self.model_entry.as_ref().map(|e| e.context_window_tokens);
+        for (i, (chunk, t)) in self.chunks.iter().enumerate() {
+            if *t == ChunkType::Unchanged {
+                continue;
+            }
+            if chunk.len() >= max_chars {
+                report.push(
+                    Severity::Error,
+                    Some(i + 1),
+                    format!(
+                        "{} characters, above the limit of {}: the chunk will not be translated",
+                        chunk.len(),
+                        max_chars
+                    ),
+                );
+            } else if let Some(context) = context {
+                // with the prompt and the answer
+                let tokens = self.estimate_tokens(chunk) + 200 + self.max_tokens;
+                if tokens > context {
+                    report.push(
+                        Severity::Error,
+                        Some(i + 1),
+                        format!(
+                            "about {} tokens with the prompt and the answer, above the context window of {} tokens",
+                            tokens, context
+                        ),
+                    );
+                }
+            }
+        }
+        report
+    }
+
+    /// Compare the labels and the references of all the chunks given by `extract_chunks`.
+    /// The warnings are in the order of the chunks.
+    pub fn cross_reference_check(&self) -> Vec<CrossRefWarning> {
+        let mut labels = vec![];
+        let mut references = vec![];
+        for (i, (chunk, _)) in self.chunks.iter().enumerate() {
+            labels.extend(
+                command_args(chunk, "label")
+                    .into_iter()
+                    .map(|(_, k)| (i, k)),
+            );
+            references.extend(extracts_references(chunk).into_iter().map(|k| (i, k)));
+        }
+        let mut warnings = vec![];
+        for (i, key) in references.iter() {
+            if !labels.iter().any(|(_, l)| l == key) {
+                warnings.push(CrossRefWarning {
+                    ref_key: key.clone(),
+                    chunk_index: *i,
+                    kind: CrossRefKind::UndefinedLabel,
+                });
+            }
+        }
+        for (i, key) in labels.iter() {
+            if !references.iter().any(|(_, r)| r == key) {
+                warnings.push(CrossRefWarning {
+                    ref_key: key.clone(),
+                    chunk_index: *i,
+                    kind: CrossRefKind::UnusedLabel,
+                });
+            }
+        }
+        warnings.sort_by_key(|w| w.chunk_index);
+        warnings.dedup();
+        warnings
+    }
+}
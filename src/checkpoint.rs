@@ -0,0 +1,161 @@
+//! Checkpoint of a translation run, written in the file `<stem>.trsltx.json`
+//! next to the translated file (e.g. `test/simple_en.trsltx.json` for `test/simple_en.tex`).
+//!
+//! The translation of a chunk is saved as soon as it is received. If the run is
+//! interrupted or cancelled (see `TrsltxBuilder::cancel_flag`) or some chunks fail,
+//! the next run reuses the saved translations and only sends the missing or failed
+//! chunks. A saved translation is reused only if
+//! the source of the chunk, the languages and the model are unchanged.
+//! The file is removed when all the chunks have been translated.
+//!
+//! ```json
+//! {
+//!   "input_lang": "fr",
+//!   "output_lang": "en",
+//!   "model": "mistral47b",
+//!   "chunks": [
+//!     {
+//!       "index": 0,
+//!       "source": "Bonjour le monde.",
+//!       "translation": "Hello world."
+//!     }
+//!   ]
+//! }
+//! ```
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::Trsltx;
+
+/// Translations saved during a translation run
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub input_lang: String,
+    pub output_lang: String,
+    pub model: String,
+    pub chunks: Vec<CheckpointChunk>,
+}
+
+/// Saved translation of one chunk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckpointChunk {
+    pub index: usize,
+    pub source: String,
+    pub translation: String,
+}
+
+impl Checkpoint {
+    /// Saved translation of the chunk `index`, if its source is unchanged
+    pub fn translation(&self, index: usize, source: &str) -> Option<&str> {
+        self.chunks
+            .iter()
+            .find(|c| c.index == index && c.source == source)
+            .map(|c| c.translation.as_str())
+    }
+
+    /// Save the translation of the chunk `index`
+    pub fn record(&mut self, index: usize, source: &str, translation: &str) {
+        self.chunks.retain(|c| c.index != index);
+        self.chunks.push(CheckpointChunk {
+            index,
+            source: source.to_string(),
+            translation: translation.to_string(),
+        });
+    }
+}
+
+/// Name of the checkpoint file associated to a translated file
+pub fn checkpoint_file_name(output_file_name: &str) -> String {
+    let stem = output_file_name
+        .strip_suffix(".tex")
+        .unwrap_or(output_file_name);
+    format!("{}.trsltx.json", stem)
+}
+
+impl Trsltx {
+    /// Checkpoint file of the translation, None if the checkpoints are disabled
+    /// or if the translation is written to the standard output
+    fn checkpoint_file(&self) -> Option<String> {
+        if self.checkpoint && !self.output_file_name.is_empty() {
+            Some(checkpoint_file_name(&self.output_file_name))
+        } else {
+            None
+        }
+    }
+
+    /// Translations saved by a previous run with the same languages and model,
+    /// or an empty checkpoint
+    pub(crate) fn load_checkpoint(&self) -> Checkpoint {
+        let empty = Checkpoint {
+            input_lang: self.input_lang.clone(),
+            output_lang: self.output_lang.clone(),
+            model: self.model_name.clone(),
+            chunks: vec![],
+        };
+        let Some(file_name) = self.checkpoint_file() else {
+            return empty;
+        };
+        let Ok(json) = std::fs::read_to_string(&file_name) else {
+            return empty;
+        };
+        match serde_json::from_str::<Checkpoint>(&json) {
+            Ok(checkpoint)
+                if checkpoint.input_lang == empty.input_lang
+                    && checkpoint.output_lang == empty.output_lang
+                    && checkpoint.model == empty.model =>
+            {
+                info!(
+                    "Resume the translation: {} chunks found in {}",
+                    checkpoint.chunks.len(),
+                    file_name
+                );
+                checkpoint
+            }
+            Ok(_) => {
+                warn!(
+                    "Ignore {}: other languages or model than the current translation",
+                    file_name
+                );
+                empty
+            }
+            Err(e) => {
+                warn!("Ignore {}: {:?}", file_name, e);
+                empty
+            }
+        }
+    }
+
+    /// Write the checkpoint after the translation of a chunk.
+    /// A failure is only reported: the translation can go on without checkpoint.
+    pub(crate) fn save_checkpoint(&self, checkpoint: &Checkpoint) {
+        let Some(file_name) = self.checkpoint_file() else {
+            return;
+        };
+        let result = serde_json::to_string_pretty(checkpoint)
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|json| std::fs::write(&file_name, json).map_err(|e| format!("{:?}", e)));
+        if let Err(e) = result {
+            warn!("Cannot write the checkpoint file {}: {}", file_name, e);
+        }
+    }
+
+    /// Remove the checkpoint at the end of the translation if no chunk has failed
+    pub(crate) fn end_checkpoint(&self, failed: usize) {
+        if self.is_cancelled() {
+            warn!("Translation cancelled, {} chunks left untranslated", failed);
+        }
+        let Some(file_name) = self.checkpoint_file() else {
+            return;
+        };
+        if failed == 0 {
+            // the file may not exist if all the chunks are unchanged
+            let _ = std::fs::remove_file(&file_name);
+        } else {
+            warn!(
+                "{} chunks failed, they will be translated again at the next run (checkpoint {})",
+                failed, file_name
+            );
+        }
+    }
+}
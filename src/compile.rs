@@ -0,0 +1,179 @@
+//! Compilation of the translation with LaTeX (`--check-compile` option).
+//!
+//! The translated document is compiled with `pdflatex` (or another engine given
+//! by the user, e.g. `xelatex` or `latexmk`) in a temporary directory. If it fails,
+//! each translated chunk is compiled alone with the preamble of the translation,
+//! and so is its source if the translation fails: the chunks whose translation fails
+//! while the source compiles are reported and marked in the translation with a comment
+//! `% trsltx: compile error: ...` before the chunk.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use tracing::{debug, info};
+
+use crate::{ChunkType, Trsltx, TrsltxError, BODY_HEADER};
+
+/// Engine used by `--check-compile` without value
+pub const DEFAULT_ENGINE: &str = "pdflatex";
+
+/// Beginning of the comment marking the chunks which do not compile
+pub const COMPILE_ERROR_MARK: &str = "% trsltx: compile error:";
+
+/// A translated chunk which introduces a compile error
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    /// chunk number, starting from 1
+    pub chunk: usize,
+    /// first error message of the LaTeX log
+    pub message: String,
+}
+
+/// First error of a LaTeX log (the lines starting with `!`)
+pub fn first_log_error(log: &str) -> Option<String> {
+    log.lines()
+        .find_map(|line| line.strip_prefix("! "))
+        .map(|msg| msg.trim().to_string())
+}
+
+/// Temporary directory of the compilations, removed when dropped
+struct CompileDir(PathBuf);
+
+impl Drop for CompileDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+impl CompileDir {
+    fn new() -> Result<CompileDir, TrsltxError> {
+        let dir = std::env::temp_dir().join(format!("trsltx-compile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).map_err(|e| TrsltxError::io(&dir.to_string_lossy(), e))?;
+        Ok(CompileDir(dir))
+    }
+
+    /// Compile a document, run from `work_dir` so that the included files are found,
+    /// and then from the directories of `inputs` (the translated included files).
+    /// Returns the first error, or None if the compilation succeeds.
+    fn compile(
+        &self,
+        engine: &str,
+        work_dir: &Path,
+        inputs: &[PathBuf],
+        name: &str,
+        latex: &str,
+    ) -> Result<Option<String>, TrsltxError> {
+        let file = self.0.join(format!("{}.tex", name));
+        std::fs::write(&file, latex).map_err(|e| TrsltxError::io(&file.to_string_lossy(), e))?;
+        let mut command = Command::new(engine);
+        if engine == "latexmk" {
+            command.arg("-pdf");
+        } else {
+            command.arg("-halt-on-error");
+        }
+        if !inputs.is_empty() {
+            // the empty path at the end keeps the default directories of TeX
+            let dirs = inputs.iter().cloned().chain([PathBuf::new()]);
+            if let Ok(texinputs) = std::env::join_paths(dirs) {
+                command.env("TEXINPUTS", texinputs);
+            }
+        }
+        let output = command
+            .arg("-interaction=nonstopmode")
+            .arg(format!("-output-directory={}", self.0.display()))
+            .arg(&file)
+            .current_dir(work_dir)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| TrsltxError::io(engine, e))?;
+        if output.status.success() {
+            return Ok(None);
+        }
+        let log = std::fs::read_to_string(self.0.join(format!("{}.log", name)))
+            .unwrap_or_else(|_| String::from_utf8_lossy(&output.stdout).to_string());
+        debug!("{} failed on {}: {}", engine, name, log);
+        Ok(Some(first_log_error(&log).unwrap_or_else(|| {
+            format!("{} failed ({})", engine, output.status)
+        })))
+    }
+}
+
+impl Trsltx {
+    /// Compile the translation with `engine` and mark the chunks introducing
+    /// compile errors (see the module `compile`). To be called after `translate` and
+    /// `process_subfiles`, so that the translated included files are found.
+    /// The files included without preamble are not compiled.
+    pub fn check_compile(&mut self, engine: &str) -> Result<Vec<CompileError>, TrsltxError> {
+        if self.fragment {
+            return Ok(vec![]);
+        }
+        let dir = CompileDir::new()?;
+        let work_dir = match self.input_dir() {
+            d if d.as_os_str().is_empty() => PathBuf::from("."),
+            d => d,
+        };
+        // the translated included files are written next to the output file,
+        // the relative paths are resolved before changing the directory
+        let inputs: Vec<PathBuf> = self
+            .output_dir()
+            .and_then(|d| match d.as_os_str().is_empty() {
+                true => PathBuf::from(".").canonicalize().ok(),
+                false => d.canonicalize().ok(),
+            })
+            .filter(|d| work_dir.canonicalize().ok().as_ref() != Some(d))
+            .into_iter()
+            .collect();
+        let mut document = vec![];
+        self.write_to(&mut document)
+            .map_err(|e| TrsltxError::io("translation", e))?;
+        let document = String::from_utf8_lossy(&document);
+        let Some(error) = dir.compile(engine, &work_dir, &inputs, "trsltx", &document)? else {
+            info!("The translation compiles with {}", engine);
+            return Ok(vec![]);
+        };
+        info!(
+            "The translation does not compile with {}: {}. Compile each chunk",
+            engine, error
+        );
+        let mut errors = vec![];
+        for (i, (source, t)) in self.chunks.iter().enumerate() {
+            let Some(trs_chunk) = self.chunks_translated.get(i) else {
+                break;
+            };
+            if *t != ChunkType::Translate {
+                continue;
+            }
+            let wrap = |chunk: &str| {
+                format!(
+                    "{}{}\n{}\n\\end{{document}}\n",
+                    self.preamble, BODY_HEADER, chunk
+                )
+            };
+            let Some(message) =
+                dir.compile(engine, &work_dir, &inputs, "chunk", &wrap(trs_chunk))?
+            else {
+                continue;
+            };
+            // the chunk may not compile alone, e.g. if an environment is split
+            if dir
+                .compile(engine, &work_dir, &inputs, "source", &wrap(source))?
+                .is_some()
+            {
+                debug!("chunk {} does not compile alone, even in the source", i + 1);
+                continue;
+            }
+            errors.push(CompileError {
+                chunk: i + 1,
+                message,
+            });
+        }
+        for error in errors.iter() {
+            let trs_chunk = &mut self.chunks_translated[error.chunk - 1];
+            *trs_chunk = format!("{} {}\n{}", COMPILE_ERROR_MARK, error.message, trs_chunk);
+        }
+        if !errors.is_empty() {
+            self.body_translated = self.assemble_body();
+        }
+        Ok(errors)
+    }
+}
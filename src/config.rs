@@ -0,0 +1,99 @@
+//! Configuration file `trsltx.toml`.
+//!
+//! The chunk types assigned by `Trsltx::extract_chunks` can be overridden
+//! in the table `[chunk_overrides]`, where the keys are the chunk numbers
+//! (starting from 1, as in the messages of `trsltx`) and the values are
+//! `"translate"` or `"unchanged"`:
+//!
+//! ```toml
+//! [chunk_overrides]
+//! 3 = "unchanged"
+//! 7 = "translate"
+//! ```
+//!
+//! The top-level keys give default values of the options of the command line
+//! tool, e.g. for all the files of a book. The options given on the command
+//! line take precedence:
+//!
+//! ```toml
+//! input_lang = "fr"
+//! output_lang = "en"
+//! model = "llama3_70B"
+//! length_split = 1500
+//! backend = "openai"
+//! base_url = "https://api.openai.com/v1"
+//! api_key_env = "OPENAI_API_KEY"
+//! prompt_file = "prompts/legal.txt"
+//! ```
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::{ChunkType, TrsltxError};
+
+/// Default name of the configuration file, in the working directory
+pub const CONFIG_FILE: &str = "trsltx.toml";
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    chunk_overrides: BTreeMap<String, String>,
+}
+
+/// Default options of the command line tool, the absent keys are None
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub input_lang: Option<String>,
+    pub output_lang: Option<String>,
+    pub model: Option<String>,
+    pub length_split: Option<usize>,
+    /// "textsynth", "openai", "ollama" or "deepl"
+    pub backend: Option<String>,
+    /// base URL of the openai, ollama or deepl API
+    pub base_url: Option<String>,
+    /// environment variable of the api key of the openai backend
+    pub api_key_env: Option<String>,
+    /// template of the prompt, see `Trsltx::read_prompt_file`
+    pub prompt_file: Option<String>,
+    // read by `read_chunk_overrides`
+    #[serde(default, rename = "chunk_overrides")]
+    _chunk_overrides: toml::Table,
+}
+
+/// Read the default options of a configuration file
+pub fn read_settings(path: &str) -> Result<Settings, TrsltxError> {
+    let content = std::fs::read_to_string(path).map_err(|e| TrsltxError::io(path, e))?;
+    parse_settings(content.as_str())
+}
+
+/// Parse the default options in the content of a configuration file
+pub fn parse_settings(content: &str) -> Result<Settings, TrsltxError> {
+    toml::from_str(content)
+        .map_err(|e| TrsltxError::Config(format!("Error in configuration file: {}", e)))
+}
+
+/// Read the chunk type overrides of a configuration file
+pub fn read_chunk_overrides(path: &str) -> Result<Vec<(usize, ChunkType)>, TrsltxError> {
+    let content = std::fs::read_to_string(path).map_err(|e| TrsltxError::io(path, e))?;
+    parse_chunk_overrides(content.as_str())
+}
+
+/// Parse the chunk type overrides in the content of a configuration file
+pub fn parse_chunk_overrides(content: &str) -> Result<Vec<(usize, ChunkType)>, TrsltxError> {
+    let config: ConfigFile = toml::from_str(content)
+        .map_err(|e| TrsltxError::Config(format!("Error in configuration file: {}", e)))?;
+    let mut overrides = vec![];
+    for (num, t) in config.chunk_overrides.iter() {
+        let num: usize = num.parse().map_err(|_| {
+            TrsltxError::Config(format!(
+                "Invalid chunk number in [chunk_overrides]: {}",
+                num
+            ))
+        })?;
+        overrides.push((num, t.parse::<ChunkType>().map_err(TrsltxError::Config)?));
+    }
+    overrides.sort_by_key(|(num, _)| *num);
+    Ok(overrides)
+}
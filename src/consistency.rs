@@ -0,0 +1,164 @@
+//! Consistency of the labels, references and citations between the source
+//! and the translation (`--consistency-report` option).
+//!
+//! The keys of each translated chunk are compared with the keys of its source.
+//! A key of the source missing in the translation is lost, a key of the translation
+//! missing in the source is invented. When a chunk has both lost and invented keys
+//! of the same kind, they are paired in order of appearance and reported as renamed.
+
+use serde::Serialize;
+
+use crate::{
+    extracts_citation_keys, extracts_labels, extracts_references, ChunkType, Trsltx, TrsltxError,
+};
+
+/// Kind of a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyKind {
+    /// `\label{key}`
+    Label,
+    /// `\ref{key}`, `\eqref{key}`...
+    Reference,
+    /// `\cite{key}`, `\citep{key}`...
+    Citation,
+}
+
+impl KeyKind {
+    const ALL: [KeyKind; 3] = [KeyKind::Label, KeyKind::Reference, KeyKind::Citation];
+
+    /// Keys of this kind, in order of appearance, without duplicates
+    fn keys(&self, latex: &str) -> Vec<String> {
+        let mut keys = match self {
+            KeyKind::Label => extracts_labels(latex),
+            KeyKind::Reference => extracts_references(latex),
+            KeyKind::Citation => extracts_citation_keys(latex),
+        };
+        let mut seen = vec![];
+        keys.retain(|key| {
+            let first = !seen.contains(key);
+            seen.push(key.clone());
+            first
+        });
+        keys
+    }
+}
+
+/// Change of a key in the translation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyChange {
+    /// the key of the source is missing in the translation
+    Lost,
+    /// the key of the translation is not in the source
+    Invented,
+    /// the key of the source is replaced by another one
+    Renamed { to: String },
+}
+
+/// A key lost, invented or renamed in the translation of a chunk
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyIssue {
+    /// chunk number, starting from 1
+    pub chunk: usize,
+    pub kind: KeyKind,
+    /// key of the source, or of the translation for an invented key
+    pub key: String,
+    pub change: KeyChange,
+}
+
+impl std::fmt::Display for KeyIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = match self.kind {
+            KeyKind::Label => "label",
+            KeyKind::Reference => "reference",
+            KeyKind::Citation => "citation",
+        };
+        match &self.change {
+            KeyChange::Lost => write!(f, "chunk {}: {} {} lost", self.chunk, kind, self.key),
+            KeyChange::Invented => {
+                write!(f, "chunk {}: {} {} invented", self.chunk, kind, self.key)
+            }
+            KeyChange::Renamed { to } => write!(
+                f,
+                "chunk {}: {} {} renamed {}",
+                self.chunk, kind, self.key, to
+            ),
+        }
+    }
+}
+
+/// Changes of the keys between a source chunk and its translation
+pub fn compare_keys(chunk: usize, source: &str, translation: &str) -> Vec<KeyIssue> {
+    let mut issues = vec![];
+    for kind in KeyKind::ALL {
+        let source_keys = kind.keys(source);
+        let translation_keys = kind.keys(translation);
+        let lost: Vec<&String> = source_keys
+            .iter()
+            .filter(|key| !translation_keys.contains(key))
+            .collect();
+        let invented: Vec<&String> = translation_keys
+            .iter()
+            .filter(|key| !source_keys.contains(key))
+            .collect();
+        let renamed = lost.len().min(invented.len());
+        let issue = |key: &str, change| KeyIssue {
+            chunk,
+            kind,
+            key: key.to_string(),
+            change,
+        };
+        for (from, to) in lost.iter().zip(invented.iter()) {
+            issues.push(issue(from, KeyChange::Renamed { to: to.to_string() }));
+        }
+        for key in &lost[renamed..] {
+            issues.push(issue(key, KeyChange::Lost));
+        }
+        for key in &invented[renamed..] {
+            issues.push(issue(key, KeyChange::Invented));
+        }
+    }
+    issues
+}
+
+/// Label, reference and citation changes of a translation
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConsistencyReport {
+    pub issues: Vec<KeyIssue>,
+}
+
+impl std::fmt::Display for ConsistencyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for issue in self.issues.iter() {
+            writeln!(f, "{}", issue)?;
+        }
+        match self.issues.len() {
+            0 => writeln!(f, "The labels, references and citations are unchanged"),
+            n => writeln!(f, "{} labels, references or citations changed", n),
+        }
+    }
+}
+
+impl Trsltx {
+    /// Compare the labels, references and citations of the translated chunks
+    /// with their sources, after `translate`
+    pub fn consistency_report(&self) -> ConsistencyReport {
+        let issues = self
+            .chunks
+            .iter()
+            .zip(self.chunks_translated.iter())
+            .enumerate()
+            .filter(|(_, ((_, t), _))| *t == ChunkType::Translate)
+            .flat_map(|(i, ((source, _), translation))| compare_keys(i + 1, source, translation))
+            .collect();
+        ConsistencyReport { issues }
+    }
+
+    /// Write the consistency report as JSON
+    pub fn write_consistency_report(&self, path: &str) -> Result<(), TrsltxError> {
+        let json = serde_json::to_string_pretty(&self.consistency_report())
+            .map_err(|e| TrsltxError::io(path, e.into()))?;
+        std::fs::write(path, json).map_err(|e| TrsltxError::io(path, e))
+    }
+}
@@ -0,0 +1,178 @@
+//! Detection of the source language (`-i auto`).
+//!
+//! The language is guessed locally, without request to the server: first from
+//! the script of the text (Cyrillic, Greek, CJK...), then from the most frequent
+//! short words of each language. If the text is too short or ambiguous, the main
+//! language declared with babel or polyglossia in the preamble is used.
+
+use tracing::{debug, info};
+
+use crate::languages::{find_language, Language, LANGUAGES};
+use crate::{command_with_options, extracts_all_text, find_uncommented, Trsltx, TrsltxError};
+
+/// Minimal number of frequent words found in the text for a detection
+const MIN_SCORE: usize = 5;
+
+/// Frequent words of the languages written with the Latin or Cyrillic alphabet,
+/// separated by spaces
+const STOPWORDS: [(&str, &str); 23] = [
+    ("en", "the and of to is in that with for are this we"),
+    ("fr", "le la les des et est une dans que pour du nous"),
+    ("es", "el los las que y es una para con del por se"),
+    ("de", "der die das und ist nicht mit ein eine den wir zu"),
+    ("it", "il di che è e della una per sono non gli nel"),
+    ("pt", "o os que e é uma para com não do da em"),
+    ("nl", "de het een en van is dat niet met voor zijn wij"),
+    ("pl", "i w na jest się nie że z do oraz to dla"),
+    ("sv", "och att är det en som av för med inte på vi"),
+    ("da", "og at er det en som af for med ikke på vi"),
+    ("no", "og at er det en som av for med ikke på vi"),
+    ("fi", "ja on ei että se joka tai kun mutta myös ovat tämä"),
+    ("cs", "a je se na že v to s z pro jsou není"),
+    ("sk", "a je sa na že v to s z pre sú nie"),
+    ("hu", "a az és hogy egy nem is van meg ez mint csak"),
+    ("ro", "și de la în este că cu un o pe nu sunt"),
+    ("ca", "el la els les i és que amb per una del no"),
+    ("tr", "ve bir bu için ile da de olan olarak çok ise değil"),
+    ("vi", "và của là có các trong được cho không một những này"),
+    ("id", "dan yang di ini untuk dari tidak itu dalam akan"),
+    ("ru", "и в не что на с это как по для мы является"),
+    ("uk", "і в не що на з це як та для ми є"),
+    ("bg", "и в не че на с е се за да от това"),
+];
+
+/// Script of a letter, `None` for the Latin alphabet and the Cyrillic alphabet,
+/// whose languages are told apart by their frequent words
+fn script_language(c: char) -> Option<&'static str> {
+    match c {
+        '\u{0370}'..='\u{03ff}' => Some("el"),
+        '\u{0590}'..='\u{05ff}' => Some("he"),
+        '\u{0600}'..='\u{06ff}' => Some("ar"),
+        '\u{0900}'..='\u{097f}' => Some("hi"),
+        '\u{3040}'..='\u{30ff}' => Some("ja"),
+        '\u{ac00}'..='\u{d7af}' => Some("ko"),
+        '\u{4e00}'..='\u{9fff}' => Some("zh"),
+        _ => None,
+    }
+}
+
+/// Language of a text by its script or its frequent words, None if the text is
+/// too short or ambiguous
+pub fn detect_text_language(text: &str) -> Option<&'static Language> {
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+    let mut scripts: Vec<(&str, usize)> = vec![];
+    for lang in letters.iter().filter_map(|c| script_language(*c)) {
+        match scripts.iter_mut().find(|(l, _)| *l == lang) {
+            Some((_, n)) => *n += 1,
+            None => scripts.push((lang, 1)),
+        }
+    }
+    // the Japanese texts also contain Chinese characters
+    let kana = scripts
+        .iter()
+        .find(|(l, _)| *l == "ja")
+        .map_or(0, |(_, n)| *n);
+    let non_latin: usize = scripts.iter().map(|(_, n)| n).sum();
+    if non_latin * 2 > letters.len() {
+        let best = if kana > 0 {
+            "ja"
+        } else {
+            scripts.iter().max_by_key(|(_, n)| *n)?.0
+        };
+        return find_language(best);
+    }
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect();
+    let mut scores: Vec<(&str, usize)> = STOPWORDS
+        .iter()
+        .map(|(lang, stopwords)| {
+            let score = words
+                .iter()
+                .filter(|w| stopwords.split(' ').any(|s| s == w.as_str()))
+                .count();
+            (*lang, score)
+        })
+        .collect();
+    scores.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+    debug!("language scores: {:?}", &scores[..3]);
+    match scores[..] {
+        [(lang, best), (_, second), ..] if best >= MIN_SCORE && best > second => {
+            find_language(lang)
+        }
+        _ => None,
+    }
+}
+
+/// Main language declared in a preamble: the argument of `\setmainlanguage`
+/// or `\setdefaultlanguage` (polyglossia), or the last language of the options
+/// of babel or of the class, or the option `main=`
+pub fn declared_language(preamble: &str) -> Option<&'static Language> {
+    let polyglossia = ["setmainlanguage", "setdefaultlanguage"]
+        .iter()
+        .filter_map(|name| {
+            let pos = find_uncommented(preamble, &format!("\\{}", name))?;
+            let (_, (_, arg)) = command_with_options(name)(&preamble[pos..]).ok()?;
+            LANGUAGES.iter().find(|l| l.polyglossia == arg.trim())
+        })
+        .next();
+    if polyglossia.is_some() {
+        return polyglossia;
+    }
+    let mut declared = None;
+    for name in ["documentclass", "usepackage"] {
+        let mut rest = preamble;
+        while let Some(pos) = find_uncommented(rest, &format!("\\{}", name)) {
+            let parsed = command_with_options(name)(&rest[pos..]);
+            rest = &rest[pos + 1..];
+            let Ok((_, (Some(options), arg))) = parsed else {
+                continue;
+            };
+            if name == "usepackage" && arg.trim() != "babel" {
+                continue;
+            }
+            for option in options.split(',') {
+                let (key, value) = match option.split_once('=') {
+                    Some((key, value)) => (key.trim(), value.trim()),
+                    None => ("", option.trim()),
+                };
+                if let Some(lang) = LANGUAGES.iter().find(|l| l.babel.contains(&value)) {
+                    // babel: the last language is the main one, unless main= is given
+                    if key == "main" {
+                        return Some(lang);
+                    }
+                    if key.is_empty() {
+                        declared = Some(lang);
+                    }
+                }
+            }
+        }
+    }
+    declared
+}
+
+impl Trsltx {
+    /// Detect the language of the file read by `read_file` (see the module `detect`)
+    /// and use it as the input language
+    pub fn detect_input_lang(&mut self) -> Result<&str, TrsltxError> {
+        let lang = detect_text_language(&extracts_all_text(&self.body))
+            .inspect(|lang| info!("Detected language of the text: {}", lang.name))
+            .or_else(|| {
+                let lang = declared_language(&self.preamble)?;
+                info!(
+                    "Language declared in the preamble: {} (the text is too short for a detection)",
+                    lang.name
+                );
+                Some(lang)
+            })
+            .ok_or_else(|| {
+                TrsltxError::Language(
+                    "Cannot detect the language of the text, give it with -i".to_string(),
+                )
+            })?;
+        self.input_lang = lang.code.to_string();
+        Ok(&self.input_lang)
+    }
+}
@@ -0,0 +1,120 @@
+//! Structural comparison of the source chunks and their translations.
+//!
+//! The LaTeX structure of a chunk is approximated by the sequence of its commands,
+//! where the labels, references, citations and environments keep their argument
+//! (`\label{eq:x}`, `\begin{equation}`...). The two sequences are compared with
+//! a longest common subsequence algorithm.
+
+use serde::Serialize;
+
+use crate::is_citation_command;
+
+/// Commands whose argument is part of the structure, with the citations
+/// (see `is_citation_command`)
+const KEYED_COMMANDS: [&str; 12] = [
+    "label", "ref", "eqref", "autoref", "pageref", "cref", "Cref", "cite", "begin", "end", "url",
+    "href",
+];
+
+/// A structural difference between a source chunk and its translation
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum AstDiff {
+    /// command present only in the translation
+    Added(String),
+    /// command present only in the source
+    Removed(String),
+    /// command of the source replaced by another one in the translation
+    Modified { from: String, to: String },
+}
+
+/// The structural differences of one chunk
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkDiff {
+    /// chunk number, starting from 1
+    pub index: usize,
+    pub diffs: Vec<AstDiff>,
+}
+
+/// Sequence of the LaTeX commands of a string, with the argument of the
+/// labels, references, citations and environments. Comments are ignored.
+pub fn structural_tokens(latex: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    for line in latex.lines() {
+        let mut chars = line.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '%' => break,
+                '\\' => {
+                    let mut end = i + 1;
+                    while let Some((j, c)) = chars.next_if(|(_, c)| c.is_ascii_alphabetic()) {
+                        end = j + c.len_utf8();
+                    }
+                    if end == i + 1 {
+                        // escaped character such as \% or \\
+                        chars.next();
+                        continue;
+                    }
+                    let name = &line[i + 1..end];
+                    let mut token = line[i..end].to_string();
+                    let keyed = KEYED_COMMANDS.contains(&name) || is_citation_command(name);
+                    if keyed && line[end..].starts_with('{') {
+                        if let Some((arg, _)) = line[end + 1..].split_once('}') {
+                            token.push_str(&format!("{{{}}}", arg));
+                        }
+                    }
+                    tokens.push(token);
+                }
+                _ => {}
+            }
+        }
+    }
+    tokens
+}
+
+/// Structural differences between a source LaTeX string and its translation
+pub fn ast_diff(source: &str, translation: &str) -> Vec<AstDiff> {
+    let a = structural_tokens(source);
+    let b = structural_tokens(translation);
+    // longest common subsequence table
+    let (n, m) = (a.len(), b.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut diffs = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] > lcs[i + 1][j]) {
+            diffs.push(AstDiff::Added(b[j].clone()));
+            j += 1;
+        } else {
+            diffs.push(AstDiff::Removed(a[i].clone()));
+            i += 1;
+        }
+    }
+    // a removal followed by an addition is a modification
+    let mut merged: Vec<AstDiff> = vec![];
+    for diff in diffs {
+        match (merged.last(), &diff) {
+            (Some(AstDiff::Removed(from)), AstDiff::Added(to)) => {
+                let from = from.clone();
+                merged.pop();
+                merged.push(AstDiff::Modified {
+                    from,
+                    to: to.clone(),
+                });
+            }
+            _ => merged.push(diff),
+        }
+    }
+    merged
+}
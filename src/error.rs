@@ -0,0 +1,62 @@
+//! Error type of the public functions of `trsltx`.
+//!
+//! The variants give the cause of the failure, so that the library users can
+//! handle them differently, e.g. retry after an `Api` error but not after a
+//! `Parse` error. The `Display` implementation gives the message printed by
+//! the command line tool.
+
+/// Error of the public functions of `trsltx`
+#[derive(Debug)]
+pub enum TrsltxError {
+    /// a file, the standard input or the standard output cannot be read or written
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    /// the LaTeX source or a data file (glossary, cache...) is malformed
+    Parse(String),
+    /// the request to the LLM server or the translator failed
+    Api(String),
+    /// invalid settings or configuration file
+    Config(String),
+    /// unsupported language or file name without language suffix
+    Language(String),
+}
+
+impl TrsltxError {
+    /// I/O error on `path`
+    pub fn io(path: &str, source: std::io::Error) -> TrsltxError {
+        TrsltxError::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+}
+
+impl std::fmt::Display for TrsltxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TrsltxError::Io { path, source } => write!(f, "{}: {}", path, source),
+            TrsltxError::Parse(msg)
+            | TrsltxError::Api(msg)
+            | TrsltxError::Config(msg)
+            | TrsltxError::Language(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TrsltxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrsltxError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// the command line tool and the backends report the errors as strings
+impl From<TrsltxError> for String {
+    fn from(e: TrsltxError) -> String {
+        e.to_string()
+    }
+}
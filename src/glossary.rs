@@ -0,0 +1,296 @@
+//! Glossary of domain-specific terms (`--glossary-file` option).
+//!
+//! The glossary file has one term per line, the source term and its translation
+//! separated by a tab (`<TAB>` below). The empty lines and the lines starting
+//! with `#` are ignored:
+//!
+//! ```text
+//! # fr -> en
+//! espace de Hilbert<TAB>Hilbert space
+//! maillage<TAB>mesh
+//! ```
+//!
+//! The terms are given to the LLM in the prompt. The source terms of the chunk still
+//! present in its translation are replaced by their translation, and a comment
+//! `% trsltx: glossary correction of ...` is added at the end of the chunk.
+//! The correction is made before the protected commands are restored, and only in the
+//! text: the mathematics, the comments, the command names and their arguments
+//! (labels, references, file names...) are left unchanged.
+use tracing::{info, warn};
+
+use crate::{
+    extracts_all_text, matching_brace, ChunkMeta, ChunkType, Trsltx, TrsltxError, MATH_ENVS,
+};
+
+/// Read a glossary file
+pub fn read_glossary(path: &str) -> Result<Vec<(String, String)>, TrsltxError> {
+    let content = std::fs::read_to_string(path).map_err(|e| TrsltxError::io(path, e))?;
+    parse_glossary(content.as_str())
+}
+
+/// Parse the content of a glossary file
+pub fn parse_glossary(content: &str) -> Result<Vec<(String, String)>, TrsltxError> {
+    let mut glossary = vec![];
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (source, target) = line
+            .split_once('\t')
+            .map(|(s, t)| (s.trim(), t.trim()))
+            .filter(|(s, t)| !s.is_empty() && !t.is_empty())
+            .ok_or_else(|| {
+                TrsltxError::Parse(format!(
+                    "Error in glossary, line {}: expected a term and its translation separated by a tab",
+                    i + 1
+                ))
+            })?;
+        glossary.push((source.to_string(), target.to_string()));
+    }
+    Ok(glossary)
+}
+
+/// Section of the prompt giving the translations of the terms
+pub fn glossary_prompt(glossary: &[(String, String)]) -> String {
+    let mut prompt = "Use the following term translations:\n".to_string();
+    for (source, target) in glossary {
+        prompt.push_str(&format!("- {} \u{2192} {}\n", source, target));
+    }
+    prompt
+}
+
+/// True if the word at `start..end` is not a part of a longer word or of a command name
+fn is_whole_word(text: &str, start: usize, end: usize) -> bool {
+    let before = text[..start].chars().next_back();
+    let after = text[end..].chars().next();
+    !before.is_some_and(|c| c.is_alphanumeric() || c == '\\')
+        && !after.is_some_and(|c| c.is_alphanumeric())
+}
+
+/// Length of the part of `latex` at its beginning which is not text: a comment,
+/// a formula, a math environment, or a command with its options and arguments.
+/// 0 if `latex` begins with text.
+// the parser ltxprs does not give the positions of the nodes, hence the raw scan
+fn non_text_len(latex: &str) -> usize {
+    let find_end = |start: usize, end: &str| {
+        latex[start..]
+            .find(end)
+            .map_or(latex.len(), |i| start + i + end.len())
+    };
+    if latex.starts_with('%') {
+        return latex.find('\n').unwrap_or(latex.len());
+    }
+    if latex.starts_with("$$") {
+        return find_end(2, "$$");
+    }
+    if latex.starts_with('$') {
+        let mut prev = ' ';
+        for (i, c) in latex.char_indices().skip(1) {
+            if c == '$' && prev != '\\' {
+                return i + 1;
+            }
+            prev = c;
+        }
+        return latex.len();
+    }
+    if latex.starts_with("\\[") {
+        return find_end(2, "\\]");
+    }
+    if latex.starts_with("\\(") {
+        return find_end(2, "\\)");
+    }
+    if !latex.starts_with('\\') {
+        return 0;
+    }
+    let name_len = latex[1..]
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(latex.len() - 1);
+    if name_len == 0 {
+        // escaped character
+        return 1 + latex[1..].chars().next().map_or(0, |c| c.len_utf8());
+    }
+    for env in MATH_ENVS {
+        for env in [env.to_string(), format!("{}*", env)] {
+            if latex.starts_with(&format!("\\begin{{{}}}", env)) {
+                return find_end(0, &format!("\\end{{{}}}", env));
+            }
+        }
+    }
+    // the options and the arguments of the command
+    let mut end = 1 + name_len;
+    loop {
+        if latex[end..].starts_with('[') {
+            match latex[end..].find(']') {
+                Some(close) => end += close + 1,
+                None => break,
+            }
+        } else if latex[end..].starts_with('{') {
+            match matching_brace(latex, end) {
+                Some(close) => end = close + 1,
+                None => break,
+            }
+        } else {
+            break;
+        }
+    }
+    end
+}
+
+/// Parts of a LaTeX string, with true for the text and false for the rest
+fn text_parts(latex: &str) -> Vec<(bool, &str)> {
+    let mut parts: Vec<(bool, &str)> = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i < latex.len() {
+        let len = non_text_len(&latex[i..]);
+        if len == 0 {
+            i += latex[i..].chars().next().map_or(1, |c| c.len_utf8());
+            continue;
+        }
+        if start < i {
+            parts.push((true, &latex[start..i]));
+        }
+        parts.push((false, &latex[i..i + len]));
+        i += len;
+        start = i;
+    }
+    if start < latex.len() {
+        parts.push((true, &latex[start..]));
+    }
+    parts
+}
+
+/// True if `term` is a whole word of `text`
+fn contains_word(text: &str, term: &str) -> bool {
+    text.match_indices(term)
+        .any(|(start, _)| is_whole_word(text, start, start + term.len()))
+}
+
+/// Replace the source terms of the glossary left in the translation of `source`
+/// by their translation, in the text only (see the module `glossary`).
+/// Returns the corrected translation and the corrected source terms.
+/// The terms absent from the text of the source are not corrected, since they may be
+/// words of the output language, neither are the terms whose translation contains the
+/// source term.
+pub fn apply_glossary(
+    source: &str,
+    translation: &str,
+    glossary: &[(String, String)],
+) -> (String, Vec<String>) {
+    let source_parts = text_parts(source);
+    let mut parts: Vec<(bool, String)> = text_parts(translation)
+        .into_iter()
+        .map(|(is_text, part)| (is_text, part.to_string()))
+        .collect();
+    let mut corrected = vec![];
+    for (term, target) in glossary {
+        if target.contains(term.as_str())
+            || !source_parts
+                .iter()
+                .any(|(is_text, part)| *is_text && contains_word(part, term))
+        {
+            continue;
+        }
+        for (_, text) in parts.iter_mut().filter(|(is_text, _)| *is_text) {
+            let mut result = String::new();
+            let mut last = 0;
+            for (start, _) in text.match_indices(term.as_str()) {
+                let end = start + term.len();
+                if is_whole_word(text, start, end) {
+                    result.push_str(&text[last..start]);
+                    result.push_str(target);
+                    last = end;
+                }
+            }
+            if last > 0 {
+                result.push_str(&text[last..]);
+                *text = result;
+                if !corrected.contains(term) {
+                    corrected.push(term.clone());
+                }
+            }
+        }
+    }
+    (parts.into_iter().map(|(_, part)| part).collect(), corrected)
+}
+
+/// Words of a LaTeX string with at least 4 letters, sorted and deduplicated
+fn words(latex: &str) -> Vec<String> {
+    let mut words: Vec<String> = extracts_all_text(latex)
+        .split(|c: char| !c.is_alphanumeric() && c != '-')
+        .map(|w| w.trim_matches('-'))
+        .filter(|w| w.chars().filter(|c| c.is_alphabetic()).count() >= 4)
+        .map(|w| w.to_string())
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+/// Words of the source chunk found unchanged in its translation:
+/// proper names, or technical terms unknown to the LLM
+pub fn untranslated_terms(source: &str, translation: &str) -> Vec<String> {
+    let translated = words(translation);
+    words(source)
+        .into_iter()
+        .filter(|w| translated.binary_search(w).is_ok())
+        .collect()
+}
+
+impl Trsltx {
+    /// Read the term translations of a glossary file, see the module `glossary`
+    pub fn read_glossary_file(&mut self, path: &str) -> Result<(), TrsltxError> {
+        let glossary = read_glossary(path)?;
+        info!("{} terms found in {}", glossary.len(), path);
+        self.glossary.extend(glossary);
+        Ok(())
+    }
+
+    /// Terms left untranslated in the translated chunks and absent from the glossary,
+    /// as suggestions for the glossary file
+    pub fn glossary_suggestions(&self) -> Vec<String> {
+        let mut terms: Vec<String> = self
+            .chunks
+            .iter()
+            .zip(self.chunks_translated.iter())
+            // the chunks whose translation failed are left unchanged
+            .filter(|((chunk, t), trs_chunk)| *t == ChunkType::Translate && chunk != *trs_chunk)
+            .flat_map(|((chunk, _), trs_chunk)| untranslated_terms(chunk, trs_chunk))
+            .filter(|term| !self.glossary.iter().any(|(source, _)| source == term))
+            .collect();
+        terms.sort();
+        terms.dedup();
+        terms
+    }
+
+    /// Correct with the glossary the translation of `source`, before the protected
+    /// commands are restored. The corrected terms are recorded in `meta`.
+    pub(crate) fn correct_glossary(
+        &self,
+        source: &str,
+        translation: String,
+        meta: &mut ChunkMeta,
+    ) -> String {
+        let (translation, corrected) = apply_glossary(source, &translation, &self.glossary);
+        meta.glossary_corrections = corrected;
+        translation
+    }
+
+    /// Add to the translation of the chunk `i` the comment giving its glossary corrections
+    pub(crate) fn glossary_comment(&self, i: usize, meta: &ChunkMeta, trs_chunk: String) -> String {
+        let corrected = &meta.glossary_corrections;
+        if corrected.is_empty() {
+            return trs_chunk;
+        }
+        warn!(
+            "glossary terms left untranslated in chunk {}: {}",
+            i + 1,
+            corrected.join(", ")
+        );
+        format!(
+            "{}\n% trsltx: glossary correction of {}",
+            trs_chunk.trim_end(),
+            corrected.join(", ")
+        )
+    }
+}
@@ -0,0 +1,107 @@
+//! Languages supported by `trsltx`.
+//!
+//! A language is given by its ISO 639-1 code (e.g. `nl`), which is also the
+//! suffix of the file names (`paper_nl.tex`). The English name is used in the
+//! prompt, the babel and polyglossia names in the preamble of the translation.
+
+/// A language of the source or the translation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Language {
+    /// ISO 639-1 code
+    pub code: &'static str,
+    /// English name, given to the LLM
+    pub name: &'static str,
+    /// option of the babel package; the other names accepted by babel
+    /// for this language are replaced in the source preamble
+    pub babel: &'static [&'static str],
+    /// argument of the polyglossia commands (`\setdefaultlanguage{...}`)
+    pub polyglossia: &'static str,
+    /// needs the Cyrillic font encoding `T2A` with pdflatex
+    pub cyrillic: bool,
+}
+
+const fn lang(
+    code: &'static str,
+    name: &'static str,
+    babel: &'static [&'static str],
+    polyglossia: &'static str,
+) -> Language {
+    Language {
+        code,
+        name,
+        babel,
+        polyglossia,
+        cyrillic: false,
+    }
+}
+
+const fn cyrillic(
+    code: &'static str,
+    name: &'static str,
+    babel: &'static [&'static str],
+    polyglossia: &'static str,
+) -> Language {
+    Language {
+        cyrillic: true,
+        ..lang(code, name, babel, polyglossia)
+    }
+}
+
+/// The supported languages. The languages without `.ldf` file (Chinese, Japanese,
+/// Korean...) are loaded by babel from its `.ini` files.
+pub const LANGUAGES: &[Language] = &[
+    lang(
+        "en",
+        "English",
+        &["english", "american", "british"],
+        "english",
+    ),
+    lang("fr", "French", &["french", "francais"], "french"),
+    lang("es", "Spanish", &["spanish"], "spanish"),
+    lang("de", "German", &["german", "ngerman"], "german"),
+    lang("it", "Italian", &["italian"], "italian"),
+    lang(
+        "pt",
+        "Portuguese",
+        &["portuguese", "brazilian", "brazil"],
+        "portuguese",
+    ),
+    cyrillic("ru", "Russian", &["russian"], "russian"),
+    lang("nl", "Dutch", &["dutch"], "dutch"),
+    lang("pl", "Polish", &["polish"], "polish"),
+    lang("sv", "Swedish", &["swedish"], "swedish"),
+    lang("da", "Danish", &["danish"], "danish"),
+    lang("no", "Norwegian", &["norsk", "norwegian"], "norwegian"),
+    lang("fi", "Finnish", &["finnish"], "finnish"),
+    lang("cs", "Czech", &["czech"], "czech"),
+    lang("sk", "Slovak", &["slovak"], "slovak"),
+    lang("hu", "Hungarian", &["magyar", "hungarian"], "hungarian"),
+    lang("ro", "Romanian", &["romanian"], "romanian"),
+    lang("ca", "Catalan", &["catalan"], "catalan"),
+    lang("el", "Greek", &["greek"], "greek"),
+    lang("tr", "Turkish", &["turkish"], "turkish"),
+    cyrillic("uk", "Ukrainian", &["ukrainian"], "ukrainian"),
+    cyrillic("bg", "Bulgarian", &["bulgarian"], "bulgarian"),
+    lang("he", "Hebrew", &["hebrew"], "hebrew"),
+    lang("ar", "Arabic", &["arabic"], "arabic"),
+    lang("hi", "Hindi", &["hindi"], "hindi"),
+    lang("vi", "Vietnamese", &["vietnamese"], "vietnamese"),
+    lang("id", "Indonesian", &["indonesian", "bahasai"], "malay"),
+    lang("zh", "Chinese", &["chinese"], "chinese"),
+    lang("ja", "Japanese", &["japanese"], "japanese"),
+    lang("ko", "Korean", &["korean"], "korean"),
+];
+
+/// Language of an ISO 639-1 code
+pub fn find_language(code: &str) -> Option<&'static Language> {
+    LANGUAGES.iter().find(|l| l.code == code)
+}
+
+/// Comma-separated list of the supported codes, for the error messages
+pub fn language_codes() -> String {
+    LANGUAGES
+        .iter()
+        .map(|l| l.code)
+        .collect::<Vec<&str>>()
+        .join(",")
+}
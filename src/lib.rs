@@ -58,8 +58,8 @@
 //! The chunk is partially translated if the server returns an error.
 //!
 //! It is also possible to mark a region that should not be translated with the markers
-//! `%trsltx-begin-ignore` and `%trsltx-end-ignore` on single lines. Ignored regions should not contain
-//! `%trsltx-split` markers. See the file `test/simple.tex` for an example.
+//! `%trsltx-begin-ignore` and `%trsltx-end-ignore` on single lines. The `%trsltx-split`
+//! markers of the ignored regions are dropped. See the file `test/simple.tex` for an example.
 //!
 //! Here are a few tips for improved results:
 //!
@@ -74,30 +74,685 @@
 //!  `{...}` groups or `\begin ... \end` environments.
 
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use ltxprs::LtxNode;
+use nom::bytes::complete::{tag, take_until};
+use nom::character::complete::char;
+use nom::combinator::opt;
+use nom::sequence::{delimited, pair, preceded};
+use nom::IResult;
+use tracing::{debug, error, info, warn};
 
-#[derive(Debug, Clone)]
-enum ChunkType {
+pub mod backend;
+pub use backend::{
+    CompletionParams, DeeplBackend, LlmBackend, OpenAiBackend, RateLimitState, TextsynthBackend,
+};
+pub mod meta;
+pub use meta::{ChunkMeta, TranslationMeta};
+pub mod config;
+pub mod diff;
+pub use diff::{AstDiff, ChunkDiff};
+pub mod models;
+pub use models::{ModelEntry, ModelRegistry};
+pub mod validate;
+pub use validate::{DelimiterError, ValidationError};
+pub mod progress;
+pub use progress::{ProgressEvent, TranslationObserver};
+pub mod check;
+pub use check::{CheckReport, CrossRefWarning};
+pub mod stats;
+pub use stats::{ChunkStat, CostEstimate};
+#[cfg(feature = "async")]
+mod asynchronous;
+pub mod checkpoint;
+pub mod glossary;
+pub use checkpoint::Checkpoint;
+pub mod cache;
+pub use cache::TranslationCache;
+pub mod error;
+pub use error::TrsltxError;
+pub mod languages;
+pub use languages::{find_language, Language};
+pub mod compile;
+pub mod consistency;
+pub use consistency::{ConsistencyReport, KeyIssue};
+pub mod detect;
+pub mod review;
+pub use compile::CompileError;
+pub mod selection;
+pub use review::{ChunkReview, ReviewDecision};
+pub mod report;
+pub use report::{RunReport, Untranslated};
+pub mod batch;
+pub use batch::{expand_file_patterns, BatchReport};
+pub mod project;
+pub use project::{Project, ProjectFile};
+pub mod bib;
+pub use bib::{parse_bib_entries, BibEntry, BibTranslation};
+pub use selection::parse_chunk_selection;
+
+/// How a chunk is processed
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkType {
+    /// the chunk is sent to the translator
     Translate,
+    /// the chunk is copied as is
     Unchanged,
 }
 
+impl std::str::FromStr for ChunkType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "translate" => Ok(ChunkType::Translate),
+            "unchanged" => Ok(ChunkType::Unchanged),
+            _ => Err(format!(
+                "Unknown chunk type: {} (expected translate or unchanged)",
+                s
+            )),
+        }
+    }
+}
+
+/// Source of the LaTeX code to be translated
+#[derive(Debug, Clone)]
+pub enum LaTeXSource {
+    File(String),
+    Stdin,
+    /// code given as a string to `read_latex` or `translate_str`, the default
+    Memory,
+}
+
+/// Policy for the successive translation attempts of a chunk
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// maximal number of requests sent to the LLM for one chunk
+    /// (the last one is sent without grammar)
+    pub max_attempts: usize,
+    /// maximal number of times a request is sent again after a transient
+    /// HTTP error (429, 5xx, timeout or connection error)
+    pub max_http_retries: usize,
+    /// delay before the first retry, doubled at each retry
+    pub initial_backoff: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            max_http_retries: 3,
+            initial_backoff: std::time::Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the retry number `retry`, starting from 0
+    pub fn backoff(&self, retry: usize) -> std::time::Duration {
+        self.initial_backoff * 2u32.saturating_pow(retry as u32)
+    }
+}
+
+/// Comment lines marking the chunks in the LaTeX file
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitMarkers {
+    /// separator of two chunks, `%trsltx-split` by default
+    pub split: String,
+    /// beginning of a chunk left unchanged, `%trsltx-begin-ignore` by default
+    pub begin_ignore: String,
+    /// end of a chunk left unchanged, `%trsltx-end-ignore` by default
+    pub end_ignore: String,
+}
+
+impl Default for SplitMarkers {
+    fn default() -> Self {
+        SplitMarkers {
+            split: "%trsltx-split".to_string(),
+            begin_ignore: "%trsltx-begin-ignore".to_string(),
+            end_ignore: "%trsltx-end-ignore".to_string(),
+        }
+    }
+}
+
+impl SplitMarkers {
+    /// The markers must be non-empty and distinct, and none of them can be
+    /// a prefix of another one, otherwise the chunks cannot be found
+    fn check(&self) -> Result<(), TrsltxError> {
+        let markers = [&self.split, &self.begin_ignore, &self.end_ignore];
+        if markers.iter().any(|m| m.trim().is_empty()) {
+            return Err(TrsltxError::Config("A split marker is empty".to_string()));
+        }
+        for (i, a) in markers.iter().enumerate() {
+            for b in markers.iter().skip(i + 1) {
+                if a.starts_with(b.as_str()) || b.starts_with(a.as_str()) {
+                    return Err(TrsltxError::Config(format!(
+                        "The split markers {} and {} cannot be told apart",
+                        a, b
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Comment line protecting the next line from the translation
+pub const IGNORE_NEXT_LINE: &str = "%trsltx-ignore-next-line";
+
+/// Command protecting its argument from the translation, e.g. a proper noun or a quote.
+/// It must be defined in the preamble: `\newcommand{\trsltxkeep}[1]{#1}`
+pub const KEEP_COMMAND: &str = "trsltxkeep";
+
+/// First line of the source of a chunk, written in comments before its
+/// translation by the bilingual output
+pub const SOURCE_BEGIN: &str = "%trsltx-source-begin";
+/// Last line of the source of a chunk in the bilingual output
+pub const SOURCE_END: &str = "%trsltx-source-end";
+
+/// Source of a chunk as a comment block, for the bilingual output
+pub fn source_comment(source: &str) -> String {
+    let mut block = format!("{}\n", SOURCE_BEGIN);
+    for line in source.trim_end().lines() {
+        if line.is_empty() {
+            block.push_str("%\n");
+        } else {
+            block.push_str(&format!("% {}\n", line));
+        }
+    }
+    block.push_str(SOURCE_END);
+    block.push('\n');
+    block
+}
+
+/// Remove the comment blocks of the bilingual output, so that a bilingual
+/// translation can be translated again
+pub fn remove_source_comments(body: &str) -> String {
+    let mut result = String::new();
+    let mut in_source = false;
+    for line in body.split_inclusive('\n') {
+        match line.trim_end() {
+            SOURCE_BEGIN => in_source = true,
+            SOURCE_END if in_source => in_source = false,
+            _ if !in_source => result.push_str(line),
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Remove the comment blocks of the bilingual output from a file, after the review.
+/// Returns the number of removed blocks.
+pub fn strip_source_comments(path: &str) -> Result<usize, TrsltxError> {
+    let latex = std::fs::read_to_string(path).map_err(|e| TrsltxError::io(path, e))?;
+    let count = latex
+        .lines()
+        .filter(|line| line.trim_end() == SOURCE_BEGIN)
+        .count();
+    if count > 0 {
+        std::fs::write(path, remove_source_comments(&latex))
+            .map_err(|e| TrsltxError::io(path, e))?;
+    }
+    Ok(count)
+}
+
 #[derive(Debug)]
 pub struct Trsltx {
     input_lang: String,
     output_lang: String,
-    input_file_name: String,
+    input: LaTeXSource,
     output_file_name: String,
     model_name: String,
+    model_entry: Option<ModelEntry>,
+    api_key: Option<String>,
+    backend: Arc<dyn LlmBackend>,
+    temperature: f32,
+    /// nucleus sampling of the LLM, None for the default of the server
+    top_p: Option<f32>,
+    max_tokens: usize,
+    max_grammar_size: usize,
+    /// maximal estimated number of tokens of a chunk sent to the translator
+    max_tokens_per_chunk: Option<usize>,
+    /// average number of bytes per token, for estimating the token counts
+    chars_per_token: f32,
+    retry_policy: RetryPolicy,
+    write_meta: bool,
+    /// save the translated chunks in the checkpoint file `<stem>.trsltx.json`
+    checkpoint: bool,
+    sort_chunks_by_complexity: bool,
+    /// maximal number of chunks translated concurrently by `translate_chunks_async`
+    jobs: usize,
+    /// write the source of each translated chunk in comments before its translation
+    bilingual: bool,
+    /// send the `%` comments to the translator instead of replacing them by placeholders
+    translate_comments: bool,
+    translate_preamble: bool,
+    markers: SplitMarkers,
     preamble: String,
     body: String,
     afterword: String,
     body_translated: String,
     chunks: Vec<(String, ChunkType)>,
+    chunks_translated: Vec<String>,
+    chunks_config: Option<Vec<(usize, ChunkType)>>,
+    chunks_meta: Vec<ChunkMeta>,
+    review_callback: Option<review::ReviewCallback>,
+    /// existing translation, when only some chunks are translated again
+    previous_translation: Option<selection::PreviousTranslation>,
+    progress_callback: Option<progress::ProgressCallback>,
+    bib_keys: Vec<String>,
+    preserve_commands: Vec<String>,
+    /// environments defined by `\newtheorem` in the preamble
+    user_environments: Vec<String>,
+    /// translations of the domain-specific terms
+    glossary: Vec<(String, String)>,
+    /// template of the prompt, `PREPROMPT` by default
+    prompt_template: Option<String>,
+    /// file included with `\input` or `\include`: the whole file is the body
+    fragment: bool,
+    /// directory of the paths of `\input` and `\include`, which are relative
+    /// to the main file, by default the directory of the input file
+    include_dir: Option<std::path::PathBuf>,
+    /// directory of the initial file, when the input file is written in another directory
+    source_dir: Option<std::path::PathBuf>,
+    /// file of the translation memory, see the module `cache`
+    cache_file: Option<String>,
+    cache: Mutex<TranslationCache>,
+    /// length of the chunks of the code split in memory
+    split_length: usize,
+    /// send a grammar with the chunks, if the backend and the model support it
+    use_grammar: bool,
+    /// set by the application for stopping the translation, see `TrsltxBuilder::cancel_flag`
+    cancel_flag: Option<Arc<AtomicBool>>,
+}
+
+/// Builder for the `Trsltx` struct
+///
+/// ```no_run
+/// let trsltx = trsltx::TrsltxBuilder::new()
+///     .input_lang("fr")
+///     .output_lang("en")
+///     .input_file("test/simple_fr.tex")
+///     .output_file("test/simple_en.tex")
+///     .model("mistral7b")
+///     .split_length(1500)
+///     .grammar(false)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct TrsltxBuilder {
+    input_lang: Option<String>,
+    output_lang: Option<String>,
+    input: Option<LaTeXSource>,
+    output_file: String,
+    model: String,
+    api_key: Option<String>,
+    backend: Option<Arc<dyn LlmBackend>>,
+    temperature: f32,
+    /// nucleus sampling of the LLM, None for the default of the server
+    top_p: Option<f32>,
+    max_tokens: usize,
+    max_grammar_size: usize,
+    /// maximal estimated number of tokens of a chunk sent to the translator
+    max_tokens_per_chunk: Option<usize>,
+    /// average number of bytes per token, for estimating the token counts
+    chars_per_token: f32,
+    retry_policy: RetryPolicy,
+    write_meta: bool,
+    /// save the translated chunks in the checkpoint file `<stem>.trsltx.json`
+    checkpoint: bool,
+    sort_chunks_by_complexity: bool,
+    /// maximal number of chunks translated concurrently by `translate_chunks_async`
+    jobs: usize,
+    /// write the source of each translated chunk in comments before its translation
+    bilingual: bool,
+    /// send the `%` comments to the translator instead of replacing them by placeholders
+    translate_comments: bool,
+    translate_preamble: bool,
+    markers: SplitMarkers,
+    preserve_commands: Vec<String>,
+    cache_file: Option<String>,
+    split_length: usize,
+    use_grammar: bool,
+    prompt_template: Option<String>,
+    cancel_flag: Option<Arc<AtomicBool>>,
+}
+
+impl Default for TrsltxBuilder {
+    fn default() -> Self {
+        TrsltxBuilder {
+            input_lang: None,
+            output_lang: None,
+            input: None,
+            output_file: String::new(),
+            model: "mistral47b".to_string(),
+            api_key: None,
+            backend: None,
+            temperature: 0.5,
+            top_p: None,
+            max_tokens: 2000,
+            max_grammar_size: MAX_GRAMMAR_SIZE,
+            max_tokens_per_chunk: None,
+            chars_per_token: CHARS_PER_TOKEN,
+            retry_policy: RetryPolicy::default(),
+            write_meta: true,
+            checkpoint: true,
+            sort_chunks_by_complexity: false,
+            jobs: 4,
+            bilingual: false,
+            translate_comments: false,
+            translate_preamble: true,
+            markers: SplitMarkers::default(),
+            preserve_commands: PRESERVE_COMMANDS
+                .iter()
+                .chain(STRUCTURE_COMMANDS.iter())
+                .chain([KEEP_COMMAND].iter())
+                .map(|c| c.to_string())
+                .collect(),
+            cache_file: None,
+            split_length: DEFAULT_SPLIT_LENGTH,
+            use_grammar: true,
+            prompt_template: None,
+            cancel_flag: None,
+        }
+    }
+}
+
+impl TrsltxBuilder {
+    pub fn new() -> TrsltxBuilder {
+        TrsltxBuilder::default()
+    }
+    /// two-letter code of the source language (required)
+    pub fn input_lang(&mut self, lang: &str) -> &mut Self {
+        self.input_lang = Some(lang.to_string());
+        self
+    }
+    /// two-letter code of the target language (required)
+    pub fn output_lang(&mut self, lang: &str) -> &mut Self {
+        self.output_lang = Some(lang.to_string());
+        self
+    }
+    /// file to be translated, none for the code given to `translate_str`
+    pub fn input_file(&mut self, file_name: &str) -> &mut Self {
+        self.input = Some(LaTeXSource::File(file_name.to_string()));
+        self
+    }
+    /// file, standard input or string to be translated
+    pub fn input_source(&mut self, input: LaTeXSource) -> &mut Self {
+        self.input = Some(input);
+        self
+    }
+    /// file where the translation is written
+    pub fn output_file(&mut self, file_name: &str) -> &mut Self {
+        self.output_file = file_name.to_string();
+        self
+    }
+    /// name of the LLM, "mistral47b" by default
+    pub fn model(&mut self, model: &str) -> &mut Self {
+        self.model = model.to_string();
+        self
+    }
+    /// api key of the LLM server. If not given, the Textsynth key is read from the file
+    /// "api_key.txt" or from the environment variable "TEXTSYNTH_API_KEY"
+    pub fn api_key(&mut self, api_key: &str) -> &mut Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+    /// LLM server, the Textsynth server by default
+    pub fn backend<B: LlmBackend + 'static>(&mut self, backend: B) -> &mut Self {
+        self.backend = Some(Arc::new(backend));
+        self
+    }
+    /// Same as `backend`, for a backend chosen at run time
+    pub fn boxed_backend(&mut self, backend: Box<dyn LlmBackend>) -> &mut Self {
+        self.backend = Some(Arc::from(backend));
+        self
+    }
+    /// sampling temperature of the LLM, 0.5 by default
+    pub fn temperature(&mut self, temperature: f32) -> &mut Self {
+        self.temperature = temperature;
+        self
+    }
+    /// nucleus sampling of the LLM in ]0.0, 1.0]: only the most probable tokens
+    /// whose cumulated probability is `top_p` are sampled. By default the value
+    /// of the server is used.
+    pub fn top_p(&mut self, top_p: f32) -> &mut Self {
+        self.top_p = Some(top_p);
+        self
+    }
+    /// maximal number of generated tokens for one chunk, 2000 by default
+    pub fn max_tokens(&mut self, max_tokens: usize) -> &mut Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+    /// maximal length of the grammar sent to the server, `MAX_GRAMMAR_SIZE` by default
+    pub fn max_grammar_size(&mut self, max_grammar_size: usize) -> &mut Self {
+        self.max_grammar_size = max_grammar_size;
+        self
+    }
+    /// maximal estimated number of tokens of a chunk sent to the translator,
+    /// by default `MAX_CHUNK_LENGTH` bytes. The longer chunks are left unchanged.
+    pub fn max_tokens_per_chunk(&mut self, tokens: usize) -> &mut Self {
+        self.max_tokens_per_chunk = Some(tokens);
+        self
+    }
+    /// average number of bytes per token of the model, `CHARS_PER_TOKEN` by default
+    pub fn chars_per_token(&mut self, chars_per_token: f32) -> &mut Self {
+        self.chars_per_token = chars_per_token;
+        self
+    }
+    pub fn retry_policy(&mut self, retry_policy: RetryPolicy) -> &mut Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+    /// write the metadata file `<stem>_meta.json` next to the output file, true by default
+    pub fn write_meta(&mut self, write_meta: bool) -> &mut Self {
+        self.write_meta = write_meta;
+        self
+    }
+    /// save the translated chunks in the checkpoint file `<stem>.trsltx.json`
+    /// next to the output file and resume an interrupted translation, true by default
+    pub fn checkpoint(&mut self, checkpoint: bool) -> &mut Self {
+        self.checkpoint = checkpoint;
+        self
+    }
+    /// JSON file of the translation memory, read before and written after the translation,
+    /// none by default. The chunks found in this file are not sent to the server.
+    pub fn cache_file(&mut self, path: &str) -> &mut Self {
+        self.cache_file = Some(path.to_string());
+        self
+    }
+    /// approximate length in characters of the chunks when the code is split in memory
+    /// (`translate_str`, included files...), `DEFAULT_SPLIT_LENGTH` by default.
+    /// The chunks are shorter if the context of the model is small.
+    pub fn split_length(&mut self, length: usize) -> &mut Self {
+        self.split_length = length;
+        self
+    }
+    /// constrain the translation of the chunks with a grammar, true by default.
+    /// The grammar is only used if the backend and the model support it.
+    pub fn grammar(&mut self, use_grammar: bool) -> &mut Self {
+        self.use_grammar = use_grammar;
+        self
+    }
+    /// template of the prompt instead of `PREPROMPT`, see `Trsltx::set_prompt_template`
+    pub fn prompt_template(&mut self, template: &str) -> &mut Self {
+        self.prompt_template = Some(template.to_string());
+        self
+    }
+    /// flag checked before sending each chunk: when the application sets it to true,
+    /// the chunks not yet sent are left untranslated, as failed chunks, and the
+    /// checkpoint file is kept for resuming the translation (see `Trsltx::is_cancelled`).
+    /// The chunks being translated by `translate_chunks_async` are completed.
+    pub fn cancel_flag(&mut self, flag: Arc<AtomicBool>) -> &mut Self {
+        self.cancel_flag = Some(flag);
+        self
+    }
+    /// translate the easiest chunks first, false by default.
+    /// The translation is always assembled in the order of the document.
+    pub fn sort_chunks_by_complexity(&mut self, sort: bool) -> &mut Self {
+        self.sort_chunks_by_complexity = sort;
+        self
+    }
+    /// maximal number of chunks translated concurrently with the `async` feature,
+    /// 4 by default
+    pub fn jobs(&mut self, jobs: usize) -> &mut Self {
+        self.jobs = jobs;
+        self
+    }
+    /// write the source of each translated chunk in a comment block
+    /// (between `%trsltx-source-begin` and `%trsltx-source-end`) before its translation,
+    /// for proofreading, false by default
+    pub fn bilingual(&mut self, bilingual: bool) -> &mut Self {
+        self.bilingual = bilingual;
+        self
+    }
+    /// translate the `%` comments of the body, false by default:
+    /// the comments are then copied unchanged
+    pub fn translate_comments(&mut self, translate_comments: bool) -> &mut Self {
+        self.translate_comments = translate_comments;
+        self
+    }
+    /// translate the title, author, date and abstract of the preamble, true by default
+    pub fn translate_preamble(&mut self, translate_preamble: bool) -> &mut Self {
+        self.translate_preamble = translate_preamble;
+        self
+    }
+    /// markers of the chunks, `%trsltx-split`... by default
+    pub fn markers(&mut self, markers: SplitMarkers) -> &mut Self {
+        self.markers = markers;
+        self
+    }
+    /// add commands copied verbatim in the translation, in addition to `PRESERVE_COMMANDS`,
+    /// `STRUCTURE_COMMANDS` and `KEEP_COMMAND`
+    pub fn preserve_commands(&mut self, commands: &[&str]) -> &mut Self {
+        for cmd in commands {
+            let cmd = cmd.trim().trim_start_matches('\\').to_string();
+            if !cmd.is_empty() && !self.preserve_commands.contains(&cmd) {
+                self.preserve_commands.push(cmd);
+            }
+        }
+        self
+    }
+    /// Build the `Trsltx` struct, or return an error naming the missing field
+    pub fn build(&self) -> Result<Trsltx, TrsltxError> {
+        let missing = |field: &str| TrsltxError::Config(format!("Missing {}", field));
+        let input_lang = self
+            .input_lang
+            .clone()
+            .ok_or_else(|| missing("input language"))?;
+        let output_lang = self
+            .output_lang
+            .clone()
+            .ok_or_else(|| missing("output language"))?;
+        let input = self.input.clone().unwrap_or(LaTeXSource::Memory);
+        if !(0.0..=1.0).contains(&self.temperature) {
+            return Err(TrsltxError::Config(format!(
+                "The temperature must be in [0.0, 1.0], got {}",
+                self.temperature
+            )));
+        }
+        if let Some(top_p) = self.top_p {
+            if top_p.is_nan() || top_p <= 0.0 || top_p > 1.0 {
+                return Err(TrsltxError::Config(format!(
+                    "top_p must be in ]0.0, 1.0], got {}",
+                    top_p
+                )));
+            }
+        }
+        if self
+            .prompt_template
+            .as_ref()
+            .is_some_and(|t| t.trim().is_empty())
+        {
+            return Err(TrsltxError::Config(
+                "The prompt template is empty".to_string(),
+            ));
+        }
+        self.markers.check()?;
+        if self.chars_per_token.is_nan() || self.chars_per_token <= 0.0 {
+            return Err(TrsltxError::Config(format!(
+                "The number of characters per token must be positive, got {}",
+                self.chars_per_token
+            )));
+        }
+        // the registry describes the models of the Textsynth server
+        let model_entry = match self.backend {
+            // the models absent from the registry are Textsynth engine ids,
+            // see `Trsltx::validate_model`
+            None => Some(
+                ModelRegistry::default()
+                    .find(&self.model)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        warn!(
+                            "model {} not in the registry, assume a context of 8192 tokens",
+                            self.model
+                        );
+                        ModelEntry::new(&self.model, &[], true, 8192)
+                    }),
+            ),
+            Some(_) => ModelRegistry::default().find(&self.model).cloned(),
+        };
+        Ok(Trsltx {
+            input_lang,
+            output_lang,
+            input,
+            output_file_name: self.output_file.clone(),
+            model_name: self.model.clone(),
+            model_entry,
+            api_key: self.api_key.clone(),
+            backend: self
+                .backend
+                .clone()
+                .unwrap_or_else(|| Arc::new(TextsynthBackend::default())),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            max_grammar_size: self.max_grammar_size,
+            max_tokens_per_chunk: self.max_tokens_per_chunk,
+            chars_per_token: self.chars_per_token,
+            retry_policy: self.retry_policy.clone(),
+            write_meta: self.write_meta,
+            checkpoint: self.checkpoint,
+            sort_chunks_by_complexity: self.sort_chunks_by_complexity,
+            jobs: self.jobs,
+            bilingual: self.bilingual,
+            translate_comments: self.translate_comments,
+            translate_preamble: self.translate_preamble,
+            markers: self.markers.clone(),
+            preamble: String::new(),
+            body: String::new(),
+            afterword: String::new(),
+            body_translated: String::new(),
+            chunks: Vec::new(),
+            chunks_translated: Vec::new(),
+            chunks_config: None,
+            chunks_meta: Vec::new(),
+            review_callback: None,
+            previous_translation: None,
+            progress_callback: None,
+            bib_keys: Vec::new(),
+            preserve_commands: self.preserve_commands.clone(),
+            user_environments: Vec::new(),
+            glossary: Vec::new(),
+            prompt_template: self.prompt_template.clone(),
+            fragment: false,
+            include_dir: None,
+            source_dir: None,
+            cache_file: self.cache_file.clone(),
+            cache: Mutex::default(),
+            split_length: self.split_length,
+            use_grammar: self.use_grammar,
+            cancel_flag: self.cancel_flag.clone(),
+        })
+    }
 }
 
 impl Trsltx {
+    #[deprecated(note = "use TrsltxBuilder instead")]
     pub fn new(
         input_lang: &str,
         output_lang: &str,
@@ -105,49 +760,469 @@ impl Trsltx {
         output_file_name: &str,
         model_name: &str,
     ) -> Trsltx {
-        Trsltx {
-            input_lang: input_lang.to_string(),
-            output_lang: output_lang.to_string(),
-            input_file_name: input_file_name.to_string(),
-            output_file_name: output_file_name.to_string(),
-            model_name: model_name.to_string(),
-            preamble: String::new(),
-            body: String::new(),
-            afterword: String::new(),
-            body_translated: String::new(),
-            chunks: Vec::new(),
+        TrsltxBuilder::new()
+            .input_lang(input_lang)
+            .output_lang(output_lang)
+            .input_file(input_file_name)
+            .output_file(output_file_name)
+            .model(model_name)
+            .build()
+            .expect("all the required fields are given")
+    }
+    /// Set the api key of the LLM server,
+    /// instead of the file "api_key.txt" or the environment variable
+    pub fn set_api_key(&mut self, key: &str) {
+        self.api_key = Some(key.trim().to_string());
+    }
+
+    /// Read the api key of the LLM server from a file
+    pub fn set_api_key_file(&mut self, path: &str) -> Result<(), TrsltxError> {
+        let key = std::fs::read_to_string(path).map_err(|e| TrsltxError::io(path, e))?;
+        if key.trim().is_empty() {
+            return Err(TrsltxError::Config(format!(
+                "The api key file {} is empty",
+                path
+            )));
+        }
+        self.set_api_key(key.as_str());
+        Ok(())
+    }
+
+    /// Replace the default prompt (see `PREPROMPT`) by a template, e.g. for legal
+    /// or medical documents. The placeholders `<lang_in>`, `<lang_out>`, `<glossary>`
+    /// and `<chunk>` are filled for each chunk.
+    pub fn set_prompt_template(&mut self, template: &str) {
+        self.prompt_template = Some(template.to_string());
+    }
+
+    /// Read the template of the prompt from a file
+    pub fn read_prompt_file(&mut self, path: &str) -> Result<(), TrsltxError> {
+        let template = std::fs::read_to_string(path).map_err(|e| TrsltxError::io(path, e))?;
+        if template.trim().is_empty() {
+            return Err(TrsltxError::Config(format!(
+                "The prompt file {} is empty",
+                path
+            )));
+        }
+        if !template.contains("<chunk>") {
+            warn!(
+                "no <chunk> in the prompt file {}, the chunk is appended",
+                path
+            );
+        }
+        self.set_prompt_template(&template);
+        Ok(())
+    }
+
+    /// Replace the preserved commands, the code samples, the file paths and URLs,
+    /// the lines marked by `IGNORE_NEXT_LINE` and, unless `translate_comments`,
+    /// the comments of a chunk by placeholders, before sending it to the translator
+    fn protect_chunk(&self, chunk: &str) -> (String, Vec<String>) {
+        let (protected, originals) = protect_commands(chunk, &self.preserve_commands);
+        let (protected, originals) = protect_paths(&protected, originals);
+        let (protected, originals) = protect_ignored_lines(&protected, originals);
+        if self.translate_comments {
+            (protected, originals)
+        } else {
+            protect_comments(&protected, originals)
+        }
+    }
+
+    /// Template of the prompt sent with each chunk
+    pub fn prompt_template(&self) -> &str {
+        self.prompt_template.as_deref().unwrap_or(PREPROMPT)
+    }
+
+    /// Override the type of some chunks after `extract_chunks`.
+    /// The chunks are numbered from 1, as in the messages of `trsltx`.
+    pub fn set_chunk_overrides(&mut self, overrides: Vec<(usize, ChunkType)>) {
+        self.chunks_config = Some(overrides);
+    }
+
+    /// Call `f` at each step of `translate_chunks`, e.g. for displaying the progress
+    /// in a GUI. The messages on the standard error are still printed.
+    pub fn set_progress_callback<F: Fn(ProgressEvent) + Send + 'static>(&mut self, f: F) {
+        self.progress_callback = Some(progress::ProgressCallback(Box::new(f)));
+    }
+
+    /// Call the methods of `observer` at each step of the translation,
+    /// instead of the callback of `set_progress_callback`
+    pub fn set_observer<O: TranslationObserver + 'static>(&mut self, observer: O) {
+        self.set_progress_callback(move |event| event.notify(&observer));
+    }
+
+    /// True if the flag of `TrsltxBuilder::cancel_flag` is set:
+    /// the translation stops before the next chunk
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_flag
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    fn report(&self, event: ProgressEvent) {
+        if let Some(callback) = &self.progress_callback {
+            (callback.0)(event);
+        }
+    }
+
+    /// The chunks and their types, after `extract_chunks`
+    pub fn chunks(&self) -> &[(String, ChunkType)] {
+        &self.chunks
+    }
+
+    /// Read the citation keys of a .bib file. They are protected in the grammar.
+    pub fn read_bib_file(&mut self, path: &str) -> Result<(), TrsltxError> {
+        let bib = std::fs::read_to_string(path).map_err(|e| TrsltxError::io(path, e))?;
+        let keys = extract_bib_keys(bib.as_str());
+        info!("{} citation keys found in {}", keys.len(), path);
+        self.bib_keys.extend(keys);
+        self.bib_keys.sort();
+        self.bib_keys.dedup();
+        Ok(())
+    }
+
+    /// Directory of the input file, the current directory for the standard input
+    fn input_dir(&self) -> std::path::PathBuf {
+        if let Some(dir) = &self.source_dir {
+            return dir.clone();
+        }
+        match &self.input {
+            LaTeXSource::File(file_name) => std::path::Path::new(file_name)
+                .parent()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_default(),
+            LaTeXSource::Stdin | LaTeXSource::Memory => std::path::PathBuf::new(),
+        }
+    }
+
+    /// Directory of the initial file, when the input file is its split file written in
+    /// another directory (`--output-dir`): the included files and the bibliography are
+    /// found from this directory, and the compilation of `check_compile` is run in it
+    pub fn set_source_dir(&mut self, dir: &std::path::Path) {
+        self.source_dir = Some(dir.to_path_buf());
+    }
+
+    /// Directory of the output file, None if the translation is not written in a file
+    pub(crate) fn output_dir(&self) -> Option<std::path::PathBuf> {
+        if self.output_file_name.is_empty() {
+            return None;
+        }
+        Some(
+            std::path::Path::new(&self.output_file_name)
+                .parent()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Builder with the same settings, for translating another file
+    fn builder(&self) -> TrsltxBuilder {
+        TrsltxBuilder {
+            input_lang: Some(self.input_lang.clone()),
+            output_lang: Some(self.output_lang.clone()),
+            input: None,
+            output_file: String::new(),
+            model: self.model_name.clone(),
+            api_key: self.api_key.clone(),
+            backend: Some(self.backend.clone()),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            max_grammar_size: self.max_grammar_size,
+            max_tokens_per_chunk: self.max_tokens_per_chunk,
+            chars_per_token: self.chars_per_token,
+            retry_policy: self.retry_policy.clone(),
+            write_meta: self.write_meta,
+            checkpoint: self.checkpoint,
+            sort_chunks_by_complexity: self.sort_chunks_by_complexity,
+            jobs: self.jobs,
+            bilingual: self.bilingual,
+            translate_comments: self.translate_comments,
+            translate_preamble: self.translate_preamble,
+            markers: self.markers.clone(),
+            preserve_commands: self.preserve_commands.clone(),
+            cache_file: self.cache_file.clone(),
+            split_length: self.split_length,
+            use_grammar: self.use_grammar,
+            prompt_template: self.prompt_template.clone(),
+            cancel_flag: self.cancel_flag.clone(),
+        }
+    }
+
+    /// Translate the files included with `\subfile{path}` or `\subfileinclude{path}`
+    /// (`subfiles` package), `\input{path}` or `\include{path}` after `translate`.
+    /// Each subfile `path.tex` is translated into `path_xy.tex`, where xy is the
+    /// output language, and the commands of the translated body are changed accordingly.
+    /// The files of `\input` and `\include` have no preamble and their paths are
+    /// relative to the main file. The translated subfiles are written in the directory
+    /// of the output file, with the same relative paths as the sources, so that the
+    /// translated main file compiles. The subfiles are processed recursively,
+    /// up to `MAX_SUBFILE_DEPTH` levels. A subfile including itself is an error.
+    pub fn process_subfiles(&mut self) -> Result<(), TrsltxError> {
+        let mut stack = vec![];
+        if let LaTeXSource::File(file_name) = &self.input {
+            if let Ok(path) = std::path::Path::new(file_name).canonicalize() {
+                stack.push(path);
+            }
+        }
+        let include_output_dir = self.output_dir();
+        self.process_subfiles_rec(&mut stack, include_output_dir)
+    }
+
+    // stack: the files being translated, from the main file to the current one
+    // include_output_dir: directory of the translations of the files of \input and
+    // \include, the directory of the main output file, None for writing next to the sources
+    fn process_subfiles_rec(
+        &mut self,
+        stack: &mut Vec<std::path::PathBuf>,
+        include_output_dir: Option<std::path::PathBuf>,
+    ) -> Result<(), TrsltxError> {
+        let mut subfiles: Vec<(usize, String, bool)> = INCLUDE_COMMANDS
+            .iter()
+            .flat_map(|&(cmd, fragment)| {
+                command_args(&self.body, cmd)
+                    .into_iter()
+                    .map(move |(pos, sub)| (pos, sub, fragment))
+            })
+            .collect();
+        subfiles.sort();
+        let mut seen = vec![];
+        subfiles.retain(|(_, sub, fragment)| {
+            let first = !seen.contains(&(sub.clone(), *fragment));
+            seen.push((sub.clone(), *fragment));
+            first
+        });
+        for (_, sub, fragment) in subfiles {
+            let file = if sub.ends_with(".tex") {
+                sub.clone()
+            } else {
+                format!("{}.tex", sub)
+            };
+            let dir = if fragment {
+                self.include_dir()
+            } else {
+                self.input_dir()
+            };
+            let path = dir.join(&file);
+            let path_name = path.to_string_lossy().to_string();
+            let Ok(canonical) = path.canonicalize() else {
+                warn!("included file {} not found", path_name);
+                continue;
+            };
+            if stack.contains(&canonical) {
+                return Err(TrsltxError::Parse(format!(
+                    "Cycle in the subfiles: {} includes itself",
+                    path_name
+                )));
+            }
+            if stack.len() > MAX_SUBFILE_DEPTH {
+                return Err(TrsltxError::Parse(format!(
+                    "Subfiles nested deeper than {} levels: {}",
+                    MAX_SUBFILE_DEPTH, path_name
+                )));
+            }
+            let output_dir = if fragment {
+                include_output_dir.clone()
+            } else {
+                self.output_dir()
+            };
+            let output_file = match output_dir {
+                Some(output_dir) => {
+                    let output = output_dir.join(lang_file_name(&file, &self.output_lang)?);
+                    if let Some(parent) = output.parent() {
+                        std::fs::create_dir_all(parent)
+                            .map_err(|e| TrsltxError::io(&parent.to_string_lossy(), e))?;
+                    }
+                    output.to_string_lossy().to_string()
+                }
+                None => lang_file_name(&path_name, &self.output_lang)?,
+            };
+            info!("Translating subfile {} into {}", path_name, output_file);
+            let mut trsltx = self
+                .builder()
+                .input_file(&path_name)
+                .output_file(&output_file)
+                .build()?;
+            trsltx.fragment = fragment;
+            trsltx.include_dir = Some(self.include_dir());
+            trsltx.read_file()?;
+            if fragment {
+                // the environments are defined in the preamble of the main file
+                trsltx.user_environments = self.user_environments.clone();
+            }
+            if !trsltx.body.contains(self.markers.split.as_str()) {
+                // default length of the -l option
+                trsltx.body = trsltx.split_body(self.split_length.min(trsltx.max_chunk_length()));
+            }
+            trsltx.bib_keys = self.bib_keys.clone();
+            trsltx.glossary = self.glossary.clone();
+            trsltx.extract_chunks()?;
+            trsltx.translate();
+            stack.push(canonical);
+            trsltx.process_subfiles_rec(stack, include_output_dir.clone())?;
+            stack.pop();
+            trsltx.write_file()?;
+            // the translated subfile is referenced in the same way as the original one
+            let translated = lang_file_name(&file, &self.output_lang)?;
+            let translated = if sub.ends_with(".tex") {
+                translated.as_str()
+            } else {
+                translated.trim_end_matches(".tex")
+            };
+            let cmds = INCLUDE_COMMANDS
+                .iter()
+                .filter(|(_, f)| *f == fragment)
+                .map(|(cmd, _)| cmd);
+            for cmd in cmds {
+                let old = format!("\\{}{{{}}}", cmd, sub);
+                let new = format!("\\{}{{{}}}", cmd, translated);
+                if self.body.contains(old.as_str()) && !self.body_translated.contains(old.as_str())
+                {
+                    warn!("{} not found in the translation", old);
+                }
+                self.body_translated = self.body_translated.replace(old.as_str(), new.as_str());
+            }
+        }
+        Ok(())
+    }
+
+    /// Directory of the paths of `\input` and `\include`
+    fn include_dir(&self) -> std::path::PathBuf {
+        self.include_dir.clone().unwrap_or_else(|| self.input_dir())
+    }
+
+    /// The .bib files given by `\bibliography{...}` or `\addbibresource{...}`
+    /// in the file, relative to the directory of the input file
+    pub fn detect_bib_files(&self) -> Vec<String> {
+        let dir = self.input_dir();
+        let latex = self.preamble.clone() + &self.body;
+        let mut files = vec![];
+        for (_, arg) in command_args(&latex, "bibliography")
+            .into_iter()
+            .chain(command_args(&latex, "addbibresource"))
+        {
+            for name in arg.split(',') {
+                let name = name.trim();
+                let name = if name.ends_with(".bib") {
+                    name.to_string()
+                } else {
+                    format!("{}.bib", name)
+                };
+                files.push(dir.join(name).to_string_lossy().to_string());
+            }
         }
+        files
+    }
+
+    pub fn read_file(&mut self) -> Result<(), TrsltxError> {
+        let input_file = match &self.input {
+            LaTeXSource::File(file_name) => {
+                std::fs::read(file_name).map_err(|e| TrsltxError::io(file_name, e))?
+            }
+            LaTeXSource::Stdin => {
+                let mut bytes = vec![];
+                std::io::Read::read_to_end(&mut std::io::stdin(), &mut bytes)
+                    .map_err(|e| TrsltxError::io("standard input", e))?;
+                bytes
+            }
+            LaTeXSource::Memory => {
+                return Err(TrsltxError::Config(
+                    "No input file: the LaTeX code is given with read_latex or translate_str"
+                        .to_string(),
+                ))
+            }
+        };
+        let input_file = decode_latex(&input_file)?;
+        self.read_latex(&input_file)
     }
-    pub fn read_file(&mut self) -> Result<(), String> {
-        let input_file = std::fs::read_to_string(&self.input_file_name)
-            .map_err(|e| format!("Cannot read file: {:?}", e))?;
+
+    /// Read the LaTeX code to translate from a string instead of the input.
+    /// A code without `\begin{document}` given on the standard input or in memory
+    /// is a fragment, e.g. a selection sent by an editor: it is translated without preamble.
+    pub fn read_latex(&mut self, input_file: &str) -> Result<(), TrsltxError> {
         // replace \r characters by nothing (appear in Windows files...)
         let input_file = input_file.replace('\r', "");
+        // nothing is kept from a previous document
+        self.preamble.clear();
+        self.afterword.clear();
+        self.body_translated.clear();
+        self.chunks.clear();
+        self.chunks_translated.clear();
+        self.chunks_meta.clear();
+        self.user_environments.clear();
+        if matches!(self.input, LaTeXSource::Stdin | LaTeXSource::Memory) {
+            self.fragment = find_uncommented(&input_file, "\\begin{document}").is_none();
+            if self.fragment {
+                debug!("No \\begin{{document}}: the code is translated as a fragment");
+            }
+        }
+        if self.fragment {
+            self.body = input_file;
+            return Ok(());
+        }
         //let input_file = input_file.replace("\\end{document}", "\\commandevide\n\\end{document}");
         //let input_file = input_file.replace("\\end{document}", "\\commandevide\n\\end{document}");
 
-        let mut input_file = input_file.split("\\begin{document}");
-        self.preamble = input_file
-            .next()
-            .ok_or("Error in extracting preamble.")?
-            .to_string();
-        let mut input_file = input_file
-            .next()
-            .ok_or("No \\begin{document} in the tex file.")?
-            .split("\\end{document}");
-        self.body = input_file
-            .next()
-            .ok_or("Error in extracting body.")?
-            .to_string();
-        self.afterword = input_file
-            .next()
-            .ok_or("No \\end{document} in the tex file.")?
-            .to_string();
+        // the delimiters in the comments are ignored
+        let begin = "\\begin{document}";
+        let end = "\\end{document}";
+        let ib = find_uncommented(&input_file, begin).ok_or(TrsltxError::Parse(
+            "No \\begin{document} in the tex file.".to_string(),
+        ))?;
+        self.preamble = input_file[..ib].to_string();
+        let input_file = &input_file[ib + begin.len()..];
+        let ie = find_uncommented(input_file, end).ok_or(TrsltxError::Parse(
+            "No \\end{document} in the tex file.".to_string(),
+        ))?;
+        self.body = input_file[..ie].to_string();
+        self.afterword = input_file[ie + end.len()..].to_string();
+        self.user_environments = extract_newtheorem_names(&self.preamble);
         Ok(())
     }
 
+    /// Translate LaTeX code in memory, without reading or writing files:
+    /// a whole document or a fragment without `\begin{document}`. The code is split
+    /// in chunks of `split_length` characters, unless it has split markers.
+    /// The chunks whose translation fails are left unchanged,
+    /// see `untranslated_chunks`.
+    ///
+    /// ```no_run
+    /// let mut trsltx = trsltx::TrsltxBuilder::new()
+    ///     .input_lang("fr")
+    ///     .output_lang("en")
+    ///     .build()
+    ///     .unwrap();
+    /// let english = trsltx.translate_str("Soit $x>0$.").unwrap();
+    /// ```
+    pub fn translate_str(&mut self, latex: &str) -> Result<String, TrsltxError> {
+        self.read_latex(latex)?;
+        self.insert_splits(self.split_length.min(self.max_chunk_length()));
+        self.extract_chunks()?;
+        self.translate();
+        let mut output = vec![];
+        self.write_to(&mut output)
+            .map_err(|e| TrsltxError::io("translation", e))?;
+        Ok(String::from_utf8_lossy(&output).to_string())
+    }
+
     /// Translate the body of the file
     pub fn translate(&mut self) {
+        self.prepare_translation();
+        self.translate_chunks();
+    }
+
+    /// Adjust the language of the preamble and translate its fields.
+    /// Called by `translate` before `translate_chunks`.
+    pub fn prepare_translation(&mut self) {
+        // the included files have no preamble
+        if self.fragment {
+            return;
+        }
+        if let Some(previous) = &self.previous_translation {
+            info!("Keep the preamble of the existing translation");
+            self.preamble = previous.preamble.clone();
+            return;
+        }
         let preamble = adjust_preamble_lang(
             self.preamble.clone(),
             self.input_lang.as_str(),
@@ -155,14 +1230,60 @@ impl Trsltx {
         );
         match preamble {
             Ok(preamble) => self.preamble = preamble,
-            Err(e) => println!("Found no babel option in preamble: {:?}", e),
+            Err(e) => warn!("Found no babel option in preamble: {:?}", e),
         }
-        self.translate_chunks();
+        if self.translate_preamble {
+            if let Err(e) = self.translate_preamble() {
+                error!("Error in translating the preamble: {:?}", e);
+                warn!("Leave the preamble untranslated");
+            }
+        }
+        if let Some(entry) = self.model_entry.as_ref().filter(|e| !e.supports_grammar) {
+            warn!(
+                "the model {} does not support the grammar feature.",
+                entry.id
+            );
+        }
+    }
+
+    /// Translate the arguments of `\title`, `\author` and `\date`
+    /// and the abstract found in the preamble.
+    /// The preamble is left unchanged if one of the translations fails.
+    pub fn translate_preamble(&mut self) -> Result<(), TrsltxError> {
+        let fields = preamble_fields(&self.preamble);
+        let mut preamble = self.preamble.clone();
+        // substitute from the end so that the positions remain valid
+        for (start, end) in fields.into_iter().rev() {
+            let field = &self.preamble[start..end];
+            info!("Translating preamble field {:?}", field);
+            let mut meta = ChunkMeta::default();
+            let trs = translate_one_chunk(field, self, &mut meta, PREAMBLE_MAX_TOKENS, None)
+                .map_err(TrsltxError::Api)?;
+            preamble.replace_range(start..end, trs.trim());
+        }
+        self.preamble = preamble;
+        Ok(())
     }
 
     /// pass the body to print_split a generate a latex string with
-    /// the "%trsltx-split" markers
+    /// the split markers ("%trsltx-split" by default). The markers are moved
+    /// before the nearest sectioning command or paragraph, see `align_splits`.
     pub fn generate_split_latex(&self, split_length: usize) -> String {
+        let body = self.split_body(split_length);
+
+        let latex = self.preamble.clone()
+            + "\\begin{document}\n"
+            + &body
+            + "\n\\end{document}\n"
+            + &self.afterword.clone();
+
+        debug!("code: {}", latex);
+
+        latex
+    }
+
+    /// The body with the split markers
+    fn split_body(&self, split_length: usize) -> String {
         let body = self.body.clone();
         let ltxparse = LtxNode::new(body.as_str());
         let body = ltxparse.print_split(0, String::new(), split_length);
@@ -175,45 +1296,70 @@ impl Trsltx {
         } else {
             body.to_string()
         };
-
-        let latex = self.preamble.clone()
-            + "\\begin{document}\n"
-            + &body
-            + "\n\\end{document}\n"
-            + &self.afterword.clone();
-
-        println!("code: {}", latex);
-
-        latex
+        // print_split always produces "%trsltx-split"
+        let default_split = SplitMarkers::default().split;
+        let body = if self.markers.split != default_split {
+            body.replace(default_split.as_str(), self.markers.split.as_str())
+        } else {
+            body
+        };
+        // print_split may cut environments, paragraphs and sentences
+        let body = remove_splits_in_environments(body.as_str(), &self.markers.split);
+        align_splits(body.as_str(), &self.markers.split, split_length / 2)
     }
 
     /// Extract the chunks to be translated from the body
-    /// the chunks are separated by the string "%trsltx-split\n"
+    /// the chunks are separated by the split marker ("%trsltx-split\n" by default)
     /// or are enclosed between "%trsltx-begin-ignore\n" and "%trsltx-end-ignore\n"
     /// by defaults, the chunks are marked as Translate
     /// the chunks enclosed between "%trsltx-begin-ignore\n" and "%trsltx-end-ignore\n"
     /// are marked as Unchanged
-    pub fn extract_chunks(&mut self) -> Result<(), String> {
-        let toscan = self.body.clone();
+    /// the chunks made of a single verbatim-like environment (lstlisting, verbatim...)
+    /// or only of mathematics are also marked as Unchanged
+    pub fn extract_chunks(&mut self) -> Result<(), TrsltxError> {
+        let SplitMarkers {
+            split,
+            begin_ignore,
+            end_ignore,
+        } = self.markers.clone();
+        let split_line = format!("{}\n", split);
+        // the line numbers of the body start after the preamble
+        let first_line = self.preamble.matches('\n').count() + 1;
+        check_ignore_markers(&self.body, &begin_ignore, &end_ignore, first_line)
+            .map_err(TrsltxError::Parse)?;
+        // the sources written by the bilingual output are not translated again
+        let toscan = remove_source_comments(&self.body);
+        // the ignored regions are single chunks
+        let toscan = remove_splits_in_regions(&toscan, &begin_ignore, &end_ignore, &split_line);
         // add %trsltx-split before each %trsltx-begin-ignore
         let toscan = toscan.replace(
-            "%trsltx-begin-ignore",
-            "%trsltx-split\n%trsltx-begin-ignore",
+            begin_ignore.as_str(),
+            format!("{}{}", split_line, begin_ignore).as_str(),
         );
         // add %trsltx-split after each %trsltx-end-ignore
-        let toscan = toscan.replace("%trsltx-end-ignore", "%trsltx-end-ignore\n%trsltx-split\n");
+        let toscan = toscan.replace(
+            end_ignore.as_str(),
+            format!("{}\n{}", end_ignore, split_line).as_str(),
+        );
         // split the body into chunks
-        let chunks = toscan.split("%trsltx-split\n");
+        self.chunks.clear();
+        let chunks = toscan.split(split_line.as_str());
         for chunk in chunks {
-            let cchunk = chunk.trim().replace("%trsltx-split\n", "");
-            if cchunk.contains("%trsltx-begin-ignore") {
-                if !cchunk.contains("%trsltx-end-ignore") {
-                    return Err("Unbalanced %trsltx-begin-ignore".to_string());
-                }
+            let cchunk = chunk.trim().replace(split_line.as_str(), "");
+            // the markers are balanced, see check_ignore_markers
+            if cchunk.contains(begin_ignore.as_str()) || is_verbatim_chunk(&cchunk) {
+                self.chunks.push((cchunk.to_string(), ChunkType::Unchanged));
+            } else if is_pure_math(&cchunk) {
+                info!("Skipping pure-math chunk {}", self.chunks.len() + 1);
                 self.chunks.push((cchunk.to_string(), ChunkType::Unchanged));
-            } else if cchunk.contains("%trsltx-end-ignore") {
-                return Err("Unbalanced %trsltx-end-ignore".to_string());
             } else {
+                if let Some(env) = find_verbatim_env(&cchunk) {
+                    debug!(
+                        "the {} environment of chunk {} is replaced by a placeholder during the translation",
+                        env,
+                        self.chunks.len() + 1
+                    );
+                }
                 self.chunks.push((cchunk.to_string(), ChunkType::Translate));
             }
         }
@@ -228,423 +1374,5038 @@ impl Trsltx {
                 self.chunks[i] = (s, ChunkType::Unchanged);
             }
         }
+        if let Some(overrides) = &self.chunks_config {
+            for (num, t) in overrides.iter() {
+                if *num == 0 || *num > numchunks {
+                    warn!("no chunk {} to override ({} chunks)", num, numchunks);
+                    continue;
+                }
+                info!("Override: chunk {} of {} is {:?}", num, numchunks, t);
+                self.chunks[num - 1].1 = t.clone();
+            }
+        }
         // mark last chunk as Unchanged
         // if numchunks > 0 {
         //     let (s, _) = self.chunks[numchunks - 1].clone();
         //     self.chunks[numchunks - 1] = (s, ChunkType::Unchanged);
         // }
-        println!("{:?}", self.chunks);
+        debug!("{:?}", self.chunks);
         Ok(())
     }
 
+    /// Number of translatable words in each chunk (zero for the chunks left unchanged)
+    pub fn word_counts(&self) -> Vec<usize> {
+        self.chunks
+            .iter()
+            .map(|(chunk, t)| match t {
+                ChunkType::Translate => count_words(chunk),
+                ChunkType::Unchanged => 0,
+            })
+            .collect()
+    }
+
+    /// Complexity of the chunks to be translated, see `complexity`.
+    /// The index starts from 0.
+    pub fn complexity_report(&self) -> Vec<(usize, f64)> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, t))| *t == ChunkType::Translate)
+            .map(|(i, (chunk, _))| (i, complexity(chunk)))
+            .collect()
+    }
+
+    /// Number of translatable words in the chunks to be translated
+    pub fn total_word_count(&self) -> usize {
+        self.word_counts().iter().sum()
+    }
+
     /// Same as function "translate"
     // this function should not fail because if it encounters an error
     // it translates the chunk without the grammar analysis or
-    // on the worst errors, it leaves the chunk unchanged
+    // on the worst errors, it leaves the chunk unchanged (see `untranslated_chunks`)
     pub fn translate_chunks(&mut self) {
-        let mut body_translated = String::new();
-        let numchunks = self.chunks.len();
-        let mut count = 0;
-        for (chunk, t) in self.chunks.iter() {
-            println!("------------------------------------------");
-            match t {
-                ChunkType::Translate => {
-                    count += 1;
-                    let chunk_length = chunk.len();
-                    let max_chunk_length = 4000;
-                    let trs_try = if chunk_length >= max_chunk_length {
-                        println!("{:?}", chunk);
-                        println!(
-                            "Chunk too long: {} above {}",
-                            chunk_length, max_chunk_length
-                        );
-                        println!("Leave chunk {} of {} unchanged", count, numchunks);
-                        Ok(chunk.to_string())
-                    } else {
-                        println!("Translating chunk {} of {}", count, numchunks);
-                        translate_one_chunk(
-                            chunk.as_str(),
-                            self.input_lang.as_str(),
-                            self.output_lang.as_str(),
-                            self.model_name.clone(),
-                        )
-                    };
-                    match trs_try {
-                        Ok(trs_chunk) => {
-                            // append the split message
-                            // so that the translated file
-                            // can be reused by trsltx
-                            if count > 1 {
-                                body_translated.push_str("\n%trsltx-split\n");
-                            }
-                            body_translated.push_str(trs_chunk.as_str());
-                        }
-                        Err(e) => {
-                            println!("Error in translating chunk: {:?}", e);
-                            println!("Leave chunk {} of {} unchanged", count, numchunks);
-                            body_translated.push_str(chunk.as_str());
-                        }
-                    }
-                }
-                ChunkType::Unchanged => {
-                    count += 1;
-                    println!("    Copying chunk {} of {}", count, numchunks);
-                    body_translated.push_str(chunk.as_str());
+        let mut results: Vec<Option<(String, ChunkMeta)>> = vec![None; self.chunks.len()];
+        self.load_cache();
+        let checkpoint = Mutex::new(self.load_checkpoint());
+        let mut failed = 0;
+        for i in self.translation_order() {
+            let (mut meta, copy) = self.start_chunk(i, &checkpoint);
+            let trs_chunk = match copy {
+                Some(chunk) => chunk,
+                None => {
+                    let start = std::time::Instant::now();
+                    let trs_try = self.translate_reviewed(i, &mut meta);
+                    meta.elapsed_ms = start.elapsed().as_millis() as u64;
+                    meta.failed = trs_try.is_err();
+                    self.end_chunk(i, &meta, trs_try, &checkpoint)
                 }
-            }
+            };
+            failed += meta.failed as usize;
+            meta.finished_at = meta::unix_millis();
+            results[i] = Some((trs_chunk, meta));
         }
-        // last cleaning:
-        // remove the %trsltx-split immediately following %trsltx-end-ignore
-        body_translated = body_translated.replace(
-            "%trsltx-end-ignore\n%trsltx-split\n",
-            "%trsltx-end-ignore\n",
-        );
-        self.body_translated = body_translated;
+        self.end_checkpoint(failed);
+        self.save_cache();
+        self.assemble(results.into_iter().flatten().collect());
     }
 
-    pub fn write_file(&self) -> Result<(), String> {
-        let mut output_file = std::fs::File::create(&self.output_file_name)
-            .map_err(|e| format!("Cannot create file: {:?}", e))?;
-        output_file
-            .write_all(self.preamble.as_bytes())
-            .map_err(|e| format!("Cannot write to file: {:?}", e))?;
+    /// Order of the translation of the chunks: the order of the document,
+    /// or the increasing complexity if `sort_chunks_by_complexity` is set
+    fn translation_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.chunks.len()).collect();
+        if self.sort_chunks_by_complexity {
+            let scores: Vec<f64> = self.chunks.iter().map(|(c, _)| complexity(c)).collect();
+            order.sort_by(|&a, &b| scores[a].total_cmp(&scores[b]));
+        }
+        order
+    }
 
-        // write the translated body
-        // create the latex env trsltx  in case the translatex chunk is enclosed between
-        // \begin{trsltx} and \end{trsltx}
-        output_file
-            .write_all(
-                "\\newenvironment{trsltx}{}{}\n\n\\newcommand{\\commandevide}{}\n\\begin{document}"
-                    .as_bytes(),
-            )
-            .map_err(|e| format!("Cannot write to file: {:?}", e))?;
-
-        output_file
-            .write_all(self.body_translated.as_bytes())
-            .map_err(|e| format!("Cannot write to file: {:?}", e))?;
-        output_file
-            .write_all("\\end{document}".as_bytes())
-            .map_err(|e| format!("Cannot write to file: {:?}", e))?;
-        output_file
-            .write_all(self.afterword.as_bytes())
-            .map_err(|e| format!("Cannot write to file: {:?}", e))?;
+    /// Metadata of the chunk `i` before its translation, with the chunk itself
+    /// if it is not sent to the translator (unchanged or too long chunk),
+    /// or its translation if it is found in the checkpoint or in the cache
+    fn start_chunk(&self, i: usize, checkpoint: &Mutex<Checkpoint>) -> (ChunkMeta, Option<String>) {
+        let numchunks = self.chunks.len();
+        let (chunk, t) = &self.chunks[i];
+        let count = i + 1;
+        debug!("------------------------------------------");
+        let mut meta = ChunkMeta {
+            index: i,
+            source_hash: format!("{:016x}", cache::chunk_hash(chunk)),
+            model: self.model_name.clone(),
+            char_count: chunk.len(),
+            started_at: meta::unix_millis(),
+            ..Default::default()
+        };
+        match t {
+            ChunkType::Translate => {
+                meta.chunk_type = "translate".to_string();
+                meta.word_count = count_words(chunk);
+                meta.complexity = complexity(chunk);
+                if let Some(trs_chunk) = self.previous_chunk(i) {
+                    self.report(ProgressEvent::ChunkSkipped { index: i });
+                    debug!(
+                        "Chunk {} of {} kept from the existing translation",
+                        count, numchunks
+                    );
+                    return (meta, Some(trs_chunk.to_string()));
+                }
+                let saved = checkpoint
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .translation(i, chunk)
+                    .map(|trs_chunk| trs_chunk.to_string());
+                if let Some(trs_chunk) = saved {
+                    self.report(ProgressEvent::ChunkSkipped { index: i });
+                    info!("Chunk {} of {} found in the checkpoint", count, numchunks);
+                    return (meta, Some(trs_chunk));
+                }
+                // a chunk selected for a new translation is not taken from the cache
+                let cached = match self.previous_translation {
+                    Some(_) => None,
+                    None => self.cached_translation(chunk),
+                };
+                if let Some(trs_chunk) = cached {
+                    self.report(ProgressEvent::ChunkSkipped { index: i });
+                    info!("Chunk {} of {} found in the cache", count, numchunks);
+                    return (meta, Some(trs_chunk));
+                }
+                let chunk_length = chunk.len();
+                let max_chunk_length = self.max_chunk_length();
+                if chunk_length >= max_chunk_length {
+                    self.report(ProgressEvent::ChunkSkipped { index: i });
+                    debug!("{:?}", chunk);
+                    warn!(
+                        "Chunk too long: {} above {}",
+                        chunk_length, max_chunk_length
+                    );
+                    warn!("Leave chunk {} of {} unchanged", count, numchunks);
+                    meta.too_long = true;
+                    (meta, Some(chunk.clone()))
+                } else if self.is_cancelled() {
+                    self.report(ProgressEvent::ChunkSkipped { index: i });
+                    debug!("Translation cancelled, leave chunk {} unchanged", count);
+                    meta.failed = true;
+                    (meta, Some(chunk.clone()))
+                } else {
+                    self.report(ProgressEvent::ChunkStarted {
+                        index: i,
+                        total: numchunks,
+                        char_count: chunk_length,
+                    });
+                    debug!("Translating chunk {} of {}", count, numchunks);
+                    (meta, None)
+                }
+            }
+            ChunkType::Unchanged => {
+                meta.chunk_type = "unchanged".to_string();
+                self.report(ProgressEvent::ChunkSkipped { index: i });
+                debug!("    Copying chunk {} of {}", count, numchunks);
+                (meta, Some(chunk.clone()))
+            }
+        }
+    }
 
-        Ok(())
+    /// Translation of the chunk `i`, saved in the checkpoint,
+    /// or the chunk itself if the translation failed
+    fn end_chunk(
+        &self,
+        i: usize,
+        meta: &ChunkMeta,
+        trs_try: Result<String, String>,
+        checkpoint: &Mutex<Checkpoint>,
+    ) -> String {
+        match trs_try {
+            Ok(trs_chunk) => {
+                self.report(ProgressEvent::ChunkTranslated {
+                    index: i,
+                    distmin: meta.distmin.unwrap_or(0),
+                    attempt: meta.attempts as u32,
+                    elapsed: std::time::Duration::from_millis(meta.elapsed_ms),
+                });
+                let trs_chunk = self.glossary_comment(i, meta, trs_chunk);
+                let mut checkpoint = checkpoint.lock().unwrap_or_else(|e| e.into_inner());
+                checkpoint.record(i, &self.chunks[i].0, &trs_chunk);
+                self.save_checkpoint(&checkpoint);
+                self.cache_translation(&self.chunks[i].0, &trs_chunk);
+                trs_chunk
+            }
+            Err(e) => {
+                self.report(ProgressEvent::ChunkFailed {
+                    index: i,
+                    error: e.clone(),
+                });
+                error!("Error in translating chunk: {:?}", e);
+                warn!("Leave chunk {} of {} unchanged", i + 1, self.chunks.len());
+                self.chunks[i].0.clone()
+            }
+        }
     }
-}
 
-/// If the babel latex option is detected, replace the source
-/// language in the babel option by the target language
-pub fn adjust_preamble_lang(
-    preamble: String,
-    inlang: &str,
-    outlang: &str,
-) -> Result<String, String> {
-    let target_lang = get_lang_name(outlang)?.to_lowercase();
-    let source_lang = get_lang_name(inlang)?.to_lowercase();
-    let mut preamble = preamble.replace(source_lang.as_str(), target_lang.as_str());
-    if target_lang == "russian" {
-        // if \usepackage[T1]{fontenc} is not present in the preamble
-        // issue a warning
-        if !preamble.contains("\\usepackage[T1]{fontenc}") {
-            println!(r#"Warning: \\usepackage[T1]{{fontenc}} is not present in the preamble"#);
-            println!(r#"The Russian language requires \\usepackage[T2A]{{fontenc}}"#);
-            println!(r#"Add \\usepackage[T2A]{{fontenc}} to the preamble"#);
+    /// Build the translated body from the translated chunks, in the order of the document
+    fn assemble(&mut self, results: Vec<(String, ChunkMeta)>) {
+        let numchunks = results.len();
+        let (chunks_translated, mut chunks_meta): (Vec<String>, Vec<ChunkMeta>) =
+            results.into_iter().unzip();
+        for (meta, trs_chunk) in chunks_meta.iter_mut().zip(chunks_translated.iter()) {
+            meta.translation = trs_chunk.clone();
         }
-        preamble = preamble.replace(
-            r#"\usepackage[T1]{fontenc}"#,
-            r#"\usepackage[T2A]{fontenc}"#,
+        self.chunks_translated = chunks_translated;
+        self.body_translated = self.assemble_body();
+        self.chunks_meta = chunks_meta;
+        self.report(ProgressEvent::Done {
+            total_chunks: numchunks,
+        });
+    }
+
+    /// The translated body, made of the translated and copied chunks separated by the
+    /// split markers: a copied verbatim environment is not glued to the previous chunk,
+    /// and the ignored regions keep their own markers
+    fn assemble_body(&self) -> String {
+        // assemble the chunks in the order of the document
+        let mut body_translated = String::new();
+        for (i, trs_chunk) in self.chunks_translated.iter().enumerate() {
+            // append the split message
+            // so that the translated file
+            // can be reused by trsltx
+            if i > 0 {
+                body_translated.push_str(&format!("\n{}\n", self.markers.split));
+            }
+            let (source, t) = &self.chunks[i];
+            if self.bilingual && *t == ChunkType::Translate && !source.trim().is_empty() {
+                body_translated.push_str(&source_comment(source));
+            }
+            body_translated.push_str(trs_chunk.as_str());
+        }
+        // last cleaning:
+        // remove the %trsltx-split immediately following %trsltx-end-ignore
+        // or immediately preceding %trsltx-begin-ignore
+        let SplitMarkers {
+            split,
+            begin_ignore,
+            end_ignore,
+        } = &self.markers;
+        body_translated = body_translated.replace(
+            format!("{}\n{}\n", end_ignore, split).as_str(),
+            format!("{}\n", end_ignore).as_str(),
         );
+        body_translated.replace(
+            format!("{}\n{}", split, begin_ignore).as_str(),
+            begin_ignore,
+        )
     }
-    Ok(preamble)
-}
 
-/// Get the long language name from the short two-letter one
-pub fn get_lang_name(lang: &str) -> Result<String, String> {
-    // list of known languages
-    const LANGUAGES: [(&str, &str); 7] = [
-        ("en", "English"),
-        ("fr", "French"),
-        ("es", "Spanish"),
-        ("de", "German"),
-        ("it", "Italian"),
-        ("pt", "Portuguese"),
-        ("ru", "Russian"),
-    ];
+    /// Structural differences between the chunks to be translated and their translations.
+    /// The chunks without differences are omitted.
+    pub fn ast_diff_report(&self) -> Vec<ChunkDiff> {
+        self.chunks
+            .iter()
+            .zip(self.chunks_translated.iter())
+            .enumerate()
+            .filter(|(_, ((_, t), _))| *t == ChunkType::Translate)
+            .map(|(i, ((source, _), translation))| ChunkDiff {
+                index: i + 1,
+                diffs: diff::ast_diff(source, translation),
+            })
+            .filter(|d| !d.diffs.is_empty())
+            .collect()
+    }
 
-    // build a dictionnary from the list of languages
-    let mut lang_dict = std::collections::HashMap::new();
-    for (k, v) in LANGUAGES.iter() {
-        lang_dict.insert(k.to_string(), v.to_string());
+    /// Write the structural differences as JSON
+    pub fn write_diff_report(&self, path: &str) -> Result<(), TrsltxError> {
+        let json = serde_json::to_string_pretty(&self.ast_diff_report())
+            .map_err(|e| TrsltxError::io(path, e.into()))?;
+        std::fs::write(path, json).map_err(|e| TrsltxError::io(path, e))
     }
 
-    let lang = lang_dict.get(lang).ok_or(
-        "The supported languages are: en,fr,es,de,it,pt,ru. Unsupported language: ".to_owned()
-            + lang,
-    )?;
-    Ok(lang.to_string())
-}
+    /// Estimated number of tokens of a text for the current model
+    pub fn estimate_tokens(&self, text: &str) -> usize {
+        estimate_tokens(text, self.chars_per_token)
+    }
 
-/// one chat operation with the textsynth LLM
-/// send the question
-/// and returns an answer
-#[allow(dead_code)]
-fn chat_with_ts(question: &str) -> Result<String, String> {
-    // get the api key from the file "api_key.txt" or if the file does not exist, from the environment variable "TEXTSYNTH_API_KEY"
-    let api_key = match std::fs::read_to_string("api_key.txt") {
-        // if the file exists, get the api key from the file
-        // removing the spaces and newlines with trim()
-        Ok(api_key) => api_key.trim().to_string(),
-        Err(_) => std::env::var("TEXTSYNTH_API_KEY").expect("You have to provide an api key in the file api_key.txt or by export TEXTSYNTH_API_KEY=api_key"),
-    };
+    /// Identifiers of the models available on the server of the backend
+    pub fn list_models(&self) -> Result<Vec<String>, TrsltxError> {
+        self.backend
+            .list_models(&self.completion_params(self.max_tokens))
+            .map_err(TrsltxError::Api)
+    }
 
-    // call the textsynth REST API
-    //let url = "https://api.textsynth.com/v1/engines/mixtral_47B_instruct/chat";
-    // also works well with the engine mistral_7B_instruct
-    let url = "https://api.textsynth.com/v1/engines/mistral_7B_instruct/chat";
-    let max_tokens = 2000;
+    /// Check that the model is available on the server, before translating.
+    /// Returns a `Config` error for an unknown model and an `Api` error if the list
+    /// of the models cannot be obtained. The translators (DeepL) are not checked.
+    pub fn validate_model(&self) -> Result<(), TrsltxError> {
+        if self.backend.is_translator() {
+            return Ok(());
+        }
+        let available = self.list_models()?;
+        // the aliases of the registry are replaced by the Textsynth ids
+        let id = self
+            .model_entry
+            .as_ref()
+            .map_or(self.model_name.as_str(), |e| e.id.as_str());
+        if available.iter().any(|m| m == id || *m == self.model_name) {
+            Ok(())
+        } else {
+            Err(TrsltxError::Config(format!(
+                "Unknown model {}, the available models are: {}",
+                self.model_name,
+                available.join(", ")
+            )))
+        }
+    }
 
-    use serde_json::json;
-    use serde_json::Value;
+    /// Parameters of the requests sent to the backend
+    pub(crate) fn completion_params(&self, max_tokens: usize) -> CompletionParams {
+        CompletionParams {
+            model: self.model_name.clone(),
+            api_key: self.api_key.clone(),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens,
+            retry: self.retry_policy.clone(),
+        }
+    }
 
-    let req = json!({
-        "messages": [question],
-        "temperature": 0.5,
-        "max_tokens": max_tokens
-    });
+    /// Maximal length in bytes of a chunk given by the settings,
+    /// see `TrsltxBuilder::max_tokens_per_chunk`
+    pub(crate) fn chunk_length_limit(&self) -> usize {
+        match self.max_tokens_per_chunk {
+            Some(tokens) => (tokens as f32 * self.chars_per_token) as usize,
+            None => MAX_CHUNK_LENGTH,
+        }
+    }
 
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&req)
-        .send()
-        .expect("Failed to send request")
-        .json::<Value>();
+    /// Maximal length in bytes of a chunk sent to the translator.
+    /// It is reduced for the models with a small context window, which must contain
+    /// the prompt, the chunk and its translation.
+    pub fn max_chunk_length(&self) -> usize {
+        let max_chunk_length = self.chunk_length_limit();
+        match &self.model_entry {
+            Some(entry) => {
+                // the answer is not longer than max_tokens
+                let available = entry
+                    .context_window_tokens
+                    .saturating_sub(self.max_tokens)
+                    .saturating_sub(200);
+                max_chunk_length.min((available as f32 * self.chars_per_token) as usize)
+            }
+            None => max_chunk_length,
+        }
+    }
 
-    let answer: String = match res {
-        Ok(resp) => {
-            let text = resp["text"]
-                .as_str()
-                .ok_or("The result of Textsynth does not contain text")?;
-            //println!("{:?}", text);
-            text.to_string()
+    /// Metadata of the last translation
+    pub fn meta(&self) -> TranslationMeta {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        TranslationMeta {
+            source_file: match &self.input {
+                LaTeXSource::File(file_name) => file_name.clone(),
+                LaTeXSource::Stdin => "-".to_string(),
+                LaTeXSource::Memory => String::new(),
+            },
+            output_file: self.output_file_name.clone(),
+            input_lang: self.input_lang.clone(),
+            output_lang: self.output_lang.clone(),
+            model: self.model_name.clone(),
+            timestamp,
+            chunks: self.chunks_meta.clone(),
         }
-        Err(e) => {
-            println!("Request error: {:?}", e);
-            "".to_string()
+    }
+
+    /// Check the balance of the braces and environments of the translation,
+    /// chunk by chunk. The errors are printed so that the user
+    /// can review the translation, which can still be written.
+    pub fn validate_output(&self) -> Result<(), Vec<ValidationError>> {
+        let errors = self.delimiter_errors();
+        if errors.is_empty() {
+            return Ok(());
         }
-    };
-    Ok(answer)
-}
+        for e in errors.iter() {
+            warn!("{}", e);
+        }
+        warn!("Please review the translation.");
+        Err(errors)
+    }
 
-/// one completion operation with the textsynth LLM
-/// send the question and a formal grammar (as Some(String) or None)
-/// and returns an answer
-fn complete_with_ts(
-    prompt: &str,
-    grammar: &Option<String>,
-    model: String,
-) -> Result<String, String> {
-    // get the api key from the file "api_key.txt"
-    //or if the file does not exist, from the environment variable "TEXTSYNTH_API_KEY"
-    let api_key = match std::fs::read_to_string("api_key.txt") {
-        // if the file exists, get the api key from the file
-        // removing the spaces and newlines with trim()
-        Ok(api_key) => api_key.trim().to_string(),
-        Err(_) => std::env::var("TEXTSYNTH_API_KEY").map_err(|e| format!("You have to provide an api key in the file api_key.txt or by export TEXTSYNTH_API_KEY=api_key. Error: {:?}", e))?,
-    };
+    /// Delimiter mismatches of the translated chunks which are not in the source chunk.
+    /// The copied chunks are not checked.
+    fn delimiter_errors(&self) -> Vec<ValidationError> {
+        let mut errors = vec![];
+        for (i, (chunk, trs_chunk)) in self.chunks.iter().zip(&self.chunks_translated).enumerate() {
+            if chunk.1 == ChunkType::Unchanged || chunk.0 == *trs_chunk {
+                continue;
+            }
+            let mut source_errors: Vec<DelimiterError> = validate::check_delimiters(&chunk.0)
+                .into_iter()
+                .map(|e| e.kind)
+                .collect();
+            for e in validate::check_delimiters(trs_chunk) {
+                // a mismatch of the source is kept in the translation
+                if let Some(pos) = source_errors.iter().position(|kind| *kind == e.kind) {
+                    source_errors.remove(pos);
+                    continue;
+                }
+                errors.push(ValidationError {
+                    chunk: Some(i + 1),
+                    ..e
+                });
+            }
+        }
+        errors
+    }
 
-    // call the textsynth REST API
-    let url = match model.as_str() {
-        "mistral47b" => "https://api.textsynth.com/v1/engines/mixtral_47B_instruct/completions",
-        _ => "https://api.textsynth.com/v1/engines/mistral_7B_instruct/completions",
-    };
+    pub fn write_file(&self) -> Result<(), TrsltxError> {
+        let output_name = self.output_file_name.as_str();
+        let mut output_file =
+            std::fs::File::create(output_name).map_err(|e| TrsltxError::io(output_name, e))?;
+        self.write_to(&mut output_file)
+            .map_err(|e| TrsltxError::io(output_name, e))?;
+        if self.write_meta {
+            let meta_file_name = meta::meta_file_name(output_name);
+            let json = serde_json::to_string_pretty(&self.meta())
+                .map_err(|e| TrsltxError::io(&meta_file_name, e.into()))?;
+            std::fs::write(&meta_file_name, json)
+                .map_err(|e| TrsltxError::io(&meta_file_name, e))?;
+        }
+        Ok(())
+    }
 
-    let max_tokens = 2000;
+    /// Write the translated LaTeX code to the standard output
+    pub fn write_stdout(&self) -> Result<(), TrsltxError> {
+        self.write_to(&mut std::io::stdout().lock())
+            .map_err(|e| TrsltxError::io("standard output", e))
+    }
 
-    use serde_json::json;
-    use serde_json::Value;
+    fn write_to(&self, output_file: &mut dyn Write) -> std::io::Result<()> {
+        if self.fragment {
+            return output_file.write_all(self.body_translated.as_bytes());
+        }
+        output_file.write_all(self.preamble.as_bytes())?;
 
-    let req = match grammar {
-        Some(gr) => {
-            json!({
-                "prompt": prompt,
-                "temperature": 0.5,
-                "max_tokens": max_tokens,
-                "grammar": gr
-            })
+        // write the translated body
+        output_file.write_all(BODY_HEADER.as_bytes())?;
+
+        output_file.write_all(self.body_translated.as_bytes())?;
+        output_file.write_all("\\end{document}".as_bytes())?;
+        output_file.write_all(self.afterword.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// Definitions written between the preamble and the translated body:
+/// the latex env trsltx in case the translated chunk is enclosed between
+/// \begin{trsltx} and \end{trsltx}
+const BODY_HEADER: &str =
+    "\\newenvironment{trsltx}{}{}\n\n\\newcommand{\\commandevide}{}\n\\begin{document}";
+
+/// Natural-language text of a LaTeX string, for instance for a spelling check.
+/// The comments, the verbatim-like environments, the mathematics, the command names
+/// and the keys of `\label`, `\ref`, `\cite`... are removed.
+/// The text inside groups, footnotes and environments is kept.
+// the parser ltxprs does not give the text nodes of the chunks, hence the raw scan
+pub fn extracts_all_text(latex: &str) -> String {
+    // remove the comments, but not the \% characters
+    let mut text: String = latex
+        .lines()
+        .map(|l| {
+            let mut prev = ' ';
+            let end = l
+                .char_indices()
+                .find(|&(_, c)| {
+                    let comment = c == '%' && prev != '\\';
+                    prev = c;
+                    comment
+                })
+                .map_or(l.len(), |(i, _)| i);
+            &l[..end]
+        })
+        .collect::<Vec<&str>>()
+        .join("\n");
+    // remove the verbatim-like environments
+    for env in VERBATIM_ENVS {
+        let begin = format!("\\begin{{{}}}", env);
+        let end = format!("\\end{{{}}}", env);
+        while let Some(ib) = text.find(begin.as_str()) {
+            let ie = text[ib..]
+                .find(end.as_str())
+                .map_or(text.len(), |i| ib + i + end.len());
+            text.replace_range(ib..ie, " ");
         }
-        None => {
-            // println!("No grammar");
-            json!({
-                "prompt": prompt,
-                "temperature": 0.5,
-                "max_tokens": max_tokens
-            })
+    }
+    let mut from = 0;
+    while let Some(ib) = text[from..].find("\\verb").map(|i| from + i) {
+        match verb_len(&text[ib..]) {
+            Some(len) => text.replace_range(ib..ib + len, " "),
+            None => from = ib + 1,
         }
-    };
-    //println!("Req= {:?}", req);
-    println!("Translate with {}", model);
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&req)
-        .send()
-        .map_err(|e| format!("Failed to send request: {:?}", e))?
-        .json::<Value>();
-    println!("{:?}", res);
+    }
+    // remove the mathematics, if the delimiters are balanced
+    if let Some((stripped, _)) = remove_math(&text) {
+        text = stripped;
+    }
+    // remove the keys
+    for citation in extracts_citations(&text) {
+        text = text.replace(citation.as_str(), "");
+    }
+    for cmd in KEY_ARG_COMMANDS {
+        let head = format!("\\{}{{", cmd);
+        while let Some(ib) = text.find(head.as_str()) {
+            let ie = text[ib..].find('}').map_or(text.len(), |i| ib + i + 1);
+            text.replace_range(ib..ie, "");
+        }
+    }
+    // remove the command names and the braces, keep the escaped characters
+    let mut all_text = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some(c) if c.is_alphabetic() => {
+                    while chars.peek().is_some_and(|c| c.is_alphabetic()) {
+                        chars.next();
+                    }
+                    // separate the words, as in `text\footnote{note}`
+                    if all_text.chars().last().is_some_and(|c| !c.is_whitespace()) {
+                        all_text.push(' ');
+                    }
+                }
+                Some('\\' | ',' | ';' | ' ') => {
+                    chars.next();
+                    all_text.push(' ');
+                }
+                Some(&c) => {
+                    chars.next();
+                    all_text.push(c);
+                }
+                None => {}
+            },
+            '{' | '}' => {}
+            _ => all_text.push(c),
+        }
+    }
+    all_text
+}
 
-    let answer: String = match res {
-        Ok(resp) => {
-            //println!("{:?}", resp);
-            let text = resp["text"]
-                .as_str()
-                .ok_or("The result of Textsynth does not contain text")?;
-            //println!("{:?}", text);
-            text.to_string()
+/// Estimate the number of natural-language words in a LaTeX string,
+/// i.e. in the text given by [`extracts_all_text`].
+pub fn count_words(latex: &str) -> usize {
+    extracts_all_text(latex)
+        .split_whitespace()
+        .filter(|w| w.chars().any(|c| c.is_alphabetic()))
+        .count()
+}
+
+/// Maximal number of generated tokens for the translation of a field of the preamble
+pub const PREAMBLE_MAX_TOKENS: usize = 200;
+
+/// Positions of the translatable parts of the preamble: the arguments of
+/// `\title`, `\date` and `\author` and the content of the abstract environment.
+/// `\author` is kept when it only contains names, i.e. has no `\thanks` and no line break.
+/// The positions are sorted, the fields without words are omitted.
+pub fn preamble_fields(preamble: &str) -> Vec<(usize, usize)> {
+    let mut fields = vec![];
+    for name in ["title", "author", "date"] {
+        let head = format!("\\{}{{", name);
+        for (pos, _) in preamble.match_indices(head.as_str()) {
+            let open = pos + head.len() - 1;
+            if let Some(close) = matching_brace(preamble, open) {
+                let arg = &preamble[open + 1..close];
+                if name == "author" && !arg.contains("\\thanks") && !arg.contains("\\\\") {
+                    continue;
+                }
+                fields.push((open + 1, close));
+            }
         }
-        Err(e) => {
-            println!("Request error: {:?}", e);
-            "".to_string()
+    }
+    let (begin, end) = ("\\begin{abstract}", "\\end{abstract}");
+    if let Some(ib) = preamble.find(begin) {
+        if let Some(ie) = preamble[ib..].find(end) {
+            fields.push((ib + begin.len(), ib + ie));
         }
-    };
+    }
+    fields.retain(|&(start, end)| count_words(&preamble[start..end]) > 0);
+    fields.sort();
+    fields
+}
 
-    Ok(answer)
+/// Math environments, which make a chunk harder to translate
+pub(crate) const MATH_ENVS: [&str; 7] = [
+    "equation",
+    "align",
+    "gather",
+    "multline",
+    "eqnarray",
+    "displaymath",
+    "math",
+];
+
+/// Estimate of the difficulty of the translation of a LaTeX string:
+/// words count for 1.0, commands for 0.3 and each level of nesting
+/// (braces and environments) for 0.5. The sum is multiplied by 1.5
+/// if the string contains a math environment.
+pub fn complexity(latex: &str) -> f64 {
+    let words = count_words(latex) as f64;
+    let commands = diff::structural_tokens(latex).len() as f64;
+    let mut depth = 0usize;
+    let mut max_depth = 0usize;
+    for line in latex.lines() {
+        let mut prev = ' ';
+        for (i, c) in line.char_indices() {
+            match c {
+                '%' if prev != '\\' => break,
+                '{' if prev != '\\' => depth += 1,
+                '}' if prev != '\\' => depth = depth.saturating_sub(1),
+                '\\' if line[i..].starts_with("\\begin{") => depth += 1,
+                '\\' if line[i..].starts_with("\\end{") => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            max_depth = max_depth.max(depth);
+            prev = c;
+        }
+    }
+    let score = words + 0.3 * commands + 0.5 * max_depth as f64;
+    let math = latex.contains("\\[")
+        || MATH_ENVS
+            .iter()
+            .any(|env| latex.contains(&format!("\\begin{{{}", env)));
+    if math {
+        1.5 * score
+    } else {
+        score
+    }
 }
 
-const PREPROMPT: &str = r#"
-Q: Translate the following <lang_in> scientific text, formatted with LateX, into <lang_out>.
-Keep the LateX syntax and formulas. The results must compile without errors with pdflatex.
-Give only the result without preliminaries. 
-Enclose the resulting LateX source between \begin{trsltx} and \end{trsltx}
-Here is the <lang_in> LateX source:
+/// Nesting of a LaTeX text scanned line by line: environments, brace groups and
+/// mathematics. A split marker may only be placed at the top level.
+// the parser ltxprs does not give the positions of the nodes, hence the raw scan
+#[derive(Debug, Default)]
+struct Nesting {
+    environments: i64,
+    braces: i64,
+    /// inside `$...$`, `$$...$$`, `\(...\)` or `\[...\]`
+    math: bool,
+}
 
-"#;
+impl Nesting {
+    fn is_top_level(&self) -> bool {
+        self.environments <= 0 && self.braces <= 0 && !self.math
+    }
 
-/// translate a latex chunk using the textsynth LLM api
-/// the preprompt is in the file "prompt.txt"
-/// the api key is in the file "api_key.txt" or
-/// in the environment variable "TEXTSYNTH_API_KEY"
-fn translate_one_chunk(chunk: &str, input_lang: &str, output_lang: &str, model: String) -> Result<String, String> {
-    println!("Translating chunk: {:?}", chunk);
-    if chunk.trim() == r#"\commandevide"# || chunk.trim() == "" {
-        println!("Empty chunk");
-        // create a string containing \commandvide followed by a newline
-        let s = "\\commandevide\n".to_string();
-        return Ok(s);
+    /// Update the nesting with a line, the comments are ignored
+    fn scan_line(&mut self, line: &str) {
+        let mut chars = line.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '%' => break,
+                '\\' => {
+                    if line[i..].starts_with("\\begin{") {
+                        self.environments += 1;
+                    } else if line[i..].starts_with("\\end{") {
+                        self.environments -= 1;
+                    }
+                    // the escaped characters \{, \$, \% are skipped
+                    match chars.next() {
+                        Some((_, '(' | '[')) => self.math = true,
+                        Some((_, ')' | ']')) => self.math = false,
+                        _ => {}
+                    }
+                }
+                '{' => self.braces += 1,
+                '}' => self.braces -= 1,
+                '$' => {
+                    // $$ is a single delimiter
+                    chars.next_if(|&(_, c)| c == '$');
+                    self.math = !self.math;
+                }
+                _ => {}
+            }
+        }
     }
-    // get the preprompt from a file
-    // let mut prompt = std::fs::read_to_string("src/prompt.txt")
-    //     .map_err(|_| "cannot read preprompt".to_string())?;
-    // or directly from the const PREPROMPT
-    let mut prompt = PREPROMPT.to_string();
-
-    let input_lang = get_lang_name(input_lang)?.to_string();
-    let output_lang = get_lang_name(output_lang)?.to_string();
-
-    // in the prompt, replace <lang_in> by the input language and <lang_out> by the output language
-    prompt = prompt.replace("<lang_in>", input_lang.as_str());
-    prompt = prompt.replace("<lang_out>", output_lang.as_str());
-
-    let question = format!("{}\n{}\nA:\n", prompt, chunk);
-    // exit(0);
-    //let trs_chunk = chat_with_ts(question.as_str());
-    let ast_chunk = LtxNode::new(chunk);
-    //let cmds = ast_chunk.extracts_commands();
-    //println!("{:?}", ast_chunk);
-    let grammar = match ast_chunk {
-        LtxNode::Problem(_) => None,
-        _ => Some(ast_chunk.to_ebnf().trim().to_string()),
-    };
-    //ast_chunk.print();
-    println!("Grammar: {}", ast_chunk.to_ebnf());
-    let mut distmin = std::usize::MAX;
-    let mut iter = 0;
-    let mut trs_chunk = "".to_string();
-    let itermax = 4;
-    // at most four attempts to get a translation
-    while distmin > 1 && iter < itermax {
-        // last iter without grammar
-        let trs_try = if iter > itermax - 2 {
-            complete_with_ts(question.as_str(), &None, model.clone())
-        } else {
-            complete_with_ts(question.as_str(), &grammar, model.clone())
-        };
-        let trs_try = match trs_try {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
-        let trs_try = trs_try.split("\\begin{trsltx}").collect::<Vec<&str>>();
-        let trs_try = if trs_try.len() >= 2 {
-            let trs_try = trs_try[1].split("\\end{trsltx}").collect::<Vec<&str>>()[0];
-            trs_try.to_string()
+}
+
+/// Remove the split markers (e.g. "%trsltx-split") placed between a `\begin{...}`
+/// and the matching `\end{...}`, inside a brace group or inside mathematics
+pub fn remove_splits_in_environments(latex: &str, split_marker: &str) -> String {
+    let mut nesting = Nesting::default();
+    let mut lines = vec![];
+    for line in latex.lines() {
+        if line.trim() == split_marker {
+            if !nesting.is_top_level() {
+                warn!("remove a split marker inside an environment, a group or a formula");
+                continue;
+            }
         } else {
-            "".to_string()
-        };
-        let trs_ltxnode = LtxNode::new(trs_try.as_str());
-        let dist = ast_chunk.distance(&trs_ltxnode);
-        println!("Syntax distance: {}", dist);
-        println!("Bnf grammar: {}", trs_ltxnode.to_ebnf());
-        if dist < distmin {
-            distmin = dist;
-            trs_chunk = trs_try;
+            nesting.scan_line(line);
         }
-        // if distmin > 0 {
-        //     // prepend a warning to the translation
-        //     let warn = format!("%Warning chunk, distance: {}", distmin);
-        //     trs_chunk = warn + trs_chunk.as_str();
-        //     let endwarn = format!("%---------------------------------");
-        //     trs_chunk = trs_chunk + endwarn.as_str();
-        // }
-        iter += 1;
+        lines.push(line);
     }
-
-    Ok(trs_chunk)
+    let mut result = lines.join("\n");
+    if latex.ends_with('\n') {
+        result.push('\n');
+    }
+    result
 }
 
-// test the chat_with_ts function
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Sectioning commands before which a split marker is placed in priority
+const SECTIONING_COMMANDS: [&str; 5] =
+    ["part", "chapter", "section", "subsection", "subsubsection"];
 
-    #[test]
-    fn test_chat_with_ts() {
+/// True if the line starts with a sectioning command, starred or not
+fn is_sectioning_line(line: &str) -> bool {
+    let line = line.trim_start();
+    SECTIONING_COMMANDS.iter().any(|cmd| {
+        line.strip_prefix('\\')
+            .and_then(|l| l.strip_prefix(cmd))
+            .is_some_and(|l| l.starts_with(['{', '*', '[']))
+    })
+}
+
+/// Move each split marker (e.g. "%trsltx-split") to the nearest line, at most
+/// `window` bytes away, which starts a section (`\section`, `\chapter`...) or,
+/// failing that, a paragraph, so that the chunks are not cut in the middle of
+/// a sentence. The lines inside the environments, groups and formulas are not considered.
+/// The markers without such a line nearby are left in place.
+pub fn align_splits(latex: &str, split_marker: &str, window: usize) -> String {
+    let lines: Vec<&str> = latex.lines().filter(|l| l.trim() != split_marker).collect();
+    // gap i is before the line i: its byte offset and its score
+    // (2 for a sectioning command, 1 for a paragraph, 0 otherwise)
+    let mut offsets = vec![0];
+    let mut scores = vec![0];
+    let mut nesting = Nesting::default();
+    for (i, line) in lines.iter().enumerate() {
+        nesting.scan_line(line);
+        offsets.push(offsets[i] + line.len() + 1);
+        let next = lines.get(i + 1).map_or("", |l| l.trim());
+        scores.push(if !nesting.is_top_level() || next.is_empty() {
+            0
+        } else if is_sectioning_line(next) {
+            2
+        } else if line.trim().is_empty() {
+            1
+        } else {
+            0
+        });
+    }
+    // gaps of the markers
+    let mut gaps = vec![];
+    let mut gap = 0;
+    for line in latex.lines() {
+        if line.trim() == split_marker {
+            gaps.push(gap);
+        } else {
+            gap += 1;
+        }
+    }
+    let mut aligned: Vec<usize> = gaps
+        .into_iter()
+        .map(|m| {
+            (0..offsets.len())
+                .filter(|&g| scores[g] > 0 && offsets[g].abs_diff(offsets[m]) <= window)
+                .max_by_key(|&g| {
+                    (
+                        scores[g],
+                        std::cmp::Reverse(offsets[g].abs_diff(offsets[m])),
+                    )
+                })
+                .unwrap_or(m)
+        })
+        .collect();
+    aligned.sort();
+    aligned.dedup();
+    let mut result = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        if aligned.contains(&i) {
+            result.push(split_marker);
+        }
+        result.push(line);
+    }
+    if aligned.contains(&lines.len()) {
+        result.push(split_marker);
+    }
+    let mut result = result.join("\n");
+    if latex.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+/// Format a human-readable message locating a parse error in a LaTeX chunk,
+/// with the line and column of the byte offset and the surrounding characters
+pub fn parse_error_report(input: &str, offset: usize) -> String {
+    let mut offset = offset.min(input.len());
+    while !input.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &input[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    let context: String = input[..offset]
+        .chars()
+        .rev()
+        .take(20)
+        .collect::<Vec<char>>()
+        .into_iter()
+        .rev()
+        .chain(input[offset..].chars().take(20))
+        .collect();
+    format!(
+        "Parse error at line {}, column {}, near: {:?}",
+        line, column, context
+    )
+}
+
+/// Find the byte offset of the first unbalanced brace or of an unclosed `$` in a LaTeX chunk.
+/// The escaped characters `\{`, `\}`, `\$` and the comments are ignored.
+pub fn find_unbalanced(latex: &str) -> Option<usize> {
+    let mut open_braces = vec![];
+    let mut dollar: Option<usize> = None;
+    let mut chars = latex.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '%' => while chars.next_if(|&(_, c)| c != '\n').is_some() {},
+            '{' => open_braces.push(i),
+            '}' if open_braces.pop().is_none() => return Some(i),
+            '$' => {
+                // $$ is handled as two consecutive $
+                dollar = match dollar {
+                    Some(_) => None,
+                    None => Some(i),
+                };
+            }
+            _ => {}
+        }
+    }
+    open_braces.first().copied().or(dollar)
+}
+
+/// Commands copied verbatim in the translation (`todonotes` package...)
+pub const PRESERVE_COMMANDS: [&str; 4] = ["todo", "fixme", "missingfigure", "listoftodos"];
+
+/// Commands including another file, and true if the file is a fragment without preamble
+const INCLUDE_COMMANDS: [(&str, bool); 4] = [
+    ("subfile", false),
+    ("subfileinclude", false),
+    ("input", true),
+    ("include", true),
+];
+
+/// Commands of the document structure and of the bibliography, also copied verbatim
+pub const STRUCTURE_COMMANDS: [&str; 10] = [
+    "input",
+    "include",
+    "nocite",
+    "bibliography",
+    "bibliographystyle",
+    "addbibresource",
+    "printbibliography",
+    "maketitle",
+    "tableofcontents",
+    "listoffigures",
+];
+
+/// Position of the brace closing the brace at `open`, taking nesting
+/// and escaped braces into account
+pub(crate) fn matching_brace(latex: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in latex[open..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// LaTeX code of the placeholder number `n`: `\trsltxplaceholderA{}`, `B`...
+// LaTeX command names cannot contain digits: the number is written with letters,
+// and the empty group ends the name, which cannot merge with the letters after it
+fn placeholder(n: usize) -> String {
+    let mut letters = vec![];
+    let mut n = n;
+    loop {
+        letters.push((b'A' + (n % 26) as u8) as char);
+        n /= 26;
+        if n == 0 {
+            break;
+        }
+    }
+    format!(
+        "\\trsltxplaceholder{}{{}}",
+        letters.iter().rev().collect::<String>()
+    )
+}
+
+/// Replace the commands `\cmd[options]{content}` of the list by placeholders.
+/// The verbatim-like environments and the `\verb|code|` commands are always replaced:
+/// the code samples are copied unchanged, without being sent to the translator.
+/// Returns the new string and the replaced commands, in the order of the placeholders.
+pub fn protect_commands(latex: &str, commands: &[String]) -> (String, Vec<String>) {
+    let mut result = String::new();
+    let mut originals = vec![];
+    let mut rest = latex;
+    while let Some(pos) = rest.find('\\') {
+        if let Some(len) = verb_len(&rest[pos..]).or_else(|| verbatim_env_len(&rest[pos..])) {
+            result.push_str(&rest[..pos]);
+            result.push_str(&placeholder(originals.len()));
+            originals.push(rest[pos..pos + len].to_string());
+            rest = &rest[pos + len..];
+            continue;
+        }
+        let after = &rest[pos + 1..];
+        let name_len = after
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(after.len());
+        let name = &after[..name_len];
+        if name.is_empty() || !commands.iter().any(|c| c == name) {
+            // skip the command name, or the escaped character
+            let skip = match name_len {
+                0 => pos + 1 + after.chars().next().map_or(0, |c| c.len_utf8()),
+                _ => pos + 1 + name_len,
+            };
+            result.push_str(&rest[..skip]);
+            rest = &rest[skip..];
+            continue;
+        }
+        // end of the command: optional [...] then optional {...}
+        let mut end = pos + 1 + name_len;
+        if rest[end..].starts_with('[') {
+            if let Some(close) = rest[end..].find(']') {
+                end += close + 1;
+            }
+        }
+        if rest[end..].starts_with('{') {
+            if let Some(close) = matching_brace(rest, end) {
+                end = close + 1;
+            }
+        }
+        result.push_str(&rest[..pos]);
+        result.push_str(&placeholder(originals.len()));
+        originals.push(rest[pos..end].to_string());
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    (result, originals)
+}
+
+/// Commands whose first argument is a key or an environment name,
+/// not a text to be translated
+const KEY_ONLY_COMMANDS: [&str; 12] = [
+    "label", "ref", "eqref", "autoref", "pageref", "cref", "Cref", "cite", "citep", "citet",
+    "begin", "end",
+];
+
+/// Commands whose first argument is a file path or a URL
+const PATH_COMMANDS: [&str; 9] = [
+    "url",
+    "href",
+    "includegraphics",
+    "input",
+    "include",
+    "subfile",
+    "bibliography",
+    "addbibresource",
+    "lstinputlisting",
+];
+
+/// Commands whose first argument is a key, an environment name, a file path or a URL,
+/// not a text to be translated: the `KEY_ONLY_COMMANDS` and the `PATH_COMMANDS`
+pub const KEY_ARG_COMMANDS: [&str; 21] = {
+    let mut commands = [""; 21];
+    let mut i = 0;
+    while i < commands.len() {
+        commands[i] = if i < KEY_ONLY_COMMANDS.len() {
+            KEY_ONLY_COMMANDS[i]
+        } else {
+            PATH_COMMANDS[i - KEY_ONLY_COMMANDS.len()]
+        };
+        i += 1;
+    }
+    commands
+};
+
+/// Replace the commands of `PATH_COMMANDS`, with their optional argument and their
+/// first argument, by placeholders numbered after the `originals` of `protect_commands`.
+/// The next arguments, such as the display text of `\href{url}{text}`, are translated.
+/// Returns the new string and all the replaced strings.
+pub fn protect_paths(latex: &str, mut originals: Vec<String>) -> (String, Vec<String>) {
+    let mut result = String::new();
+    let mut rest = latex;
+    while let Some(pos) = rest.find('\\') {
+        let after = &rest[pos + 1..];
+        let name_len = after
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(after.len());
+        let name = &after[..name_len];
+        let tail = PATH_COMMANDS
+            .contains(&name)
+            .then(|| command_with_options(name)(&rest[pos..]).ok())
+            .flatten()
+            .map(|(tail, _)| tail);
+        let Some(tail) = tail else {
+            // skip the command name, or the escaped character
+            let skip = pos
+                + 1
+                + after
+                    .chars()
+                    .next()
+                    .map_or(0, |c| c.len_utf8())
+                    .max(name_len);
+            result.push_str(&rest[..skip]);
+            rest = &rest[skip..];
+            continue;
+        };
+        let end = rest.len() - tail.len();
+        result.push_str(&rest[..pos]);
+        result.push_str(&placeholder(originals.len()));
+        originals.push(rest[pos..end].to_string());
+        rest = tail;
+    }
+    result.push_str(rest);
+    (result, originals)
+}
+
+/// Replace the lines following the `IGNORE_NEXT_LINE` markers, with the markers,
+/// by placeholders numbered after the `originals` of `protect_commands`.
+/// Returns the new string and all the replaced strings.
+pub fn protect_ignored_lines(latex: &str, mut originals: Vec<String>) -> (String, Vec<String>) {
+    let mut lines: Vec<String> = vec![];
+    let mut iter = latex.split('\n');
+    while let Some(line) = iter.next() {
+        if line.trim() != IGNORE_NEXT_LINE {
+            lines.push(line.to_string());
+            continue;
+        }
+        let original = match iter.next() {
+            Some(next) => format!("{}\n{}", line, next),
+            None => line.to_string(),
+        };
+        lines.push(placeholder(originals.len()));
+        originals.push(original);
+    }
+    (lines.join("\n"), originals)
+}
+
+/// Replace the `%` comments by placeholders, numbered after the `originals`
+/// of `protect_commands`. The escaped characters `\%` are kept.
+/// Returns the new string and all the replaced strings.
+pub fn protect_comments(latex: &str, mut originals: Vec<String>) -> (String, Vec<String>) {
+    let mut result = String::new();
+    for (i, line) in latex.split('\n').enumerate() {
+        if i > 0 {
+            result.push('\n');
+        }
+        let mut escaped = false;
+        let start = line.char_indices().find_map(|(i, c)| match c {
+            _ if escaped => {
+                escaped = false;
+                None
+            }
+            '\\' => {
+                escaped = true;
+                None
+            }
+            '%' => Some(i),
+            _ => None,
+        });
+        match start {
+            Some(start) => {
+                result.push_str(&line[..start]);
+                result.push_str(&placeholder(originals.len()));
+                originals.push(line[start..].to_string());
+            }
+            None => result.push_str(line),
+        }
+    }
+    (result, originals)
+}
+
+/// Put back the commands replaced by `protect_commands`
+pub fn restore_commands(latex: &str, originals: &[String]) -> String {
+    let mut latex = latex.to_string();
+    // the last placeholders first: a comment replaced by a placeholder
+    // may contain the placeholders of the commands
+    for (n, original) in originals.iter().enumerate().rev() {
+        let ph = placeholder(n);
+        if !latex.contains(ph.as_str()) {
+            warn!("{} lost in translation", original);
+        }
+        latex = latex.replace(ph.as_str(), original);
+    }
+    latex
+}
+
+/// Environments whose content must never be translated
+const VERBATIM_ENVS: [&str; 4] = ["lstlisting", "verbatim", "minted", "Verbatim"];
+
+/// Check that the `begin` and `end` markers of the ignored regions alternate, line by line,
+/// and return an error giving the line numbers of the unbalanced or nested markers.
+/// The first line of `body` is the line `first_line` of the file.
+/// The sources written in comments by the bilingual output are skipped.
+fn check_ignore_markers(
+    body: &str,
+    begin: &str,
+    end: &str,
+    first_line: usize,
+) -> Result<(), String> {
+    let mut open: Option<usize> = None;
+    let mut in_source = false;
+    for (i, line) in body.lines().enumerate() {
+        let num = first_line + i;
+        match line.trim() {
+            SOURCE_BEGIN => in_source = true,
+            SOURCE_END => in_source = false,
+            _ => {}
+        }
+        if in_source {
+            continue;
+        }
+        let mut markers: Vec<(usize, bool)> = line
+            .match_indices(begin)
+            .map(|(pos, _)| (pos, true))
+            .chain(line.match_indices(end).map(|(pos, _)| (pos, false)))
+            .collect();
+        markers.sort();
+        for (_, is_begin) in markers {
+            match (is_begin, open) {
+                (true, None) => open = Some(num),
+                (true, Some(opened)) => {
+                    return Err(format!(
+                        "Nested {} at line {}: the region opened at line {} is not closed",
+                        begin, num, opened
+                    ))
+                }
+                (false, Some(_)) => open = None,
+                (false, None) => {
+                    return Err(format!(
+                        "Unbalanced {} at line {}: no {} before",
+                        end, num, begin
+                    ))
+                }
+            }
+        }
+    }
+    match open {
+        Some(opened) => Err(format!(
+            "Unbalanced {} at line {}: no {} after",
+            begin, opened, end
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Remove the split lines found between the `begin` and `end` markers of the ignored regions.
+/// The unbalanced markers are left for `extract_chunks`, which reports them.
+fn remove_splits_in_regions(latex: &str, begin: &str, end: &str, split_line: &str) -> String {
+    let mut result = String::new();
+    let mut rest = latex;
+    while let Some(ib) = rest.find(begin) {
+        let Some(ie) = rest[ib..].find(end).map(|i| ib + i) else {
+            break;
+        };
+        result.push_str(&rest[..ib]);
+        result.push_str(&rest[ib..ie].replace(split_line, ""));
+        rest = &rest[ie..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Return the first verbatim-like environment found in the chunk, if any
+// simple pattern matching: the parser may fail on verbatim content
+fn find_verbatim_env(chunk: &str) -> Option<&'static str> {
+    VERBATIM_ENVS
+        .iter()
+        .find(|env| chunk.contains(format!("\\begin{{{}}}", env).as_str()))
+        .copied()
+}
+
+/// Length of the verbatim-like environment at the beginning of `latex`, if any
+fn verbatim_env_len(latex: &str) -> Option<usize> {
+    VERBATIM_ENVS.iter().find_map(|env| {
+        if !latex.starts_with(format!("\\begin{{{}}}", env).as_str()) {
+            return None;
+        }
+        let end = format!("\\end{{{}}}", env);
+        latex.find(end.as_str()).map(|i| i + end.len())
+    })
+}
+
+/// Length of the command `\verb|code|` (or `\verb*|code|`) at the beginning of `latex`:
+/// the delimiter is the character following `\verb`
+fn verb_len(latex: &str) -> Option<usize> {
+    let after = latex.strip_prefix("\\verb")?;
+    let star = usize::from(after.starts_with('*'));
+    let delim = after[star..]
+        .chars()
+        .next()
+        .filter(|c| !c.is_alphabetic() && !c.is_whitespace())?;
+    let start = "\\verb".len() + star + delim.len_utf8();
+    latex[start..]
+        .find(delim)
+        .map(|i| start + i + delim.len_utf8())
+}
+
+/// Check if the chunk consists entirely of a single verbatim-like environment
+fn is_verbatim_chunk(chunk: &str) -> bool {
+    let chunk = chunk.trim();
+    VERBATIM_ENVS.iter().any(|env| {
+        let begin = format!("\\begin{{{}}}", env);
+        let end = format!("\\end{{{}}}", env);
+        chunk.starts_with(begin.as_str())
+            && chunk.ends_with(end.as_str())
+            && chunk.matches(end.as_str()).count() == 1
+    })
+}
+
+/// Check if the chunk contains mathematics and no text outside of the mathematics:
+/// display math `\[...\]`, `$$...$$`, inline math `$...$` and math environments.
+// the parser ltxprs does not distinguish the text inside and outside the math
+fn is_pure_math(chunk: &str) -> bool {
+    match remove_math(chunk) {
+        Some((text, found)) => found && count_words(&text) == 0,
+        None => false,
+    }
+}
+
+/// Commands whose argument is text inside the mathematics
+const MATH_TEXT_COMMANDS: [&str; 4] = ["text", "mbox", "intertext", "textrm"];
+
+/// The arguments of the text commands of a mathematical formula, each followed by a space
+fn math_text(math: &str) -> String {
+    let mut text = String::new();
+    for cmd in MATH_TEXT_COMMANDS {
+        let head = format!("\\{}{{", cmd);
+        let mut from = 0;
+        while let Some(ib) = math[from..].find(head.as_str()).map(|i| from + i) {
+            let open = ib + head.len() - 1;
+            let Some(close) = matching_brace(math, open) else {
+                break;
+            };
+            text.push_str(&math[open + 1..close]);
+            text.push(' ');
+            from = close;
+        }
+    }
+    text
+}
+
+/// Remove the mathematics of a LaTeX string, except the arguments of the text commands
+/// (`\text{...}`, `\mbox{...}`...). Also return whether some mathematics
+/// has been found, or `None` if a math delimiter is not closed.
+fn remove_math(latex: &str) -> Option<(String, bool)> {
+    let mut text = latex.to_string();
+    let mut found = false;
+    let mut delimiters: Vec<(String, String)> = MATH_ENVS
+        .iter()
+        .flat_map(|env| [env.to_string(), format!("{}*", env)])
+        .map(|env| (format!("\\begin{{{}}}", env), format!("\\end{{{}}}", env)))
+        .collect();
+    delimiters.push(("\\[".to_string(), "\\]".to_string()));
+    delimiters.push(("$$".to_string(), "$$".to_string()));
+    for (begin, end) in delimiters.iter() {
+        while let Some(ib) = text.find(begin.as_str()) {
+            let ie = text[ib + begin.len()..].find(end.as_str())?;
+            let math = format!(
+                " {}",
+                math_text(&text[ib + begin.len()..ib + begin.len() + ie])
+            );
+            text.replace_range(ib..ib + begin.len() + ie + end.len(), &math);
+            found = true;
+        }
+    }
+    // inline math, ignoring \$
+    let mut stripped = String::new();
+    let mut math = String::new();
+    let mut in_math = false;
+    let mut prev = ' ';
+    for c in text.chars() {
+        if c == '$' && prev != '\\' {
+            if in_math {
+                stripped.push_str(&math_text(&math));
+                math.clear();
+            }
+            in_math = !in_math;
+            found = true;
+        } else if in_math {
+            math.push(c);
+        } else {
+            stripped.push(c);
+        }
+        prev = c;
+    }
+    (!in_math).then_some((stripped, found))
+}
+
+/// Parser of `\name[options]{argument}`, giving the options, if any, and the argument
+fn command_with_options<'a>(
+    name: &'a str,
+) -> impl FnMut(&'a str) -> IResult<&'a str, (Option<&'a str>, &'a str)> {
+    preceded(
+        pair(char('\\'), tag(name)),
+        pair(
+            opt(delimited(char('['), take_until("]"), char(']'))),
+            delimited(char('{'), take_until("}"), char('}')),
+        ),
+    )
+}
+
+/// Replace the language names `sources` by `target` in a comma-separated list of options,
+/// such as `english,french` or `main=french`. The other options are kept.
+fn replace_lang_option(options: &str, sources: &[&str], target: &str) -> String {
+    options
+        .split(',')
+        .map(|option| {
+            let value = option.rsplit('=').next().unwrap_or(option);
+            if let Some(source) = sources.iter().find(|s| value.trim() == **s) {
+                let start = option.len() - value.len() + value.find(source).unwrap_or(0);
+                format!(
+                    "{}{}{}",
+                    &option[..start],
+                    target,
+                    &option[start + source.len()..]
+                )
+            } else {
+                option.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Remove the repeated languages of a list such as `english,french,english`,
+/// which appear when the target language was already a secondary language
+fn dedup_lang_list(list: &str) -> String {
+    let mut seen = vec![];
+    let langs: Vec<&str> = list
+        .split(',')
+        .filter(|l| {
+            let new = !seen.contains(&l.trim());
+            seen.push(l.trim());
+            new
+        })
+        .collect();
+    langs.join(",")
+}
+
+/// Commands declaring the languages of the document, with `true` if the languages
+/// are in the options (babel, or global options of the class) and `false` if they
+/// are in the argument (polyglossia)
+const LANG_COMMANDS: [(&str, bool); 6] = [
+    ("documentclass", true),
+    ("usepackage", true),
+    ("setdefaultlanguage", false),
+    ("setmainlanguage", false),
+    ("setotherlanguages", false),
+    ("setotherlanguage", false),
+];
+
+/// If the babel latex option is detected, replace the source
+/// language in the babel option by the target language (see `Language::babel`
+/// and `Language::polyglossia`). With polyglossia, the options of the replaced
+/// language are removed and the font encoding is not changed.
+/// The packages of the CJK and right-to-left languages are added if needed.
+/// Only the options of `\documentclass` and `\usepackage` and the arguments of
+/// the polyglossia commands are changed, not the macro names or the comments.
+pub fn adjust_preamble_lang(
+    preamble: String,
+    inlang: &str,
+    outlang: &str,
+) -> Result<String, TrsltxError> {
+    let source = language(inlang)?;
+    let target = language(outlang)?;
+    let mut adjusted = String::new();
+    let mut rest = preamble.as_str();
+    while let Some(c) = rest.chars().next() {
+        if c == '%' {
+            let end = rest.find('\n').unwrap_or(rest.len());
+            adjusted.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+        if c == '\\' {
+            let mut found = false;
+            for (name, in_options) in LANG_COMMANDS {
+                let Ok((tail, (options, arg))) = command_with_options(name)(rest) else {
+                    continue;
+                };
+                let (options, arg) = if in_options {
+                    let replaced =
+                        options.map(|o| replace_lang_option(o, source.babel, target.babel[0]));
+                    // the languages are the options of babel and the global options
+                    // of the class, the options of the other packages are kept
+                    let options = if name == "usepackage"
+                        && !arg.split(',').any(|p| p.trim() == "babel")
+                    {
+                        if replaced.as_deref() != options {
+                            warn!(
+                                "the options of \\usepackage{{{}}} contain the source language, they are not changed",
+                                arg
+                            );
+                        }
+                        options.map(|o| o.to_string())
+                    } else {
+                        replaced
+                    };
+                    (options, arg.to_string())
+                } else {
+                    let sources = [source.polyglossia];
+                    let replaced = replace_lang_option(arg, &sources, target.polyglossia);
+                    // the options (e.g. variant=british) are those of the source language
+                    let options = if replaced != arg {
+                        if let Some(o) = options {
+                            warn!("remove the options [{}] of \\{}{{{}}}", o, name, arg);
+                        }
+                        None
+                    } else {
+                        options.map(|o| o.to_string())
+                    };
+                    (options, dedup_lang_list(&replaced))
+                };
+                adjusted.push_str(&format!("\\{}", name));
+                if let Some(options) = options {
+                    adjusted.push_str(&format!("[{}]", options));
+                }
+                adjusted.push_str(&format!("{{{}}}", arg));
+                rest = tail;
+                found = true;
+                break;
+            }
+            if found {
+                continue;
+            }
+            // skip the escaped character, for instance in \%
+            if let Some(e) = rest[1..].chars().next() {
+                adjusted.push('\\');
+                adjusted.push(e);
+                rest = &rest[1 + e.len_utf8()..];
+                continue;
+            }
+        }
+        adjusted.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    let mut preamble = adjusted;
+    // with polyglossia (XeLaTeX or LuaLaTeX), the fonts are in Unicode
+    if target.cyrillic && !preamble.contains("{polyglossia}") {
+        // if \usepackage[T1]{fontenc} is not present in the preamble
+        // issue a warning
+        if !preamble.contains("\\usepackage[T1]{fontenc}") {
+            warn!(r#"\\usepackage[T1]{{fontenc}} is not present in the preamble"#);
+            warn!(
+                r#"The {} language requires \\usepackage[T2A]{{fontenc}}"#,
+                target.name
+            );
+            warn!(r#"Add \\usepackage[T2A]{{fontenc}} to the preamble"#);
+        }
+        preamble = preamble.replace(
+            r#"\usepackage[T1]{fontenc}"#,
+            r#"\usepackage[T2A]{fontenc}"#,
+        );
+    }
+    Ok(add_script_packages(preamble, target))
+}
+
+/// Packages needed by the targets written with CJK characters or from right to left,
+/// and the packages which make them unnecessary
+const SCRIPT_PACKAGES: [(&str, &str, &[&str]); 5] = [
+    ("zh", "ctex", &["ctex", "xeCJK", "CJK", "CJKutf8"]),
+    ("ja", "xeCJK", &["xeCJK", "luatexja", "CJK", "CJKutf8"]),
+    ("ko", "kotex", &["kotex", "xeCJK", "CJK", "CJKutf8"]),
+    // polyglossia loads bidi with XeLaTeX
+    ("ar", "bidi", &["bidi", "polyglossia", "arabtex"]),
+    ("he", "bidi", &["bidi", "polyglossia"]),
+];
+
+/// Add the package needed by the script of the target language, if it is absent:
+/// the CJK packages after `\documentclass`, `bidi` at the end of the preamble
+/// since it must be loaded last. The recommended engine is XeLaTeX.
+fn add_script_packages(preamble: String, target: &Language) -> String {
+    let Some((_, package, present)) = SCRIPT_PACKAGES.iter().find(|(l, _, _)| *l == target.code)
+    else {
+        return preamble;
+    };
+    warn!(
+        "the {} translation should be compiled with XeLaTeX (or LuaLaTeX)",
+        target.name
+    );
+    if present
+        .iter()
+        .any(|p| find_uncommented(&preamble, &format!("{{{}}}", p)).is_some())
+    {
+        return preamble;
+    }
+    warn!("add \\usepackage{{{}}} to the preamble", package);
+    let line = format!("\\usepackage{{{}}}\n", package);
+    let class_end = find_uncommented(&preamble, "\\documentclass").and_then(|pos| {
+        let (tail, _) = command_with_options("documentclass")(&preamble[pos..]).ok()?;
+        Some(preamble.len() - tail.len())
+    });
+    match class_end {
+        Some(end) if *package != "bidi" => {
+            let end = end + preamble[end..].find('\n').map_or(0, |i| i + 1);
+            let sep = if preamble[..end].ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            };
+            format!("{}{}{}{}", &preamble[..end], sep, line, &preamble[end..])
+        }
+        _ => {
+            let sep = if preamble.is_empty() || preamble.ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            };
+            format!("{}{}{}", preamble, sep, line)
+        }
+    }
+}
+
+/// Encodings of `\usepackage[...]{inputenc}` decoded as Windows-1252,
+/// which is a superset of Latin-1 for the printable characters
+const LATIN_ENCODINGS: [&str; 2] = ["latin1", "cp1252"];
+
+/// Encoding declared with `\usepackage[...]{inputenc}` in the first 2 KB of a file
+fn inputenc_option(bytes: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(2048)]);
+    head.match_indices("\\usepackage").find_map(|(pos, _)| {
+        match command_with_options("usepackage")(&head[pos..]) {
+            Ok((_, (Some(options), "inputenc"))) => Some(options.trim().to_string()),
+            _ => None,
+        }
+    })
+}
+
+/// Decode the content of a LaTeX file. The files declaring the `latin1` or `cp1252`
+/// encoding with `inputenc` are converted to UTF-8 and their declaration becomes
+/// `\usepackage[utf8]{inputenc}`. The other files must be encoded in UTF-8.
+pub fn decode_latex(bytes: &[u8]) -> Result<String, TrsltxError> {
+    match inputenc_option(bytes) {
+        Some(encoding) if LATIN_ENCODINGS.contains(&encoding.as_str()) => {
+            warn!(
+                "the file is encoded in {}, it is converted to UTF-8",
+                encoding
+            );
+            warn!("Consider converting the original file to UTF-8");
+            let (latex, _) = encoding_rs::WINDOWS_1252.decode_without_bom_handling(bytes);
+            Ok(latex.replace(
+                format!("\\usepackage[{}]{{inputenc}}", encoding).as_str(),
+                "\\usepackage[utf8]{inputenc}",
+            ))
+        }
+        _ => String::from_utf8(bytes.to_vec())
+            .map_err(|e| TrsltxError::Parse(format!("The file is not encoded in UTF-8: {:?}", e))),
+    }
+}
+
+/// Name of the file of the given language associated to the initial file:
+/// `dir/paper.tex` gives `dir/paper_fr.tex` for `fr`.
+/// Only the file name is changed, the directories are kept as they are.
+pub fn lang_file_name(init_file: &str, lang: &str) -> Result<String, TrsltxError> {
+    let path = std::path::Path::new(init_file);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .filter(|_| path.extension().is_some_and(|e| e == "tex"))
+        .ok_or_else(|| {
+            TrsltxError::Language(format!("The file {} is not a .tex file", init_file))
+        })?;
+    Ok(path
+        .with_file_name(format!("{}_{}.tex", stem, lang))
+        .to_string_lossy()
+        .to_string())
+}
+
+/// Split a file name `dir/base_xy.tex` into its base `dir/base` and its language `xy`.
+/// The language is after the last underscore, so that the base may contain underscores.
+pub fn split_lang_file_name(file: &str) -> Result<(String, String), TrsltxError> {
+    let path = std::path::Path::new(file);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .filter(|_| path.extension().is_some_and(|e| e == "tex"))
+        .ok_or_else(|| TrsltxError::Language(format!("The file {} is not a .tex file", file)))?;
+    let (base, lang) = stem
+        .rsplit_once('_')
+        .filter(|(base, lang)| !base.is_empty() && !lang.is_empty())
+        .ok_or_else(|| {
+            TrsltxError::Language(format!(
+                "The file name {} does not end with _xy.tex, where xy is a language",
+                file
+            ))
+        })?;
+    let base = path.with_file_name(base).to_string_lossy().to_string();
+    Ok((base, lang.to_string()))
+}
+
+/// Position of the first occurrence of `pattern` which is not in a comment
+fn find_uncommented(latex: &str, pattern: &str) -> Option<usize> {
+    latex.match_indices(pattern).map(|(i, _)| i).find(|&i| {
+        let line_start = latex[..i].rfind('\n').map_or(0, |j| j + 1);
+        let mut prev = ' ';
+        !latex[line_start..i].chars().any(|c| {
+            let comment = c == '%' && prev != '\\';
+            prev = c;
+            comment
+        })
+    })
+}
+
+/// Get the long language name from the short two-letter one
+pub fn get_lang_name(lang: &str) -> Result<String, TrsltxError> {
+    Ok(language(lang)?.name.to_string())
+}
+
+/// Language of a code, see the module `languages`
+fn language(lang: &str) -> Result<&'static Language, TrsltxError> {
+    find_language(lang).ok_or_else(|| {
+        TrsltxError::Language(format!(
+            "The supported languages are: {}. Unsupported language: {}",
+            languages::language_codes(),
+            lang
+        ))
+    })
+}
+
+/// Extract the names of the environments `\begin{name}` found in a LaTeX string.
+/// The names are sorted and deduplicated so that the generated grammar is deterministic.
+// this is a scan of the raw text: the parser ltxprs does not
+// provide environment nodes yet
+pub fn extracts_environments(latex: &str) -> Vec<String> {
+    let mut envs: Vec<String> = latex
+        .split("\\begin{")
+        .skip(1)
+        .filter_map(|s| s.split_once('}').map(|(name, _)| name.trim().to_string()))
+        .filter(|name| !name.is_empty() && !name.contains('\n'))
+        .collect();
+    envs.sort();
+    envs.dedup();
+    envs
+}
+
+/// Extract the names of the environments defined by `\newtheorem{name}{Title}`
+/// or `\newtheorem*{name}{Title}` in the preamble, sorted and deduplicated.
+/// The names of `\newtheoremstyle` are styles, not environments, and are ignored.
+pub fn extract_newtheorem_names(preamble: &str) -> Vec<String> {
+    let mut names: Vec<String> = ["newtheorem", "newtheorem*"]
+        .iter()
+        .flat_map(|cmd| command_args(preamble, cmd))
+        .map(|(_, name)| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Complete a grammar generated by `LtxNode::to_ebnf` with the constructs
+/// of the LaTeX chunk that the parser ltxprs does not protect:
+/// environments, URLs, `\eqref`, `\autoref`, `\cref` and footnotes
+pub fn complete_ebnf(grammar: &str, latex: &str) -> String {
+    let grammar = add_environments_to_ebnf(grammar, &extracts_environments(latex));
+    let grammar = add_protected_commands_to_ebnf(&grammar, latex);
+    add_footnotes_to_ebnf(&grammar, latex)
+}
+
+/// Add an `environment` production to a grammar generated by `LtxNode::to_ebnf`.
+/// Each environment of the original chunk becomes an alternative
+/// `"\\begin{name}" stuff "\\end{name}"`, which discourages the LLM
+/// from renaming environments or dropping the closing `\end{name}`.
+/// If the production already exists, the missing environments are added to it.
+/// The grammar is returned unchanged if it has no `construct` production.
+pub fn add_environments_to_ebnf(grammar: &str, envs: &[String]) -> String {
+    if envs.is_empty() || !grammar.lines().any(|l| l.starts_with("construct ::=")) {
+        return grammar.to_string();
+    }
+    let existing: Vec<String> = grammar
+        .lines()
+        .find_map(|l| l.strip_prefix("environment ::="))
+        .map(split_alternatives)
+        .unwrap_or_default();
+    let alternatives: Vec<String> = envs
+        .iter()
+        .map(|e| {
+            format!(
+                "{} stuff {}",
+                ebnf_quote(format!("\\begin{{{}}}", e).as_str()),
+                ebnf_quote(format!("\\end{{{}}}", e).as_str())
+            )
+        })
+        .filter(|a| !existing.contains(a))
+        .collect();
+    if alternatives.is_empty() {
+        grammar.to_string()
+    } else if !existing.is_empty() {
+        add_alternatives_to_ebnf(grammar, "environment", &alternatives)
+    } else {
+        let grammar = add_alternatives_to_ebnf(grammar, "construct", &["environment".to_string()]);
+        format!(
+            "{}\n\nenvironment ::= {}",
+            grammar,
+            alternatives.join(" | ")
+        )
+    }
+}
+
+/// Commands whose optional argument and first argument must be copied literally by the LLM,
+/// a selection of the `KEY_ARG_COMMANDS`.
+/// The command with its arguments becomes a quoted terminal of the grammar.
+const PROTECTED_COMMANDS: [&str; 8] = [
+    "url",
+    "href",
+    "eqref",
+    "autoref",
+    "pageref",
+    "cref",
+    "Cref",
+    "includegraphics",
+];
+
+/// Find the commands `\name[options]{arg}` in a LaTeX string, the optional argument
+/// being optional, and return them as written in the string
+// the parser ltxprs does not give the arguments of the commands
+fn command_calls(latex: &str, name: &str) -> Vec<String> {
+    let head = format!("\\{}", name);
+    latex
+        .match_indices(head.as_str())
+        .filter_map(|(pos, _)| {
+            let rest = &latex[pos..];
+            let (tail, _) = command_with_options(name)(rest).ok()?;
+            Some(rest[..rest.len() - tail.len()].to_string())
+        })
+        .collect()
+}
+
+/// Find the first arguments of the command `\name{arg}` in a LaTeX string,
+/// with their positions
+fn command_args(latex: &str, name: &str) -> Vec<(usize, String)> {
+    let head = format!("\\{}{{", name);
+    latex
+        .match_indices(head.as_str())
+        .filter_map(|(pos, _)| {
+            latex[pos + head.len()..]
+                .split_once('}')
+                .map(|(arg, _)| (pos, arg.to_string()))
+        })
+        .collect()
+}
+
+/// Extract the arguments of the given commands, in order of appearance
+fn extracts_args(latex: &str, names: &[&str]) -> Vec<String> {
+    let mut args: Vec<(usize, String)> = names
+        .iter()
+        .flat_map(|name| command_args(latex, name))
+        .collect();
+    args.sort();
+    args.into_iter().map(|(_, arg)| arg).collect()
+}
+
+/// Extract the keys of the `\label{key}` commands found in a LaTeX string,
+/// in order of appearance
+pub fn extracts_labels(latex: &str) -> Vec<String> {
+    extracts_args(latex, &["label"])
+}
+
+/// Extract the targets of the `\url{target}` and `\href{target}{display}` commands
+/// found in a LaTeX string, in order of appearance
+pub fn extracts_urls(latex: &str) -> Vec<String> {
+    extracts_args(latex, &["url", "href"])
+}
+
+/// Kind of a reference to a label
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefKind {
+    /// `\ref{key}`
+    Ref,
+    /// `\eqref{key}` of the amsmath package
+    Eqref,
+    /// `\autoref{key}` of the hyperref package
+    Autoref,
+    /// `\pageref{key}`
+    Pageref,
+    /// `\cref{key1,key2}` of the cleveref package
+    Cref,
+    /// `\Cref{key1,key2}` of the cleveref package, at the beginning of a sentence
+    CrefCapital,
+}
+
+impl RefKind {
+    pub const ALL: [RefKind; 6] = [
+        RefKind::Ref,
+        RefKind::Eqref,
+        RefKind::Autoref,
+        RefKind::Pageref,
+        RefKind::Cref,
+        RefKind::CrefCapital,
+    ];
+
+    /// Name of the LaTeX command
+    pub fn command(&self) -> &'static str {
+        match self {
+            RefKind::Ref => "ref",
+            RefKind::Eqref => "eqref",
+            RefKind::Autoref => "autoref",
+            RefKind::Pageref => "pageref",
+            RefKind::Cref => "cref",
+            RefKind::CrefCapital => "Cref",
+        }
+    }
+}
+
+/// Extract the keys of the `\ref{key}`, `\eqref{key}`, `\autoref{key}`, `\pageref{key}`,
+/// `\cref{key}` and `\Cref{key}` commands found in a LaTeX string, in order of appearance.
+/// The lists of keys of cleveref (`\cref{key1,key2}`) are split.
+pub fn extracts_references(latex: &str) -> Vec<String> {
+    let names: Vec<&str> = RefKind::ALL.iter().map(|k| k.command()).collect();
+    split_keys(extracts_args(latex, &names))
+}
+
+/// Extract the keys of the references of the given kind, in order of appearance
+pub fn extracts_references_by_kind(latex: &str, kind: RefKind) -> Vec<String> {
+    split_keys(extracts_args(latex, &[kind.command()]))
+}
+
+/// Split the comma-separated lists of keys
+fn split_keys(args: Vec<String>) -> Vec<String> {
+    args.iter()
+        .flat_map(|arg| arg.split(','))
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect()
+}
+
+/// Add the protected commands of the original chunk (URLs, `\eqref`, `\autoref`, `\cref`...)
+/// as quoted terminals of the `command` production, so that the LLM copies them literally.
+/// The display text of `\href{target}{display}` remains a translatable group.
+/// The optional arguments are part of the terminal, e.g. `\includegraphics[width=5cm]{file}`.
+pub fn add_protected_commands_to_ebnf(grammar: &str, latex: &str) -> String {
+    let mut terminals: Vec<String> = PROTECTED_COMMANDS
+        .iter()
+        .flat_map(|name| command_calls(latex, name))
+        .map(|call| ebnf_quote(&call))
+        .collect();
+    terminals.sort();
+    terminals.dedup();
+    add_alternatives_to_ebnf(grammar, "command", &terminals)
+}
+
+/// Add a `footnote` production to a grammar generated by `LtxNode::to_ebnf`
+/// if the chunk contains footnotes.
+/// The content of the footnote is free `stuff` (it has to be translated),
+/// but the grammar enforces the braces around it.
+/// The optional marks `\footnote[mark]{...}` of the chunk are kept literally.
+pub fn add_footnotes_to_ebnf(grammar: &str, latex: &str) -> String {
+    if !latex.contains("\\footnote") || !grammar.lines().any(|l| l.starts_with("construct ::=")) {
+        return grammar.to_string();
+    }
+    let mut heads = vec![ebnf_quote("\\footnote{")];
+    for (pos, _) in latex.match_indices("\\footnote[") {
+        if let Some((mark, _)) = latex[pos + "\\footnote[".len()..].split_once(']') {
+            heads.push(ebnf_quote(format!("\\footnote[{}]{{", mark).as_str()));
+        }
+    }
+    heads.sort();
+    heads.dedup();
+    let alternatives: Vec<String> = heads
+        .iter()
+        .map(|h| format!(r#"{} stuff "}}""#, h))
+        .collect();
+    let grammar = add_alternatives_to_ebnf(grammar, "construct", &["footnote".to_string()]);
+    format!("{}\n\nfootnote ::= {}", grammar, alternatives.join(" | "))
+}
+
+/// Extract the citation keys of the entries of a .bib file
+/// (`@article{key,`, `@book{key,`...).
+/// The `@string`, `@preamble` and `@comment` entries are ignored.
+pub fn extract_bib_keys(bib_content: &str) -> Vec<String> {
+    let mut keys = vec![];
+    for entry in bib_content.split('@').skip(1) {
+        let Some(open) = entry.find(['{', '(']) else {
+            continue;
+        };
+        let kind = entry[..open].trim().to_lowercase();
+        if kind.is_empty()
+            || !kind.chars().all(|c| c.is_ascii_alphabetic())
+            || ["string", "preamble", "comment"].contains(&kind.as_str())
+        {
+            continue;
+        }
+        if let Some((key, _)) = entry[open + 1..].split_once(',') {
+            let key = key.trim();
+            if !key.is_empty() && !key.contains(char::is_whitespace) {
+                keys.push(key.to_string());
+            }
+        }
+    }
+    keys
+}
+
+/// Citation commands of biblatex whose name does not start with `cite`
+const BIBLATEX_CITE_COMMANDS: [&str; 8] = [
+    "parencite",
+    "textcite",
+    "autocite",
+    "footcite",
+    "footcitetext",
+    "smartcite",
+    "supercite",
+    "fullcite",
+];
+
+/// True if `\name` is a citation command: `\cite`, the natbib commands
+/// (`\citep`, `\citet`, `\citealt`...) and the biblatex commands (`\parencite`,
+/// `\textcite`, `\autocite`...), with their capitalized and starred forms.
+/// `\nocite` is not a citation: it is copied verbatim.
+pub fn is_citation_command(name: &str) -> bool {
+    let name = name.trim_end_matches('*');
+    let mut chars = name.chars();
+    let lower = match chars.next() {
+        Some(c) => c.to_ascii_lowercase().to_string() + chars.as_str(),
+        None => return false,
+    };
+    lower.starts_with("cite") || BIBLATEX_CITE_COMMANDS.contains(&lower.as_str())
+}
+
+/// Extract the citations of a LaTeX string as they are written: the commands
+/// given by `is_citation_command`, with their optional arguments and their keys,
+/// e.g. `\cite[p. 3]{key}` or `\parencite[see][12]{key}`.
+/// The citations are returned in order of appearance, without duplicates.
+/// The biblatex multicite commands (`\parencites{a}{b}`) are not recognized.
+// this is a scan of the raw text: the parser ltxprs splits the citations
+// into a command followed by groups
+pub fn extracts_citations(latex: &str) -> Vec<String> {
+    let mut citations: Vec<String> = vec![];
+    for (pos, _) in latex.match_indices('\\') {
+        let rest = &latex[pos + 1..];
+        let name_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic() && c != '*')
+            .unwrap_or(rest.len());
+        if !is_citation_command(&rest[..name_len]) {
+            continue;
+        }
+        let mut end = pos + 1 + name_len;
+        // optional arguments [...]
+        while latex[end..].trim_start().starts_with('[') {
+            let open = end + latex[end..].find('[').unwrap_or(0);
+            match latex[open..].find(']') {
+                Some(close) => end = open + close + 1,
+                None => break,
+            }
+        }
+        let Some(open) = latex[end..]
+            .find(|c: char| !c.is_whitespace())
+            .map(|i| end + i)
+            .filter(|&i| latex[i..].starts_with('{'))
+        else {
+            continue;
+        };
+        if let Some(close) = matching_brace(latex, open) {
+            let citation = latex[pos..=close].to_string();
+            if !citations.contains(&citation) {
+                citations.push(citation);
+            }
+        }
+    }
+    citations
+}
+
+/// Keys of the citations of a LaTeX string (`\cite{a,b}` gives `a` and `b`),
+/// in order of appearance, without duplicates
+pub fn extracts_citation_keys(latex: &str) -> Vec<String> {
+    let mut keys: Vec<String> = vec![];
+    for citation in extracts_citations(latex) {
+        let Some(open) = citation.rfind('{') else {
+            continue;
+        };
+        for key in citation[open + 1..citation.len() - 1].split(',') {
+            let key = key.trim().to_string();
+            if !key.is_empty() && !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    keys
+}
+
+/// Add the citations of the chunk, and its keys found in the bibliography, as quoted
+/// terminals `"\\cite{key}"` of the `command` production.
+/// The keys of the bibliography not cited by the chunk are not added:
+/// the grammar would exceed its maximal size with a whole bibliography.
+pub fn add_citations_to_ebnf(grammar: &str, latex: &str, bib_keys: &[String]) -> String {
+    let cited_bib_keys = extracts_citation_keys(latex)
+        .into_iter()
+        .filter(|key| bib_keys.contains(key))
+        .map(|key| format!("\\cite{{{}}}", key));
+    let mut terminals: Vec<String> = extracts_citations(latex)
+        .into_iter()
+        .chain(cited_bib_keys)
+        .map(|citation| ebnf_quote(citation.as_str()))
+        .collect();
+    terminals.sort();
+    terminals.dedup();
+    add_alternatives_to_ebnf(grammar, "command", &terminals)
+}
+
+/// Maximal depth of the nested subfiles
+pub const MAX_SUBFILE_DEPTH: usize = 5;
+
+/// Default maximal length of the grammar sent to the server
+pub const MAX_GRAMMAR_SIZE: usize = 8192;
+
+/// Default maximal length in bytes of a chunk sent to the translator
+pub const MAX_CHUNK_LENGTH: usize = 4000;
+
+/// Default approximate length in characters of the chunks (`-l` option)
+pub const DEFAULT_SPLIT_LENGTH: usize = 1000;
+
+/// Default average number of bytes per token, for the European languages
+pub const CHARS_PER_TOKEN: f32 = 3.0;
+
+/// Estimated number of tokens of a text, from the average number of bytes per token.
+/// This is a heuristic: the tokenizers of the models are not available.
+pub fn estimate_tokens(text: &str, chars_per_token: f32) -> usize {
+    (text.len() as f32 / chars_per_token).ceil() as usize
+}
+
+/// Commands kept in priority when a grammar is truncated
+/// (`cite` also matches the natbib and biblatex citations)
+const HIGH_VALUE_COMMANDS: [&str; 7] = [
+    "\\\\label",
+    "\\\\ref",
+    "cite",
+    "\\\\eqref",
+    "\\\\autoref",
+    "\\\\cref",
+    "\\\\Cref",
+];
+
+/// Reduce the `command` production of a grammar until the grammar is not longer than `max_len`.
+/// The labels, references and citations are kept, the other commands are removed
+/// starting from the last ones.
+/// Returns the grammar and a flag which is true if some commands have been removed.
+/// The grammar may still be too long if the labels, references and citations
+/// are too numerous.
+pub fn bound_ebnf(grammar: &str, max_len: usize) -> (String, bool) {
+    if grammar.len() <= max_len {
+        return (grammar.to_string(), false);
+    }
+    let mut lines: Vec<String> = grammar.lines().map(|l| l.to_string()).collect();
+    let Some(icmd) = lines.iter().position(|l| l.starts_with("command ::=")) else {
+        return (grammar.to_string(), false);
+    };
+    let rhs = lines[icmd]["command ::=".len()..].to_string();
+    let alternatives = split_alternatives(rhs.as_str());
+    let (kept, mut others): (Vec<String>, Vec<String>) = alternatives
+        .into_iter()
+        .partition(|a| HIGH_VALUE_COMMANDS.iter().any(|c| a.contains(c)));
+    let mut truncated = false;
+    loop {
+        let all: Vec<String> = others.iter().chain(kept.iter()).cloned().collect();
+        lines[icmd] = format!("command ::= {}", all.join(" | "));
+        // the production must keep at least one alternative
+        let last = others.is_empty() || (kept.is_empty() && others.len() == 1);
+        if last || lines.join("\n").len() <= max_len {
+            break;
+        }
+        others.pop();
+        truncated = true;
+    }
+    (lines.join("\n"), truncated)
+}
+
+/// Split the right-hand side of a grammar production at the `|` outside quotes
+fn split_alternatives(rhs: &str) -> Vec<String> {
+    let mut alternatives = vec![];
+    let mut current = String::new();
+    let mut in_quote = false;
+    let mut escaped = false;
+    for c in rhs.chars() {
+        match c {
+            '\\' if in_quote && !escaped => escaped = true,
+            '"' if !escaped => in_quote = !in_quote,
+            '|' if !in_quote => {
+                alternatives.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => escaped = false,
+        }
+        current.push(c);
+    }
+    alternatives.push(current.trim().to_string());
+    alternatives.retain(|a| !a.is_empty());
+    alternatives
+}
+
+/// Append alternatives to the production `rule` of an EBNF grammar.
+/// The grammar is returned unchanged if the production does not exist.
+fn add_alternatives_to_ebnf(grammar: &str, rule: &str, alternatives: &[String]) -> String {
+    if alternatives.is_empty() {
+        return grammar.to_string();
+    }
+    let head = format!("{} ::=", rule);
+    grammar
+        .lines()
+        .map(|l| {
+            if l.starts_with(head.as_str()) {
+                format!("{} | {}", l.trim_end(), alternatives.join(" | "))
+            } else {
+                l.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Quote a string as a terminal of an EBNF grammar
+fn ebnf_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// one chat operation with the textsynth LLM
+/// send the question
+/// and returns an answer
+#[allow(dead_code)]
+fn chat_with_ts(question: &str) -> Result<String, String> {
+    // get the api key from the file "api_key.txt" or if the file does not exist, from the environment variable "TEXTSYNTH_API_KEY"
+    let api_key = match std::fs::read_to_string("api_key.txt") {
+        // if the file exists, get the api key from the file
+        // removing the spaces and newlines with trim()
+        Ok(api_key) => api_key.trim().to_string(),
+        Err(_) => std::env::var("TEXTSYNTH_API_KEY").expect("You have to provide an api key in the file api_key.txt or by export TEXTSYNTH_API_KEY=api_key"),
+    };
+
+    // call the textsynth REST API
+    //let url = "https://api.textsynth.com/v1/engines/mixtral_47B_instruct/chat";
+    // also works well with the engine mistral_7B_instruct
+    let url = "https://api.textsynth.com/v1/engines/mistral_7B_instruct/chat";
+    let max_tokens = 2000;
+
+    use serde_json::json;
+    use serde_json::Value;
+
+    let req = json!({
+        "messages": [question],
+        "temperature": 0.5,
+        "max_tokens": max_tokens
+    });
+
+    let client = reqwest::blocking::Client::new();
+    let res = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", api_key))
+        .json(&req)
+        .send()
+        .expect("Failed to send request")
+        .json::<Value>();
+
+    let answer: String = match res {
+        Ok(resp) => {
+            let text = resp["text"]
+                .as_str()
+                .ok_or("The result of Textsynth does not contain text")?;
+            //println!("{:?}", text);
+            text.to_string()
+        }
+        Err(e) => {
+            error!("Request error: {:?}", e);
+            "".to_string()
+        }
+    };
+    Ok(answer)
+}
+
+/// one completion operation with the textsynth LLM
+/// send the question and a formal grammar (as Some(String) or None)
+/// and returns an answer, with the rate limit given by the headers of the answer.
+/// The request is sent again after a transient error, according to `retry`.
+fn complete_with_ts(
+    prompt: &str,
+    grammar: &Option<String>,
+    params: &CompletionParams,
+) -> Result<(String, RateLimitState), String> {
+    let (url, api_key, req) = ts_request(prompt, grammar, params)?;
+    info!("Translate with {}", params.model);
+    let client = reqwest::blocking::Client::new();
+    let request = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", api_key))
+        .json(&req);
+    let res = backend::send_with_backoff(request, &params.retry)?;
+    let rate_limit = RateLimitState::from_headers(res.headers());
+    Ok((ts_answer(res.json::<serde_json::Value>())?, rate_limit))
+}
+
+/// api key of the textsynth server: the given key, or the content of the file
+/// "api_key.txt", or if the file does not exist, the environment variable "TEXTSYNTH_API_KEY"
+pub(crate) fn ts_api_key(api_key: &Option<String>) -> Result<String, String> {
+    match api_key {
+        Some(api_key) => Ok(api_key.clone()),
+        None => match std::fs::read_to_string("api_key.txt") {
+            // if the file exists, get the api key from the file
+            // removing the spaces and newlines with trim()
+            Ok(api_key) => Ok(api_key.trim().to_string()),
+            Err(_) => std::env::var("TEXTSYNTH_API_KEY").map_err(|e| format!("You have to provide an api key in the file api_key.txt or by export TEXTSYNTH_API_KEY=api_key. Error: {:?}", e)),
+        },
+    }
+}
+
+/// URL, api key and JSON body of a completion request to the textsynth server
+fn ts_request(
+    prompt: &str,
+    grammar: &Option<String>,
+    params: &CompletionParams,
+) -> Result<(String, String, serde_json::Value), String> {
+    let api_key = ts_api_key(&params.api_key)?;
+
+    // call the textsynth REST API
+    // the models absent from the registry are Textsynth engine ids
+    let url = match ModelRegistry::default().find(&params.model) {
+        Some(entry) => entry.url.clone(),
+        None => ModelEntry::new(&params.model, &[], true, 0).url,
+    };
+
+    use serde_json::json;
+
+    let mut req = match grammar {
+        Some(gr) => {
+            json!({
+                "prompt": prompt,
+                "temperature": params.temperature,
+                "max_tokens": params.max_tokens,
+                "grammar": gr
+            })
+        }
+        None => {
+            // println!("No grammar");
+            json!({
+                "prompt": prompt,
+                "temperature": params.temperature,
+                "max_tokens": params.max_tokens
+            })
+        }
+    };
+    if let Some(top_p) = params.top_p {
+        req["top_p"] = json!(top_p);
+    }
+    //println!("Req= {:?}", req);
+    Ok((url, api_key, req))
+}
+
+/// Text of the answer of the textsynth server
+fn ts_answer(res: Result<serde_json::Value, reqwest::Error>) -> Result<String, String> {
+    debug!("{:?}", res);
+
+    let answer: String = match res {
+        Ok(resp) => {
+            //println!("{:?}", resp);
+            let text = resp["text"]
+                .as_str()
+                .ok_or("The result of Textsynth does not contain text")?;
+            //println!("{:?}", text);
+            text.to_string()
+        }
+        Err(e) => {
+            error!("Request error: {:?}", e);
+            "".to_string()
+        }
+    };
+
+    Ok(answer)
+}
+
+/// Default template of the prompt. The placeholders `<lang_in>` and `<lang_out>`
+/// are replaced by the names of the languages, `<glossary>` by the term translations
+/// (empty without glossary) and `<chunk>` by the LaTeX chunk.
+pub const PREPROMPT: &str = r#"
+Q: Translate the following <lang_in> scientific text, formatted with LateX, into <lang_out>.
+Keep the LateX syntax and formulas. The results must compile without errors with pdflatex.
+Give only the result without preliminaries. 
+Enclose the resulting LateX source between \begin{trsltx} and \end{trsltx}
+<glossary>Here is the <lang_in> LateX source:
+
+
+<chunk>
+A:
+"#;
+
+/// Fill the placeholders of a prompt template. The chunk is appended to the
+/// templates without `<chunk>`.
+pub fn fill_prompt(
+    template: &str,
+    lang_in: &str,
+    lang_out: &str,
+    glossary: &str,
+    chunk: &str,
+) -> String {
+    let prompt = template
+        .replace("<lang_in>", lang_in)
+        .replace("<lang_out>", lang_out)
+        .replace("<glossary>", glossary);
+    // the chunk is inserted last: its text is not searched for placeholders
+    match prompt.split_once("<chunk>") {
+        Some((before, after)) => format!("{}{}{}", before, chunk, after),
+        None => format!("{}\n{}\nA:\n", prompt, chunk),
+    }
+}
+
+/// translate a latex chunk using the textsynth LLM api
+/// the preprompt is in the file "prompt.txt"
+/// the api key is in the file "api_key.txt" or
+/// in the environment variable "TEXTSYNTH_API_KEY"
+/// the hint of the reviewer, if any, is added to the prompt
+fn translate_one_chunk(
+    chunk: &str,
+    trsltx: &Trsltx,
+    meta: &mut ChunkMeta,
+    max_tokens: usize,
+    hint: Option<&str>,
+) -> Result<String, String> {
+    debug!("Translating chunk: {:?}", chunk);
+    if trsltx.backend.is_translator() {
+        if hint.is_some() {
+            warn!("the hints are ignored by the translator");
+        }
+        return translate_without_prompt(chunk, trsltx, meta, max_tokens);
+    }
+    let Some(request) = ChunkRequest::new(chunk, trsltx, max_tokens, hint)? else {
+        debug!("Empty chunk");
+        // create a string containing \commandvide followed by a newline
+        let s = "\\commandevide\n".to_string();
+        return Ok(s);
+    };
+    let mut distmin = usize::MAX;
+    let mut iter = 0;
+    let mut trs_chunk = "".to_string();
+    let itermax = trsltx.retry_policy.max_attempts;
+    // the last attempt is without grammar
+    meta.used_grammar = request.grammar.is_some() && itermax > 1;
+    // at most itermax attempts to get a translation (four by default)
+    while distmin > 1 && iter < itermax {
+        meta.attempts += 1;
+        if iter > 0 {
+            trsltx.report(ProgressEvent::ChunkRetried {
+                index: meta.index,
+                attempt: meta.attempts as u32,
+            });
+        }
+        let answer = match trsltx.backend.complete(
+            request.question.as_str(),
+            request.grammar(iter, itermax),
+            &request.params,
+        ) {
+            Ok(answer) => answer,
+            // keep the translation of the previous attempts
+            Err(e) if iter > 0 => {
+                warn!(
+                    "attempt {} failed, keep the previous translation: {}",
+                    iter + 1,
+                    e
+                );
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let (trs_try, dist) = request.evaluate(&answer);
+        if dist < distmin {
+            distmin = dist;
+            trs_chunk = trs_try;
+        }
+        iter += 1;
+    }
+    if iter > 0 {
+        meta.distmin = Some(distmin);
+    }
+    let trs_chunk = trsltx.correct_glossary(chunk, trs_chunk, meta);
+    Ok(restore_commands(&trs_chunk, &request.originals))
+}
+
+/// translate a latex chunk with a backend which is not a LLM (DeepL),
+/// in one attempt and without grammar
+fn translate_without_prompt(
+    chunk: &str,
+    trsltx: &Trsltx,
+    meta: &mut ChunkMeta,
+    max_tokens: usize,
+) -> Result<String, String> {
+    if chunk.trim() == r#"\commandevide"# || chunk.trim() == "" {
+        debug!("Empty chunk");
+        return Ok(chunk.to_string());
+    }
+    let (protected, originals) = trsltx.protect_chunk(chunk);
+    let params = trsltx.completion_params(max_tokens);
+    meta.attempts += 1;
+    let trs_chunk =
+        trsltx
+            .backend
+            .translate(&protected, &trsltx.input_lang, &trsltx.output_lang, &params)?;
+    let trs_chunk = trsltx.correct_glossary(chunk, trs_chunk, meta);
+    let trs_chunk = restore_commands(&trs_chunk, &originals);
+    meta.distmin = Some(LtxNode::new(chunk).distance(&LtxNode::new(&trs_chunk)));
+    Ok(trs_chunk)
+}
+
+/// A chunk ready to be sent to the translator
+struct ChunkRequest {
+    /// prompt and chunk
+    question: String,
+    grammar: Option<String>,
+    /// syntax tree of the chunk, for evaluating the translations
+    ast_chunk: LtxNode,
+    /// commands replaced by placeholders
+    originals: Vec<String>,
+    params: CompletionParams,
+}
+
+impl ChunkRequest {
+    /// Prepare the prompt and the grammar of a chunk.
+    /// Returns None for an empty chunk, which is not sent to the translator.
+    fn new(
+        chunk: &str,
+        trsltx: &Trsltx,
+        max_tokens: usize,
+        hint: Option<&str>,
+    ) -> Result<Option<ChunkRequest>, String> {
+        if chunk.trim() == r#"\commandevide"# || chunk.trim() == "" {
+            return Ok(None);
+        }
+        // the preserved commands are replaced by placeholders during the translation
+        let (protected, originals) = trsltx.protect_chunk(chunk);
+        let chunk = protected.as_str();
+        let input_lang = get_lang_name(trsltx.input_lang.as_str())?;
+        let output_lang = get_lang_name(trsltx.output_lang.as_str())?;
+        let mut terms = if trsltx.glossary.is_empty() {
+            String::new()
+        } else {
+            glossary::glossary_prompt(&trsltx.glossary)
+        };
+        if let Some(hint) = hint {
+            terms.push_str(&format!("{}\n", hint.trim()));
+        }
+        let question = fill_prompt(
+            trsltx.prompt_template(),
+            &input_lang,
+            &output_lang,
+            &terms,
+            chunk,
+        );
+        let ast_chunk = LtxNode::new(chunk);
+        let grammar = match ast_chunk {
+            _ if !trsltx.use_grammar => None,
+            LtxNode::Problem(_) => {
+                warn!("the parser failed on the chunk, translate without grammar");
+                match find_unbalanced(chunk) {
+                    Some(offset) => warn!("{}", parse_error_report(chunk, offset)),
+                    None => {
+                        warn!("The braces and dollars are balanced: unsupported LaTeX construct?")
+                    }
+                }
+                None
+            }
+            _ if !trsltx.backend.supports_grammar() => None,
+            _ if trsltx
+                .model_entry
+                .as_ref()
+                .is_some_and(|e| !e.supports_grammar) =>
+            {
+                None
+            }
+            _ => {
+                let grammar = complete_ebnf(ast_chunk.to_ebnf().trim(), chunk);
+                let grammar = add_environments_to_ebnf(&grammar, &trsltx.user_environments);
+                let grammar = add_citations_to_ebnf(&grammar, chunk, &trsltx.bib_keys);
+                let (grammar, truncated) = bound_ebnf(&grammar, trsltx.max_grammar_size);
+                if truncated {
+                    warn!(
+                        "grammar longer than {} characters, some commands are removed from it",
+                        trsltx.max_grammar_size
+                    );
+                }
+                if grammar.len() > trsltx.max_grammar_size {
+                    warn!("grammar still too long, translate without grammar");
+                    None
+                } else {
+                    Some(grammar)
+                }
+            }
+        };
+        let params = trsltx.completion_params(max_tokens);
+        debug!("Grammar: {}", ast_chunk.to_ebnf());
+        Ok(Some(ChunkRequest {
+            question,
+            grammar,
+            ast_chunk,
+            originals,
+            params,
+        }))
+    }
+
+    /// Grammar of the attempt `iter`: the last attempt is without grammar
+    fn grammar(&self, iter: usize, itermax: usize) -> Option<&str> {
+        if iter + 2 > itermax {
+            None
+        } else {
+            self.grammar.as_deref()
+        }
+    }
+
+    /// Extract the translation from the answer of the LLM,
+    /// with its syntax distance to the chunk
+    fn evaluate(&self, answer: &str) -> (String, usize) {
+        let trs_try = answer.split("\\begin{trsltx}").collect::<Vec<&str>>();
+        let trs_try = if trs_try.len() >= 2 {
+            let trs_try = trs_try[1].split("\\end{trsltx}").collect::<Vec<&str>>()[0];
+            trs_try.to_string()
+        } else {
+            "".to_string()
+        };
+        let trs_ltxnode = LtxNode::new(trs_try.as_str());
+        let dist = self.ast_chunk.distance(&trs_ltxnode);
+        debug!("Syntax distance: {}", dist);
+        debug!("Bnf grammar: {}", trs_ltxnode.to_ebnf());
+        (trs_try, dist)
+    }
+}
+
+// test the chat_with_ts function
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Translator from French to English of a LaTeX string, with the default settings
+    fn test_trsltx() -> Trsltx {
+        TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .build()
+            .unwrap()
+    }
+
+    /// Same as `test_trsltx`, with a backend giving always the same answer
+    fn echo_trsltx(answer: &str) -> Trsltx {
+        TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .backend(EchoBackend {
+                answer: answer.to_string(),
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_chat_with_ts() {
         let question = "Q: Is Madrid the capital of Spain ?\nA:";
         let answer = chat_with_ts(question).unwrap();
         println!("{:?}", answer);
         assert!(answer.contains("Madrid"));
     }
+    /// parameters of the requests sent to the textsynth server by the tests
+    fn ts_params() -> CompletionParams {
+        CompletionParams {
+            model: "mistral47b".to_string(),
+            api_key: None,
+            temperature: 0.5,
+            top_p: None,
+            max_tokens: 2000,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    #[test]
+    fn test_complete_grammar_ts() {
+        let question = "Q: Is Tokyo the capital of Spain ?\nA:\n";
+        let grammar = r#"root   ::= "yes" | "no""#;
+        let grammar = grammar.to_string();
+        println!("{:?}", grammar);
+        let answer = complete_with_ts(question, &Some(grammar), &ts_params())
+            .unwrap()
+            .0;
+        //let answer = complete_with_ts(question, None);
+        println!("{:?}", answer);
+        assert!(answer.contains("No") || answer.contains("no"));
+    }
+    #[test]
+    fn test_2complete_grammar_ts() {
+        let question = r#"
+Question: 
+What is the capital of France?
+Give a false answer.
+
+Answer:
+
+"#;
+        let grammar = r#"root   ::= [A-Z][a-z]*"#;
+        let grammar = grammar.to_string();
+        println!("{:?}", grammar);
+        let answer = complete_with_ts(question, &Some(grammar), &ts_params())
+            .unwrap()
+            .0;
+        // let answer = complete_with_ts(question, None);
+        println!("{:?}", answer);
+    }
+
+    #[test]
+    fn test_extracts_environments() {
+        let latex = std::fs::read_to_string("test/simple.tex").expect("cannot read test file");
+        let envs = extracts_environments(&latex);
+        println!("{:?}", envs);
+        assert_eq!(
+            envs,
+            vec![
+                "abstract",
+                "array",
+                "document",
+                "equation",
+                "itemize",
+                "thebibliography"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_environments_in_ebnf() {
+        let grammar = std::fs::read_to_string("src/sample.ebnf").expect("cannot read grammar");
+        let envs = vec!["equation".to_string(), "proof".to_string()];
+        let grammar = add_environments_to_ebnf(&grammar, &envs);
+        println!("{}", grammar);
+        assert!(grammar.contains("construct ::= group | environment"));
+        assert!(grammar.contains(
+            r#"environment ::= "\\begin{equation}" stuff "\\end{equation}" | "\\begin{proof}" stuff "\\end{proof}""#
+        ));
+    }
+
+    #[test]
+    fn test_newtheorem_environments() {
+        let preamble = r#"\newtheorem{theorem}{Theorem}
+\newtheorem{lemma}[theorem]{Lemma}
+\newtheorem*{remark}{Remark}
+\newtheoremstyle{note}{3pt}{3pt}{}{}{\bfseries}{.}{.5em}{}"#;
+        let envs = extract_newtheorem_names(preamble);
+        assert_eq!(envs, vec!["lemma", "remark", "theorem"]);
+
+        let grammar = std::fs::read_to_string("src/sample.ebnf").expect("cannot read grammar");
+        let grammar = add_environments_to_ebnf(&grammar, &["lemma".to_string()]);
+        let grammar = add_environments_to_ebnf(&grammar, &envs);
+        assert_eq!(grammar.matches("environment ::=").count(), 1);
+        assert_eq!(grammar.matches(r#""\\begin{lemma}""#).count(), 1);
+        assert!(grammar.contains(r#""\\begin{theorem}" stuff "\\end{theorem}""#));
+    }
+
+    #[test]
+    fn test_verbatim_chunk_unchanged() {
+        let listing = "\\begin{lstlisting}\nfor i in range(10):\n    print(i)\n\\end{lstlisting}";
+        let mut trsltx = test_trsltx();
+        trsltx.body = format!("\n{}\n", listing);
+        trsltx.extract_chunks().unwrap();
+        assert!(matches!(trsltx.chunks[0].1, ChunkType::Unchanged));
+        trsltx.translate_chunks();
+        assert_eq!(trsltx.body_translated, listing);
+    }
+
+    #[test]
+    fn test_assemble_copied_chunks() {
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .backend(EchoBackend {
+                answer: "Hello.".to_string(),
+            })
+            .build()
+            .unwrap();
+        trsltx.body = "Bonjour.\n%trsltx-split\n\\begin{verbatim}\nx = 1\n\\end{verbatim}\n%trsltx-split\nSalut.\n%trsltx-begin-ignore\nPas ceci.\n%trsltx-end-ignore\nFin.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        assert_eq!(
+            trsltx.body_translated,
+            "Hello.\n%trsltx-split\n\\begin{verbatim}\nx = 1\n\\end{verbatim}\n%trsltx-split\nHello.\n%trsltx-begin-ignore\nPas ceci.\n%trsltx-end-ignore\nHello."
+        );
+    }
+
+    #[test]
+    fn test_urls_in_ebnf() {
+        let latex = r#"See \url{https://example.com/~user/a\%20b#sec} and
+\href{https://example.com/doc#part}{Cliquez ici}."#;
+        let urls = extracts_urls(latex);
+        assert_eq!(
+            urls,
+            vec![
+                r#"https://example.com/~user/a\%20b#sec"#,
+                "https://example.com/doc#part"
+            ]
+        );
+        let grammar = std::fs::read_to_string("src/sample.ebnf").expect("cannot read grammar");
+        let grammar = add_protected_commands_to_ebnf(&grammar, latex);
+        println!("{}", grammar);
+        assert!(grammar.contains(r#""\\url{https://example.com/~user/a\\%20b#sec}""#));
+        assert!(grammar.contains(r#""\\href{https://example.com/doc#part}""#));
+
+        let latex =
+            r#"\includegraphics[width=0.5\textwidth]{fig/plot.pdf} et \includegraphics{logo}"#;
+        assert_eq!(
+            command_calls(latex, "includegraphics"),
+            vec![
+                r#"\includegraphics[width=0.5\textwidth]{fig/plot.pdf}"#,
+                r#"\includegraphics{logo}"#
+            ]
+        );
+        let grammar = add_protected_commands_to_ebnf(&grammar, latex);
+        assert!(grammar.contains(r#""\\includegraphics[width=0.5\\textwidth]{fig/plot.pdf}""#));
+        assert!(grammar.contains(r#""\\includegraphics{logo}""#));
+    }
+
+    #[test]
+    fn test_builder_missing_field() {
+        let err = TrsltxBuilder::new()
+            .input_lang("fr")
+            .input_file("test/simple_fr.tex")
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, TrsltxError::Config(_)));
+        assert_eq!(err.to_string(), "Missing output language");
+        let err = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("test/simple_fr.tex")
+            .temperature(1.5)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The temperature must be in [0.0, 1.0], got 1.5"
+        );
+        let err = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("test/simple_fr.tex")
+            .top_p(0.0)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, TrsltxError::Config(_)));
+    }
+
+    #[test]
+    fn test_references_in_ebnf() {
+        let latex = r#"Voir \eqref{eq:energy}, \ref{eq_fraction} et \autoref{sec:intro}."#;
+        assert_eq!(
+            extracts_references(latex),
+            vec!["eq:energy", "eq_fraction", "sec:intro"]
+        );
+        let grammar = std::fs::read_to_string("src/sample.ebnf").expect("cannot read grammar");
+        let grammar = complete_ebnf(&grammar, latex);
+        println!("{}", grammar);
+        assert!(grammar.contains(r#""\\eqref{eq:energy}""#));
+        assert!(grammar.contains(r#""\\autoref{sec:intro}""#));
+
+        let latex = r#"\eqref{eq:a}, \ref{fig:b}, \pageref{fig:b} et \eqref{eq:c}"#;
+        assert_eq!(
+            extracts_references_by_kind(latex, RefKind::Eqref),
+            vec!["eq:a", "eq:c"]
+        );
+        assert_eq!(
+            extracts_references_by_kind(latex, RefKind::Ref),
+            vec!["fig:b"]
+        );
+        assert_eq!(extracts_references(latex).len(), 4);
+        let grammar = complete_ebnf(&grammar, latex);
+        assert!(grammar.contains(r#""\\pageref{fig:b}""#));
+
+        let latex = r#"\Cref{sec:intro} et \cref{eq:a, eq:b} détaillent \ref{fig:b}."#;
+        assert_eq!(
+            extracts_references(latex),
+            vec!["sec:intro", "eq:a", "eq:b", "fig:b"]
+        );
+        assert_eq!(
+            extracts_references_by_kind(latex, RefKind::Cref),
+            vec!["eq:a", "eq:b"]
+        );
+        let grammar = complete_ebnf(&grammar, latex);
+        assert!(grammar.contains(r#""\\cref{eq:a, eq:b}""#));
+        assert!(grammar.contains(r#""\\Cref{sec:intro}""#));
+        assert!(!extracts_all_text(latex).contains("sec:intro"));
+    }
+
+    #[test]
+    fn test_bound_ebnf() {
+        let grammar = std::fs::read_to_string("src/sample.ebnf").expect("cannot read grammar");
+        let commands: Vec<String> = (0..200)
+            .map(|i| format!(r#""\\commandnumber{}""#, i))
+            .collect();
+        let grammar = add_alternatives_to_ebnf(&grammar, "command", &commands);
+        assert!(grammar.len() > 2000);
+        let (bounded, truncated) = bound_ebnf(&grammar, 2000);
+        println!("{}", bounded);
+        assert!(truncated);
+        assert!(bounded.len() <= 2000);
+        assert!(bounded.contains(r#""\\label{eq:formule}""#));
+        assert!(bounded.contains(r#""\\ref{eq:autre_formule}""#));
+        assert!(bounded.contains("group ::="));
+        assert_eq!(bound_ebnf(&grammar, 100000), (grammar, false));
+    }
+
+    #[test]
+    fn test_no_split_in_environments() {
+        let mut latex = "Un texte.\n%trsltx-split\n\\begin{align}\n".to_string();
+        for i in 0..50 {
+            latex.push_str(&format!("x_{{{}}} &= {} \\\\\n%trsltx-split\n", i, i));
+        }
+        latex.push_str("y &= 0\n\\end{align}\n%trsltx-split\nUn autre texte.\n");
+        let split = remove_splits_in_environments(&latex, "%trsltx-split");
+        println!("{}", split);
+        assert_eq!(split.matches("%trsltx-split").count(), 2);
+        let begin = split.find("\\begin{align}").unwrap();
+        let end = split.find("\\end{align}").unwrap();
+        assert!(!split[begin..end].contains("%trsltx-split"));
+        // formulas and groups
+        let latex = r#"Soit $x
+%trsltx-split
+= 1$ et \[ y
+%trsltx-split
+\] \textbf{un
+%trsltx-split
+mot} 50\% {\{}
+%trsltx-split
+$$z$$
+%trsltx-split
+Fin."#;
+        let split = remove_splits_in_environments(latex, "%trsltx-split");
+        assert_eq!(split.matches("%trsltx-split").count(), 2);
+        assert!(split.ends_with("{\\{}\n%trsltx-split\n$$z$$\n%trsltx-split\nFin."));
+    }
+
+    #[test]
+    fn test_align_splits() {
+        let latex = r#"Un texte
+sur deux lignes.
+%trsltx-split
+
+\section{Titre}
+Une phrase
+%trsltx-split
+coupée.
+
+Un paragraphe.
+%trsltx-split
+\begin{align}
+
+x &= 1
+\end{align}
+Fin."#;
+        let aligned = align_splits(latex, "%trsltx-split", 40);
+        println!("{}", aligned);
+        assert_eq!(
+            aligned,
+            r#"Un texte
+sur deux lignes.
+
+%trsltx-split
+\section{Titre}
+Une phrase
+coupée.
+
+%trsltx-split
+Un paragraphe.
+\begin{align}
+
+x &= 1
+\end{align}
+Fin."#
+        );
+        // no paragraph nearby: the marker is kept
+        let latex = "Un texte.\n%trsltx-split\nUn autre texte.\n";
+        assert_eq!(align_splits(latex, "%trsltx-split", 40), latex);
+        assert!(is_sectioning_line("  \\subsection*{Titre}"));
+        assert!(!is_sectioning_line("\\sectionmark{Titre}"));
+    }
+
+    #[test]
+    fn test_config_settings() {
+        let content = r#"
+input_lang = "de"
+model = "llama3_70B"
+length_split = 1500
+prompt_file = "prompts/legal.txt"
+
+[chunk_overrides]
+2 = "unchanged"
+"#;
+        let settings = config::parse_settings(content).unwrap();
+        assert_eq!(settings.input_lang.as_deref(), Some("de"));
+        assert_eq!(settings.output_lang, None);
+        assert_eq!(settings.model.as_deref(), Some("llama3_70B"));
+        assert_eq!(settings.length_split, Some(1500));
+        assert_eq!(settings.prompt_file.as_deref(), Some("prompts/legal.txt"));
+        // the chunk overrides are still read from the same file
+        assert_eq!(
+            config::parse_chunk_overrides(content).unwrap(),
+            vec![(2, ChunkType::Unchanged)]
+        );
+        assert_eq!(config::parse_settings("").unwrap(), Default::default());
+        // misspelled key
+        assert!(matches!(
+            config::parse_settings("modle = \"x\""),
+            Err(TrsltxError::Config(_))
+        ));
+        assert!(config::parse_settings("length_split = \"long\"").is_err());
+    }
+
+    #[test]
+    fn test_chunk_overrides() {
+        let overrides = config::parse_chunk_overrides(
+            r#"
+[chunk_overrides]
+2 = "unchanged"
+1 = "translate"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            overrides,
+            vec![(1, ChunkType::Translate), (2, ChunkType::Unchanged)]
+        );
+        assert!(config::parse_chunk_overrides("[chunk_overrides]\n1 = \"maybe\"").is_err());
+        let mut trsltx = test_trsltx();
+        trsltx.body = "Un.\n%trsltx-split\nDeux.\n%trsltx-split\nTrois.".to_string();
+        trsltx.set_chunk_overrides(overrides);
+        trsltx.extract_chunks().unwrap();
+        let types: Vec<ChunkType> = trsltx.chunks().iter().map(|(_, t)| t.clone()).collect();
+        assert_eq!(
+            types,
+            vec![
+                ChunkType::Translate,
+                ChunkType::Unchanged,
+                ChunkType::Translate
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_error_report() {
+        let latex =
+            "Une formule $x=\\frac{1}{2}$\net une accolade {non fermée.\n% {dans un commentaire";
+        let offset = find_unbalanced(latex).unwrap();
+        assert_eq!(&latex[offset..offset + 1], "{");
+        let report = parse_error_report(latex, offset);
+        println!("{}", report);
+        assert!(report.starts_with("Parse error at line 2, column 17"));
+        assert!(report.contains("{non fermée."));
+        assert_eq!(find_unbalanced("\\{ $x$ {y} \\}"), None);
+        assert_eq!(find_unbalanced("a } b"), Some(2));
+    }
+
+    #[test]
+    fn test_bib_keys() {
+        let bib = r#"@string{jcp = "J. Comput. Phys."}
+@article{helluy2024,
+  author = {Helluy, Philippe},
+  title = {Un titre},
+}
+@Book{tutu, title={Un livre}}
+@comment{ignored, entry}
+"#;
+        let keys = extract_bib_keys(bib);
+        assert_eq!(keys, vec!["helluy2024", "tutu"]);
+        let grammar = std::fs::read_to_string("src/sample.ebnf").expect("cannot read grammar");
+        let grammar = add_citations_to_ebnf(&grammar, r#"Voir \cite{helluy2024,toto}."#, &keys);
+        assert!(grammar.contains(r#""\\cite{helluy2024,toto}""#));
+        assert!(grammar.contains(r#""\\cite{helluy2024}""#));
+        // the keys of the bibliography not cited by the chunk are left out
+        assert!(!grammar.contains(r#""\\cite{tutu}""#));
+        assert!(!grammar.contains(r#""\\cite{toto}""#));
+        let latex = r#"Voir \cite[p. 3]{toto}, \citep{a,b} et \citealt {c}, \cite{toto} \citet{a,b}, \nocite{d}."#;
+        assert_eq!(
+            extracts_citations(latex),
+            vec![
+                r#"\cite[p. 3]{toto}"#,
+                r#"\citep{a,b}"#,
+                r#"\citealt {c}"#,
+                r#"\cite{toto}"#,
+                r#"\citet{a,b}"#
+            ]
+        );
+        let latex = r#"\Textcite{a} et \parencite[voir][12]{b} ou \autocite*{c}, \footcite{d}, \excite{e}."#;
+        assert_eq!(
+            extracts_citations(latex),
+            vec![
+                r#"\Textcite{a}"#,
+                r#"\parencite[voir][12]{b}"#,
+                r#"\autocite*{c}"#,
+                r#"\footcite{d}"#
+            ]
+        );
+        let grammar = add_citations_to_ebnf("command ::= \"\\\\emph\"", latex, &[]);
+        assert!(grammar.contains(r#""\\parencite[voir][12]{b}""#));
+        let (bounded, truncated) = bound_ebnf(&grammar, 60);
+        assert!(truncated);
+        assert!(bounded.contains("parencite") && !bounded.contains("emph"));
+        assert!(!extracts_all_text(latex).contains("voir"));
+    }
+
+    #[test]
+    fn test_ast_diff() {
+        let source = r#"Soit \begin{equation}x \label{eq_x}\end{equation} et \ref{eq_x}, \cite{tutu} \emph{voir}."#;
+        let translation =
+            r#"Let \begin{equation}x \label{eq_y}\end{equation} and \ref{eq_x}, \textbf{see}."#;
+        let diffs = diff::ast_diff(source, translation);
+        assert_eq!(
+            diffs,
+            vec![
+                AstDiff::Modified {
+                    from: "\\label{eq_x}".to_string(),
+                    to: "\\label{eq_y}".to_string()
+                },
+                AstDiff::Removed("\\cite{tutu}".to_string()),
+                AstDiff::Modified {
+                    from: "\\emph".to_string(),
+                    to: "\\textbf".to_string()
+                },
+            ]
+        );
+        assert!(diff::ast_diff(source, source).is_empty());
+    }
+
+    #[test]
+    fn test_placeholder_boundaries() {
+        let commands = vec!["todo".to_string()];
+        // a protected command immediately followed by a word
+        let (protected, originals) = protect_commands(r"\todo{x}Bonjour", &commands);
+        assert_eq!(protected, r"\trsltxplaceholderA{}Bonjour");
+        assert_eq!(
+            restore_commands(r"\trsltxplaceholderA{}Hello", &originals),
+            r"\todo{x}Hello"
+        );
+        // more than 26 placeholders: B and BA differ, even followed by a capital A
+        let latex: String = (0..30).map(|i| format!(r"\todo{{{}}}A ", i)).collect();
+        let (protected, originals) = protect_commands(&latex, &commands);
+        assert_eq!(originals.len(), 30);
+        assert!(protected.starts_with(r"\trsltxplaceholderA{}A \trsltxplaceholderB{}A "));
+        assert!(protected.contains(r"\trsltxplaceholderBA{}A "));
+        assert_eq!(restore_commands(&protected, &originals), latex);
+    }
+
+    #[test]
+    fn test_preserve_commands() {
+        let commands: Vec<String> = PRESERVE_COMMANDS.iter().map(|c| c.to_string()).collect();
+        let latex =
+            r#"\listoftodos Un texte\todo[inline]{revoir {ceci}} et \todolist{a}.\fixme{à faire}"#;
+        let (protected, originals) = protect_commands(latex, &commands);
+        assert_eq!(
+            protected,
+            r#"\trsltxplaceholderA{} Un texte\trsltxplaceholderB{} et \todolist{a}.\trsltxplaceholderC{}"#
+        );
+        assert_eq!(
+            originals,
+            vec![
+                r#"\listoftodos"#,
+                r#"\todo[inline]{revoir {ceci}}"#,
+                r#"\fixme{à faire}"#
+            ]
+        );
+        let translated = r#"\trsltxplaceholderA{} A text\trsltxplaceholderB{} and \todolist{a}.\trsltxplaceholderC{}"#;
+        assert_eq!(
+            restore_commands(translated, &originals),
+            r#"\listoftodos A text\todo[inline]{revoir {ceci}} and \todolist{a}.\fixme{à faire}"#
+        );
+        assert_eq!(placeholder(27), "\\trsltxplaceholderBB{}");
+        let trsltx = test_trsltx();
+        let latex = r#"\maketitle Voir \nocite{*}\bibliographystyle{plain}\bibliography{refs}"#;
+        let (protected, originals) = protect_commands(latex, &trsltx.preserve_commands);
+        assert_eq!(
+            protected,
+            r#"\trsltxplaceholderA{} Voir \trsltxplaceholderB{}\trsltxplaceholderC{}\trsltxplaceholderD{}"#
+        );
+        assert_eq!(originals[2], r#"\bibliographystyle{plain}"#);
+
+        let latex = "Tapez \\verb|\\todo{x}| ou \\verb*+a|b+ :\n\\begin{minted}{rust}\nlet x = \"\\\\\";\n\\end{minted}\nEnsuite \\verbatimfont.";
+        let (protected, originals) = protect_commands(latex, &trsltx.preserve_commands);
+        assert_eq!(
+            protected,
+            "Tapez \\trsltxplaceholderA{} ou \\trsltxplaceholderB{} :\n\\trsltxplaceholderC{}\nEnsuite \\verbatimfont."
+        );
+        assert_eq!(originals[0], "\\verb|\\todo{x}|");
+        assert_eq!(originals[1], "\\verb*+a|b+");
+        assert!(originals[2].ends_with("\\end{minted}"));
+        assert_eq!(restore_commands(&protected, &originals), latex);
+        assert_eq!(
+            extracts_all_text(latex)
+                .split_whitespace()
+                .collect::<Vec<_>>(),
+            vec!["Tapez", "ou", ":", "Ensuite", "."]
+        );
+    }
+
+    #[test]
+    fn test_model_registry() {
+        let registry = ModelRegistry::default();
+        let entry = registry.find("mistral47b").unwrap();
+        assert_eq!(entry.id, "mixtral_47B_instruct");
+        assert_eq!(
+            entry.url,
+            "https://api.textsynth.com/v1/engines/mixtral_47B_instruct/completions"
+        );
+        assert!(registry.find("gpt-2").is_none());
+        assert_eq!(
+            registry.find_or_default("gpt-2").id,
+            models::DEFAULT_MODEL_ID
+        );
+        // small context window: shorter chunks
+        let trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .model("mistral7b")
+            .max_tokens(7000)
+            .build()
+            .unwrap();
+        assert!(trsltx.max_chunk_length() < 4000);
+    }
+
+    #[test]
+    fn test_max_tokens_per_chunk() {
+        assert_eq!(estimate_tokens("", CHARS_PER_TOKEN), 0);
+        assert_eq!(estimate_tokens("Bonjour", 3.0), 3);
+        assert_eq!(estimate_tokens("Bonjour", 3.5), 2);
+        let mut builder = TrsltxBuilder::new();
+        builder.input_lang("fr").output_lang("en").input_file("");
+        assert_eq!(
+            builder.build().unwrap().max_chunk_length(),
+            MAX_CHUNK_LENGTH
+        );
+        builder.max_tokens_per_chunk(500).chars_per_token(4.0);
+        let mut trsltx = builder.build().unwrap();
+        assert_eq!(trsltx.max_chunk_length(), 2000);
+        assert_eq!(trsltx.estimate_tokens("Bonjour le monde."), 5);
+        // the chunks above the limit are not translated
+        trsltx.body = format!("Bonjour.\n%trsltx-split\n{}", "Bonjour. ".repeat(300));
+        trsltx.extract_chunks().unwrap();
+        let report = trsltx.check();
+        assert!(report.issues.iter().any(|issue| issue.chunk == Some(2)));
+        assert!(builder.chars_per_token(0.0).build().is_err());
+    }
+
+    #[test]
+    fn test_complexity() {
+        assert_eq!(complexity("Un deux trois."), 3.0);
+        // 2 words, 1 command, depth 1
+        assert_eq!(complexity("Un \\textbf{deux}"), 2.0 + 0.3 + 0.5);
+        let math = "\\begin{equation}\nx = y\n\\end{equation}";
+        let text = "Un deux trois quatre cinq six.";
+        assert!(complexity(math) < complexity(text));
+        assert!(complexity(&format!("{}\n{}", text, math)) > 1.5 * complexity(text));
+    }
+
+    #[test]
+    fn test_preamble_fields() {
+        let preamble = r#"\documentclass{article}
+\title{Une \emph{belle} traduction}
+\author{Jean Dupont}
+\date{\today}
+\begin{abstract}
+Un résumé.
+\end{abstract}
+"#;
+        let fields: Vec<&str> = preamble_fields(preamble)
+            .into_iter()
+            .map(|(start, end)| &preamble[start..end])
+            .collect();
+        assert_eq!(
+            fields,
+            vec!["Une \\emph{belle} traduction", "\nUn résumé.\n"]
+        );
+        let preamble = r#"\author{Jean Dupont\thanks{Université de Strasbourg}}"#;
+        assert_eq!(preamble_fields(preamble).len(), 1);
+    }
+
+    #[test]
+    fn test_check_delimiters() {
+        let latex = r#"\begin{itemize}
+\item \textbf{un}} deux
+\end{enumerate}
+\begin{verbatim}{\end{verbatim}
+\emph{trois % }
+"#;
+        let errors = validate::check_delimiters(latex);
+        let kinds: Vec<(usize, DelimiterError)> =
+            errors.into_iter().map(|e| (e.line, e.kind)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (
+                    1,
+                    DelimiterError::UnclosedEnvironment("itemize".to_string())
+                ),
+                (2, DelimiterError::UnmatchedBrace),
+                (
+                    3,
+                    DelimiterError::MismatchedEnd {
+                        expected: "itemize".to_string(),
+                        found: "enumerate".to_string()
+                    }
+                ),
+                (5, DelimiterError::UnclosedBrace),
+            ]
+        );
+        assert!(validate::check_delimiters("\\{ \\begin{a}{x}\\end{a}").is_empty());
+
+        // only the mismatches absent from the source chunk are reported
+        let mut trsltx = test_trsltx();
+        trsltx.chunks = vec![
+            ("\\begin{proof} Un.".to_string(), ChunkType::Translate),
+            ("\\emph{Deux}.".to_string(), ChunkType::Translate),
+            ("{x".to_string(), ChunkType::Unchanged),
+        ];
+        trsltx.chunks_translated = vec![
+            "\\begin{proof} One.".to_string(),
+            "\\emph{Two}}.".to_string(),
+            "{x".to_string(),
+        ];
+        let errors = trsltx.validate_output().unwrap_err();
+        let lines: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec!["chunk 2, line 1, column 11: unmatched closing brace"]
+        );
+    }
+
+    #[test]
+    fn test_lang_file_name() {
+        assert_eq!(
+            lang_file_name("test/simple.tex", "fr").unwrap(),
+            "test/simple_fr.tex"
+        );
+        assert_eq!(
+            lang_file_name("chapter_two_draft.tex", "en").unwrap(),
+            "chapter_two_draft_en.tex"
+        );
+        assert_eq!(
+            lang_file_name(r"C:\Users\Alice\thesis.tex", "en").unwrap(),
+            r"C:\Users\Alice\thesis_en.tex"
+        );
+        assert!(lang_file_name("test/simple.txt", "en").is_err());
+        assert_eq!(
+            split_lang_file_name("my.tex.files/chapter_two_draft_fr.tex").unwrap(),
+            (
+                "my.tex.files/chapter_two_draft".to_string(),
+                "fr".to_string()
+            )
+        );
+        assert_eq!(
+            split_lang_file_name(r"C:\Users\Alice\my_thesis_en.tex")
+                .unwrap()
+                .1,
+            "en"
+        );
+        assert!(split_lang_file_name("thesis.tex").is_err());
+    }
+
+    #[test]
+    fn test_custom_markers() {
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .markers(SplitMarkers {
+                split: "%%cut".to_string(),
+                begin_ignore: "%%keep".to_string(),
+                end_ignore: "%%endkeep".to_string(),
+            })
+            .build()
+            .unwrap();
+        trsltx.body =
+            "Un.\n%%cut\nDeux %trsltx-split\n%%keep\nTrois.\n%%endkeep\nQuatre.".to_string();
+        trsltx.extract_chunks().unwrap();
+        let types: Vec<ChunkType> = trsltx.chunks().iter().map(|(_, t)| t.clone()).collect();
+        assert_eq!(
+            types,
+            vec![
+                ChunkType::Translate,
+                ChunkType::Translate,
+                ChunkType::Unchanged,
+                ChunkType::Translate
+            ]
+        );
+        assert!(trsltx.chunks()[1].0.contains("%trsltx-split"));
+
+        // the markers must be distinct, and not prefixes of each other
+        for (split, begin_ignore) in [("", "%%keep"), ("%%keep", "%%keep"), ("%%k", "%%keep")] {
+            let err = TrsltxBuilder::new()
+                .input_lang("fr")
+                .output_lang("en")
+                .markers(SplitMarkers {
+                    split: split.to_string(),
+                    begin_ignore: begin_ignore.to_string(),
+                    end_ignore: "%%endkeep".to_string(),
+                })
+                .build()
+                .unwrap_err();
+            assert!(matches!(err, TrsltxError::Config(_)));
+        }
+
+        // the split markers of an ignored region are dropped
+        let mut trsltx = test_trsltx();
+        trsltx.body =
+            "Un.\n%trsltx-begin-ignore\nDeux.\n%trsltx-split\nTrois.\n%trsltx-end-ignore\nQuatre."
+                .to_string();
+        trsltx.extract_chunks().unwrap();
+        assert_eq!(
+            trsltx.chunks(),
+            vec![
+                ("Un.".to_string(), ChunkType::Translate),
+                (
+                    "%trsltx-begin-ignore\nDeux.\nTrois.\n%trsltx-end-ignore".to_string(),
+                    ChunkType::Unchanged
+                ),
+                ("Quatre.".to_string(), ChunkType::Translate),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pure_math_chunk() {
+        assert!(is_pure_math(r"\[ \int_0^\infty f(x)\,dx = 1 \]"));
+        assert!(is_pure_math(
+            "\\begin{align*}\nx &= 1 \\label{eq_x}\n\\end{align*}\n% un commentaire"
+        ));
+        assert!(!is_pure_math(r"Soit \[ x = 1 \] une équation."));
+        assert!(!is_pure_math(r"Un texte qui coûte 3 \$."));
+        assert!(!is_pure_math(r"\[ x=1 \text{ si } y>0 \]"));
+        assert!(!is_pure_math(r"$x \mbox{pour tout} y$"));
+        assert!(is_pure_math(r"\[ \text{\ref{a}} \]"));
+        let mut trsltx = test_trsltx();
+        trsltx.body =
+            "Un texte.\n%trsltx-split\n\\[ \\int_0^\\infty f(x)\\,dx = 1 \\]\n".to_string();
+        trsltx.extract_chunks().unwrap();
+        assert_eq!(trsltx.chunks()[0].1, ChunkType::Translate);
+        assert_eq!(trsltx.chunks()[1].1, ChunkType::Unchanged);
+    }
+
+    #[test]
+    fn test_check() {
+        let mut trsltx = test_trsltx();
+        trsltx.preamble = "\\usepackage[french]{babel}\n".to_string();
+        trsltx.body = "Voir \\eqref{eq_x} et \\ref{eq_y}.\n%trsltx-split\n\\begin{equation}x \\label{eq_x}\\end{equation}".to_string();
+        let report = trsltx.check();
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(report.issues[0].severity, check::Severity::Error);
+        assert!(report.issues[0].message.contains("eq_y"));
+        assert_eq!(report.exit_code(), 2);
+        let mut trsltx = test_trsltx();
+        trsltx.body = "Un \\label{a}.\n%trsltx-begin-ignore\nx".to_string();
+        let report = trsltx.check();
+        // no babel, unused label, unbalanced ignore marker
+        assert_eq!(report.issues.len(), 3);
+        assert!(report
+            .to_string()
+            .contains("Unbalanced %trsltx-begin-ignore at line 2"));
+    }
+
+    #[test]
+    fn test_ignore_markers() {
+        let check = |body: &str| check_ignore_markers(body, "%keep", "%endkeep", 10);
+        assert!(check("a\n%keep\nb\n%endkeep\n%keep %endkeep\nc").is_ok());
+        assert_eq!(
+            check("a\n%keep\nb\n%keep\n%endkeep").unwrap_err(),
+            "Nested %keep at line 13: the region opened at line 11 is not closed"
+        );
+        assert_eq!(
+            check("a\n%endkeep").unwrap_err(),
+            "Unbalanced %endkeep at line 11: no %keep before"
+        );
+        assert_eq!(
+            check("%keep\n%endkeep\n%keep\nb").unwrap_err(),
+            "Unbalanced %keep at line 12: no %endkeep after"
+        );
+        assert!(check("%trsltx-source-begin\n% %keep\n%trsltx-source-end").is_ok());
+        let mut trsltx = test_trsltx();
+        // the body starts on the line of \begin{document}
+        trsltx.preamble = "\\documentclass{article}\n\n".to_string();
+        trsltx.body = "\nUn.\n%trsltx-end-ignore\n".to_string();
+        let err = trsltx.extract_chunks().unwrap_err().to_string();
+        assert!(err.contains("at line 5"), "{}", err);
+    }
+
+    #[test]
+    fn test_cross_reference_check() {
+        let mut trsltx = test_trsltx();
+        trsltx.body = "Voir \\ref{fig:a} et \\eqref{eq:b}.\n%trsltx-split\n\\label{fig:a} \\label{c}\n%trsltx-split\nEncore \\ref{eq:b}.".to_string();
+        trsltx.extract_chunks().unwrap();
+        let warnings = trsltx.cross_reference_check();
+        assert_eq!(
+            warnings,
+            vec![
+                CrossRefWarning {
+                    ref_key: "eq:b".to_string(),
+                    chunk_index: 0,
+                    kind: check::CrossRefKind::UndefinedLabel,
+                },
+                CrossRefWarning {
+                    ref_key: "c".to_string(),
+                    chunk_index: 1,
+                    kind: check::CrossRefKind::UnusedLabel,
+                },
+                CrossRefWarning {
+                    ref_key: "eq:b".to_string(),
+                    chunk_index: 2,
+                    kind: check::CrossRefKind::UndefinedLabel,
+                },
+            ]
+        );
+        assert_eq!(
+            warnings[1].to_string(),
+            "chunk 2: \\label{c} is never referenced"
+        );
+    }
+
+    #[test]
+    fn test_commented_document() {
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("test/commented_document.tex")
+            .build()
+            .unwrap();
+        trsltx.read_file().unwrap();
+        assert!(trsltx.preamble.contains("% \\begin{document}"));
+        assert!(trsltx.preamble.contains("\\usepackage"));
+        assert_eq!(
+            trsltx.body.trim(),
+            "Un texte. % \\end{document}\n50 \\% du texte."
+        );
+        assert_eq!(trsltx.afterword.trim(), "% fin");
+    }
+
+    #[test]
+    fn test_prompt_template() {
+        // the default template gives the prompt of the previous versions
+        let question = fill_prompt(PREPROMPT, "French", "English", "", "Bonjour.");
+        assert!(question.ends_with("Here is the French LateX source:\n\n\nBonjour.\nA:\n"));
+        assert!(question.contains("scientific text, formatted with LateX, into English."));
+        let question = fill_prompt(
+            PREPROMPT,
+            "French",
+            "English",
+            "Use the following term translations:\n",
+            "Bonjour.",
+        );
+        assert!(question.contains("\\end{trsltx}\nUse the following term translations:\nHere is"));
+        // custom template, the chunk is not searched for placeholders
+        let template = "Legal text in <lang_in>, to <lang_out>.\n<glossary><chunk>\nEnd.";
+        let question = fill_prompt(template, "French", "German", "", "Le <lang_in>.");
+        assert_eq!(
+            question,
+            "Legal text in French, to German.\nLe <lang_in>.\nEnd."
+        );
+        // without <chunk>, the chunk is appended
+        let question = fill_prompt("Translate <lang_in>.", "French", "German", "", "Oui.");
+        assert_eq!(question, "Translate French.\nOui.\nA:\n");
+        let mut trsltx = test_trsltx();
+        assert_eq!(trsltx.prompt_template(), PREPROMPT);
+        assert!(matches!(
+            trsltx.read_prompt_file("test/no_such_prompt.txt"),
+            Err(TrsltxError::Io { .. })
+        ));
+        trsltx.set_prompt_template(template);
+        assert_eq!(trsltx.prompt_template(), template);
+    }
+
+    #[test]
+    fn test_glossary() {
+        let glossary = glossary::parse_glossary(
+            "# fr -> en\nespace de Hilbert\tHilbert space\n\nmaillage\tmesh\n",
+        )
+        .unwrap();
+        assert_eq!(glossary.len(), 2);
+        assert!(glossary::parse_glossary("maillage mesh").is_err());
+        let prompt = glossary::glossary_prompt(&glossary);
+        assert!(prompt.contains("- maillage \u{2192} mesh\n"));
+        let (text, corrected) = glossary::apply_glossary(
+            "Le maillage de l'espace de Hilbert, voir \\ref{sec:maillage}.",
+            "The maillage of the espace de Hilbert $maillage$ (\\ref{sec:maillage}), \\maillage{maillage}, not the maillages.",
+            &glossary,
+        );
+        // the mathematics and the commands are not corrected
+        assert_eq!(
+            text,
+            "The mesh of the Hilbert space $maillage$ (\\ref{sec:maillage}), \\maillage{maillage}, not the maillages."
+        );
+        assert_eq!(corrected, vec!["espace de Hilbert", "maillage"]);
+        // the terms absent from the source may be words of the output language
+        let glossary = vec![("application".to_string(), "map".to_string())];
+        let (text, corrected) =
+            glossary::apply_glossary("Soit f.", "Let f be an application.", &glossary);
+        assert_eq!(text, "Let f be an application.");
+        assert!(corrected.is_empty());
+        // the protected commands are restored after the correction
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .backend(EchoBackend {
+                answer: "The maillage \\trsltxplaceholderA{}.".to_string(),
+            })
+            .build()
+            .unwrap();
+        trsltx.glossary = vec![("maillage".to_string(), "mesh".to_string())];
+        trsltx.body = "Le maillage \\todo{maillage}.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        assert_eq!(
+            trsltx.chunks_translated[0],
+            "The mesh \\todo{maillage}.\n% trsltx: glossary correction of maillage"
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("X-RateLimit-Remaining", "0".parse().unwrap());
+        headers.insert("X-RateLimit-Reset", "1700000060".parse().unwrap());
+        let state = RateLimitState::from_headers(&headers);
+        assert_eq!(state.remaining, Some(0));
+        assert_eq!(state.reset, Some(1700000060));
+        assert_eq!(
+            state.wait_time(1700000000),
+            Some(std::time::Duration::from_secs(60))
+        );
+        assert_eq!(state.wait_time(1700000100), None);
+        headers.insert("X-RateLimit-Remaining", "12".parse().unwrap());
+        assert_eq!(
+            RateLimitState::from_headers(&headers).wait_time(1700000000),
+            None
+        );
+        let state = RateLimitState::from_headers(&reqwest::header::HeaderMap::new());
+        assert_eq!(state, RateLimitState::default());
+    }
+
+    #[test]
+    fn test_http_retry() {
+        let retry = RetryPolicy::default();
+        assert_eq!(retry.backoff(0), std::time::Duration::from_secs(1));
+        assert_eq!(retry.backoff(2), std::time::Duration::from_secs(4));
+        assert!(backend::is_transient_status(
+            reqwest::StatusCode::TOO_MANY_REQUESTS
+        ));
+        assert!(backend::is_transient_status(
+            reqwest::StatusCode::SERVICE_UNAVAILABLE
+        ));
+        assert!(!backend::is_transient_status(
+            reqwest::StatusCode::UNAUTHORIZED
+        ));
+        // no server on this port: connection errors
+        let retry = RetryPolicy {
+            max_http_retries: 2,
+            initial_backoff: std::time::Duration::ZERO,
+            ..RetryPolicy::default()
+        };
+        let request = reqwest::blocking::Client::new().post("http://127.0.0.1:9/v1/engines");
+        let err = backend::send_with_backoff(request, &retry).unwrap_err();
+        assert!(err.starts_with("Failed to send request after 2 retries"));
+    }
+
+    #[test]
+    fn test_split_stats() {
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("test/simple.tex")
+            .build()
+            .unwrap();
+        trsltx.body = "Un texte court.\n%trsltx-split\n\\[ x = 1 \\]\n".to_string();
+        trsltx.extract_chunks().unwrap();
+        let stats = trsltx.split_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].chunk_type, ChunkType::Translate);
+        assert_eq!(stats[0].char_count, 15);
+        assert_eq!(stats[0].word_count, 3);
+        assert!(stats[0].estimated_tokens > 10);
+        assert_eq!(stats[1].chunk_type, ChunkType::Unchanged);
+        assert_eq!(stats[1].estimated_tokens, 0);
+    }
+
+    #[test]
+    fn test_cost_estimate() {
+        let mut trsltx = test_trsltx();
+        trsltx.preamble = "\\title{Un titre}\n".to_string();
+        trsltx.body = "Un texte court.\n%trsltx-split\n\\[ x = 1 \\]\n%trsltx-split\nUn autre texte.\n\\input{intro}\n".to_string();
+        trsltx.extract_chunks().unwrap();
+        let estimate = trsltx.cost_estimate();
+        assert_eq!(estimate.chunks, 3);
+        assert_eq!(estimate.translated_chunks, 2);
+        assert_eq!(estimate.preamble_fields, 1);
+        assert_eq!(estimate.requests(), 3);
+        assert_eq!(estimate.subfiles, 1);
+        assert_eq!(estimate.max_attempts, RetryPolicy::default().max_attempts);
+        let prompt = trsltx.estimate_tokens(trsltx.prompt_template());
+        assert!(estimate.prompt_tokens > 3 * prompt);
+        assert!(estimate.completion_tokens < estimate.prompt_tokens);
+        let cost = estimate.total_tokens() as f64 * 2.0 / 1e6;
+        assert!((estimate.cost(2.0) - cost).abs() < 1e-12);
+        trsltx.translate_preamble = false;
+        assert_eq!(trsltx.cost_estimate().requests(), 2);
+    }
+
     #[test]
-    fn test_complete_grammar_ts() {
-        let question = "Q: Is Tokyo the capital of Spain ?\nA:\n";
-        let grammar = r#"root   ::= "yes" | "no""#;
-        let grammar = grammar.to_string();
-        println!("{:?}", grammar);
-        let answer = complete_with_ts(question, &Some(grammar), "mistral47b".to_string()).unwrap();
-        //let answer = complete_with_ts(question, None);
-        println!("{:?}", answer);
-        assert!(answer.contains("No") || answer.contains("no"));
+    fn test_decode_latex() {
+        let latin1 = b"\\usepackage[latin1]{inputenc}\n\\begin{document}Caf\xe9 \xe0 Paris";
+        assert_eq!(
+            decode_latex(latin1).unwrap(),
+            "\\usepackage[utf8]{inputenc}\n\\begin{document}Café à Paris"
+        );
+        let cp1252 = b"\\usepackage[cp1252]{inputenc}\n\x93Caf\xe9\x94";
+        assert_eq!(
+            decode_latex(cp1252).unwrap(),
+            "\\usepackage[utf8]{inputenc}\n\u{201c}Café\u{201d}"
+        );
+        let utf8 = "\\usepackage[utf8]{inputenc}\nCafé";
+        assert_eq!(decode_latex(utf8.as_bytes()).unwrap(), utf8);
+        assert!(decode_latex(b"\\usepackage[T1]{fontenc}\nCaf\xe9").is_err());
     }
+
     #[test]
-    fn test_2complete_grammar_ts() {
-        let question = r#"
-Question: 
-What is the capital of France?
-Give a false answer.
+    fn test_count_words() {
+        let latex = r#"\section{Objectifs}
+L'objectif est de traduire\footnote{Une note.} (\ref{eq_fraction}) % un commentaire
+\begin{equation}
+x = y \label{eq_x}
+\end{equation}
+\begin{verbatim}
+not translated
+\end{verbatim}"#;
+        // Objectifs L'objectif est de traduire Une note.
+        assert_eq!(count_words(latex), 7);
+    }
 
-Answer:
+    #[test]
+    fn test_adjust_preamble_lang() {
+        let adjust =
+            |preamble: &str| adjust_preamble_lang(preamble.to_string(), "fr", "de").unwrap();
+        assert_eq!(
+            adjust("\\usepackage[english,french]{babel}"),
+            "\\usepackage[english,german]{babel}"
+        );
+        assert_eq!(
+            adjust("\\usepackage[T1]{fontenc}"),
+            "\\usepackage[T1]{fontenc}"
+        );
+        assert_eq!(adjust("\\frenchspacing"), "\\frenchspacing");
+        // only the options of babel are changed
+        assert_eq!(
+            adjust("\\usepackage[french]{datetime2}\\usepackage[french]{babel,varioref}"),
+            "\\usepackage[french]{datetime2}\\usepackage[german]{babel,varioref}"
+        );
+        assert_eq!(
+            adjust("\\documentclass[a4paper, french]{article} % french"),
+            "\\documentclass[a4paper, german]{article} % french"
+        );
+        assert_eq!(
+            adjust("\\usepackage[main=french]{babel}\n\\setotherlanguage{french}"),
+            "\\usepackage[main=german]{babel}\n\\setotherlanguage{german}"
+        );
+        // babel aliases and languages without ldf file
+        let preamble = "\\usepackage[ngerman]{babel}\n\\setmainlanguage{german}";
+        assert_eq!(
+            adjust_preamble_lang(preamble.to_string(), "de", "nl").unwrap(),
+            "\\usepackage[dutch]{babel}\n\\setmainlanguage{dutch}"
+        );
+        let preamble = "\\usepackage[T1]{fontenc}\n\\usepackage[english]{babel}";
+        assert_eq!(
+            adjust_preamble_lang(preamble.to_string(), "en", "uk").unwrap(),
+            "\\usepackage[T2A]{fontenc}\n\\usepackage[ukrainian]{babel}"
+        );
+        assert_eq!(
+            adjust_preamble_lang("\\setdefaultlanguage{english}".to_string(), "en", "id").unwrap(),
+            "\\setdefaultlanguage{malay}"
+        );
+        // polyglossia (XeLaTeX)
+        let preamble = r#"\usepackage{polyglossia}
+\setmainlanguage[variant=british]{english}
+\setotherlanguages{russian,french}
+\usepackage[T1]{fontenc}"#;
+        assert_eq!(
+            adjust_preamble_lang(preamble.to_string(), "en", "ru").unwrap(),
+            r#"\usepackage{polyglossia}
+\setmainlanguage{russian}
+\setotherlanguages{russian,french}
+\usepackage[T1]{fontenc}"#
+        );
+        assert_eq!(
+            adjust(
+                "\\setmainlanguage[babelshorthands]{german}\n\\setotherlanguages{french,german}"
+            ),
+            "\\setmainlanguage[babelshorthands]{german}\n\\setotherlanguages{german}"
+        );
+    }
 
-"#;
-        let grammar = r#"root   ::= [A-Z][a-z]*"#;
-        let grammar = grammar.to_string();
-        println!("{:?}", grammar);
-        let answer = complete_with_ts(question, &Some(grammar),"mistral47b".to_string()).unwrap();
-        // let answer = complete_with_ts(question, None);
-        println!("{:?}", answer);
+    #[test]
+    fn test_script_packages() {
+        let preamble = "\\documentclass[11pt]{article}\n\\usepackage[english]{babel}\n";
+        assert_eq!(
+            adjust_preamble_lang(preamble.to_string(), "en", "zh").unwrap(),
+            "\\documentclass[11pt]{article}\n\\usepackage{ctex}\n\\usepackage[chinese]{babel}\n"
+        );
+        assert_eq!(
+            adjust_preamble_lang(preamble.to_string(), "en", "he").unwrap(),
+            "\\documentclass[11pt]{article}\n\\usepackage[hebrew]{babel}\n\\usepackage{bidi}\n"
+        );
+        // already present
+        let preamble = "\\documentclass{article}\n\\usepackage{xeCJK}";
+        assert_eq!(
+            adjust_preamble_lang(preamble.to_string(), "en", "ja").unwrap(),
+            preamble
+        );
+        let preamble =
+            "\\documentclass{article}\n\\usepackage{polyglossia}\n\\setmainlanguage{english}";
+        assert_eq!(
+            adjust_preamble_lang(preamble.to_string(), "en", "ar").unwrap(),
+            "\\documentclass{article}\n\\usepackage{polyglossia}\n\\setmainlanguage{arabic}"
+        );
+        assert_eq!(
+            adjust_preamble_lang(preamble.to_string(), "en", "fr").unwrap(),
+            "\\documentclass{article}\n\\usepackage{polyglossia}\n\\setmainlanguage{french}"
+        );
+    }
+
+    #[test]
+    fn test_detect_language() {
+        let detect = |text: &str| detect::detect_text_language(text).map(|l| l.code);
+        assert_eq!(
+            detect(
+                r"The \emph{mesh} of the domain is given in \cite{knuth}, and we prove that the method converges for all the cases with $h \to 0$."
+            ),
+            Some("en")
+        );
+        assert_eq!(
+            detect("Das Gitter ist nicht regelmäßig und wir zeigen, dass die Methode mit der Zeit konvergiert, und das ist eine gute Nachricht."),
+            Some("de")
+        );
+        assert_eq!(
+            detect("Это доказательство является простым, и мы покажем, что метод сходится на сетке, как и для других задач."),
+            Some("ru")
+        );
+        assert_eq!(detect("これは日本語の文章です。"), Some("ja"));
+        assert_eq!(detect("这是一个中文句子。"), Some("zh"));
+        // too short
+        assert_eq!(detect("Théorème 1."), None);
+        let declared = |preamble: &str| detect::declared_language(preamble).map(|l| l.code);
+        assert_eq!(
+            declared("\\documentclass{article}\n\\usepackage[english,ngerman]{babel}"),
+            Some("de")
+        );
+        assert_eq!(
+            declared("\\usepackage[main=french,english]{babel}"),
+            Some("fr")
+        );
+        assert_eq!(
+            declared(
+                "% \\setmainlanguage{english}\n\\setmainlanguage[variant=brazilian]{portuguese}"
+            ),
+            Some("pt")
+        );
+        assert_eq!(declared("\\usepackage[T1]{fontenc}"), None);
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("auto")
+            .output_lang("en")
+            .input_file("")
+            .build()
+            .unwrap();
+        trsltx.preamble = "\\usepackage[italian]{babel}\n".to_string();
+        trsltx.body = "Teorema 1.".to_string();
+        assert_eq!(trsltx.detect_input_lang().unwrap(), "it");
+        trsltx.preamble = String::new();
+        assert!(matches!(
+            trsltx.detect_input_lang(),
+            Err(TrsltxError::Language(_))
+        ));
+    }
+
+    #[test]
+    fn test_languages() {
+        for lang in languages::LANGUAGES {
+            assert_eq!(lang.code.len(), 2);
+            assert!(!lang.babel.is_empty());
+            assert_eq!(
+                languages::LANGUAGES
+                    .iter()
+                    .filter(|l| l.code == lang.code)
+                    .count(),
+                1
+            );
+        }
+        assert_eq!(get_lang_name("nl").unwrap(), "Dutch");
+        assert_eq!(get_lang_name("zh").unwrap(), "Chinese");
+        let err = get_lang_name("xx").unwrap_err();
+        assert!(matches!(err, TrsltxError::Language(_)));
+        assert!(err.to_string().contains("en,fr,es,de,it,pt,ru,nl"));
+    }
+
+    #[test]
+    fn test_extracts_all_text() {
+        assert_eq!(
+            extracts_all_text(r"\textbf{Hello} world, $x^2$"),
+            "Hello world, "
+        );
+        assert_eq!(
+            extracts_all_text(r"Voir \cite{knuth} \emph{page} 50 \% % note"),
+            "Voir  page 50 % "
+        );
+    }
+
+    #[test]
+    fn test_footnotes_in_ebnf() {
+        let grammar = std::fs::read_to_string("src/sample.ebnf").expect("cannot read grammar");
+        let latex = r#"Un texte\footnote{Une note.} et\footnote[2]{Une autre note.}"#;
+        let grammar = complete_ebnf(&grammar, latex);
+        println!("{}", grammar);
+        assert!(grammar.contains("construct ::= group | footnote"));
+        assert!(grammar
+            .contains(r#"footnote ::= "\\footnote[2]{" stuff "}" | "\\footnote{" stuff "}""#));
+        // no footnote production without footnote
+        let grammar = complete_ebnf("construct ::= group", "Un texte sans note.");
+        assert_eq!(grammar, "construct ::= group");
+    }
+
+    /// backend answering the same translation, without server
+    #[derive(Debug)]
+    struct EchoBackend {
+        answer: String,
+    }
+
+    impl LlmBackend for EchoBackend {
+        fn complete(
+            &self,
+            _prompt: &str,
+            grammar: Option<&str>,
+            _params: &CompletionParams,
+        ) -> Result<String, String> {
+            assert!(grammar.is_none());
+            Ok(format!("\\begin{{trsltx}}{}\\end{{trsltx}}", self.answer))
+        }
+
+        fn list_models(&self, _params: &CompletionParams) -> Result<Vec<String>, String> {
+            Ok(vec!["echo".to_string()])
+        }
+    }
+
+    #[test]
+    fn test_validate_model() {
+        let resp = serde_json::json!({"data": [{"id": "gpt-4o"}, {"id": "gpt-4o-mini"}]});
+        assert_eq!(
+            backend::model_ids(&resp).unwrap(),
+            vec!["gpt-4o", "gpt-4o-mini"]
+        );
+        let resp = serde_json::json!({"engines": ["mistral_7B_instruct"]});
+        assert_eq!(
+            backend::model_ids(&resp).unwrap(),
+            vec!["mistral_7B_instruct"]
+        );
+        assert!(backend::model_ids(&serde_json::json!({"error": "no key"})).is_err());
+        let mut builder = TrsltxBuilder::new();
+        builder
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .backend(EchoBackend {
+                answer: String::new(),
+            });
+        let trsltx = builder.model("echo").build().unwrap();
+        assert_eq!(trsltx.list_models().unwrap(), vec!["echo"]);
+        assert!(trsltx.validate_model().is_ok());
+        let err = builder.model("ehco").build().unwrap().validate_model();
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "Unknown model ehco, the available models are: echo"
+        );
+        // no silent fallback to the default model of the Textsynth server
+        let mut builder = TrsltxBuilder::new();
+        builder.input_lang("fr").output_lang("en").input_file("");
+        let trsltx = builder.model("llama3_8B").build().unwrap();
+        assert_eq!(trsltx.model_entry.unwrap().id, "llama3_8B");
+    }
+
+    #[test]
+    fn test_openai_chat() {
+        let params = CompletionParams {
+            model: "gpt-4o-mini".to_string(),
+            api_key: None,
+            temperature: 0.5,
+            top_p: None,
+            max_tokens: 100,
+            retry: RetryPolicy::default(),
+        };
+        let backend = OpenAiBackend::new("http://localhost:8000/v1/", None);
+        let (url, req) = backend.request("Bonjour", &params);
+        assert_eq!(url, "http://localhost:8000/v1/completions");
+        assert_eq!(req["prompt"], "Bonjour");
+        let resp = serde_json::json!({"choices": [{"text": "Hello"}]});
+        assert_eq!(backend.answer(&resp).unwrap(), "Hello");
+
+        let backend = backend.chat(true).api_key_env("VLLM_API_KEY");
+        let (url, req) = backend.request("Bonjour", &params);
+        assert_eq!(url, "http://localhost:8000/v1/chat/completions");
+        assert_eq!(req["messages"][0]["content"], "Bonjour");
+        assert_eq!(req["model"], "gpt-4o-mini");
+        let resp = serde_json::json!({"choices": [{"message": {"role": "assistant", "content": "Hello"}}]});
+        assert_eq!(backend.answer(&resp).unwrap(), "Hello");
+        assert!(backend
+            .answer(&serde_json::json!({"error": "no model"}))
+            .is_err());
+        // top_p is only sent if it is given
+        assert!(req.get("top_p").is_none());
+        let params = CompletionParams {
+            top_p: Some(0.5),
+            api_key: Some("key".to_string()),
+            ..params
+        };
+        assert_eq!(backend.request("Bonjour", &params).1["top_p"], 0.5);
+        let (_, _, req) = ts_request("Bonjour", &None, &params).unwrap();
+        assert_eq!(req["top_p"], 0.5);
+    }
+
+    #[test]
+    fn test_ollama_url() {
+        let params = CompletionParams {
+            model: "mistral".to_string(),
+            api_key: None,
+            temperature: 0.5,
+            top_p: None,
+            max_tokens: 100,
+            retry: RetryPolicy::default(),
+        };
+        for url in [
+            None,
+            Some("http://localhost:11434"),
+            Some("http://localhost:11434/v1/"),
+        ] {
+            let (url, _) = OpenAiBackend::ollama(url).request("Bonjour", &params);
+            assert_eq!(url, "http://localhost:11434/v1/completions");
+        }
+        assert!(!OpenAiBackend::ollama(None).supports_grammar());
+    }
+
+    #[test]
+    fn test_deepl_xml() {
+        let latex = r#"Soit \textbf{une} fonction $f < 1$ (voir \eqref{eq:f}) % note
+\begin{equation} f(x) = x \end{equation}
+Fin~\cite[p.~2]{knuth}."#;
+        let (xml, originals) = backend::latex_to_xml(latex);
+        assert_eq!(
+            xml,
+            r#"Soit <x i="0"/>une<x i="1"/> fonction <x i="2"/> (voir <x i="3"/>) <x i="4"/>
+<x i="5"/>
+Fin<x i="6"/>."#
+        );
+        assert_eq!(originals[2], "$f < 1$");
+        assert_eq!(originals[6], "~\\cite[p.~2]{knuth}");
+        assert_eq!(backend::xml_to_latex(&xml, &originals), latex);
+        let (xml, originals) = backend::latex_to_xml("a < b & c");
+        assert_eq!(xml, r#"a &lt; b <x i="0"/> c"#);
+        assert_eq!(backend::xml_to_latex(&xml, &originals), "a < b & c");
+        // the markup looking like an entity is restored as it is
+        let latex = r"Voir \url{a.org/?x=1&amp;y=2} % a &lt; b";
+        let (xml, originals) = backend::latex_to_xml(latex);
+        assert_eq!(backend::xml_to_latex(&xml, &originals), latex);
+    }
+
+    #[test]
+    fn test_set_api_key() {
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .api_key("key1")
+            .build()
+            .unwrap();
+        assert_eq!(trsltx.api_key.as_deref(), Some("key1"));
+        trsltx.set_api_key("key2");
+        assert_eq!(trsltx.api_key.as_deref(), Some("key2"));
+        assert!(trsltx.set_api_key_file("test/no_such_file.txt").is_err());
+        let path = std::env::temp_dir().join("trsltx_test_api_key.txt");
+        std::fs::write(&path, "key3\n").unwrap();
+        trsltx.set_api_key_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(trsltx.api_key.as_deref(), Some("key3"));
+    }
+
+    #[test]
+    fn test_custom_backend() {
+        let mut trsltx = echo_trsltx("Hello world.");
+        trsltx.body = "Bonjour le monde.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        assert_eq!(trsltx.body_translated, "Hello world.");
+        let meta = trsltx.meta();
+        assert_eq!(meta.chunks.len(), 1);
+        assert_eq!(meta.chunks[0].chunk_type, "translate");
+        assert_eq!(meta.chunks[0].word_count, 3);
+        assert!(!meta.chunks[0].used_grammar);
+        assert_eq!(meta.chunks[0].translation, "Hello world.");
+        assert_eq!(
+            meta.chunks[0].source_hash,
+            format!("{:016x}", cache::chunk_hash("Bonjour le monde."))
+        );
+        assert_eq!(meta.chunks[0].model, meta.model);
+        assert!(meta.chunks[0].started_at > 0);
+        assert!(meta.chunks[0].finished_at >= meta.chunks[0].started_at);
+
+        let backend: Box<dyn LlmBackend> = Box::new(EchoBackend {
+            answer: "Good evening.".to_string(),
+        });
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .boxed_backend(backend)
+            .build()
+            .unwrap();
+        trsltx.body = "Bonsoir.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        assert_eq!(trsltx.body_translated, "Good evening.");
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = std::env::temp_dir().join("trsltx_test_checkpoint");
+        std::fs::create_dir_all(&dir).unwrap();
+        let output_file = dir.join("doc_en.tex").to_string_lossy().to_string();
+        let checkpoint_file = checkpoint::checkpoint_file_name(&output_file);
+        assert!(checkpoint_file.ends_with("doc_en.trsltx.json"));
+        let mut saved = Checkpoint {
+            input_lang: "fr".to_string(),
+            output_lang: "en".to_string(),
+            model: "mistral47b".to_string(),
+            chunks: vec![],
+        };
+        saved.record(0, "Bonjour.", "Hi.");
+        saved.record(0, "Bonjour.", "Hello.");
+        // the source of this chunk has been modified since the last run
+        saved.record(1, "Salut !", "Hi!");
+        assert_eq!(saved.chunks.len(), 2);
+        assert_eq!(saved.translation(0, "Bonjour."), Some("Hello."));
+        assert_eq!(saved.translation(1, "Salut."), None);
+        std::fs::write(&checkpoint_file, serde_json::to_string(&saved).unwrap()).unwrap();
+
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .output_file(&output_file)
+            .backend(EchoBackend {
+                answer: "Bye.".to_string(),
+            })
+            .build()
+            .unwrap();
+        trsltx.body = "Bonjour.\n%trsltx-split\nSalut.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        assert_eq!(trsltx.chunks_translated, vec!["Hello.", "Bye."]);
+        // all the chunks are translated
+        assert!(!std::path::Path::new(&checkpoint_file).exists());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let dir = std::env::temp_dir().join("trsltx_test_cancel");
+        std::fs::create_dir_all(&dir).unwrap();
+        let output_file = dir.join("doc_en.tex").to_string_lossy().to_string();
+        let checkpoint_file = checkpoint::checkpoint_file_name(&output_file);
+        let _ = std::fs::remove_file(&checkpoint_file);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .output_file(&output_file)
+            .cancel_flag(cancel.clone())
+            .backend(EchoBackend {
+                answer: "Hello.".to_string(),
+            })
+            .build()
+            .unwrap();
+        // the application stops the translation after the first chunk
+        let cancel_cb = cancel.clone();
+        trsltx.set_progress_callback(move |e| {
+            if let ProgressEvent::ChunkTranslated { .. } = e {
+                cancel_cb.store(true, Ordering::Relaxed);
+            }
+        });
+        trsltx.body = "Bonjour.\n%trsltx-split\nSalut.\n%trsltx-split\nAu revoir.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        assert!(trsltx.is_cancelled());
+        assert_eq!(
+            trsltx.chunks_translated,
+            vec!["Hello.", "Salut.", "Au revoir."]
+        );
+        assert_eq!(
+            trsltx.untranslated_chunks(),
+            vec![(2, Untranslated::Failed), (3, Untranslated::Failed)]
+        );
+        // the checkpoint keeps the translated chunk for the next run
+        let json = std::fs::read_to_string(&checkpoint_file).unwrap();
+        let saved: Checkpoint = serde_json::from_str(&json).unwrap();
+        assert_eq!(saved.translation(0, "Bonjour."), Some("Hello."));
+        assert_eq!(saved.chunks.len(), 1);
+        std::fs::remove_file(&checkpoint_file).unwrap();
+    }
+
+    #[test]
+    fn test_chunk_selection() {
+        assert_eq!(
+            parse_chunk_selection("3,7,10-12").unwrap(),
+            vec![3, 7, 10, 11, 12]
+        );
+        assert_eq!(parse_chunk_selection(" 2 , 1-2").unwrap(), vec![1, 2]);
+        for spec in ["0", "4-2", "a", "1,,2", ""] {
+            assert!(parse_chunk_selection(spec).is_err(), "{:?}", spec);
+        }
+
+        let output_file = std::env::temp_dir().join("trsltx_selection_en.tex");
+        let output_file = output_file.to_string_lossy().to_string();
+        std::fs::write(
+            &output_file,
+            "\\title{Old}\n\\begin{document}\nHello.\n%trsltx-split\nBad.\n%trsltx-split\n\\[ x \\]\n\\end{document}\n",
+        )
+        .unwrap();
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .output_file(&output_file)
+            .checkpoint(false)
+            .backend(EchoBackend {
+                answer: "Hi.".to_string(),
+            })
+            .build()
+            .unwrap();
+        trsltx.preamble = "\\title{Titre}\n".to_string();
+        trsltx.body = "Bonjour.\n%trsltx-split\nSalut.\n%trsltx-split\n\\[ x \\]".to_string();
+        trsltx.extract_chunks().unwrap();
+        assert!(trsltx.load_previous_translation(&[4]).is_err());
+        trsltx.load_previous_translation(&[2]).unwrap();
+        trsltx.translate();
+        assert_eq!(trsltx.chunks_translated, vec!["Hello.", "Hi.", "\\[ x \\]"]);
+        assert_eq!(trsltx.preamble, "\\title{Old}\n");
+
+        // the translation does not match the split of the source
+        trsltx.chunks.pop();
+        assert!(matches!(
+            trsltx.load_previous_translation(&[1]),
+            Err(TrsltxError::Parse(_))
+        ));
+        std::fs::remove_file(&output_file).unwrap();
+    }
+
+    #[test]
+    fn test_translation_cache() {
+        assert_eq!(cache::chunk_hash(""), 0xcbf29ce484222325);
+        assert_eq!(cache::chunk_hash("a"), 0xaf63dc4c8601ec8c);
+        let path = std::env::temp_dir().join("trsltx_test_cache.json");
+        let path = path.to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&path);
+        let translate = |body: &str, answer: &str| {
+            let mut trsltx = TrsltxBuilder::new()
+                .input_lang("fr")
+                .output_lang("en")
+                .input_file("")
+                .cache_file(&path)
+                .backend(EchoBackend {
+                    answer: answer.to_string(),
+                })
+                .build()
+                .unwrap();
+            trsltx.body = body.to_string();
+            trsltx.extract_chunks().unwrap();
+            trsltx.translate_chunks();
+            trsltx.chunks_translated
+        };
+        assert_eq!(
+            translate("Bonjour.\n%trsltx-split\nSalut.", "Hello."),
+            vec!["Hello.", "Hello."]
+        );
+        let cache = TranslationCache::read(&path).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(
+            cache.get(&cache::cache_key("Salut.", "fr", "en", "mistral47b")),
+            Some("Hello.")
+        );
+        // only the modified chunk is sent to the backend
+        assert_eq!(
+            translate("Bonjour.\n%trsltx-split\nSalut !", "Hi!"),
+            vec!["Hello.", "Hi!"]
+        );
+        assert_eq!(TranslationCache::read(&path).unwrap().len(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_subfiles() {
+        let dir = std::env::temp_dir().join("trsltx_test_subfiles");
+        std::fs::create_dir_all(&dir).unwrap();
+        let doc = |body: &str| {
+            format!(
+                "\\documentclass[main]{{subfiles}}\n\\begin{{document}}\n{}\n\\end{{document}}\n",
+                body
+            )
+        };
+        std::fs::write(dir.join("ch1.tex"), doc("Bonjour.\n%trsltx-split\nSalut.")).unwrap();
+        std::fs::write(
+            dir.join("loop.tex"),
+            doc("\\subfile{loop}\n%trsltx-split\nFin."),
+        )
+        .unwrap();
+        let main_file = dir.join("main_fr.tex").to_string_lossy().to_string();
+        std::fs::write(&main_file, doc("\\subfile{ch1}")).unwrap();
+        let build = || {
+            TrsltxBuilder::new()
+                .input_lang("fr")
+                .output_lang("en")
+                .input_file(&main_file)
+                .backend(EchoBackend {
+                    answer: "Hello.".to_string(),
+                })
+                .build()
+                .unwrap()
+        };
+        let mut trsltx = build();
+        trsltx.body = "\\subfile{ch1}".to_string();
+        trsltx.body_translated = trsltx.body.clone();
+        trsltx.process_subfiles().unwrap();
+        assert_eq!(trsltx.body_translated, "\\subfile{ch1_en}");
+        let ch1 = std::fs::read_to_string(dir.join("ch1_en.tex")).unwrap();
+        assert!(ch1.contains("Hello."));
+        let mut trsltx = build();
+        trsltx.body = "\\subfile{loop}".to_string();
+        let err = trsltx.process_subfiles().unwrap_err();
+        assert!(matches!(err, TrsltxError::Parse(_)));
+        assert!(err.to_string().contains("Cycle"));
+    }
+
+    #[test]
+    fn test_batch() {
+        let dir = std::env::temp_dir().join("trsltx_test_batch");
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in ["a.tex", "a_fr.tex", "a_en.tex", "b.tex"] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        let dir = dir.to_string_lossy().to_string();
+        let files = expand_file_patterns(&[
+            format!("{}/*.tex", dir),
+            format!("{}/b.tex", dir),
+            "c.tex".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(
+            files,
+            vec![
+                format!("{}/a.tex", dir),
+                format!("{}/b.tex", dir),
+                "c.tex".to_string()
+            ]
+        );
+        assert!(expand_file_patterns(&[format!("{}/*.md", dir)]).is_err());
+
+        let report = |model: &str, sent, mean| RunReport {
+            model: model.to_string(),
+            chunks: 4,
+            translated_chunks: sent,
+            sent_chunks: sent,
+            mean_distance: Some(mean),
+            max_distance: Some(mean as usize),
+            total_latency_ms: 100 * sent as u64,
+            ..RunReport::default()
+        };
+        let mut batch = BatchReport::default();
+        batch.add("a.tex", report("mistral", 1, 1.0));
+        batch.add("b.tex", report("mistral", 3, 3.0));
+        batch.add("c.tex", report("llama", 0, 0.0));
+        assert_eq!(batch.files.len(), 3);
+        assert_eq!(batch.total.chunks, 12);
+        assert_eq!(batch.total.translated_chunks, 4);
+        assert_eq!(batch.total.mean_distance, Some(2.5));
+        assert_eq!(batch.total.max_distance, Some(3));
+        assert_eq!(batch.total.mean_latency_ms, 100);
+        assert_eq!(batch.total.model, "mistral, llama");
+    }
+
+    #[test]
+    fn test_project() {
+        let root = std::env::temp_dir().join("trsltx_test_project");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sections")).unwrap();
+        std::fs::write(
+            root.join("main.tex"),
+            "\\documentclass{article}\n\\input{macros}\n\\addbibresource{refs.bib}\n\
+             \\begin{document}\n\\input{sections/intro}\n% \\input{old}\n\
+             \\include{sections/chap}\n\\input{sections/intro}\n\\end{document}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("sections/intro.tex"),
+            "Bonjour.\n%trsltx-split\nSalut.\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("sections/chap.tex"), "Salut.\n\\input{loop}\n").unwrap();
+        std::fs::write(root.join("loop.tex"), "\\input{sections/chap}\n").unwrap();
+        for file in ["macros.tex", "refs.bib", "fig.png", "main.aux", ".hidden"] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+        let main_file = root.join("main.tex").to_string_lossy().to_string();
+        let err = Project::discover(&main_file).unwrap_err();
+        assert!(err.to_string().contains("Cycle"));
+
+        std::fs::write(root.join("sections/chap.tex"), "Salut.\n").unwrap();
+        let project = Project::discover(&main_file).unwrap();
+        let paths: Vec<String> = project
+            .documents
+            .iter()
+            .map(|d| d.path.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(
+            paths,
+            vec!["main.tex", "sections/intro.tex", "sections/chap.tex"]
+        );
+        assert!(!project.documents[0].fragment && project.documents[1].fragment);
+        assert_eq!(
+            project.bib_files,
+            vec![std::path::PathBuf::from("refs.bib")]
+        );
+        let out_dir = project.default_output_dir("en");
+        assert!(out_dir.ends_with("trsltx_test_project_en"));
+        let _ = std::fs::remove_dir_all(&out_dir);
+        // macros.tex, refs.bib, fig.png and loop.tex, no longer included
+        assert_eq!(project.copy_assets(&out_dir).unwrap(), 4);
+        assert!(out_dir.join("macros.tex").exists() && !out_dir.join("main.aux").exists());
+
+        let mut builder = TrsltxBuilder::new();
+        builder
+            .input_lang("fr")
+            .output_lang("en")
+            .backend(EchoBackend {
+                answer: "Hello.".to_string(),
+            });
+        let mut trsltx = project
+            .document(&mut builder, &project.documents[1], &out_dir)
+            .unwrap();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate();
+        trsltx.write_file().unwrap();
+        let intro = std::fs::read_to_string(out_dir.join("sections/intro.tex")).unwrap();
+        assert_eq!(intro, "Hello.\n%trsltx-split\nHello.");
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+
+    #[test]
+    fn test_bib_translation() {
+        let bib = "@string{jan = \"January\"}\n\
+                   @article{dupont2020,\n  author = {Dupont, Jean},\n  \
+                   title = {Sur l'équation de {Fourier}},\n  month = jan,\n  year = 2020,\n  \
+                   note = \"Pr\\'epublication {arXiv}\"\n}\n\
+                   @book{martin,\n  title = {Un livre},\n  titleaddon = {déjà traduit},\n}\n";
+        let entries = parse_bib_entries(bib);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            (entries[0].kind.as_str(), entries[0].key.as_str()),
+            ("article", "dupont2020")
+        );
+        let names: Vec<&str> = entries[0].fields.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["author", "title", "month", "year", "note"]);
+        assert_eq!(entries[0].fields[2].value, None);
+        assert_eq!(
+            entries[0].fields[4].value.as_deref(),
+            Some("Pr\\'epublication {arXiv}")
+        );
+        assert_eq!(
+            bib::protected_groups("{T}he {DNA} of \\emph{words} {\\'e}t{\\'e}"),
+            vec!["{DNA}"]
+        );
+
+        let trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("refs.bib")
+            .backend(EchoBackend {
+                answer: "On the {Fourier} equation".to_string(),
+            })
+            .build()
+            .unwrap();
+        let translation = trsltx.translate_bib(bib, &["title", "note"]);
+        // the book already has a titleaddon, the note loses {arXiv}
+        assert_eq!((translation.fields, translation.translated), (2, 1));
+        assert_eq!(translation.failed, vec!["dupont2020: note ({arXiv} lost)"]);
+        assert!(translation.content.contains(
+            "  title = {Sur l'équation de {Fourier}},\n  titleaddon = {On the {Fourier} equation},\n  month"
+        ));
+        assert_eq!(parse_bib_entries(&translation.content)[0].fields.len(), 6);
+        assert_eq!(bib::bib_lang_file_name("refs.bib", "en"), "refs_en.bib");
+    }
+
+    #[test]
+    fn test_stdin_fragment() {
+        let build = |source: LaTeXSource| {
+            TrsltxBuilder::new()
+                .input_lang("fr")
+                .output_lang("en")
+                .input_source(source)
+                .backend(EchoBackend {
+                    answer: "Hello.".to_string(),
+                })
+                .build()
+                .unwrap()
+        };
+        // a chunk sent by an editor, without preamble
+        let mut trsltx = build(LaTeXSource::Stdin);
+        trsltx
+            .read_latex("Bonjour.\r\n%trsltx-split\nSalut.")
+            .unwrap();
+        assert!(trsltx.fragment);
+        trsltx.insert_splits(1000);
+        assert_eq!(trsltx.body, "Bonjour.\n%trsltx-split\nSalut.");
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate();
+        let mut output = vec![];
+        trsltx.write_to(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Hello.\n%trsltx-split\nHello."
+        );
+        let mut trsltx = build(LaTeXSource::Stdin);
+        trsltx
+            .read_latex("\\documentclass{article}\n\\begin{document}\nBonjour.\n\\end{document}\n")
+            .unwrap();
+        assert!(!trsltx.fragment);
+        assert_eq!(trsltx.preamble, "\\documentclass{article}\n");
+        // a file needs a \begin{document}
+        let mut trsltx = build(LaTeXSource::File("chunk.tex".to_string()));
+        assert!(trsltx.read_latex("Bonjour.").is_err());
+    }
+
+    #[test]
+    fn test_translate_str() {
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .backend(EchoBackend {
+                answer: "Hello.".to_string(),
+            })
+            .build()
+            .unwrap();
+        assert!(trsltx.read_file().is_err());
+        assert_eq!(
+            trsltx
+                .translate_str("Bonjour.\n%trsltx-split\nSalut.")
+                .unwrap(),
+            "Hello.\n%trsltx-split\nHello."
+        );
+        // a whole document after a fragment
+        let english = trsltx
+            .translate_str(
+                "\\documentclass{article}\n\\begin{document}\nBonjour.\n%trsltx-split\nSalut.\n\\end{document}\n",
+            )
+            .unwrap();
+        assert_eq!(
+            english,
+            "\\documentclass{article}\n\\newenvironment{trsltx}{}{}\n\n\\newcommand{\\commandevide}{}\n\\begin{document}Hello.\n%trsltx-split\nHello.\\end{document}\n"
+        );
+        assert!(trsltx.untranslated_chunks().is_empty());
+        // nothing is left from the previous calls
+        assert_eq!(
+            trsltx
+                .translate_str("Salut.\n%trsltx-split\nAu revoir.")
+                .unwrap(),
+            "Hello.\n%trsltx-split\nHello."
+        );
+        assert_eq!(trsltx.chunks_meta.len(), 2);
+    }
+
+    #[test]
+    fn test_builder_options() {
+        let build = |use_grammar: bool| {
+            TrsltxBuilder::new()
+                .input_lang("fr")
+                .output_lang("en")
+                .grammar(use_grammar)
+                .prompt_template("Translate from <lang_in> to <lang_out>: <chunk>")
+                .build()
+                .unwrap()
+        };
+        let trsltx = build(true);
+        let request = ChunkRequest::new("Bonjour.", &trsltx, 100, None)
+            .unwrap()
+            .unwrap();
+        assert!(request.grammar.is_some());
+        assert!(request.question.starts_with("Translate from "));
+        let trsltx = build(false);
+        let request = ChunkRequest::new("Bonjour.", &trsltx, 100, None)
+            .unwrap()
+            .unwrap();
+        assert!(request.grammar.is_none());
+        let err = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .prompt_template(" ")
+            .build()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "The prompt template is empty");
+    }
+
+    #[test]
+    fn test_input_files() {
+        let dir = std::env::temp_dir().join("trsltx_test_input");
+        std::fs::create_dir_all(dir.join("sections")).unwrap();
+        // the paths of \input are relative to the main file, even in an included file
+        std::fs::write(
+            dir.join("sections/intro.tex"),
+            "Bonjour.\n%trsltx-split\n%trsltx-begin-ignore\n\\input{sections/details}\n%trsltx-end-ignore\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("sections/details.tex"),
+            "Salut.\n%trsltx-split\nFin.",
+        )
+        .unwrap();
+        let main_file = dir.join("main_fr.tex").to_string_lossy().to_string();
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file(&main_file)
+            .backend(EchoBackend {
+                answer: "Hello.".to_string(),
+            })
+            .build()
+            .unwrap();
+        trsltx.body = "\\input{sections/intro}\n\\include{sections/intro}".to_string();
+        trsltx.body_translated = trsltx.body.clone();
+        trsltx.process_subfiles().unwrap();
+        assert_eq!(
+            trsltx.body_translated,
+            "\\input{sections/intro_en}\n\\include{sections/intro_en}"
+        );
+        let intro = std::fs::read_to_string(dir.join("sections/intro_en.tex")).unwrap();
+        assert!(intro.starts_with("Hello."));
+        assert!(intro.contains("\\input{sections/details_en}"));
+        assert!(!intro.contains("\\begin{document}"));
+        let details = std::fs::read_to_string(dir.join("sections/details_en.tex")).unwrap();
+        assert_eq!(details, "Hello.\n%trsltx-split\nHello.");
+        // with an output directory, the split file and the translations are written in it,
+        // with the same relative paths
+        let out_dir = dir.join("out");
+        let _ = std::fs::remove_dir_all(&out_dir);
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let split_file = out_dir.join("main_fr.tex").to_string_lossy().to_string();
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file(&split_file)
+            .output_file(&out_dir.join("main_en.tex").to_string_lossy())
+            .backend(EchoBackend {
+                answer: "Hello.".to_string(),
+            })
+            .build()
+            .unwrap();
+        trsltx.set_source_dir(&dir);
+        trsltx.body = "\\input{sections/intro}".to_string();
+        trsltx.body_translated = trsltx.body.clone();
+        trsltx.process_subfiles().unwrap();
+        assert_eq!(trsltx.body_translated, "\\input{sections/intro_en}");
+        let intro = std::fs::read_to_string(out_dir.join("sections/intro_en.tex")).unwrap();
+        assert!(intro.contains("\\input{sections/details_en}"));
+        assert!(out_dir.join("sections/details_en.tex").exists());
+    }
+
+    #[test]
+    fn test_bilingual() {
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .bilingual(true)
+            .backend(EchoBackend {
+                answer: "Hello.".to_string(),
+            })
+            .build()
+            .unwrap();
+        trsltx.body =
+            "Bonjour.\n\nSalut.\n%trsltx-split\n%trsltx-begin-ignore\nx\n%trsltx-end-ignore"
+                .to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        let expected = "%trsltx-source-begin\n% Bonjour.\n%\n% Salut.\n%trsltx-source-end\nHello.";
+        assert!(trsltx.body_translated.starts_with(expected));
+        // the unchanged chunks are not repeated
+        assert_eq!(trsltx.body_translated.matches(SOURCE_BEGIN).count(), 1);
+        assert_eq!(trsltx.chunks_translated[0], "Hello.");
+        // the sources are ignored when the bilingual output is translated again
+        // and removed by strip_source_comments
+        assert_eq!(
+            remove_source_comments(&trsltx.body_translated),
+            trsltx
+                .body_translated
+                .replace(&source_comment("Bonjour.\n\nSalut."), "")
+        );
+        let path = std::env::temp_dir().join("trsltx_bilingual_en.tex");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, &trsltx.body_translated).unwrap();
+        assert_eq!(strip_source_comments(path).unwrap(), 1);
+        assert!(std::fs::read_to_string(path)
+            .unwrap()
+            .starts_with("Hello.\n%trsltx-split\n"));
+        assert_eq!(strip_source_comments(path).unwrap(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// backend answering "Hello." and keeping the prompts
+    #[derive(Debug, Default)]
+    struct RecordingBackend {
+        prompts: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl LlmBackend for RecordingBackend {
+        fn complete(
+            &self,
+            prompt: &str,
+            _grammar: Option<&str>,
+            _params: &CompletionParams,
+        ) -> Result<String, String> {
+            self.prompts.lock().unwrap().push(prompt.to_string());
+            Ok("\\begin{trsltx}Hello.\\end{trsltx}".to_string())
+        }
+    }
+
+    #[test]
+    fn test_consistency_report() {
+        assert_eq!(
+            extracts_citation_keys("\\cite[p. 2]{a, b} et \\citep{b,c}"),
+            vec!["a", "b", "c"]
+        );
+        let source = "Voir \\eqref{eq:1} et \\cite{knuth}.\n\\label{sec:un}\\label{sec:deux}";
+        let translation = "See \\eqref{eq:1} and \\cite{lamport}.\n\\label{sec:one}";
+        let issues = consistency::compare_keys(3, source, translation);
+        let lines: Vec<String> = issues.iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "chunk 3: label sec:un renamed sec:one",
+                "chunk 3: label sec:deux lost",
+                "chunk 3: citation knuth renamed lamport",
+            ]
+        );
+        let json = serde_json::to_value(&issues[1]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"chunk": 3, "kind": "label", "key": "sec:deux", "change": "lost"})
+        );
+        assert!(consistency::compare_keys(1, translation, translation).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_compile() {
+        use std::os::unix::fs::PermissionsExt;
+        let log = "This is pdfTeX\n! Undefined control sequence.\nl.5 \\foo\n";
+        assert_eq!(
+            compile::first_log_error(log),
+            Some("Undefined control sequence.".to_string())
+        );
+        // fake engine: the documents containing BAD do not compile
+        let engine = std::env::temp_dir().join("trsltx_fake_latex.sh");
+        std::fs::write(
+            &engine,
+            "#!/bin/sh\nfor f; do :; done\n! grep -q BAD \"$f\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&engine, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let engine = engine.to_str().unwrap();
+
+        let mut trsltx = test_trsltx();
+        trsltx.body = "Un.\n%trsltx-split\nDeux.\n%trsltx-split\nBAD source.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.assemble(
+            ["One.", "BAD two.", "BAD source."]
+                .iter()
+                .map(|c| (c.to_string(), ChunkMeta::default()))
+                .collect(),
+        );
+        // the third chunk does not compile in the source either
+        let errors = trsltx.check_compile(engine).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].chunk, 2);
+        assert_eq!(
+            trsltx.chunks_translated[1],
+            format!(
+                "{} {}\nBAD two.",
+                compile::COMPILE_ERROR_MARK,
+                errors[0].message
+            )
+        );
+        assert!(trsltx.body_translated.contains(compile::COMPILE_ERROR_MARK));
+        assert!(trsltx.check_compile("true").unwrap().is_empty());
+        std::fs::remove_file(engine).unwrap();
+    }
+
+    #[test]
+    fn test_review_callback() {
+        let backend = RecordingBackend::default();
+        let prompts = backend.prompts.clone();
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .backend(backend)
+            .build()
+            .unwrap();
+        let reviews = Arc::new(std::sync::Mutex::new(vec![]));
+        let reviews_cb = reviews.clone();
+        trsltx.set_review_callback(move |review| {
+            let mut reviews = reviews_cb.lock().unwrap();
+            reviews.push((review.index, review.translation.to_string()));
+            match reviews.len() {
+                1 => ReviewDecision::Retry {
+                    hint: Some("Say hello.".to_string()),
+                },
+                2 => ReviewDecision::Accept,
+                _ => ReviewDecision::KeepOriginal,
+            }
+        });
+        trsltx.body = "Bonjour.\n%trsltx-split\nSalut.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        assert_eq!(
+            *reviews.lock().unwrap(),
+            vec![
+                (0, "Hello.".to_string()),
+                (0, "Hello.".to_string()),
+                (1, "Hello.".to_string())
+            ]
+        );
+        assert_eq!(trsltx.chunks_translated, vec!["Hello.", "Salut."]);
+        let prompts = prompts.lock().unwrap();
+        assert!(!prompts[0].contains("Say hello."));
+        assert!(prompts[1].contains("Say hello.\n"));
+        assert!(!prompts[2].contains("Say hello."));
+    }
+
+    #[test]
+    fn test_run_report() {
+        let mut trsltx = echo_trsltx("Hello \\label{b}.");
+        trsltx.body =
+            "Bonjour \\label{a}.\n%trsltx-split\n%trsltx-begin-ignore\nx\n%trsltx-end-ignore\n"
+                .to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        let report = trsltx.run_report();
+        // with the empty chunks around the ignored region
+        assert_eq!(report.chunks, 4);
+        assert_eq!(
+            (
+                report.translated_chunks,
+                report.copied_chunks,
+                report.failed_chunks
+            ),
+            (1, 3, 0)
+        );
+        assert_eq!(report.requests, 1);
+        assert!(report.estimated_tokens > 0);
+        assert_eq!(report.max_distance, Some(0));
+        assert_eq!(report.warnings, vec!["chunk 1: label a renamed b"]);
+        assert!(trsltx.untranslated_chunks().is_empty());
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["failed_chunks"], 0);
+
+        #[derive(Debug)]
+        struct FailingBackend;
+        impl LlmBackend for FailingBackend {
+            fn complete(
+                &self,
+                _prompt: &str,
+                _grammar: Option<&str>,
+                _params: &CompletionParams,
+            ) -> Result<String, String> {
+                Err("server down".to_string())
+            }
+        }
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .backend(FailingBackend)
+            .build()
+            .unwrap();
+        trsltx.body = "Bonjour.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        let report = trsltx.run_report();
+        assert_eq!(report.failed_chunks, 1);
+        assert_eq!(report.translated_chunks, 0);
+        assert_eq!(
+            trsltx.untranslated_chunks(),
+            vec![(1, Untranslated::Failed)]
+        );
+    }
+
+    #[test]
+    fn test_retry_error() {
+        // a bad translation, then a server error
+        #[derive(Debug, Default)]
+        struct FlakyBackend(std::sync::Mutex<usize>);
+        impl LlmBackend for FlakyBackend {
+            fn complete(
+                &self,
+                _prompt: &str,
+                _grammar: Option<&str>,
+                _params: &CompletionParams,
+            ) -> Result<String, String> {
+                let mut calls = self.0.lock().unwrap();
+                *calls += 1;
+                match *calls {
+                    1 => Ok("\\begin{trsltx}{a}{b}{c}\\end{trsltx}".to_string()),
+                    _ => Err("server down".to_string()),
+                }
+            }
+        }
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .backend(FlakyBackend::default())
+            .build()
+            .unwrap();
+        trsltx.body = "Bonjour.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        // the first translation is kept
+        assert!(trsltx.chunks_translated[0].contains("{a}{b}{c}"));
+        assert_eq!(trsltx.run_report().failed_chunks, 0);
+    }
+
+    #[test]
+    fn test_translate_comments() {
+        let latex = "Un taux de 5\\% % TODO: à revoir\n% tout\nFin.";
+        let (protected, originals) = protect_comments(latex, vec!["\\todo{x}".to_string()]);
+        assert_eq!(
+            protected,
+            "Un taux de 5\\% \\trsltxplaceholderB{}\n\\trsltxplaceholderC{}\nFin."
+        );
+        assert_eq!(originals[1..], ["% TODO: à revoir", "% tout"]);
+        assert_eq!(restore_commands(&protected, &originals), latex);
+
+        let paths = r#"Voir \href{https://exemple.fr/a%20b}{le site}, \url{http://x.fr}
+\includegraphics[width=5cm]{figures/courbe.pdf}\input{chapitres/intro}\\urlstyle{same}"#;
+        let (protected, originals) = protect_paths(paths, vec![]);
+        assert_eq!(
+            protected,
+            r#"Voir \trsltxplaceholderA{}{le site}, \trsltxplaceholderB{}
+\trsltxplaceholderC{}\trsltxplaceholderD{}\\urlstyle{same}"#
+        );
+        assert_eq!(originals[0], r#"\href{https://exemple.fr/a%20b}"#);
+        assert_eq!(restore_commands(&protected, &originals), paths);
+        for translate_comments in [false, true] {
+            let backend = RecordingBackend::default();
+            let prompts = backend.prompts.clone();
+            let mut trsltx = TrsltxBuilder::new()
+                .input_lang("fr")
+                .output_lang("en")
+                .input_file("")
+                .translate_comments(translate_comments)
+                .backend(backend)
+                .build()
+                .unwrap();
+            trsltx.body = latex.to_string();
+            trsltx.extract_chunks().unwrap();
+            trsltx.translate_chunks();
+            let prompts = prompts.lock().unwrap();
+            assert_eq!(prompts[0].contains("à revoir"), translate_comments);
+        }
+    }
+
+    #[test]
+    fn test_keep_markers() {
+        let backend = RecordingBackend::default();
+        let prompts = backend.prompts.clone();
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .input_file("")
+            .translate_comments(true)
+            .backend(backend)
+            .build()
+            .unwrap();
+        let chunk = "Selon \\trsltxkeep{Le Petit Prince}, \\url{http://x.fr}\n%trsltx-ignore-next-line\nVive la \\url{http://y.fr} !\nFin.";
+        let (protected, originals) = trsltx.protect_chunk(chunk);
+        assert_eq!(
+            protected,
+            "Selon \\trsltxplaceholderA{}, \\trsltxplaceholderB{}\n\\trsltxplaceholderD{}\nFin."
+        );
+        assert_eq!(
+            originals[3],
+            "%trsltx-ignore-next-line\nVive la \\trsltxplaceholderC{} !"
+        );
+        assert_eq!(restore_commands(&protected, &originals), chunk);
+        trsltx.body = chunk.to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        let prompts = prompts.lock().unwrap();
+        assert!(!prompts[0].contains("Petit Prince"));
+        assert!(!prompts[0].contains("Vive"));
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        let mut trsltx = echo_trsltx("Hello.");
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let events_cb = events.clone();
+        trsltx.set_progress_callback(move |e| events_cb.lock().unwrap().push(e));
+        trsltx.body = "Bonjour.\n%trsltx-begin-ignore\nx\n%trsltx-end-ignore\nSalut.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events[0],
+            ProgressEvent::ChunkStarted {
+                index: 0,
+                total: 3,
+                char_count: 8
+            }
+        );
+        assert!(matches!(
+            events[1],
+            ProgressEvent::ChunkTranslated {
+                index: 0,
+                attempt: 1,
+                ..
+            }
+        ));
+        assert_eq!(events[2], ProgressEvent::ChunkSkipped { index: 1 });
+        // the echo translation is close enough to the source
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, ProgressEvent::ChunkRetried { .. })));
+        assert_eq!(
+            events.last(),
+            Some(&ProgressEvent::Done { total_chunks: 3 })
+        );
+    }
+
+    #[test]
+    fn test_observer() {
+        #[derive(Default)]
+        struct Recorder(Arc<std::sync::Mutex<Vec<String>>>);
+        impl TranslationObserver for Recorder {
+            fn on_chunk_start(&self, index: usize, total: usize, _char_count: usize) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("start {}/{}", index, total));
+            }
+            fn on_chunk_done(
+                &self,
+                index: usize,
+                _distmin: usize,
+                attempts: u32,
+                _: std::time::Duration,
+            ) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("done {} ({})", index, attempts));
+            }
+            fn on_chunk_failed(&self, index: usize, error: &str) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("failed {}: {}", index, error));
+            }
+            fn on_done(&self, total_chunks: usize) {
+                self.0.lock().unwrap().push(format!("end {}", total_chunks));
+            }
+        }
+        let mut trsltx = TrsltxBuilder::new()
+            .input_lang("fr")
+            .output_lang("en")
+            .backend(EchoBackend {
+                answer: "Hello.".to_string(),
+            })
+            .build()
+            .unwrap();
+        let recorder = Recorder::default();
+        let calls = recorder.0.clone();
+        trsltx.set_observer(recorder);
+        trsltx.body = "Bonjour.\n%trsltx-begin-ignore\nx\n%trsltx-end-ignore\nSalut.".to_string();
+        trsltx.extract_chunks().unwrap();
+        trsltx.translate_chunks();
+        // the ignored chunk is skipped, which the recorder does not observe
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "start 0/3",
+                "done 0 (1)",
+                "start 2/3",
+                "done 2 (1)",
+                "end 3"
+            ]
+        );
+        let recorder = Recorder::default();
+        ProgressEvent::ChunkFailed {
+            index: 4,
+            error: "server down".to_string(),
+        }
+        .notify(&recorder);
+        assert_eq!(*recorder.0.lock().unwrap(), vec!["failed 4: server down"]);
     }
 
     #[test]
@@ -654,7 +6415,7 @@ Answer:
             std::fs::read_to_string("test/trs_sample_gram.txt").expect("cannot read prompt");
         // grammar in "src/sample.ebnf"
         let grammar = std::fs::read_to_string("src/sample.ebnf").expect("cannot read grammar");
-        let str = complete_with_ts(&prompt, &None, "mistral47b".to_string()).unwrap();
+        let str = complete_with_ts(&prompt, &None, &ts_params()).unwrap().0;
         // print str in the terminal with true newlines
         println!("No grammar -------------------------------------------");
         let parts = str.split("\\n");
@@ -662,7 +6423,9 @@ Answer:
             println!("{}", part);
         }
 
-        let str = complete_with_ts(&prompt, &Some(grammar), "mistral47b".to_string()).unwrap();
+        let str = complete_with_ts(&prompt, &Some(grammar), &ts_params())
+            .unwrap()
+            .0;
         // print str in the terminal with true newlines
         println!("With grammar -------------------------------------------");
         let parts = str.split("\\n");
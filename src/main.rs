@@ -4,23 +4,306 @@
 
 //use std::path;
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 
 #[derive(Parser, Debug)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// LaTeX file to be translated, or "-" for reading the standard input;
+    /// repeat the option or give a pattern (e.g. 'chapters/*.tex') for translating
+    /// several files with the same settings
     #[clap(short, long, default_value = "test/simple.tex")]
-    file_init: String,
+    file_init: Vec<String>,
+    /// read the LaTeX source from the standard input and write the translation
+    /// to the standard output, as -f -; a source without \begin{document} is
+    /// translated as a fragment
+    #[clap(long, conflicts_with = "file_init")]
+    stdin: bool,
+    /// language of the LaTeX file, or "auto" for detecting it from the text
     #[clap(short, long, default_value = "fr")]
     input_lang: String,
     #[clap(short, long, default_value = "en")]
     output_lang: String,
-    #[clap(short, long, default_value = "1000")]
+    #[clap(short, long, default_value_t = trsltx::DEFAULT_SPLIT_LENGTH)]
     length_split: usize,
     #[clap(short, long, default_value = "mistral47b")]
     model: String,
+    /// sampling temperature in [0.0, 1.0]: lower values give more literal translations
+    #[clap(long, default_value = "0.5", value_parser = parse_temperature)]
+    temperature: f32,
+    /// nucleus sampling in ]0.0, 1.0]: only the most probable tokens are sampled,
+    /// by default the value of the server
+    #[clap(long, value_parser = parse_top_p)]
+    top_p: Option<f32>,
+    /// maximal number of tokens generated for one chunk
+    #[clap(long, visible_alias = "max-new-tokens", default_value = "2000")]
+    max_tokens: usize,
+    /// maximal estimated number of tokens of a chunk sent to the server,
+    /// by default 4000 characters; the longer chunks are left unchanged
+    #[clap(long)]
+    max_tokens_per_chunk: Option<usize>,
+    /// average number of characters per token of the model, for estimating the token counts
+    #[clap(long, default_value_t = trsltx::CHARS_PER_TOKEN)]
+    chars_per_token: f32,
+    /// translate without grammar, even if the server supports it
+    #[clap(long)]
+    no_grammar: bool,
+    /// maximal length of the grammar sent to the server
+    #[clap(long, default_value_t = trsltx::MAX_GRAMMAR_SIZE)]
+    max_grammar_size: usize,
+    /// print the number of words to be translated in each chunk and exit
+    #[clap(long)]
+    word_count: bool,
+    /// print the size, word count, parse status and estimated tokens of each chunk and exit
+    #[clap(long)]
+    stats: bool,
+    /// split the file in memory, print the estimated number of requests, tokens,
+    /// time and cost of the translation and exit, without network call
+    #[clap(long)]
+    dry_run: bool,
+    /// price per million tokens of the model, for the cost estimate of --dry-run
+    #[clap(long, value_name = "PRICE")]
+    price_per_mtok: Option<f64>,
+    /// do not warn about the references without labels and the labels without references
+    #[clap(long)]
+    no_xref_check: bool,
+    /// print the syntax tree of each chunk given by the parser (its Debug output) before translating
+    #[clap(long)]
+    print_ast: bool,
+    /// file of term translations, one "source<TAB>target" pair per line
+    #[clap(long)]
+    glossary_file: Option<String>,
+    /// file of the prompt template, with the placeholders <lang_in>, <lang_out>,
+    /// <glossary> and <chunk>, instead of the default prompt
+    #[clap(long)]
+    prompt_file: Option<String>,
+    /// print the terms left untranslated, as suggestions for the glossary file
+    #[clap(long)]
+    build_glossary: bool,
+    /// do not write the metadata file _zt_meta.json next to the translation
+    #[clap(long)]
+    no_meta: bool,
+    /// do not save the translated chunks in the checkpoint file _zt.trsltx.json,
+    /// which allows to resume an interrupted translation
+    #[clap(long)]
+    no_checkpoint: bool,
+    /// JSON file of the translation memory: the chunks already translated with the same
+    /// languages and model are taken from this file instead of being sent to the server
+    #[clap(long)]
+    cache_file: Option<String>,
+    /// directory of the generated files (split file, translation and metadata),
+    /// by default the directory of the initial file
+    #[clap(long)]
+    output_dir: Option<String>,
+    /// .bib file whose citation keys are protected in the grammar,
+    /// by default the files of \bibliography{...} or \addbibresource{...}
+    #[clap(long)]
+    bib_file: Option<String>,
+    /// fields of the entries translated when the file to translate is a .bib file
+    #[clap(long, value_delimiter = ',', default_value = "title,abstract,note")]
+    bib_fields: Vec<String>,
+    /// write the structural differences between the chunks and their translations
+    /// in this JSON file
+    #[clap(long)]
+    diff_report: Option<String>,
+    /// print on the standard error the labels, references and citations lost, invented or renamed
+    /// in each translated chunk
+    #[clap(long)]
+    consistency_report: bool,
+    /// write the report of --consistency-report in this JSON file
+    #[clap(long, value_name = "FILE")]
+    consistency_json: Option<String>,
+    /// write a summary of the run in this JSON file: chunks translated, copied
+    /// and failed, tokens, latency, syntax distances and warnings
+    #[clap(long, value_name = "FILE")]
+    report: Option<String>,
+    /// commands copied verbatim in the translation, in addition to
+    /// todo, fixme, missingfigure, listoftodos and the bibliography commands
+    #[clap(long, value_delimiter = ',')]
+    preserve_commands: Vec<String>,
+    /// write the translation to the standard output instead of the _zt.tex file
+    #[clap(long)]
+    stdout: bool,
+    /// LLM server (the grammar feature is only available with textsynth),
+    /// or the DeepL translator
+    #[clap(long, default_value = "textsynth", value_parser = ["textsynth", "openai", "ollama", "deepl"])]
+    backend: String,
+    /// base URL of the openai, ollama or deepl API
+    #[clap(long)]
+    base_url: Option<String>,
+    /// environment variable of the api key of the openai backend
+    #[clap(long, default_value = "OPENAI_API_KEY")]
+    api_key_env: String,
+    /// use the chat completion API (chat/completions) of the openai or ollama backend
+    #[clap(long)]
+    chat: bool,
+    /// translate the easiest chunks first, so that partial results are available quickly
+    #[clap(long)]
+    sort_chunks_by_complexity: bool,
+    /// show each translated chunk with the differences of its commands and labels
+    /// with the source, and ask to accept it, translate it again or keep the source
+    #[clap(long)]
+    interactive: bool,
+    /// compile the translation with pdflatex (or the given engine, e.g. xelatex or latexmk)
+    /// and mark the chunks introducing compile errors
+    #[clap(long, value_name = "ENGINE", num_args = 0..=1, default_missing_value = DEFAULT_ENGINE)]
+    check_compile: Option<String>,
+    /// translate again only these chunks (e.g. 3,7,10-14) and keep the others
+    /// from the existing translation
+    #[clap(long, value_name = "LIST")]
+    chunks: Option<String>,
+    /// write the source of each translated chunk in comments before its translation,
+    /// for proofreading (see the command strip-comments)
+    #[clap(long, visible_alias = "keep-original")]
+    bilingual: bool,
+    /// send the % comments to the translator, instead of copying them unchanged
+    #[clap(long)]
+    translate_comments: bool,
+    /// number of chunks translated concurrently (with the async feature)
+    #[clap(long, default_value = "4")]
+    jobs: usize,
+    /// number of times a request is sent again after a 429 or 5xx HTTP error,
+    /// with exponential backoff
+    #[clap(long, default_value = "3")]
+    http_retries: usize,
+    /// delay in seconds before the first retry, doubled at each retry
+    #[clap(long, default_value = "1.0")]
+    backoff: f32,
+    /// do not translate the title, author, date and abstract of the preamble
+    #[clap(long)]
+    no_preamble_translate: bool,
+    /// marker line separating two chunks
+    #[clap(long, default_value = "%trsltx-split")]
+    split_marker: String,
+    /// marker line beginning a region left unchanged
+    #[clap(long, default_value = "%trsltx-begin-ignore")]
+    begin_ignore_marker: String,
+    /// marker line ending a region left unchanged
+    #[clap(long, default_value = "%trsltx-end-ignore")]
+    end_ignore_marker: String,
+    /// do not translate the files included with \subfile{...}, \input{...} or \include{...};
+    /// they are never translated with the standard input
+    #[clap(long)]
+    no_subfiles: bool,
+    /// check the split markers, the chunk sizes, the labels and the language declaration
+    /// without translating; exit code 0 if clean, 1 if warnings, 2 if errors
+    #[clap(long)]
+    check: bool,
+    /// print the report of --check in JSON
+    #[clap(long)]
+    json: bool,
+    /// configuration file giving the chunk types and default options,
+    /// by default trsltx.toml in the working directory if it exists
+    #[clap(long)]
+    config: Option<String>,
+    /// print the models available on the server of the backend and exit
+    #[clap(long)]
+    list_models: bool,
+    /// print more diagnostics on the standard error: -v for the chunks and the grammars,
+    /// -vv for everything
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// print fewer diagnostics: -q for the warnings and errors only, -qq for the errors only
+    #[clap(short, long, action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+    /// do not display the progress bar (it is only displayed on a terminal)
+    #[clap(long)]
+    no_progress: bool,
 }
 
-use trsltx::Trsltx;
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// remove the sources written in comments by --bilingual, once the review is done
+    StripComments {
+        /// translated files, modified in place
+        #[clap(required = true)]
+        files: Vec<String>,
+    },
+    /// translate the main file of a project and the files it includes into a
+    /// parallel directory (paper_en/ for paper/, or --output-dir), with a copy of
+    /// the other files of the project
+    Project {
+        /// main LaTeX file of the project
+        main: String,
+    },
+}
+
+// the diagnostics of trsltx are printed on the standard error,
+// the standard output is kept for the translation and the reports
+fn init_logging(verbose: u8, quiet: u8) {
+    let level = match (verbose, quiet) {
+        (0, 0) => tracing::Level::INFO,
+        (1, _) => tracing::Level::DEBUG,
+        (_, 0) => tracing::Level::TRACE,
+        (_, 1) => tracing::Level::WARN,
+        _ => tracing::Level::ERROR,
+    };
+    tracing_subscriber::fmt()
+        .with_writer(|| LogWriter)
+        .with_max_level(level)
+        .with_target(false)
+        .without_time()
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stderr()))
+        .init();
+}
+
+fn parse_top_p(s: &str) -> Result<f32, String> {
+    let top_p: f32 = s.parse().map_err(|e| format!("{:?}", e))?;
+    if top_p.is_nan() || top_p <= 0.0 || top_p > 1.0 {
+        return Err(format!("top_p must be in ]0.0, 1.0], got {}", top_p));
+    }
+    Ok(top_p)
+}
+
+fn parse_temperature(s: &str) -> Result<f32, String> {
+    let temperature: f32 = s.parse().map_err(|e| format!("{:?}", e))?;
+    if !(0.0..=1.0).contains(&temperature) {
+        return Err(format!(
+            "The temperature must be in [0.0, 1.0], got {}",
+            temperature
+        ));
+    }
+    Ok(temperature)
+}
+
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use ltxprs::LtxNode;
+use tracing::{debug, error, info, warn};
+use trsltx::backend::OPENAI_URL;
+use trsltx::batch::BATCH_CACHE_FILE;
+use trsltx::bib::bib_lang_file_name;
+use trsltx::compile::{COMPILE_ERROR_MARK, DEFAULT_ENGINE};
+use trsltx::config::{read_chunk_overrides, read_settings, Settings, CONFIG_FILE};
+use trsltx::{
+    expand_file_patterns, lang_file_name, parse_chunk_selection, split_lang_file_name,
+    strip_source_comments, AstDiff, BatchReport, ChunkReview, ChunkType, DeeplBackend, LaTeXSource,
+    ModelRegistry, OpenAiBackend, ProgressEvent, Project, RetryPolicy, ReviewDecision, RunReport,
+    SplitMarkers, Trsltx, TrsltxBuilder, TrsltxError,
+};
+
+/// Progress bar of the translation, set when the translation starts
+static PROGRESS_BAR: OnceLock<ProgressBar> = OnceLock::new();
+
+// the diagnostics are printed above the progress bar
+struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match PROGRESS_BAR.get() {
+            Some(bar) => bar.suspend(|| std::io::stderr().write(buf)),
+            None => std::io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
 
 // init_file: the tex file to be translated
 // input_lang: the language of the input file
@@ -30,79 +313,1083 @@ use trsltx::Trsltx;
 // if the input file does not exist, it is created with the content of the init file
 // and additional markers for splitting the file into chunks
 // if the input file exists, it is not modified by the command
+// if init_file is "-", the LaTeX source is read from stdin and the translation
+// is written to stdout
 fn main() -> Result<(), String> {
-    let args = Cli::parse();
-    let init_file = args.file_init.as_str();
-    let in_lang = args.input_lang.as_str();
-    let in_lang = format!("_{}.tex", in_lang);
-    let input_file = init_file.replace(".tex", &in_lang).clone();
-    let out_lang = args.output_lang.as_str();
-    let out_lang = format!("_{}.tex", out_lang);
-    let output_file = init_file.replace(".tex", &out_lang).clone();
-
-    let init_file_name = init_file;
-    let input_file_name = input_file.clone();
-    let output_file_name = output_file.clone();
-
-    // asserts that the filenames end with .tex
-    assert!(input_file.ends_with(".tex"));
-    assert!(output_file.ends_with(".tex"));
-
-    // removes the .tex suffix
-    // before check that length is > 4
-    assert!(input_file.len() > 4);
-    assert!(output_file.len() > 4);
-    let input_file = &input_file[..input_file.len() - 4];
-    let output_file = &output_file[..output_file.len() - 4];
-
-    // split the string at the _
-    let input_file: Vec<&str> = input_file.split('_').collect();
-    let output_file: Vec<&str> = output_file.split('_').collect();
-
-    assert!(input_file.len() == 2, "Don't use \"_\" in the file name, please rename it.");
-    assert!(output_file.len() == 2, "Don't use \"_\" in the file name, please rename it.");
-
-    let input_lang = input_file[1];
-    let output_lang = output_file[1];
+    let matches = Cli::command().get_matches();
+    let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    init_logging(args.verbose, args.quiet);
+    if let Some(Command::StripComments { files }) = &args.command {
+        for file in files {
+            let count = strip_source_comments(file)?;
+            info!("{}: {} source blocks removed", file, count);
+        }
+        return Ok(());
+    }
+    let config = config_file(&args);
+    if let Some(path) = &config {
+        info!("Reading {}", path);
+        apply_settings(&mut args, &matches, read_settings(path)?)?;
+    }
+    let mut builder = TrsltxBuilder::new();
+    builder
+        .input_lang(args.input_lang.as_str())
+        .output_lang(args.output_lang.as_str())
+        .model(args.model.as_str())
+        .temperature(args.temperature)
+        .max_tokens(args.max_tokens)
+        .max_grammar_size(args.max_grammar_size)
+        .chars_per_token(args.chars_per_token)
+        .split_length(args.length_split)
+        .grammar(!args.no_grammar)
+        .write_meta(!args.no_meta)
+        .checkpoint(!args.no_checkpoint)
+        .sort_chunks_by_complexity(args.sort_chunks_by_complexity)
+        .bilingual(args.bilingual)
+        .translate_comments(args.translate_comments)
+        .jobs(args.jobs)
+        .retry_policy(RetryPolicy {
+            max_http_retries: args.http_retries,
+            initial_backoff: std::time::Duration::from_secs_f32(args.backoff.max(0.0)),
+            ..RetryPolicy::default()
+        })
+        .translate_preamble(!args.no_preamble_translate)
+        .markers(SplitMarkers {
+            split: args.split_marker.clone(),
+            begin_ignore: args.begin_ignore_marker.clone(),
+            end_ignore: args.end_ignore_marker.clone(),
+        })
+        .preserve_commands(
+            &args
+                .preserve_commands
+                .iter()
+                .map(|c| c.as_str())
+                .collect::<Vec<&str>>(),
+        );
+    if let Some(top_p) = args.top_p {
+        builder.top_p(top_p);
+    }
+    if let Some(tokens) = args.max_tokens_per_chunk {
+        builder.max_tokens_per_chunk(tokens);
+    }
+    if let Some(path) = &args.cache_file {
+        builder.cache_file(path);
+    }
+    match args.backend.as_str() {
+        "openai" => {
+            let url = args.base_url.as_deref().unwrap_or(OPENAI_URL);
+            builder.backend(
+                OpenAiBackend::new(url, None)
+                    .api_key_env(&args.api_key_env)
+                    .chat(args.chat),
+            );
+        }
+        "ollama" => {
+            builder.backend(OpenAiBackend::ollama(args.base_url.as_deref()).chat(args.chat));
+        }
+        "deepl" => {
+            builder.backend(DeeplBackend::new(args.base_url.as_deref(), None));
+        }
+        _ => {}
+    }
+    if args.list_models {
+        // the input is not read
+        print_models(&builder.input_source(LaTeXSource::Stdin).build()?);
+        return Ok(());
+    }
+
+    if let Some(Command::Project { main }) = &args.command {
+        return translate_project(&args, &mut builder, &config, main);
+    }
+    if args.stdin {
+        return translate_stdin(args, builder, &config);
+    }
+    let files = expand_file_patterns(&args.file_init)?;
+    if files.iter().any(|file| file == "-") {
+        if files.len() > 1 {
+            return Err("The standard input cannot be translated with other files".to_string());
+        }
+        return translate_stdin(args, builder, &config);
+    }
+    if args.check {
+        let mut code = 0;
+        for file in files.iter().filter(|file| !file.ends_with(".bib")) {
+            code = code.max(check_file(&args, &mut builder, &config, file)?);
+        }
+        if code != 0 {
+            std::process::exit(code);
+        }
+        return Ok(());
+    }
+    if let [file] = &files[..] {
+        if let Some((report, code)) = translate_file(&args, &mut builder, &config, file)? {
+            if let Some(path) = &args.report {
+                report.write(path)?;
+            }
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        return Ok(());
+    }
+    translate_batch(&args, &mut builder, &config, &files)
+}
+
+// translate the standard input and write the translation on the standard output
+fn translate_stdin(
+    mut args: Cli,
+    mut builder: TrsltxBuilder,
+    config: &Option<String>,
+) -> Result<(), String> {
+    if args.chunks.is_some() {
+        return Err("--chunks needs a translation file, not the standard input".to_string());
+    }
+    if args.interactive {
+        return Err("--interactive reads the answers on the standard input".to_string());
+    }
+    let mut trsltx = builder.input_source(LaTeXSource::Stdin).build()?;
+    load_config(&mut trsltx, config)?;
+    trsltx.read_file()?;
+    if args.input_lang == "auto" {
+        args.input_lang = trsltx.detect_input_lang()?.to_string();
+    }
+    if args.input_lang == args.output_lang {
+        return Err("The source and target languages are the same".to_string());
+    }
+    if args.check {
+        let code = check(&mut trsltx, args.json)?;
+        if code != 0 {
+            std::process::exit(code);
+        }
+        return Ok(());
+    }
+    // the source is split in memory, unless it has split markers
+    trsltx.insert_splits(args.length_split.min(trsltx.max_chunk_length()));
+    load_bib(&mut trsltx, &args.bib_file)?;
+    if let Some(path) = &args.glossary_file {
+        trsltx.read_glossary_file(path)?;
+    }
+    if let Some(path) = &args.prompt_file {
+        trsltx.read_prompt_file(path)?;
+    }
+    trsltx.extract_chunks()?;
+    if args.word_count {
+        print_word_count(&trsltx);
+        return Ok(());
+    }
+    if args.stats {
+        print_stats(&trsltx);
+        return Ok(());
+    }
+    if args.dry_run {
+        print_cost_estimate(&trsltx, args.price_per_mtok);
+        return Ok(());
+    }
+    if !args.no_xref_check {
+        print_xref_warnings(&trsltx);
+    }
+    if args.print_ast {
+        print_ast(&trsltx);
+    }
+    check_model(&trsltx)?;
+    translate(&mut trsltx, &args);
+    if let Some(engine) = &args.check_compile {
+        check_compile(&mut trsltx, engine)?;
+    }
+    if args.build_glossary {
+        print_glossary_suggestions(&trsltx);
+    }
+    // nothing is written but the translation: the included files are not translated
+    if let Some(path) = &args.diff_report {
+        trsltx.write_diff_report(path)?;
+    }
+    // the standard output is kept for the translation
+    if args.consistency_report {
+        eprint!("{}", trsltx.consistency_report());
+    }
+    if let Some(path) = &args.consistency_json {
+        trsltx.write_consistency_report(path)?;
+    }
+    if let Some(path) = &args.report {
+        trsltx.write_run_report(path)?;
+    }
+    let valid = trsltx.validate_output().is_ok();
+    trsltx.write_stdout()?;
+    let code = partial_translation_code(&trsltx, valid);
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+// names of the split file and of the translated file of init_file_name,
+// in the output directory if any
+fn split_file_names(
+    args: &Cli,
+    builder: &mut TrsltxBuilder,
+    init_file_name: &str,
+) -> Result<(String, String), String> {
+    // the language is needed for the name of the split file
+    let mut input_lang = args.input_lang.clone();
+    if input_lang == "auto" {
+        let mut trsltx = builder.input_file(init_file_name).build()?;
+        trsltx.read_file()?;
+        input_lang = trsltx.detect_input_lang()?.to_string();
+        builder.input_lang(&input_lang);
+    }
+    let input_file_name = lang_file_name(init_file_name, input_lang.as_str())?;
+    let output_file_name = lang_file_name(init_file_name, args.output_lang.as_str())?;
+
+    // the languages are given by the suffixes of the file names
+    let (_, input_lang) = split_lang_file_name(&input_file_name)?;
+    let (_, output_lang) = split_lang_file_name(&output_file_name)?;
 
     if output_lang == input_lang {
         return Err("The source and target languages are the same".to_string());
     }
 
+    // the generated files are placed in the output directory
+    // the initial file is always read from its original path
+    let (input_file_name, output_file_name) = match &args.output_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Cannot create directory {}: {:?}", dir, e))?;
+            (
+                in_dir(dir, input_file_name.as_str()),
+                in_dir(dir, output_file_name.as_str()),
+            )
+        }
+        None => (input_file_name, output_file_name),
+    };
+    Ok((input_file_name, output_file_name))
+}
+
+// check the split file if it exists, or the initial file; returns the exit code of the check
+fn check_file(
+    args: &Cli,
+    builder: &mut TrsltxBuilder,
+    config: &Option<String>,
+    init_file_name: &str,
+) -> Result<i32, String> {
+    let (input_file_name, _) = split_file_names(args, builder, init_file_name)?;
+    let file_name = if std::path::Path::new(&input_file_name).exists() {
+        input_file_name.as_str()
+    } else {
+        init_file_name
+    };
+    let mut trsltx = builder.input_file(file_name).build()?;
+    load_config(&mut trsltx, config)?;
+    trsltx.read_file()?;
+    check(&mut trsltx, args.json)
+}
+
+// translate a file, or write its split file if it does not exist yet;
+// returns the report of the translation and its exit code, None if nothing was translated
+fn translate_file(
+    args: &Cli,
+    builder: &mut TrsltxBuilder,
+    config: &Option<String>,
+    init_file_name: &str,
+) -> Result<Option<(RunReport, i32)>, String> {
+    if init_file_name.ends_with(".bib") {
+        return translate_bib_file(args, builder, init_file_name).map(Some);
+    }
+    let (input_file_name, output_file_name) = split_file_names(args, builder, init_file_name)?;
+
     // if the input file does not exist read the init file, split it and write it to the input file
     let path_to_file = std::path::Path::new(&input_file_name);
-    println!("{},path_to_file={:?}", input_file_name, path_to_file);
+    debug!("{},path_to_file={:?}", input_file_name, path_to_file);
     //assert!(1==2);
-    if !path_to_file.exists() {
-        println!("File {} does not exist", input_file_name);
-        println!("Creating file {}", input_file_name);
+    if !path_to_file.exists() && !args.dry_run {
+        info!("File {} does not exist", input_file_name);
+        info!("Creating file {}", input_file_name);
         // read init_file
         //println!("Reading input file {}", input_file_name);
         //let s = std::fs::read_to_string(init_file_name).map_err(|e| e.to_string())?;
 
-        let mut trsltx = Trsltx::new(input_lang, output_lang, init_file_name, "", args.model.as_str());
+        let mut trsltx = builder.input_file(init_file_name).build()?;
         trsltx.read_file()?;
-        println!("{:?}", trsltx);
-        let s = trsltx.generate_split_latex(args.length_split);
+        debug!("{:?}", trsltx);
+        // the chunks must fit in the context window of the model
+        let s = trsltx.generate_split_latex(args.length_split.min(trsltx.max_chunk_length()));
 
         // save to input_file
-        println!("Writing input file {}", input_file_name);
+        info!("Writing input file {}", input_file_name);
         std::fs::write(&input_file_name, s).map_err(|e| e.to_string())?;
-        println!("File {} created. Please review it: check that the split regions are well positioned, check latex compilation. Then relaunch trsltx.", input_file_name);
-        return Ok(());
+        info!("File {} created. Please review it: check that the split regions are well positioned, check latex compilation. Then relaunch trsltx.", input_file_name);
+        return Ok(None);
+    }
+    // with --dry-run, the split of the initial file is done in memory
+    let split_in_memory = !path_to_file.exists();
+    let mut trsltx = builder
+        .input_file(if split_in_memory {
+            init_file_name
+        } else {
+            input_file_name.as_str()
+        })
+        .output_file(output_file_name.as_str())
+        .build()?;
+    load_config(&mut trsltx, config)?;
+    // the split file is in the output directory, the included files next to the initial file
+    if args.output_dir.is_some() {
+        if let Some(dir) = std::path::Path::new(init_file_name).parent() {
+            trsltx.set_source_dir(dir);
+        }
     }
-    let mut trsltx = Trsltx::new(
-        input_lang,
-        output_lang,
-        input_file_name.as_str(),
-        output_file_name.as_str(),
-        args.model.as_str(),
-    );
 
     trsltx.read_file()?;
+    if split_in_memory {
+        info!(
+            "File {} does not exist, split {} in memory",
+            input_file_name, init_file_name
+        );
+        trsltx.insert_splits(args.length_split.min(trsltx.max_chunk_length()));
+    }
+    load_bib(&mut trsltx, &args.bib_file)?;
+    if let Some(path) = &args.glossary_file {
+        trsltx.read_glossary_file(path)?;
+    }
+    if let Some(path) = &args.prompt_file {
+        trsltx.read_prompt_file(path)?;
+    }
     trsltx.extract_chunks()?;
+    if args.word_count {
+        print_word_count(&trsltx);
+        return Ok(None);
+    }
+    if args.stats {
+        print_stats(&trsltx);
+        return Ok(None);
+    }
+    if args.dry_run {
+        print_cost_estimate(&trsltx, args.price_per_mtok);
+        return Ok(None);
+    }
+    if !args.no_xref_check {
+        print_xref_warnings(&trsltx);
+    }
+    if args.print_ast {
+        print_ast(&trsltx);
+    }
+    if let Some(spec) = &args.chunks {
+        trsltx.load_previous_translation(&parse_chunk_selection(spec)?)?;
+    }
+    check_model(&trsltx)?;
+    translate(&mut trsltx, args);
+    // the translated included files are needed by the compilation
+    if !args.no_subfiles {
+        trsltx.process_subfiles()?;
+    }
+    if let Some(engine) = &args.check_compile {
+        check_compile(&mut trsltx, engine)?;
+    }
+    if args.build_glossary {
+        print_glossary_suggestions(&trsltx);
+    }
+    if let Some(path) = &args.diff_report {
+        trsltx.write_diff_report(path)?;
+    }
+    if args.consistency_report {
+        eprint!("{}", trsltx.consistency_report());
+    }
+    if let Some(path) = &args.consistency_json {
+        trsltx.write_consistency_report(path)?;
+    }
+    // a partial translation is better than nothing: the file is written
+    // even if it has unbalanced delimiters
+    let valid = trsltx.validate_output().is_ok();
+    if args.stdout {
+        trsltx.write_stdout()?;
+    } else {
+        trsltx.write_file()?;
+    }
+    let code = partial_translation_code(&trsltx, valid);
+    Ok(Some((trsltx.run_report(), code)))
+}
+
+// translate the fields of the entries of a bibliography file into file_xy.bib
+fn translate_bib_file(
+    args: &Cli,
+    builder: &mut TrsltxBuilder,
+    bib_file: &str,
+) -> Result<(RunReport, i32), String> {
+    if args.input_lang == "auto" {
+        return Err("The language of a .bib file is not detected, give it with -i".to_string());
+    }
+    let bib = std::fs::read_to_string(bib_file).map_err(|e| TrsltxError::io(bib_file, e))?;
+    let trsltx = builder.input_file(bib_file).build()?;
+    check_model(&trsltx)?;
+    let fields: Vec<&str> = args.bib_fields.iter().map(|f| f.as_str()).collect();
+    let translation = trsltx.translate_bib(&bib, &fields);
+    info!(
+        "{} of {} fields translated",
+        translation.translated, translation.fields
+    );
+    let output_file_name = bib_lang_file_name(bib_file, &args.output_lang);
+    let output_file_name = match &args.output_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Cannot create directory {}: {:?}", dir, e))?;
+            in_dir(dir, &output_file_name)
+        }
+        None => output_file_name,
+    };
+    if args.stdout {
+        print!("{}", translation.content);
+    } else {
+        info!("Writing {}", output_file_name);
+        std::fs::write(&output_file_name, &translation.content)
+            .map_err(|e| TrsltxError::io(&output_file_name, e))?;
+    }
+    let report = RunReport {
+        model: args.model.clone(),
+        chunks: translation.fields,
+        translated_chunks: translation.translated,
+        failed_chunks: translation.failed.len(),
+        warnings: translation.failed.clone(),
+        ..RunReport::default()
+    };
+    if translation.failed.is_empty() {
+        return Ok((report, 0));
+    }
+    warn!(
+        "{} fields left untranslated: {}",
+        translation.failed.len(),
+        translation.failed.join(", ")
+    );
+    Ok((report, 3))
+}
+
+// translate the files one after the other with the same settings and a shared cache,
+// then sum up the runs
+fn translate_batch(
+    args: &Cli,
+    builder: &mut TrsltxBuilder,
+    config: &Option<String>,
+    files: &[String],
+) -> Result<(), String> {
+    if let Some(option) = single_file_option(args) {
+        return Err(format!("{} needs a single file", option));
+    }
+    share_cache(args, builder);
+    let mut batch = BatchReport::default();
+    let mut code = 0;
+    let mut failed_files = 0;
+    for (i, file) in files.iter().enumerate() {
+        info!("File {} of {}: {}", i + 1, files.len(), file);
+        match translate_file(args, builder, config, file) {
+            Ok(Some((report, file_code))) => {
+                batch.add(file, report);
+                code = code.max(file_code);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("{}: {}", file, e);
+                failed_files += 1;
+            }
+        }
+    }
+    print_batch_summary(&batch);
+    if let Some(path) = &args.report {
+        batch.write(path)?;
+    }
+    if failed_files > 0 {
+        return Err(format!(
+            "{} of {} files not translated",
+            failed_files,
+            files.len()
+        ));
+    }
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+// the options which apply to the translation of one file
+fn single_file_option(args: &Cli) -> Option<&'static str> {
+    [
+        ("--chunks", args.chunks.is_some()),
+        ("--diff-report", args.diff_report.is_some()),
+        ("--consistency-json", args.consistency_json.is_some()),
+        ("--stdout", args.stdout),
+    ]
+    .into_iter()
+    .find(|(_, given)| *given)
+    .map(|(option, _)| option)
+}
+
+// the files of a batch or a project share the translation cache
+fn share_cache(args: &Cli, builder: &mut TrsltxBuilder) {
+    if args.cache_file.is_none() {
+        let path = match &args.output_dir {
+            Some(dir) => in_dir(dir, BATCH_CACHE_FILE),
+            None => BATCH_CACHE_FILE.to_string(),
+        };
+        info!("Translation cache shared by the files: {}", path);
+        builder.cache_file(&path);
+    }
+}
+
+// translate the documents of the project of main_file into a parallel directory
+// and copy the other files
+fn translate_project(
+    args: &Cli,
+    builder: &mut TrsltxBuilder,
+    config: &Option<String>,
+    main_file: &str,
+) -> Result<(), String> {
+    if let Some(option) = single_file_option(args) {
+        return Err(format!("{} cannot be used with a project", option));
+    }
+    let project = Project::discover(main_file)?;
+    info!(
+        "{} files to translate, {} bibliography files",
+        project.documents.len(),
+        project.bib_files.len()
+    );
+    if args.input_lang == "auto" {
+        let mut trsltx = builder.input_file(main_file).build()?;
+        trsltx.read_file()?;
+        builder.input_lang(trsltx.detect_input_lang()?);
+    }
+    let out_dir = match &args.output_dir {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => project.default_output_dir(&args.output_lang),
+    };
+    // the estimates only read the files
+    let estimate = args.word_count || args.stats || args.dry_run;
+    if !estimate {
+        let copied = project.copy_assets(&out_dir)?;
+        info!("{} files copied into {}", copied, out_dir.display());
+        share_cache(args, builder);
+    }
+    let mut batch = BatchReport::default();
+    let mut code = 0;
+    for (i, file) in project.documents.iter().enumerate() {
+        let name = file.path.to_string_lossy();
+        info!("File {} of {}: {}", i + 1, project.documents.len(), name);
+        let mut trsltx = project.document(builder, file, &out_dir)?;
+        load_config(&mut trsltx, config)?;
+        if let Some(path) = &args.bib_file {
+            trsltx.read_bib_file(path)?;
+        }
+        if let Some(path) = &args.glossary_file {
+            trsltx.read_glossary_file(path)?;
+        }
+        if let Some(path) = &args.prompt_file {
+            trsltx.read_prompt_file(path)?;
+        }
+        trsltx.extract_chunks()?;
+        if args.word_count {
+            print_word_count(&trsltx);
+            continue;
+        }
+        if args.stats {
+            print_stats(&trsltx);
+            continue;
+        }
+        if args.dry_run {
+            print_cost_estimate(&trsltx, args.price_per_mtok);
+            continue;
+        }
+        // the labels and the references may be in different files:
+        // the cross-references are not checked file by file
+        if i == 0 {
+            check_model(&trsltx)?;
+        }
+        translate(&mut trsltx, args);
+        if let Some(engine) = &args.check_compile {
+            check_compile(&mut trsltx, engine)?;
+        }
+        if args.build_glossary {
+            print_glossary_suggestions(&trsltx);
+        }
+        if args.consistency_report {
+            eprint!("{}", trsltx.consistency_report());
+        }
+        let valid = trsltx.validate_output().is_ok();
+        trsltx.write_file()?;
+        code = code.max(partial_translation_code(&trsltx, valid));
+        batch.add(&name, trsltx.run_report());
+    }
+    if estimate {
+        return Ok(());
+    }
+    print_batch_summary(&batch);
+    if let Some(path) = &args.report {
+        batch.write(path)?;
+    }
+    info!("The translated project is in {}", out_dir.display());
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+fn print_batch_summary(batch: &BatchReport) {
+    for file in batch.files.iter() {
+        info!("{}: {}", file.file, report_summary(&file.report));
+    }
+    info!("Total: {}", report_summary(&batch.total));
+}
+
+fn report_summary(report: &RunReport) -> String {
+    format!(
+        "{} chunks translated, {} copied, {} failed, {} too long, {} warnings",
+        report.translated_chunks,
+        report.copied_chunks,
+        report.failed_chunks,
+        report.too_long_chunks,
+        report.warnings.len()
+    )
+}
+
+// the translation is written, but the exit code tells that it is incomplete:
+// 3 if chunks are left untranslated (server errors, too long chunks),
+// 1 if the delimiters are unbalanced
+fn partial_translation_code(trsltx: &Trsltx, valid: bool) -> i32 {
+    let untranslated = trsltx.untranslated_chunks();
+    if !untranslated.is_empty() {
+        let list: Vec<String> = untranslated
+            .iter()
+            .map(|(chunk, reason)| format!("{} ({})", chunk, reason))
+            .collect();
+        warn!(
+            "{} of {} chunks left untranslated: {}",
+            untranslated.len(),
+            trsltx.chunks().len(),
+            list.join(", ")
+        );
+        3
+    } else if !valid {
+        1
+    } else {
+        0
+    }
+}
+
+fn translate(trsltx: &mut Trsltx, args: &Cli) {
+    let show = !args.no_progress && args.quiet == 0;
+    show_progress(trsltx, show);
+    if args.interactive {
+        trsltx.set_review_callback(|review| match PROGRESS_BAR.get() {
+            Some(bar) => bar.suspend(|| review_chunk(review)),
+            None => review_chunk(review),
+        });
+    }
+    run_translation(trsltx, args);
+}
+
+// the text in color on a terminal
+fn paint(text: &str, color: u8) -> String {
+    if std::io::IsTerminal::is_terminal(&std::io::stderr()) {
+        format!("\x1b[{}m{}\x1b[0m", color, text)
+    } else {
+        text.to_string()
+    }
+}
+
+fn read_answer(question: &str) -> Option<String> {
+    eprint!("{}", question);
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(n) if n > 0 => Some(line.trim().to_string()),
+        _ => None,
+    }
+}
+
+// print a translated chunk with the differences of structure with the source
+// and ask the user what to do
+fn review_chunk(review: &ChunkReview) -> ReviewDecision {
+    eprintln!(
+        "{}",
+        paint(
+            &format!(
+                "---- chunk {} of {}: source",
+                review.index + 1,
+                review.total
+            ),
+            1
+        )
+    );
+    eprintln!("{}", review.source.trim_end());
+    eprintln!("{}", paint("---- translation", 1));
+    eprintln!("{}", review.translation.trim_end());
+    let diffs = trsltx::diff::ast_diff(review.source, review.translation);
+    if diffs.is_empty() {
+        eprintln!("{}", paint("Same commands and labels as the source", 32));
+    }
+    for diff in diffs {
+        let line = match diff {
+            AstDiff::Removed(command) => paint(&format!("- {}", command), 31),
+            AstDiff::Added(command) => paint(&format!("+ {}", command), 32),
+            AstDiff::Modified { from, to } => paint(&format!("~ {} -> {}", from, to), 33),
+        };
+        eprintln!("{}", line);
+    }
+    loop {
+        // the translation is accepted at the end of the input
+        let Some(answer) =
+            read_answer("[a]ccept, [r]etry, retry with a [h]int, [k]eep the source? ")
+        else {
+            return ReviewDecision::Accept;
+        };
+        match answer.as_str() {
+            "" | "a" => return ReviewDecision::Accept,
+            "r" => return ReviewDecision::Retry { hint: None },
+            "h" => {
+                let hint = read_answer("Hint for the translator: ").filter(|h| !h.is_empty());
+                return ReviewDecision::Retry { hint };
+            }
+            "k" => return ReviewDecision::KeepOriginal,
+            _ => {}
+        }
+    }
+}
+
+// the progress bar shows the number of processed chunks, the elapsed time,
+// the estimated remaining time and the number of retries;
+// without terminal, a line is printed for each translated chunk
+fn show_progress(trsltx: &mut Trsltx, show: bool) {
+    let total = trsltx.chunks().len() as u64;
+    let bar = PROGRESS_BAR
+        .get_or_init(|| {
+            let target = if show {
+                ProgressDrawTarget::stderr()
+            } else {
+                ProgressDrawTarget::hidden()
+            };
+            let style = ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40} {pos}/{len} chunks, ETA {eta} {msg}",
+            )
+            .expect("invalid progress bar template");
+            ProgressBar::with_draw_target(Some(total), target).with_style(style)
+        })
+        .clone();
+    // the bar is reused by the next file of a batch
+    bar.reset();
+    bar.set_length(total);
+    let retries = Arc::new(AtomicUsize::new(0));
+    trsltx.set_progress_callback(move |event| match event {
+        ProgressEvent::ChunkStarted { index, .. } => {
+            bar.set_message(format!("(chunk {})", index + 1));
+        }
+        ProgressEvent::ChunkRetried { .. } => {
+            let retries = retries.fetch_add(1, Ordering::Relaxed) + 1;
+            bar.set_message(format!("({} retries)", retries));
+        }
+        ProgressEvent::ChunkTranslated {
+            index,
+            attempt,
+            elapsed,
+            ..
+        } => {
+            bar.inc(1);
+            let line = format!(
+                "Chunk {} of {} translated in {:.1} s ({} attempts)",
+                index + 1,
+                total,
+                elapsed.as_secs_f32(),
+                attempt
+            );
+            if bar.is_hidden() {
+                info!("{}", line);
+            } else {
+                debug!("{}", line);
+            }
+        }
+        ProgressEvent::ChunkFailed { .. } | ProgressEvent::ChunkSkipped { .. } => bar.inc(1),
+        ProgressEvent::Done { .. } => {
+            let retries = retries.load(Ordering::Relaxed);
+            bar.finish_with_message(format!("({} retries)", retries));
+        }
+    });
+}
+
+#[cfg(not(feature = "async"))]
+fn run_translation(trsltx: &mut Trsltx, _args: &Cli) {
     trsltx.translate();
-    trsltx.write_file()?;
+}
+
+// the chunks are translated concurrently with the textsynth backend
+// the blocking steps (preamble) run outside of the async runtime
+#[cfg(feature = "async")]
+fn run_translation(trsltx: &mut Trsltx, args: &Cli) {
+    // the review is done chunk by chunk
+    if args.interactive {
+        trsltx.translate();
+        return;
+    }
+    trsltx.prepare_translation();
+    let runtime = tokio::runtime::Runtime::new().expect("Cannot start the async runtime");
+    // the other backends translate the chunks one at a time
+    if let Err(e) = runtime.block_on(trsltx.translate_chunks_async()) {
+        info!("{}, the chunks are translated one at a time", e);
+        trsltx.translate_chunks();
+    }
+}
+
+// the chunks with compile errors are marked in the translation, which is still written
+fn check_compile(trsltx: &mut Trsltx, engine: &str) -> Result<(), String> {
+    let errors = trsltx.check_compile(engine)?;
+    for error in errors.iter() {
+        warn!(
+            "chunk {} does not compile with {}: {}",
+            error.chunk, engine, error.message
+        );
+    }
+    if !errors.is_empty() {
+        warn!(
+            "{} chunks introduce compile errors, they are marked with \"{}\"",
+            errors.len(),
+            COMPILE_ERROR_MARK
+        );
+    }
+    Ok(())
+}
+
+// print the report of the check and return its exit code
+fn check(trsltx: &mut Trsltx, json: bool) -> Result<i32, String> {
+    let report = trsltx.check();
+    if json {
+        let json = serde_json::to_string_pretty(&report)
+            .map_err(|e| format!("Cannot serialize the check report: {:?}", e))?;
+        println!("{}", json);
+    } else {
+        print!("{}", report);
+    }
+    Ok(report.exit_code())
+}
+
+fn print_word_count(trsltx: &Trsltx) {
+    let counts = trsltx.word_counts();
+    for (i, count) in counts.iter().enumerate() {
+        println!("Chunk {} of {}: {} words", i + 1, counts.len(), count);
+    }
+    println!("Total: {} words", trsltx.total_word_count());
+}
+
+fn print_cost_estimate(trsltx: &Trsltx, price_per_mtok: Option<f64>) {
+    let estimate = trsltx.cost_estimate();
+    println!(
+        "Chunks: {} ({} to translate)",
+        estimate.chunks, estimate.translated_chunks
+    );
+    println!(
+        "Requests: {} ({} preamble fields), up to {} with retries",
+        estimate.requests(),
+        estimate.preamble_fields,
+        estimate.requests() * estimate.max_attempts
+    );
+    println!(
+        "Estimated tokens: {} ({} prompt, {} completion)",
+        estimate.total_tokens(),
+        estimate.prompt_tokens,
+        estimate.completion_tokens
+    );
+    println!(
+        "Estimated time: {} at {} tokens/s, up to {} with retries",
+        format_duration(estimate.seconds()),
+        trsltx::stats::TOKENS_PER_SECOND,
+        format_duration(estimate.seconds() * estimate.max_attempts as f64)
+    );
+    match price_per_mtok {
+        Some(price) => println!(
+            "Estimated cost: {:.2}, up to {:.2} with retries",
+            estimate.cost(price),
+            estimate.cost(price) * estimate.max_attempts as f64
+        ),
+        None => println!("Estimated cost: give the price of the model with --price-per-mtok"),
+    }
+    if estimate.subfiles > 0 {
+        println!("{} included files are not counted", estimate.subfiles);
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    match seconds {
+        0..=119 => format!("{} s", seconds),
+        120..=7199 => format!("{} min", seconds / 60),
+        _ => format!("{} h {} min", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+fn print_stats(trsltx: &Trsltx) {
+    let stats = trsltx.split_stats();
+    println!(
+        "{:>5}  {:<9}  {:>6}  {:>6}  {:>5}  {:>7}",
+        "chunk", "type", "chars", "words", "parse", "tokens"
+    );
+    for stat in stats.iter() {
+        println!(
+            "{:>5}  {:<9}  {:>6}  {:>6}  {:>5}  {:>7}",
+            stat.index + 1,
+            format!("{:?}", stat.chunk_type).to_lowercase(),
+            stat.char_count,
+            stat.word_count,
+            if stat.parse_ok { "ok" } else { "error" },
+            stat.estimated_tokens
+        );
+    }
+    println!(
+        "{:>5}  {:<9}  {:>6}  {:>6}  {:>5}  {:>7}",
+        "total",
+        "",
+        stats.iter().map(|s| s.char_count).sum::<usize>(),
+        stats.iter().map(|s| s.word_count).sum::<usize>(),
+        format!(
+            "{}/{}",
+            stats.iter().filter(|s| s.parse_ok).count(),
+            stats.len()
+        ),
+        stats.iter().map(|s| s.estimated_tokens).sum::<usize>()
+    );
+}
 
+// the translation goes on: the labels may be defined in another file
+fn print_xref_warnings(trsltx: &Trsltx) {
+    for warning in trsltx.cross_reference_check() {
+        warn!("{}", warning);
+    }
+}
+
+// debug output, on the standard error as the other messages
+fn print_ast(trsltx: &Trsltx) {
+    for (i, (chunk, t)) in trsltx.chunks().iter().enumerate() {
+        if *t == ChunkType::Translate {
+            eprintln!("Chunk {}:\n{:#?}", i + 1, LtxNode::new(chunk));
+        }
+    }
+}
+
+// on the standard error, in the format of the glossary file,
+// the translation of each term has to be completed
+fn print_glossary_suggestions(trsltx: &Trsltx) {
+    eprintln!("Untranslated terms, to be added to the glossary file:");
+    for term in trsltx.glossary_suggestions() {
+        eprintln!("{}\t{}", term, term);
+    }
+}
+
+// print the models of the server, with the details known by the registry,
+// or the models of the registry if the server cannot be queried
+fn print_models(trsltx: &Trsltx) {
+    let registry = ModelRegistry::default();
+    let ids = match trsltx.list_models() {
+        Ok(ids) => ids,
+        Err(e) => {
+            warn!("{}, print the models of the registry", e);
+            registry.models().iter().map(|m| m.id.clone()).collect()
+        }
+    };
+    for id in ids {
+        match registry.find(&id) {
+            Some(model) => println!(
+                "{} (aliases: {}), grammar: {}, context: {} tokens",
+                model.id,
+                model.aliases.join(", "),
+                if model.supports_grammar { "yes" } else { "no" },
+                model.context_window_tokens
+            ),
+            None => println!("{}", id),
+        }
+    }
+}
+
+// stop before the translation if the model is not available on the server
+fn check_model(trsltx: &Trsltx) -> Result<(), String> {
+    match trsltx.validate_model() {
+        Err(TrsltxError::Api(e)) => {
+            warn!("Cannot check the model: {}", e);
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+// path of the file with the same name in the directory dir
+fn in_dir(dir: &str, file_name: &str) -> String {
+    let name = std::path::Path::new(file_name)
+        .file_name()
+        .unwrap_or(file_name.as_ref());
+    std::path::Path::new(dir)
+        .join(name)
+        .to_string_lossy()
+        .to_string()
+}
+
+// read the chunk type overrides in trsltx.toml, if it exists
+fn load_config(trsltx: &mut Trsltx, config: &Option<String>) -> Result<(), String> {
+    if let Some(path) = config {
+        trsltx.set_chunk_overrides(read_chunk_overrides(path)?);
+    }
     Ok(())
 }
+
+// the file given by --config, or trsltx.toml in the working directory if it exists
+fn config_file(args: &Cli) -> Option<String> {
+    match &args.config {
+        Some(path) => Some(path.clone()),
+        None if std::path::Path::new(CONFIG_FILE).exists() => Some(CONFIG_FILE.to_string()),
+        None => None,
+    }
+}
+
+// replace the default values of the options by the values of the configuration file,
+// the options given on the command line take precedence
+fn apply_settings(
+    args: &mut Cli,
+    matches: &clap::ArgMatches,
+    settings: Settings,
+) -> Result<(), String> {
+    let from_cli =
+        |id: &str| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine);
+    macro_rules! apply {
+        ($($field:ident),*) => {
+            $(
+                if let Some(value) = settings.$field {
+                    if !from_cli(stringify!($field)) {
+                        args.$field = value.into();
+                    }
+                }
+            )*
+        };
+    }
+    apply!(
+        input_lang,
+        output_lang,
+        model,
+        length_split,
+        backend,
+        base_url,
+        api_key_env,
+        prompt_file
+    );
+    if !["textsynth", "openai", "ollama", "deepl"].contains(&args.backend.as_str()) {
+        return Err(format!(
+            "Unknown backend in the configuration file: {}",
+            args.backend
+        ));
+    }
+    Ok(())
+}
+
+// read the citation keys of the given .bib file
+// or of the .bib files found in the LaTeX file
+fn load_bib(trsltx: &mut Trsltx, bib_file: &Option<String>) -> Result<(), String> {
+    match bib_file {
+        Some(bib_file) => Ok(trsltx.read_bib_file(bib_file)?),
+        None => {
+            for bib_file in trsltx.detect_bib_files() {
+                if std::path::Path::new(&bib_file).exists() {
+                    trsltx.read_bib_file(&bib_file)?;
+                } else {
+                    warn!("bibliography file {} not found", bib_file);
+                }
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Metadata of a translation run, written in the file `<stem>_meta.json`
+//! next to the translated file (e.g. `test/simple_en_meta.json` for `test/simple_en.tex`).
+//!
+//! The JSON schema is stable: fields may be added in minor versions,
+//! but not removed or renamed.
+//!
+//! ```json
+//! {
+//!   "source_file": "test/simple_fr.tex",
+//!   "output_file": "test/simple_en.tex",
+//!   "input_lang": "fr",
+//!   "output_lang": "en",
+//!   "model": "mistral47b",
+//!   "timestamp": 1700000000,
+//!   "chunks": [
+//!     {
+//!       "index": 0,
+//!       "chunk_type": "translate",
+//!       "source_hash": "af63dc4c8601ec8c",
+//!       "translation": "The translated chunk...",
+//!       "model": "mistral47b",
+//!       "char_count": 512,
+//!       "word_count": 80,
+//!       "distmin": 0,
+//!       "attempts": 1,
+//!       "used_grammar": true,
+//!       "complexity": 95.5,
+//!       "elapsed_ms": 4200,
+//!       "failed": false,
+//!       "too_long": false,
+//!       "started_at": 1699999995800,
+//!       "finished_at": 1700000000000
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `timestamp` is the number of seconds since the Unix epoch at the end of the translation.
+//! `chunk_type` is `"translate"` or `"unchanged"`.
+//! `source_hash` is the hash of the source chunk used by the cache (see `trsltx::cache::chunk_hash`),
+//! in hexadecimal, and `translation` the chunk written in the translated file.
+//! `distmin` is the syntax distance between the chunk and its best translation,
+//! `null` if the chunk has not been sent to the server.
+//! `complexity` is the estimated difficulty of the translation of the chunk
+//! (see `trsltx::complexity`), 0 for the chunks left unchanged.
+//! `elapsed_ms` is the time spent on the requests of the chunk, in milliseconds,
+//! 0 if the chunk has not been sent to the server.
+//! `failed` is true if the server did not give a translation, and `too_long` if the chunk
+//! was not sent because it exceeds the context of the model: the chunk is left unchanged.
+//! `started_at` and `finished_at` are the numbers of milliseconds since the Unix epoch
+//! at the beginning and at the end of the processing of the chunk.
+
+use serde::Serialize;
+
+/// Metadata of a translation run
+#[derive(Debug, Clone, Serialize)]
+pub struct TranslationMeta {
+    pub source_file: String,
+    pub output_file: String,
+    pub input_lang: String,
+    pub output_lang: String,
+    pub model: String,
+    pub timestamp: u64,
+    pub chunks: Vec<ChunkMeta>,
+}
+
+/// Metadata of the translation of one chunk
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChunkMeta {
+    pub index: usize,
+    pub chunk_type: String,
+    pub source_hash: String,
+    pub translation: String,
+    pub model: String,
+    pub char_count: usize,
+    pub word_count: usize,
+    pub distmin: Option<usize>,
+    pub attempts: usize,
+    pub used_grammar: bool,
+    pub complexity: f64,
+    pub elapsed_ms: u64,
+    pub failed: bool,
+    pub too_long: bool,
+    /// source terms of the glossary corrected in the translation
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub glossary_corrections: Vec<String>,
+    pub started_at: u64,
+    pub finished_at: u64,
+}
+
+/// Number of milliseconds since the Unix epoch
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Name of the metadata file associated to a translated file
+pub fn meta_file_name(output_file_name: &str) -> String {
+    let stem = output_file_name
+        .strip_suffix(".tex")
+        .unwrap_or(output_file_name);
+    format!("{}_meta.json", stem)
+}
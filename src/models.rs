@@ -0,0 +1,97 @@
+//! Registry of the Textsynth models known by `trsltx`.
+//!
+//! A model is selected with the `-m` option either by its Textsynth id
+//! (e.g. `mixtral_47B_instruct`) or by a short alias (e.g. `mistral47b`).
+
+use tracing::warn;
+
+/// A model of the Textsynth server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelEntry {
+    /// Textsynth engine id
+    pub id: String,
+    /// short names accepted by the `-m` option
+    pub aliases: Vec<String>,
+    /// URL of the completion API
+    pub url: String,
+    /// true if the server accepts a grammar for constraining the output
+    pub supports_grammar: bool,
+    /// size of the context window in tokens (prompt and answer)
+    pub context_window_tokens: usize,
+}
+
+impl ModelEntry {
+    pub fn new(
+        id: &str,
+        aliases: &[&str],
+        supports_grammar: bool,
+        context_window_tokens: usize,
+    ) -> ModelEntry {
+        ModelEntry {
+            id: id.to_string(),
+            aliases: aliases.iter().map(|a| a.to_string()).collect(),
+            url: format!("https://api.textsynth.com/v1/engines/{}/completions", id),
+            supports_grammar,
+            context_window_tokens,
+        }
+    }
+
+    /// true if `name` is the id or an alias of the model
+    pub fn matches(&self, name: &str) -> bool {
+        self.id == name || self.aliases.iter().any(|a| a == name)
+    }
+}
+
+/// Default model, when the name given by the user is unknown
+pub const DEFAULT_MODEL_ID: &str = "mistral_7B_instruct";
+
+/// List of the available models
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    models: Vec<ModelEntry>,
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        ModelRegistry {
+            models: vec![
+                ModelEntry::new("mistral_7B_instruct", &["mistral7b"], true, 8192),
+                ModelEntry::new(
+                    "mixtral_47B_instruct",
+                    &["mistral47b", "mixtral47b"],
+                    true,
+                    32768,
+                ),
+            ],
+        }
+    }
+}
+
+impl ModelRegistry {
+    /// Add a model, for instance a new model of the Textsynth server
+    pub fn register(&mut self, entry: ModelEntry) {
+        self.models.push(entry);
+    }
+
+    pub fn models(&self) -> &[ModelEntry] {
+        &self.models
+    }
+
+    /// Find a model by its id or one of its aliases
+    pub fn find(&self, name: &str) -> Option<&ModelEntry> {
+        self.models.iter().find(|m| m.matches(name))
+    }
+
+    /// Find a model, or return the default model if the name is unknown
+    pub fn find_or_default(&self, name: &str) -> ModelEntry {
+        match self.find(name) {
+            Some(entry) => entry.clone(),
+            None => {
+                warn!("unknown model {}, use {} instead", name, DEFAULT_MODEL_ID);
+                self.find(DEFAULT_MODEL_ID)
+                    .cloned()
+                    .unwrap_or_else(|| ModelEntry::new(DEFAULT_MODEL_ID, &[], true, 8192))
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Progress of a translation, reported to a callback given by the library user
+//! (for instance for updating a progress bar in a GUI).
+//!
+//! The events are received by a closure (`Trsltx::set_progress_callback`)
+//! or by the methods of a `TranslationObserver` (`Trsltx::set_observer`).
+//! The chunks are indexed from 0, in the order of the document.
+
+use std::time::Duration;
+
+/// A step of the translation
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    /// a chunk is sent to the translator
+    ChunkStarted {
+        index: usize,
+        total: usize,
+        char_count: usize,
+    },
+    /// a chunk has been translated, `distmin` is the syntax distance
+    /// of the best translation, `attempt` the number of requests
+    /// and `elapsed` the time spent on them
+    ChunkTranslated {
+        index: usize,
+        distmin: usize,
+        attempt: u32,
+        elapsed: std::time::Duration,
+    },
+    /// a chunk is sent again because the previous translation was not close
+    /// enough to the source; `attempt` is the number of the new request, from 2
+    ChunkRetried { index: usize, attempt: u32 },
+    /// the translation of a chunk failed, the chunk is left unchanged
+    ChunkFailed { index: usize, error: String },
+    /// a chunk is copied without translation (ignored or too long)
+    ChunkSkipped { index: usize },
+    /// all the chunks have been processed
+    Done { total_chunks: usize },
+}
+
+impl ProgressEvent {
+    /// Call the method of `observer` corresponding to the event
+    pub fn notify(&self, observer: &dyn TranslationObserver) {
+        match self {
+            ProgressEvent::ChunkStarted {
+                index,
+                total,
+                char_count,
+            } => observer.on_chunk_start(*index, *total, *char_count),
+            ProgressEvent::ChunkTranslated {
+                index,
+                distmin,
+                attempt,
+                elapsed,
+            } => observer.on_chunk_done(*index, *distmin, *attempt, *elapsed),
+            ProgressEvent::ChunkRetried { index, attempt } => observer.on_retry(*index, *attempt),
+            ProgressEvent::ChunkFailed { index, error } => observer.on_chunk_failed(*index, error),
+            ProgressEvent::ChunkSkipped { index } => observer.on_chunk_skipped(*index),
+            ProgressEvent::Done { total_chunks } => observer.on_done(*total_chunks),
+        }
+    }
+}
+
+/// Observer of the translation, with a method for each `ProgressEvent`.
+/// The methods do nothing by default: implement only the useful ones.
+///
+/// ```no_run
+/// struct Log;
+///
+/// impl trsltx::progress::TranslationObserver for Log {
+///     fn on_chunk_done(&self, index: usize, _distmin: usize, _attempts: u32, _elapsed: std::time::Duration) {
+///         println!("chunk {} translated", index + 1);
+///     }
+/// }
+/// ```
+pub trait TranslationObserver: Send {
+    /// a chunk is sent to the translator
+    fn on_chunk_start(&self, _index: usize, _total: usize, _char_count: usize) {}
+    /// a chunk has been translated after `attempts` requests
+    fn on_chunk_done(&self, _index: usize, _distmin: usize, _attempts: u32, _elapsed: Duration) {}
+    /// the translation of a chunk failed, the chunk is left unchanged
+    fn on_chunk_failed(&self, _index: usize, _error: &str) {}
+    /// a chunk is sent again, `attempt` is the number of the new request
+    fn on_retry(&self, _index: usize, _attempt: u32) {}
+    /// a chunk is copied without translation
+    fn on_chunk_skipped(&self, _index: usize) {}
+    /// all the chunks have been processed
+    fn on_done(&self, _total_chunks: usize) {}
+}
+
+/// The callback receiving the progress events
+pub struct ProgressCallback(pub Box<dyn Fn(ProgressEvent) + Send>);
+
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ProgressCallback")
+    }
+}
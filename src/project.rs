@@ -0,0 +1,284 @@
+//! Translation of a whole LaTeX project from its main file (`trsltx project main.tex`).
+//!
+//! The documents of the project are found from the main file by following the
+//! `\input`, `\include`, `\subfile` and `\subfileinclude` commands of the bodies,
+//! and the bibliography files by `\bibliography` and `\addbibresource`. The files
+//! included in a preamble (macros...) are not translated.
+//!
+//! Each document is translated into the same path of a parallel directory, `paper_en/`
+//! for the project `paper/` by default, so that the include commands are unchanged.
+//! The other files of the project (figures, bibliography, styles, macros...) are copied
+//! as they are: the translated project compiles as the original one.
+
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, warn};
+
+use crate::{
+    command_args, decode_latex, extract_newtheorem_names, find_uncommented, protect_comments,
+    Trsltx, TrsltxBuilder, TrsltxError, INCLUDE_COMMANDS, MAX_SUBFILE_DEPTH,
+};
+
+/// Extensions of the files written by the compilation, which are not copied
+pub const BUILD_EXTENSIONS: [&str; 10] = [
+    "aux",
+    "log",
+    "toc",
+    "lof",
+    "lot",
+    "out",
+    "blg",
+    "fls",
+    "fdb_latexmk",
+    "synctex.gz",
+];
+
+/// A document of the project, to be translated
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectFile {
+    /// path relative to the directory of the main file
+    pub path: PathBuf,
+    /// included by `\input` or `\include`, without preamble
+    pub fragment: bool,
+}
+
+/// The files of a project, found from its main file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Project {
+    /// directory of the main file
+    pub root: PathBuf,
+    /// the documents, from the main file, in the order of inclusion
+    pub documents: Vec<ProjectFile>,
+    /// the bibliography files, relative to the root directory
+    pub bib_files: Vec<PathBuf>,
+    // environments defined in the preamble of the main file, used by the fragments
+    user_environments: Vec<String>,
+}
+
+// LaTeX code of a file, without the \r characters
+fn read_latex(path: &Path) -> Result<String, TrsltxError> {
+    let name = path.to_string_lossy();
+    let bytes = std::fs::read(path).map_err(|e| TrsltxError::io(&name, e))?;
+    Ok(decode_latex(&bytes)?.replace('\r', ""))
+}
+
+impl Project {
+    /// Find the documents and the bibliography files of the project of `main_file`.
+    /// The included files that do not exist or are outside the directory of the main file
+    /// are skipped with a warning. A file including itself is an error.
+    pub fn discover(main_file: &str) -> Result<Project, TrsltxError> {
+        let main = Path::new(main_file)
+            .canonicalize()
+            .map_err(|e| TrsltxError::io(main_file, e))?;
+        let mut project = Project {
+            root: main.parent().map(|p| p.to_path_buf()).unwrap_or_default(),
+            documents: vec![],
+            bib_files: vec![],
+            user_environments: vec![],
+        };
+        let name = main.file_name().map(PathBuf::from).unwrap_or_default();
+        project.visit(name, false, &mut vec![])?;
+        Ok(project)
+    }
+
+    // add the document `path` and the files it includes
+    // stack: the documents being visited, from the main file to `path`
+    fn visit(
+        &mut self,
+        path: PathBuf,
+        fragment: bool,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<(), TrsltxError> {
+        debug!("project file {}", path.display());
+        let latex = read_latex(&self.root.join(&path))?;
+        let latex = protect_comments(&latex, vec![]).0;
+        let body = if fragment {
+            latex.as_str()
+        } else {
+            let begin = "\\begin{document}";
+            let ib = find_uncommented(&latex, begin).ok_or_else(|| {
+                TrsltxError::Parse(format!("No \\begin{{document}} in {}", path.display()))
+            })?;
+            if stack.is_empty() {
+                self.user_environments = extract_newtheorem_names(&latex[..ib]);
+            }
+            &latex[ib + begin.len()..]
+        };
+        // the paths of \input and \include are relative to the main file,
+        // the paths of the subfiles to the including file
+        let dir = |fragment: bool| {
+            if fragment {
+                PathBuf::new()
+            } else {
+                path.parent().map(|p| p.to_path_buf()).unwrap_or_default()
+            }
+        };
+        for (_, arg) in command_args(&latex, "bibliography")
+            .into_iter()
+            .chain(command_args(&latex, "addbibresource"))
+        {
+            for name in arg.split(',') {
+                let name = name.trim();
+                let name = if name.ends_with(".bib") {
+                    name.to_string()
+                } else {
+                    format!("{}.bib", name)
+                };
+                if let Some(bib) = self.relative_path(&dir(fragment).join(name), "bibliography") {
+                    if !self.bib_files.contains(&bib) {
+                        self.bib_files.push(bib);
+                    }
+                }
+            }
+        }
+        self.documents.push(ProjectFile {
+            path: path.clone(),
+            fragment,
+        });
+        let mut includes: Vec<(usize, String, bool)> = INCLUDE_COMMANDS
+            .iter()
+            .flat_map(|&(cmd, fragment)| {
+                command_args(body, cmd)
+                    .into_iter()
+                    .map(move |(pos, sub)| (pos, sub, fragment))
+            })
+            .collect();
+        includes.sort();
+        stack.push(path.clone());
+        for (_, sub, sub_fragment) in includes {
+            let file = if sub.ends_with(".tex") {
+                sub
+            } else {
+                format!("{}.tex", sub)
+            };
+            let Some(sub_path) = self.relative_path(&dir(sub_fragment).join(file), "included")
+            else {
+                continue;
+            };
+            if stack.contains(&sub_path) {
+                return Err(TrsltxError::Parse(format!(
+                    "Cycle in the included files: {} includes itself",
+                    sub_path.display()
+                )));
+            }
+            if stack.len() > MAX_SUBFILE_DEPTH {
+                return Err(TrsltxError::Parse(format!(
+                    "Files nested deeper than {} levels: {}",
+                    MAX_SUBFILE_DEPTH,
+                    sub_path.display()
+                )));
+            }
+            // a file included twice is translated once
+            if !self.documents.iter().any(|d| d.path == sub_path) {
+                self.visit(sub_path, sub_fragment, stack)?;
+            }
+        }
+        stack.pop();
+        Ok(())
+    }
+
+    // path relative to the root directory of an existing file of the project
+    fn relative_path(&self, path: &Path, kind: &str) -> Option<PathBuf> {
+        let Ok(canonical) = self.root.join(path).canonicalize() else {
+            warn!("{} file {} not found", kind, path.display());
+            return None;
+        };
+        match canonical.strip_prefix(&self.root) {
+            Ok(relative) => Some(relative.to_path_buf()),
+            Err(_) => {
+                warn!(
+                    "{} file {} is outside the project directory, it is left out",
+                    kind,
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    /// Directory of the translation next to the project: `paper_en` for the project `paper`
+    pub fn default_output_dir(&self, output_lang: &str) -> PathBuf {
+        let name = self
+            .root
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "project".to_string());
+        self.root
+            .with_file_name(format!("{}_{}", name, output_lang))
+    }
+
+    /// Copy the files of the project which are not translated into `out_dir`,
+    /// with the same paths. The hidden files, the files written by the compilation
+    /// (see `BUILD_EXTENSIONS`) and `out_dir` itself are skipped.
+    /// Returns the number of copied files.
+    pub fn copy_assets(&self, out_dir: &Path) -> Result<usize, TrsltxError> {
+        let io = |path: &Path, e| TrsltxError::io(&path.to_string_lossy(), e);
+        std::fs::create_dir_all(out_dir).map_err(|e| io(out_dir, e))?;
+        let out_dir_canonical = out_dir.canonicalize().map_err(|e| io(out_dir, e))?;
+        let mut count = 0;
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            let source_dir = self.root.join(&dir);
+            for entry in std::fs::read_dir(&source_dir).map_err(|e| io(&source_dir, e))? {
+                let entry = entry.map_err(|e| io(&source_dir, e))?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                let path = dir.join(&name);
+                let source = self.root.join(&path);
+                if name.starts_with('.') {
+                    continue;
+                }
+                if source.is_dir() {
+                    if source.canonicalize().ok().as_ref() != Some(&out_dir_canonical) {
+                        dirs.push(path);
+                    }
+                    continue;
+                }
+                let build = BUILD_EXTENSIONS
+                    .iter()
+                    .any(|ext| name.ends_with(&format!(".{}", ext)));
+                if build || self.documents.iter().any(|d| d.path == path) {
+                    continue;
+                }
+                let target = out_dir.join(&path);
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent).map_err(|e| io(parent, e))?;
+                }
+                std::fs::copy(&source, &target).map_err(|e| io(&target, e))?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Read the document `file` for its translation into the same path of `out_dir`.
+    /// The document is split in memory if it has no split marker (see
+    /// `TrsltxBuilder::split_length`), and the citation keys of the bibliography files
+    /// of the project are protected.
+    pub fn document(
+        &self,
+        builder: &mut TrsltxBuilder,
+        file: &ProjectFile,
+        out_dir: &Path,
+    ) -> Result<Trsltx, TrsltxError> {
+        let output = out_dir.join(&file.path);
+        if let Some(parent) = output.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| TrsltxError::io(&parent.to_string_lossy(), e))?;
+        }
+        let mut trsltx = builder
+            .input_file(&self.root.join(&file.path).to_string_lossy())
+            .output_file(&output.to_string_lossy())
+            .build()?;
+        trsltx.fragment = file.fragment;
+        trsltx.include_dir = Some(self.root.clone());
+        trsltx.read_file()?;
+        if file.fragment {
+            trsltx.user_environments = self.user_environments.clone();
+        }
+        trsltx.insert_splits(trsltx.split_length.min(trsltx.max_chunk_length()));
+        for bib in self.bib_files.iter() {
+            trsltx.read_bib_file(&self.root.join(bib).to_string_lossy())?;
+        }
+        Ok(trsltx)
+    }
+}
@@ -0,0 +1,212 @@
+//! Summary of a translation run in JSON (`--report` option), for the CI pipelines.
+//!
+//! ```json
+//! {
+//!   "model": "mistral47b",
+//!   "chunks": 12,
+//!   "translated_chunks": 9,
+//!   "copied_chunks": 2,
+//!   "failed_chunks": 1,
+//!   "too_long_chunks": 0,
+//!   "sent_chunks": 10,
+//!   "source_chars": 15320,
+//!   "translated_chars": 14980,
+//!   "requests": 11,
+//!   "estimated_tokens": 14200,
+//!   "total_latency_ms": 52000,
+//!   "mean_latency_ms": 5200,
+//!   "max_latency_ms": 9100,
+//!   "mean_distance": 0.4,
+//!   "max_distance": 3,
+//!   "warnings": ["chunk 4: label sec:un lost"]
+//! }
+//! ```
+//!
+//! The copied chunks are the chunks left unchanged on purpose (ignored regions,
+//! verbatim environments, mathematics, empty chunks...), the failed chunks are left unchanged
+//! because the server did not give a translation, and the too long chunks because they
+//! exceed the context of the model (see `Trsltx::untranslated_chunks`).
+//! The sent chunks are the chunks sent to the server, failed or not, and
+//! the latencies are the times spent on their requests,
+//! including the retries. The distances are the syntax distances between the chunks
+//! and their best translations (`null` if no chunk was sent to the server).
+//! The warnings are the label, reference and citation changes, the delimiter
+//! mismatches and the compile errors of the translation.
+//! The included files, translated by `process_subfiles`, are not counted.
+
+use serde::Serialize;
+
+use crate::compile::COMPILE_ERROR_MARK;
+use crate::{Trsltx, TrsltxError};
+
+/// Reason why a chunk to translate was left unchanged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Untranslated {
+    /// the server did not give a translation
+    Failed,
+    /// the chunk exceeds the context of the model
+    TooLong,
+}
+
+impl std::fmt::Display for Untranslated {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Untranslated::Failed => write!(f, "translation failed"),
+            Untranslated::TooLong => write!(f, "too long"),
+        }
+    }
+}
+
+/// Summary of a translation run
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RunReport {
+    pub model: String,
+    pub chunks: usize,
+    pub translated_chunks: usize,
+    pub copied_chunks: usize,
+    pub failed_chunks: usize,
+    pub too_long_chunks: usize,
+    pub sent_chunks: usize,
+    pub source_chars: usize,
+    pub translated_chars: usize,
+    /// requests sent to the server for the chunks, with the retries
+    pub requests: usize,
+    /// estimated tokens of the prompts and of the answers
+    pub estimated_tokens: usize,
+    pub total_latency_ms: u64,
+    pub mean_latency_ms: u64,
+    pub max_latency_ms: u64,
+    pub mean_distance: Option<f64>,
+    pub max_distance: Option<usize>,
+    pub warnings: Vec<String>,
+}
+
+impl RunReport {
+    /// Add the counts of another run, e.g. for the total of a batch.
+    /// The means are weighted by the numbers of chunks sent to the server.
+    pub fn merge(&mut self, other: &RunReport) {
+        let mean = |a: f64, na: usize, b: f64, nb: usize| {
+            (a * na as f64 + b * nb as f64) / (na + nb).max(1) as f64
+        };
+        self.mean_distance = match (self.mean_distance, other.mean_distance) {
+            (Some(a), Some(b)) => Some(mean(a, self.sent_chunks, b, other.sent_chunks)),
+            (a, b) => a.or(b),
+        };
+        self.max_distance = self.max_distance.max(other.max_distance);
+        self.chunks += other.chunks;
+        self.translated_chunks += other.translated_chunks;
+        self.copied_chunks += other.copied_chunks;
+        self.failed_chunks += other.failed_chunks;
+        self.too_long_chunks += other.too_long_chunks;
+        self.sent_chunks += other.sent_chunks;
+        self.source_chars += other.source_chars;
+        self.translated_chars += other.translated_chars;
+        self.requests += other.requests;
+        self.estimated_tokens += other.estimated_tokens;
+        self.total_latency_ms += other.total_latency_ms;
+        self.max_latency_ms = self.max_latency_ms.max(other.max_latency_ms);
+        if self.sent_chunks > 0 {
+            self.mean_latency_ms = self.total_latency_ms / self.sent_chunks as u64;
+        }
+        // the models of the runs, separated by commas
+        if self.model.is_empty() {
+            self.model = other.model.clone();
+        } else if !self.model.split(", ").any(|model| model == other.model) {
+            self.model = format!("{}, {}", self.model, other.model);
+        }
+        self.warnings.extend(other.warnings.iter().cloned());
+    }
+
+    /// Write the report as JSON
+    pub fn write(&self, path: &str) -> Result<(), TrsltxError> {
+        let json =
+            serde_json::to_string_pretty(self).map_err(|e| TrsltxError::io(path, e.into()))?;
+        std::fs::write(path, json).map_err(|e| TrsltxError::io(path, e))
+    }
+}
+
+impl Trsltx {
+    /// Summary of the last translation, see the module `report`
+    pub fn run_report(&self) -> RunReport {
+        let prompt = self.estimate_tokens(self.prompt_template());
+        let mut report = RunReport {
+            model: self.model_name.clone(),
+            chunks: self.chunks_meta.len(),
+            ..RunReport::default()
+        };
+        let mut latencies = vec![];
+        let mut distances = vec![];
+        for (meta, (source, _)) in self.chunks_meta.iter().zip(self.chunks.iter()) {
+            report.source_chars += source.len();
+            report.translated_chars += meta.translation.len();
+            // the empty chunks are not sent to the server
+            match (meta.failed, meta.chunk_type.as_str()) {
+                (true, _) => report.failed_chunks += 1,
+                _ if meta.too_long => report.too_long_chunks += 1,
+                (false, "translate") if !source.trim().is_empty() => report.translated_chunks += 1,
+                _ => report.copied_chunks += 1,
+            }
+            if meta.attempts > 0 {
+                report.requests += meta.attempts;
+                report.estimated_tokens += meta.attempts
+                    * (prompt
+                        + self.estimate_tokens(source)
+                        + self.estimate_tokens(&meta.translation));
+                latencies.push(meta.elapsed_ms);
+            }
+            distances.extend(meta.distmin);
+        }
+        report.sent_chunks = latencies.len();
+        report.total_latency_ms = latencies.iter().sum();
+        report.max_latency_ms = latencies.iter().copied().max().unwrap_or(0);
+        if !latencies.is_empty() {
+            report.mean_latency_ms = report.total_latency_ms / latencies.len() as u64;
+        }
+        report.max_distance = distances.iter().copied().max();
+        if !distances.is_empty() {
+            report.mean_distance =
+                Some(distances.iter().sum::<usize>() as f64 / distances.len() as f64);
+        }
+        report.warnings.extend(
+            self.consistency_report()
+                .issues
+                .iter()
+                .map(|issue| issue.to_string()),
+        );
+        report
+            .warnings
+            .extend(self.delimiter_errors().iter().map(|e| e.to_string()));
+        for (i, trs_chunk) in self.chunks_translated.iter().enumerate() {
+            if let Some(message) = trs_chunk.strip_prefix(COMPILE_ERROR_MARK) {
+                let message = message.lines().next().unwrap_or_default().trim();
+                report
+                    .warnings
+                    .push(format!("chunk {}: compile error: {}", i + 1, message));
+            }
+        }
+        report
+    }
+
+    /// Chunks left unchanged by the last translation although they had to be translated,
+    /// with their numbers from 1
+    pub fn untranslated_chunks(&self) -> Vec<(usize, Untranslated)> {
+        self.chunks_meta
+            .iter()
+            .enumerate()
+            .filter_map(|(i, meta)| {
+                if meta.failed {
+                    Some((i + 1, Untranslated::Failed))
+                } else if meta.too_long {
+                    Some((i + 1, Untranslated::TooLong))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Write the summary of the last translation as JSON
+    pub fn write_run_report(&self, path: &str) -> Result<(), TrsltxError> {
+        self.run_report().write(path)
+    }
+}
@@ -0,0 +1,80 @@
+//! Review of each translated chunk before it is kept (`--interactive` option).
+//!
+//! The library user gives a callback, which receives the source and the translation
+//! of a chunk and decides to accept the translation, to translate the chunk again
+//! (optionally with a hint added to the prompt) or to keep the source unchanged.
+//! The review is only done by `translate_chunks`, not by the concurrent translation.
+
+use crate::{translate_one_chunk, ChunkMeta, Trsltx};
+
+/// A translated chunk submitted to the review
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkReview<'a> {
+    /// chunk number, starting from 0
+    pub index: usize,
+    pub total: usize,
+    pub source: &'a str,
+    pub translation: &'a str,
+}
+
+/// Decision of the reviewer on a translated chunk
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReviewDecision {
+    /// keep the translation
+    Accept,
+    /// translate the chunk again, with an instruction added to the prompt
+    Retry { hint: Option<String> },
+    /// leave the chunk untranslated
+    KeepOriginal,
+}
+
+/// The callback reviewing the translated chunks
+pub struct ReviewCallback(pub Box<dyn Fn(&ChunkReview) -> ReviewDecision + Send>);
+
+impl std::fmt::Debug for ReviewCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ReviewCallback")
+    }
+}
+
+impl Trsltx {
+    /// Call `f` after the translation of each chunk by `translate_chunks`,
+    /// e.g. for asking the user to accept the translation
+    pub fn set_review_callback<F: Fn(&ChunkReview) -> ReviewDecision + Send + 'static>(
+        &mut self,
+        f: F,
+    ) {
+        self.review_callback = Some(ReviewCallback(Box::new(f)));
+    }
+
+    /// Translation of the chunk `i`, until the reviewer accepts it
+    pub(crate) fn translate_reviewed(
+        &self,
+        i: usize,
+        meta: &mut ChunkMeta,
+    ) -> Result<String, String> {
+        let source = self.chunks[i].0.as_str();
+        let mut hint = None;
+        loop {
+            let translation =
+                translate_one_chunk(source, self, meta, self.max_tokens, hint.as_deref())?;
+            let Some(callback) = &self.review_callback else {
+                return Ok(translation);
+            };
+            let review = ChunkReview {
+                index: i,
+                total: self.chunks.len(),
+                source,
+                translation: &translation,
+            };
+            match (callback.0)(&review) {
+                ReviewDecision::Accept => return Ok(translation),
+                ReviewDecision::KeepOriginal => {
+                    meta.glossary_corrections.clear();
+                    return Ok(source.to_string());
+                }
+                ReviewDecision::Retry { hint: new_hint } => hint = new_hint,
+            }
+        }
+    }
+}
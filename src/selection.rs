@@ -0,0 +1,101 @@
+//! Translation of some chunks only (`--chunks` option).
+//!
+//! When a few chunks are badly translated, they can be translated again without
+//! sending the whole document: the other chunks and the preamble are taken from
+//! the existing translation, which must have the same chunks as the source
+//! (the split markers are written in the translation by `trsltx`).
+//! The chunks are numbered from 1, as in the messages of `trsltx`.
+
+use tracing::info;
+
+use crate::{Trsltx, TrsltxError};
+
+/// The existing translation and the chunks to translate again
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PreviousTranslation {
+    /// numbers of the chunks to translate again, from 1
+    pub(crate) selection: Vec<usize>,
+    pub(crate) preamble: String,
+    pub(crate) chunks: Vec<String>,
+}
+
+/// Parse a list of chunk numbers and ranges, e.g. `3,7,10-14`.
+/// The numbers are sorted and deduplicated.
+pub fn parse_chunk_selection(spec: &str) -> Result<Vec<usize>, TrsltxError> {
+    let invalid = |item: &str| {
+        TrsltxError::Config(format!(
+            "Invalid chunk selection {:?} in {:?}: expected numbers from 1 or ranges, e.g. 3,7,10-14",
+            item, spec
+        ))
+    };
+    let mut selection = vec![];
+    for item in spec.split(',').map(|item| item.trim()) {
+        let (first, last) = item.split_once('-').unwrap_or((item, item));
+        let first: usize = first.trim().parse().map_err(|_| invalid(item))?;
+        let last: usize = last.trim().parse().map_err(|_| invalid(item))?;
+        if first == 0 || last < first {
+            return Err(invalid(item));
+        }
+        selection.extend(first..=last);
+    }
+    selection.sort();
+    selection.dedup();
+    Ok(selection)
+}
+
+impl Trsltx {
+    /// Read the existing translation, so that only the chunks of `selection`
+    /// (numbered from 1) are sent to the translator by `translate`.
+    /// To be called after `extract_chunks`.
+    pub fn load_previous_translation(&mut self, selection: &[usize]) -> Result<(), TrsltxError> {
+        if self.output_file_name.is_empty() {
+            return Err(TrsltxError::Config(
+                "The selection of chunks needs an existing translation file".to_string(),
+            ));
+        }
+        let numchunks = self.chunks.len();
+        if let Some(num) = selection.iter().find(|&&num| num == 0 || num > numchunks) {
+            return Err(TrsltxError::Config(format!(
+                "No chunk {} to translate ({} chunks)",
+                num, numchunks
+            )));
+        }
+        let mut previous = self.builder().input_file(&self.output_file_name).build()?;
+        previous.fragment = self.fragment;
+        previous.read_file()?;
+        previous.extract_chunks()?;
+        if previous.chunks.len() != numchunks {
+            return Err(TrsltxError::Parse(format!(
+                "{} has {} chunks and the source {}: translate the whole file again",
+                self.output_file_name,
+                previous.chunks.len(),
+                numchunks
+            )));
+        }
+        info!(
+            "Translate again the chunks {:?} of {}, keep the others",
+            selection, self.output_file_name
+        );
+        self.previous_translation = Some(PreviousTranslation {
+            selection: selection.to_vec(),
+            preamble: previous.preamble,
+            chunks: previous
+                .chunks
+                .into_iter()
+                .map(|(chunk, _)| chunk)
+                .collect(),
+        });
+        Ok(())
+    }
+
+    /// Existing translation of the chunk `i` (from 0) if it is not selected
+    /// for a new translation
+    pub(crate) fn previous_chunk(&self, i: usize) -> Option<&str> {
+        let previous = self.previous_translation.as_ref()?;
+        if previous.selection.contains(&(i + 1)) {
+            None
+        } else {
+            previous.chunks.get(i).map(|chunk| chunk.as_str())
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Statistics of the chunks before the translation (`--stats` option)
+//! and estimate of the cost of the translation (`--dry-run` option).
+//!
+//! They help to tune the split length before sending requests to the server.
+
+use ltxprs::LtxNode;
+
+use crate::{
+    command_args, count_words, preamble_fields, ChunkType, Trsltx, INCLUDE_COMMANDS,
+    PREAMBLE_MAX_TOKENS,
+};
+
+/// Generation speed assumed for the estimate of the translation time, in tokens per second
+pub const TOKENS_PER_SECOND: f64 = 30.0;
+
+/// Statistics of one chunk
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkStat {
+    /// chunk number, starting from 0
+    pub index: usize,
+    pub chunk_type: ChunkType,
+    pub char_count: usize,
+    pub word_count: usize,
+    /// the parser ltxprs accepts the chunk, so that a grammar can be generated
+    pub parse_ok: bool,
+    /// estimated number of tokens of the prompt and of the answer,
+    /// 0 for the chunks left unchanged
+    pub estimated_tokens: usize,
+}
+
+/// Estimate of the requests of a translation, computed without network call
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CostEstimate {
+    /// number of chunks, translated or not
+    pub chunks: usize,
+    /// chunks sent to the server
+    pub translated_chunks: usize,
+    /// fields of the preamble sent to the server (title, abstract...)
+    pub preamble_fields: usize,
+    /// estimated tokens of the prompts
+    pub prompt_tokens: usize,
+    /// estimated tokens of the answers
+    pub completion_tokens: usize,
+    /// maximal number of attempts for one request, see `RetryPolicy`
+    pub max_attempts: usize,
+    /// files included with `\input`, `\include`..., which are not counted
+    pub subfiles: usize,
+}
+
+impl CostEstimate {
+    /// Number of requests if no chunk is retried
+    pub fn requests(&self) -> usize {
+        self.translated_chunks + self.preamble_fields
+    }
+
+    pub fn total_tokens(&self) -> usize {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// Cost for a price per million tokens, if no chunk is retried.
+    /// In the worst case, each request is sent `max_attempts` times.
+    pub fn cost(&self, price_per_million_tokens: f64) -> f64 {
+        self.total_tokens() as f64 * price_per_million_tokens / 1e6
+    }
+
+    /// Translation time in seconds with one request at a time, if no chunk is retried
+    pub fn seconds(&self) -> f64 {
+        self.completion_tokens as f64 / TOKENS_PER_SECOND
+    }
+}
+
+impl Trsltx {
+    /// Statistics of the chunks given by `extract_chunks`
+    pub fn split_stats(&self) -> Vec<ChunkStat> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .map(|(index, (chunk, t))| {
+                // the empty chunks are not sent to the server
+                let translated = *t == ChunkType::Translate && !chunk.trim().is_empty();
+                ChunkStat {
+                    index,
+                    chunk_type: t.clone(),
+                    char_count: chunk.len(),
+                    word_count: if translated { count_words(chunk) } else { 0 },
+                    parse_ok: !matches!(LtxNode::new(chunk), LtxNode::Problem(_)),
+                    // the answer is as long as the chunk
+                    estimated_tokens: if translated {
+                        self.estimate_tokens(self.prompt_template())
+                            + 2 * self.estimate_tokens(chunk)
+                    } else {
+                        0
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Estimate of the requests needed to translate the chunks given by
+    /// `extract_chunks` and the preamble. Nothing is sent to the server.
+    pub fn cost_estimate(&self) -> CostEstimate {
+        let prompt = self.estimate_tokens(self.prompt_template());
+        let mut estimate = CostEstimate {
+            chunks: self.chunks.len(),
+            max_attempts: self.retry_policy.max_attempts,
+            subfiles: INCLUDE_COMMANDS
+                .iter()
+                .map(|(cmd, _)| command_args(&self.body, cmd).len())
+                .sum(),
+            ..CostEstimate::default()
+        };
+        for (chunk, t) in self.chunks.iter() {
+            if *t == ChunkType::Translate && !chunk.trim().is_empty() {
+                let tokens = self.estimate_tokens(chunk);
+                estimate.translated_chunks += 1;
+                estimate.prompt_tokens += prompt + tokens;
+                estimate.completion_tokens += tokens;
+            }
+        }
+        if self.translate_preamble {
+            for (start, end) in preamble_fields(&self.preamble) {
+                let tokens = self.estimate_tokens(&self.preamble[start..end]);
+                estimate.preamble_fields += 1;
+                estimate.prompt_tokens += prompt + tokens;
+                estimate.completion_tokens += tokens.min(PREAMBLE_MAX_TOKENS);
+            }
+        }
+        estimate
+    }
+
+    /// Insert the split markers in the body, as in the split file written
+    /// with `generate_split_latex`. A body already split is unchanged.
+    pub fn insert_splits(&mut self, split_length: usize) {
+        if !self.body.contains(self.markers.split.as_str()) {
+            self.body = self.split_body(split_length);
+        }
+    }
+}
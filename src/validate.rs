@@ -0,0 +1,166 @@
+//! Balance of the delimiters of a LaTeX string: braces and environments.
+//!
+//! Even with the grammar, the LLM may produce a translation with a missing brace
+//! or an orphaned `\end{...}`. The translation is checked before being written.
+
+use std::fmt;
+
+/// Nature of a delimiter mismatch
+#[derive(Debug, Clone, PartialEq)]
+pub enum DelimiterError {
+    /// `{` without the closing brace
+    UnclosedBrace,
+    /// `}` without the opening brace
+    UnmatchedBrace,
+    /// `\begin{env}` without `\end{env}`
+    UnclosedEnvironment(String),
+    /// `\end{env}` without `\begin{env}`
+    UnmatchedEnd(String),
+    /// `\end{found}` closing `\begin{expected}`
+    MismatchedEnd { expected: String, found: String },
+}
+
+impl fmt::Display for DelimiterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DelimiterError::UnclosedBrace => write!(f, "unclosed brace"),
+            DelimiterError::UnmatchedBrace => write!(f, "unmatched closing brace"),
+            DelimiterError::UnclosedEnvironment(env) => {
+                write!(f, "unclosed \\begin{{{}}}", env)
+            }
+            DelimiterError::UnmatchedEnd(env) => write!(f, "unmatched \\end{{{}}}", env),
+            DelimiterError::MismatchedEnd { expected, found } => {
+                write!(f, "\\end{{{}}} closes \\begin{{{}}}", found, expected)
+            }
+        }
+    }
+}
+
+/// A delimiter mismatch in the translation
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    /// chunk number, starting from 1, or `None` for the whole translated body
+    pub chunk: Option<usize>,
+    /// line number in the chunk (or in the body), starting from 1
+    pub line: usize,
+    /// column number, starting from 1
+    pub column: usize,
+    pub kind: DelimiterError,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.chunk {
+            Some(chunk) => write!(f, "chunk {}, ", chunk)?,
+            None => write!(f, "body, ")?,
+        }
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.kind
+        )
+    }
+}
+
+/// Line and column, starting from 1, of a byte offset
+fn line_column(latex: &str, offset: usize) -> (usize, usize) {
+    let before = &latex[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    (line, column)
+}
+
+/// Name of the environment of `\begin{name}` or `\end{name}` starting at `pos`
+fn env_name(latex: &str, pos: usize) -> Option<&str> {
+    latex[pos..]
+        .split_once('}')
+        .map(|(name, _)| name)
+        .filter(|name| !name.contains('\n'))
+}
+
+/// Check that the braces and the environments of a LaTeX string are balanced.
+/// The escaped characters, the comments and the content of the verbatim-like
+/// environments are ignored. The errors have no chunk number.
+pub fn check_delimiters(latex: &str) -> Vec<ValidationError> {
+    let mut errors = vec![];
+    let mut push = |offset: usize, kind: DelimiterError| {
+        let (line, column) = line_column(latex, offset);
+        errors.push(ValidationError {
+            chunk: None,
+            line,
+            column,
+            kind,
+        });
+    };
+    let mut braces = vec![];
+    let mut envs: Vec<(usize, &str)> = vec![];
+    let mut i = 0;
+    while i < latex.len() {
+        let rest = &latex[i..];
+        let c = rest.chars().next().unwrap_or(' ');
+        if rest.starts_with("\\begin{") {
+            if let Some(name) = env_name(latex, i + 7) {
+                if crate::VERBATIM_ENVS.contains(&name) {
+                    // jump after the end of the verbatim environment
+                    let end = format!("\\end{{{}}}", name);
+                    match rest.find(end.as_str()) {
+                        Some(ie) => i += ie + end.len(),
+                        None => {
+                            push(i, DelimiterError::UnclosedEnvironment(name.to_string()));
+                            i = latex.len();
+                        }
+                    }
+                    continue;
+                }
+                envs.push((i, name));
+                i += 7 + name.len() + 1;
+                continue;
+            }
+        } else if rest.starts_with("\\end{") {
+            if let Some(name) = env_name(latex, i + 5) {
+                match envs.pop() {
+                    Some((_, expected)) if expected == name => {}
+                    Some((pos, expected)) => {
+                        push(
+                            i,
+                            DelimiterError::MismatchedEnd {
+                                expected: expected.to_string(),
+                                found: name.to_string(),
+                            },
+                        );
+                        // the \end probably closes an outer environment
+                        if !envs.iter().any(|(_, e)| *e == name) {
+                            envs.push((pos, expected));
+                        } else {
+                            while let Some((_, e)) = envs.pop() {
+                                if e == name {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    None => push(i, DelimiterError::UnmatchedEnd(name.to_string())),
+                }
+                i += 5 + name.len() + 1;
+                continue;
+            }
+        }
+        match c {
+            // skip the escaped character
+            '\\' => i += rest.chars().nth(1).map_or(0, |c| c.len_utf8()),
+            '%' => i += rest.find('\n').unwrap_or(rest.len()),
+            '{' => braces.push(i),
+            '}' if braces.pop().is_none() => push(i, DelimiterError::UnmatchedBrace),
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    for pos in braces {
+        push(pos, DelimiterError::UnclosedBrace);
+    }
+    for (pos, name) in envs {
+        push(pos, DelimiterError::UnclosedEnvironment(name.to_string()));
+    }
+    errors.sort_by_key(|e| (e.line, e.column));
+    errors
+}